@@ -14,4 +14,6 @@ fn main() {
         .unwrap();
 
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
+    // The runner tells you to point gdb at it in debug mode
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
 }
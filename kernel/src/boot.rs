@@ -0,0 +1,263 @@
+// Boot sequence
+//
+// The kernel comes up in a fixed order of stages: serial before anything logs, the GDT before the
+// IDT (the double fault handler uses an IST stack from the TSS), the heap before anything allocates,
+// ACPI before the APIC and the APIC before the drivers that need interrupts. Each stage returns a
+// Result and `run` stops at the first one that fails, so a broken boot says where it broke.
+
+use core::fmt;
+
+use bootloader_api::{
+    BootInfo,
+    info::{FrameBuffer, MemoryRegions, Optional},
+};
+use x86_64::{VirtAddr, structures::paging::OffsetPageTable};
+
+use crate::{
+    drivers::{self, apic::ApicAddresses},
+    gdt,
+    graphics::{self, Framebuffer},
+    interrupts,
+    mm::{self, allocator, memory::BootInfoFrameAllocator, user::BuddyFrameAllocator},
+    serial_println, tasks,
+};
+
+/// One step of the boot sequence
+pub struct Stage<C> {
+    pub name: &'static str,
+    pub run: fn(&mut C) -> Result<(), &'static str>,
+}
+
+impl<C> Stage<C> {
+    pub const fn new(name: &'static str, run: fn(&mut C) -> Result<(), &'static str>) -> Self {
+        Self { name, run }
+    }
+}
+
+/// The stage that failed and why
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StageError {
+    pub stage: &'static str,
+    pub error: &'static str,
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "boot stage {} failed: {}", self.stage, self.error)
+    }
+}
+
+/// Run `stages` in order, stops at the first one that fails
+pub fn run<C>(context: &mut C, stages: &[Stage<C>]) -> Result<(), StageError> {
+    for stage in stages {
+        (stage.run)(context).map_err(|error| StageError {
+            stage: stage.name,
+            error,
+        })?;
+    }
+
+    Ok(())
+}
+
+/// What the stages hand to each other, filled in as the boot goes on
+pub struct BootContext {
+    memory_regions: &'static MemoryRegions,
+    boot_framebuffer: &'static mut Optional<FrameBuffer>,
+    physical_memory_offset: Option<u64>,
+    rsdp_addr: Option<u64>,
+
+    /// Set by `init_memory`
+    phys_mem_offset: Option<VirtAddr>,
+    mapper: Option<OffsetPageTable<'static>>,
+    /// Set by `init_memory`, empty after `init_heap` handed its frames to the buddy allocator
+    frame_allocator: Option<BootInfoFrameAllocator>,
+    /// Set by `init_graphics`, None when running headless
+    framebuffer: Option<Framebuffer>,
+    /// Set by `init_acpi`
+    apic: Option<ApicAddresses>,
+}
+
+impl BootContext {
+    pub fn new(boot_info: &'static mut BootInfo) -> Self {
+        Self {
+            memory_regions: &boot_info.memory_regions,
+            boot_framebuffer: &mut boot_info.framebuffer,
+            physical_memory_offset: boot_info.physical_memory_offset.into_option(),
+            rsdp_addr: boot_info.rsdp_addr.into_option(),
+            phys_mem_offset: None,
+            mapper: None,
+            frame_allocator: None,
+            framebuffer: None,
+            apic: None,
+        }
+    }
+
+    fn phys_mem_offset(&self) -> Result<VirtAddr, &'static str> {
+        self.phys_mem_offset.ok_or("memory isn't initialized")
+    }
+}
+
+/// The kernel after a successful boot
+pub struct Kernel {
+    pub phys_mem_offset: VirtAddr,
+    pub mapper: OffsetPageTable<'static>,
+    pub framebuffer: Option<Framebuffer>,
+}
+
+const STAGES: [Stage<BootContext>; 10] = [
+    Stage::new("serial", init_serial),
+    Stage::new("gdt", init_gdt),
+    Stage::new("idt", init_idt),
+    Stage::new("memory", init_memory),
+    // Needs the boot allocator for a contiguous back buffer, so it runs before the heap takes over
+    Stage::new("graphics", init_graphics),
+    Stage::new("heap", init_heap),
+    Stage::new("acpi", init_acpi),
+    Stage::new("apic", init_apic),
+    Stage::new("drivers", init_drivers),
+    Stage::new("tasks", init_tasks),
+];
+
+/// Run the boot sequence, logs the stage that failed
+pub fn init(boot_info: &'static mut BootInfo) -> Result<Kernel, StageError> {
+    let mut context = BootContext::new(boot_info);
+
+    if let Err(e) = run(&mut context, &STAGES) {
+        serial_println!("[ERROR] {}", e);
+        return Err(e);
+    }
+
+    Ok(Kernel {
+        phys_mem_offset: context.phys_mem_offset.expect("memory stage ran"),
+        mapper: context.mapper.expect("memory stage ran"),
+        framebuffer: context.framebuffer,
+    })
+}
+
+fn init_serial(_: &mut BootContext) -> Result<(), &'static str> {
+    drivers::init_serial();
+    serial_println!("Hello World!");
+    Ok(())
+}
+
+fn init_gdt(_: &mut BootContext) -> Result<(), &'static str> {
+    gdt::init();
+    Ok(())
+}
+
+fn init_idt(_: &mut BootContext) -> Result<(), &'static str> {
+    interrupts::init();
+    Ok(())
+}
+
+fn init_memory(context: &mut BootContext) -> Result<(), &'static str> {
+    let offset = context
+        .physical_memory_offset
+        .ok_or("the bootloader didn't map the physical memory")?;
+    let offset = VirtAddr::new(offset);
+
+    context.phys_mem_offset = Some(offset);
+    context.mapper = Some(unsafe { mm::memory::init(offset) });
+    context.frame_allocator = Some(unsafe { BootInfoFrameAllocator::init(context.memory_regions) });
+
+    Ok(())
+}
+
+fn init_graphics(context: &mut BootContext) -> Result<(), &'static str> {
+    serial_println!("Initializing graphics...");
+
+    let offset = context.phys_mem_offset()?;
+    let frame_allocator = context
+        .frame_allocator
+        .as_mut()
+        .ok_or("memory isn't initialized")?;
+
+    // No framebuffer isn't an error, we just run headless
+    context.framebuffer =
+        graphics::from_boot_info(context.boot_framebuffer, frame_allocator, offset.as_u64());
+
+    Ok(())
+}
+
+fn init_heap(context: &mut BootContext) -> Result<(), &'static str> {
+    serial_println!("Initializing heap...");
+
+    let offset = context.phys_mem_offset()?;
+    let frame_allocator = context
+        .frame_allocator
+        .as_mut()
+        .ok_or("memory isn't initialized")?;
+
+    log_boot_frames(frame_allocator);
+
+    let handed_off = mm::init(frame_allocator, offset)?;
+    serial_println!("Handed {} KiB to the buddy allocator", handed_off / 1024);
+
+    allocator::log_stats();
+
+    Ok(())
+}
+
+fn init_acpi(context: &mut BootContext) -> Result<(), &'static str> {
+    let offset = context.phys_mem_offset()?;
+    let rsdp_addr = context.rsdp_addr.ok_or("no RSDP from the bootloader")?;
+
+    context.apic = Some(drivers::apic::read_apic_addresses(
+        rsdp_addr as usize,
+        offset,
+    )?);
+
+    // We can still power off through QEMU without it
+    if let Err(e) = drivers::acpi::init_power_off(rsdp_addr as usize, offset) {
+        serial_println!("[WARNING] No ACPI power off: {}", e);
+    }
+
+    // Without it the RTC's year is assumed to be in the 2000s
+    match drivers::acpi::read_century_register(rsdp_addr as usize, offset) {
+        Ok(Some(register)) => drivers::rtc::set_century_register(register),
+        Ok(None) => {}
+        Err(e) => serial_println!("[WARNING] No RTC century register: {}", e),
+    }
+
+    Ok(())
+}
+
+fn init_apic(context: &mut BootContext) -> Result<(), &'static str> {
+    serial_println!("Initializing APIC...");
+
+    let addresses = context.apic.ok_or("ACPI tables weren't read")?;
+    let mapper = context.mapper.as_mut().ok_or("memory isn't initialized")?;
+
+    unsafe { drivers::apic::init(addresses, mapper, &mut BuddyFrameAllocator) };
+
+    Ok(())
+}
+
+fn init_drivers(context: &mut BootContext) -> Result<(), &'static str> {
+    // Headless the cursor has nowhere to go and stays at (0, 0)
+    if let Some(framebuffer) = &context.framebuffer {
+        drivers::mouse::set_bounds(framebuffer.width, framebuffer.height);
+    }
+
+    drivers::init()
+}
+
+fn init_tasks(context: &mut BootContext) -> Result<(), &'static str> {
+    let mapper = context.mapper.as_mut().ok_or("memory isn't initialized")?;
+
+    // Before the first task, so every address space gets the kernel stacks
+    tasks::stack::init(mapper, &mut BuddyFrameAllocator)?;
+    tasks::init();
+    Ok(())
+}
+
+/// Print how fragmented the boot allocator's memory is before it's handed to the buddy allocator
+fn log_boot_frames(frame_allocator: &BootInfoFrameAllocator) {
+    let boot = frame_allocator.fragmentation();
+    serial_println!(
+        "Boot frames: {} KiB free in {} ranges, largest range {} KiB",
+        boot.free_bytes / 1024,
+        boot.range_count,
+        boot.largest_contiguous_bytes / 1024
+    );
+}
@@ -0,0 +1,27 @@
+// CPU bookkeeping
+//
+// Keeps track of how many CPUs the firmware reports and how many are actually running our code.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of usable CPUs reported by the firmware (MADT)
+/// Starts at 1 because we're obviously running on at least one
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Number of CPUs running the kernel, only the BSP until we bring up the APs
+static ONLINE_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Record the number of CPUs the firmware reports
+pub fn set_count(count: usize) {
+    CPU_COUNT.store(count.max(1), Ordering::Relaxed);
+}
+
+/// Number of CPUs the firmware reports, even if we don't use them yet
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// Number of CPUs that are running the kernel
+pub fn online_count() -> usize {
+    ONLINE_COUNT.load(Ordering::Relaxed)
+}
@@ -0,0 +1,271 @@
+// GDB remote serial protocol
+//
+// Just enough of the protocol for a host gdb to look at the kernel when it hits a breakpoint:
+// read registers, read memory and continue. Packets look like `$<data>#<checksum>` where the
+// checksum is the sum of the data bytes modulo 256, as two hex digits.
+
+use alloc::vec::Vec;
+
+use crate::tasks::task::TaskContext;
+
+/// Signal reported when we stop, SIGTRAP
+const STOP_SIGNAL: u8 = 5;
+
+/// Largest packet we accept, reported to gdb with qSupported
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+/// Register file in the order gdb expects for x86_64
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 - r15
+    pub gpr: [u64; 16],
+    pub rip: u64,
+    pub eflags: u32,
+    pub cs: u32,
+    pub ss: u32,
+}
+
+impl From<&TaskContext> for Registers {
+    fn from(context: &TaskContext) -> Self {
+        Self {
+            gpr: [
+                context.rax,
+                context.rbx,
+                context.rcx,
+                context.rdx,
+                context.rsi,
+                context.rdi,
+                context.rbp,
+                context.rsp,
+                context.r8,
+                context.r9,
+                context.r10,
+                context.r11,
+                context.r12,
+                context.r13,
+                context.r14,
+                context.r15,
+            ],
+            rip: context.rip,
+            eflags: context.rflags as u32,
+            cs: context.cs as u32,
+            ss: context.ss as u32,
+        }
+    }
+}
+
+impl Registers {
+    /// Encode for a `g` reply: every register as little endian hex, the data segments are always 0
+    fn encode(&self, out: &mut Vec<u8>) {
+        for reg in self.gpr.iter().chain([&self.rip]) {
+            encode_hex(&reg.to_le_bytes(), out);
+        }
+        // eflags, cs, ss, ds, es, fs, gs are 32 bit
+        for reg in [self.eflags, self.cs, self.ss, 0, 0, 0, 0] {
+            encode_hex(&reg.to_le_bytes(), out);
+        }
+    }
+}
+
+/// What the stub is debugging
+pub trait Target {
+    /// Registers at the point we stopped
+    fn registers(&self) -> Registers;
+
+    /// Fill `buf` with the memory at `addr`, false if any of it isn't mapped
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> bool;
+}
+
+/// What to do after handling a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Send this reply and wait for the next packet
+    Reply(Vec<u8>),
+    /// Resume execution (no reply, gdb gets a stop reply when we stop again)
+    Continue,
+    /// Send "OK" and resume, gdb is gone
+    Detach,
+}
+
+/// Parser state for incoming packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for `$`
+    Idle,
+    Data,
+    Checksum1,
+    Checksum2(u8),
+}
+
+/// Turns the bytes coming from gdb into packets
+pub struct PacketReader {
+    state: State,
+    data: Vec<u8>,
+}
+
+/// Result of feeding a byte to `PacketReader`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// A complete packet with a valid checksum, acknowledge it with `+`
+    Packet(Vec<u8>),
+    /// A complete packet with a bad checksum, ask for it again with `-`
+    BadChecksum,
+}
+
+impl PacketReader {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            data: Vec::new(),
+        }
+    }
+
+    /// Feed the next byte, returns something once a packet is complete
+    /// Acks (`+`/`-`) and anything between packets is ignored.
+    pub fn feed(&mut self, byte: u8) -> Option<Received> {
+        match self.state {
+            State::Idle => {
+                if byte == b'$' {
+                    self.data.clear();
+                    self.state = State::Data;
+                }
+                None
+            }
+            State::Data => {
+                match byte {
+                    b'#' => self.state = State::Checksum1,
+                    // A new packet start means the old one got cut off
+                    b'$' => self.data.clear(),
+                    _ if self.data.len() < MAX_PACKET_SIZE => self.data.push(byte),
+                    _ => self.state = State::Idle,
+                }
+                None
+            }
+            State::Checksum1 => {
+                self.state = match hex_value(byte) {
+                    Some(high) => State::Checksum2(high),
+                    None => State::Idle,
+                };
+                None
+            }
+            State::Checksum2(high) => {
+                self.state = State::Idle;
+
+                let expected = (high << 4) | hex_value(byte)?;
+                if checksum(&self.data) == expected {
+                    Some(Received::Packet(core::mem::take(&mut self.data)))
+                } else {
+                    Some(Received::BadChecksum)
+                }
+            }
+        }
+    }
+}
+
+impl Default for PacketReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sum of the bytes modulo 256
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Wrap data in a packet: `$<data>#<checksum>`
+pub fn frame(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.push(b'#');
+    encode_hex(&[checksum(data)], &mut packet);
+    packet
+}
+
+/// Stop reply sent when we hit a breakpoint (and for `?`)
+pub fn stop_reply() -> Vec<u8> {
+    let mut reply = Vec::from(*b"S");
+    encode_hex(&[STOP_SIGNAL], &mut reply);
+    reply
+}
+
+/// Handle a packet from gdb
+pub fn handle(packet: &[u8], target: &impl Target) -> Action {
+    let Some((&command, args)) = packet.split_first() else {
+        return Action::Reply(Vec::new());
+    };
+
+    match command {
+        b'?' => Action::Reply(stop_reply()),
+        b'g' => {
+            let mut reply = Vec::new();
+            target.registers().encode(&mut reply);
+            Action::Reply(reply)
+        }
+        b'm' => Action::Reply(read_memory(args, target)),
+        b'c' => Action::Continue,
+        b'D' => Action::Detach,
+        // Only one thread, whatever gdb selects is fine
+        b'H' => Action::Reply(Vec::from(*b"OK")),
+        b'q' if args.starts_with(b"Supported") => {
+            let mut reply = Vec::from(*b"PacketSize=");
+            encode_hex(&(MAX_PACKET_SIZE as u16).to_be_bytes(), &mut reply);
+            Action::Reply(reply)
+        }
+        // We're attached to something that was already running
+        b'q' if args == b"Attached" => Action::Reply(Vec::from(*b"1")),
+        // An empty reply tells gdb we don't support the packet
+        _ => Action::Reply(Vec::new()),
+    }
+}
+
+/// `m<addr>,<length>`: read memory, replies with the bytes as hex or an error
+fn read_memory(args: &[u8], target: &impl Target) -> Vec<u8> {
+    let Some((addr, length)) = split_args(args) else {
+        return Vec::from(*b"E22"); // EINVAL
+    };
+    // The reply has two hex digits per byte
+    let length = length.min((MAX_PACKET_SIZE / 2) as u64) as usize;
+
+    let mut buf = alloc::vec![0u8; length];
+    if !target.read_memory(addr, &mut buf) {
+        return Vec::from(*b"E14"); // EFAULT
+    }
+
+    let mut reply = Vec::with_capacity(length * 2);
+    encode_hex(&buf, &mut reply);
+    reply
+}
+
+/// Parse `<hex>,<hex>`
+fn split_args(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|&byte| byte == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &digit| {
+        Some((value << 4) | hex_value(digit)? as u64)
+    })
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn encode_hex(bytes: &[u8], out: &mut Vec<u8>) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for &byte in bytes {
+        out.push(DIGITS[(byte >> 4) as usize]);
+        out.push(DIGITS[(byte & 0xF) as usize]);
+    }
+}
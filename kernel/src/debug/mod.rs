@@ -0,0 +1,87 @@
+// Kernel debugging
+//
+// Runs a GDB stub on the debug serial port (COM2) whenever the kernel or a task hits an int3.
+// Everything else is stopped while gdb is attached, the stub runs in the breakpoint handler.
+
+use uart_16550::SerialPort;
+use x86_64::{VirtAddr, structures::paging::Translate};
+
+use crate::{drivers::serial, mm::memory, tasks::task::TaskContext};
+
+pub mod gdbstub;
+
+use gdbstub::{Action, PacketReader, Received, Registers, Target};
+
+/// Whatever was running when we hit the breakpoint
+struct Stopped {
+    registers: Registers,
+}
+
+impl Target for Stopped {
+    fn registers(&self) -> Registers {
+        self.registers
+    }
+
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> bool {
+        // Through the physical memory mapping, the active page tables decide what's mapped
+        let mapper = unsafe { memory::active_mapper() };
+        let physical_memory_offset = memory::physical_memory_offset();
+
+        for (i, byte) in buf.iter_mut().enumerate() {
+            let Some(virt) = addr
+                .checked_add(i as u64)
+                .and_then(|addr| VirtAddr::try_new(addr).ok())
+            else {
+                return false;
+            };
+            let Some(phys) = mapper.translate_addr(virt) else {
+                return false;
+            };
+            *byte = unsafe { *(physical_memory_offset + phys.as_u64()).as_ptr::<u8>() };
+        }
+
+        true
+    }
+}
+
+/// Hand control to gdb until it continues, called by the breakpoint handler
+/// `context` holds the registers of whatever hit the int3, saved by the handler's entry stub.
+/// Returns false if there is no debug port.
+pub fn enter_debugger(context: &TaskContext) -> bool {
+    let target = Stopped {
+        registers: Registers::from(context),
+    };
+
+    serial::with_debug_port(|port| serve(port, &target)).is_some()
+}
+
+/// Tell gdb we stopped and answer its packets until it continues
+fn serve(port: &mut SerialPort, target: &impl Target) {
+    send_packet(port, &gdbstub::stop_reply());
+
+    let mut reader = PacketReader::new();
+    loop {
+        match reader.feed(port.receive()) {
+            Some(Received::Packet(packet)) => {
+                port.send_raw(b'+');
+
+                match gdbstub::handle(&packet, target) {
+                    Action::Reply(reply) => send_packet(port, &reply),
+                    Action::Continue => return,
+                    Action::Detach => {
+                        send_packet(port, b"OK");
+                        return;
+                    }
+                }
+            }
+            Some(Received::BadChecksum) => port.send_raw(b'-'),
+            None => {}
+        }
+    }
+}
+
+fn send_packet(port: &mut SerialPort, data: &[u8]) {
+    for &byte in &gdbstub::frame(data) {
+        port.send_raw(byte);
+    }
+}
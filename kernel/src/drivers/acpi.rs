@@ -1,5 +1,32 @@
-use acpi::{AcpiTables, Handler};
+use acpi::{AcpiTables, Handler, address::AddressSpace, sdt::fadt::Fadt};
+use spin::Mutex;
 use x86_64::VirtAddr;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::drivers::{pci, pit};
+use crate::{tasks, time};
+
+/// PM1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_TYP_MASK: u16 = 0b111 << SLP_TYP_SHIFT;
+const SLP_EN: u16 = 1 << 13;
+
+/// Size of the header in front of the AML in the DSDT
+const SDT_HEADER_SIZE: usize = 36;
+
+/// AML opcodes we need to find the \_S5 package
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_PREFIX: u8 = b'\\';
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+
+/// Everything `shutdown` needs, read from the ACPI tables at boot
+static POWER_OFF: Mutex<Option<PowerOff>> = Mutex::new(None);
 
 #[derive(Clone)]
 pub struct AcpiHandler {
@@ -37,7 +64,7 @@ impl Handler for AcpiHandler {
     }
 
     fn nanos_since_boot(&self) -> u64 {
-        0 // TODO: Implement timer to get actual uptime
+        time::uptime_nanos()
     }
 
     fn breakpoint(&self) {
@@ -70,14 +97,21 @@ impl Handler for AcpiHandler {
         unimplemented!()
     }
 
-    fn stall(&self, _microseconds: u64) {
-        // TODO: Implement proper stall
-        unimplemented!()
+    fn stall(&self, microseconds: u64) {
+        // Firmware expects a busy-wait here, it may run with interrupts disabled
+        pit::busy_wait_micros(microseconds);
     }
 
-    fn sleep(&self, _milliseconds: u64) {
-        // TODO: Implement proper sleep
-        unimplemented!()
+    fn sleep(&self, milliseconds: u64) {
+        let nanos = milliseconds.saturating_mul(1_000_000);
+
+        // Without interrupts the tick counter stands still
+        if interrupts::are_enabled() {
+            let until = time::deadline_after(time::ticks(), nanos, time::tick_frequency());
+            tasks::sleep_until(until);
+        } else {
+            pit::busy_wait_micros(milliseconds.saturating_mul(1000));
+        }
     }
 
     fn read_io_u16(&self, port: u16) -> u16 {
@@ -92,16 +126,16 @@ impl Handler for AcpiHandler {
         unsafe { x86_64::instructions::port::Port::new(port).read() }
     }
 
-    fn read_pci_u16(&self, _adress: acpi::PciAddress, _offset: u16) -> u16 {
-        unimplemented!()
+    fn read_pci_u16(&self, address: acpi::PciAddress, offset: u16) -> u16 {
+        read_pci(address, offset, 2) as u16
     }
 
-    fn read_pci_u32(&self, _adress: acpi::PciAddress, _offset: u16) -> u32 {
-        unimplemented!()
+    fn read_pci_u32(&self, address: acpi::PciAddress, offset: u16) -> u32 {
+        read_pci(address, offset, 4)
     }
 
-    fn read_pci_u8(&self, _adress: acpi::PciAddress, _offset: u16) -> u8 {
-        unimplemented!()
+    fn read_pci_u8(&self, address: acpi::PciAddress, offset: u16) -> u8 {
+        read_pci(address, offset, 1) as u8
     }
 
     fn read_u16(&self, adress: usize) -> u16 {
@@ -132,17 +166,16 @@ impl Handler for AcpiHandler {
         unsafe { x86_64::instructions::port::Port::new(port).write(value) }
     }
 
-    fn write_pci_u16(&self, _adress: acpi::PciAddress, _offset: u16, _value: u16) {
-        // TODO: Implement PCI write
-        unimplemented!()
+    fn write_pci_u16(&self, address: acpi::PciAddress, offset: u16, value: u16) {
+        write_pci(address, offset, 2, value as u32);
     }
 
-    fn write_pci_u32(&self, _address: acpi::PciAddress, _offset: u16, _value: u32) {
-        unimplemented!()
+    fn write_pci_u32(&self, address: acpi::PciAddress, offset: u16, value: u32) {
+        write_pci(address, offset, 4, value);
     }
 
-    fn write_pci_u8(&self, _address: acpi::PciAddress, _offset: u16, _value: u8) {
-        unimplemented!()
+    fn write_pci_u8(&self, address: acpi::PciAddress, offset: u16, value: u8) {
+        write_pci(address, offset, 1, value as u32);
     }
 
     fn write_u16(&self, address: usize, value: u16) {
@@ -162,11 +195,222 @@ impl Handler for AcpiHandler {
     }
 }
 
+/// The function `address` points to, None on segments other than 0 (we only have the legacy mechanism)
+fn pci_function(address: acpi::PciAddress) -> Option<pci::Function> {
+    (address.segment() == 0).then(|| pci::Function {
+        bus: address.bus(),
+        device: address.device(),
+        function: address.function(),
+    })
+}
+
+/// Missing functions read as all ones, like on real hardware
+fn read_pci(address: acpi::PciAddress, offset: u16, size: u16) -> u32 {
+    match pci_function(address) {
+        Some(function) => pci::read(function, offset, size),
+        None => u32::MAX,
+    }
+}
+
+fn write_pci(address: acpi::PciAddress, offset: u16, size: u16, value: u32) {
+    if let Some(function) = pci_function(address) {
+        pci::write(function, offset, size, value);
+    }
+}
+
+/// SLP_TYP values for the PM1a and PM1b control registers to enter a sleep state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// PM1 control value that enters the sleep state `slp_typ`, keeping the bits of `current` we don't touch
+pub fn sleep_control_value(current: u16, slp_typ: u8) -> u16 {
+    (current & !SLP_TYP_MASK) | ((slp_typ as u16) << SLP_TYP_SHIFT & SLP_TYP_MASK) | SLP_EN
+}
+
+/// Find the SLP_TYP values of the S5 (soft off) state in the AML of the DSDT
+///
+/// A full AML interpreter is overkill for this, every DSDT defines it as a plain
+/// `Name (_S5, Package () { a, b, ... })` that we can pick out of the byte stream.
+pub fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|(_, name)| name == b"_S5_")
+        .find_map(|(i, _)| {
+            // A name definition, maybe with the root prefix
+            let named = match i {
+                0 => false,
+                1 => aml[0] == NAME_OP,
+                _ => aml[i - 1] == NAME_OP || (aml[i - 1] == ROOT_PREFIX && aml[i - 2] == NAME_OP),
+            };
+            if !named {
+                return None;
+            }
+            parse_s5_package(&aml[i + 4..])
+        })
+}
+
+fn parse_s5_package(aml: &[u8]) -> Option<SleepType> {
+    let (&op, rest) = aml.split_first()?;
+    if op != PACKAGE_OP {
+        return None;
+    }
+
+    // The top two bits of the PkgLength lead byte say how many more bytes it has
+    let length_bytes = (*rest.first()? >> 6) as usize + 1;
+    // Then the number of elements
+    let rest = rest.get(length_bytes + 1..)?;
+
+    let (a, rest) = parse_integer(rest)?;
+    let (b, _) = parse_integer(rest)?;
+    Some(SleepType { a, b })
+}
+
+/// Parse an integer constant, SLP_TYP is only 3 bits so we keep the low byte
+fn parse_integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+    let (&op, rest) = aml.split_first()?;
+    let size = match op {
+        ZERO_OP => return Some((0, rest)),
+        ONE_OP => return Some((1, rest)),
+        BYTE_PREFIX => 1,
+        WORD_PREFIX => 2,
+        DWORD_PREFIX => 4,
+        _ => return None,
+    };
+
+    Some((*rest.first()?, rest.get(size..)?))
+}
+
+/// The registers and values to power off the machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerOff {
+    pub pm1a_control: u16,
+    pub pm1b_control: Option<u16>,
+    pub s5: SleepType,
+    /// Port to ask the firmware to hand the registers over to us, 0 if it already did
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+}
+
+/// Port of a PM1 control block, we only support them in I/O space
+fn io_port(address: acpi::address::GenericAddress) -> Result<u16, &'static str> {
+    match address.address_space {
+        AddressSpace::SystemIo => {
+            u16::try_from(address.address).map_err(|_| "PM1 control port out of range")
+        }
+        _ => Err("PM1 control block isn't in I/O space"),
+    }
+}
+
+/// Read what we need to power off from the FADT and DSDT
+pub fn read_power_off(
+    rsdp_addr: usize,
+    physical_memory_offset: VirtAddr,
+) -> Result<PowerOff, &'static str> {
+    let tables = read_acpi_tables(rsdp_addr, physical_memory_offset)?;
+    let fadt = tables.find_table::<Fadt>().ok_or("No FADT")?;
+
+    let pm1a_control = io_port(
+        fadt.pm1a_control_block()
+            .map_err(|_| "Bad PM1a control block")?,
+    )?;
+    let pm1b_control = match fadt.pm1b_control_block() {
+        Ok(Some(address)) => Some(io_port(address)?),
+        Ok(None) => None,
+        Err(_) => return Err("Bad PM1b control block"),
+    };
+
+    let dsdt = tables.dsdt().map_err(|_| "No DSDT")?;
+    let aml_length = (dsdt.length as usize)
+        .checked_sub(SDT_HEADER_SIZE)
+        .ok_or("DSDT too small")?;
+    let aml = unsafe {
+        core::slice::from_raw_parts(
+            (physical_memory_offset + (dsdt.phys_address + SDT_HEADER_SIZE) as u64).as_ptr::<u8>(),
+            aml_length,
+        )
+    };
+    let s5 = parse_s5(aml).ok_or("No \\_S5 package in the DSDT")?;
+
+    Ok(PowerOff {
+        pm1a_control,
+        pm1b_control,
+        s5,
+        smi_command: u16::try_from(fadt.smi_cmd_port).unwrap_or(0),
+        acpi_enable: fadt.acpi_enable,
+    })
+}
+
+/// CMOS register that holds the century according to the FADT, None if there's none
+pub fn read_century_register(
+    rsdp_addr: usize,
+    physical_memory_offset: VirtAddr,
+) -> Result<Option<u8>, &'static str> {
+    let tables = read_acpi_tables(rsdp_addr, physical_memory_offset)?;
+    let fadt = tables.find_table::<Fadt>().ok_or("No FADT")?;
+
+    Ok(match fadt.century {
+        0 => None,
+        register => Some(register),
+    })
+}
+
+/// Read and keep what `shutdown` needs, called at boot
+pub fn init_power_off(
+    rsdp_addr: usize,
+    physical_memory_offset: VirtAddr,
+) -> Result<(), &'static str> {
+    let power_off = read_power_off(rsdp_addr, physical_memory_offset)?;
+    *POWER_OFF.lock() = Some(power_off);
+    Ok(())
+}
+
+/// Enter S5 (soft off) through the PM1 control registers
+///
+/// Only returns if that didn't work, with the reason.
+pub fn shutdown() -> &'static str {
+    let Some(power_off) = *POWER_OFF.lock() else {
+        return "No ACPI power off data";
+    };
+
+    interrupts::disable();
+
+    let mut pm1a = Port::<u16>::new(power_off.pm1a_control);
+    unsafe {
+        // The firmware owns the registers until we ask for them
+        if pm1a.read() & SCI_EN == 0 && power_off.smi_command != 0 {
+            Port::<u8>::new(power_off.smi_command).write(power_off.acpi_enable);
+            for _ in 0..100 {
+                if pm1a.read() & SCI_EN != 0 {
+                    break;
+                }
+                pit::busy_wait_micros(10_000);
+            }
+        }
+
+        let value = sleep_control_value(pm1a.read(), power_off.s5.a);
+        pm1a.write(value);
+
+        if let Some(port) = power_off.pm1b_control {
+            let mut pm1b = Port::<u16>::new(port);
+            let value = sleep_control_value(pm1b.read(), power_off.s5.b);
+            pm1b.write(value);
+        }
+    }
+
+    // Powering off can take a moment
+    pit::busy_wait_micros(100_000);
+    "The machine is still on after entering S5"
+}
+
 pub fn read_acpi_tables(
     rsdp_addr: usize,
     physical_memory_offset: VirtAddr,
-) -> AcpiTables<AcpiHandler> {
+) -> Result<AcpiTables<AcpiHandler>, &'static str> {
     let handler = AcpiHandler::new(physical_memory_offset);
 
-    unsafe { AcpiTables::from_rsdp(handler, rsdp_addr).unwrap() }
+    unsafe { AcpiTables::from_rsdp(handler, rsdp_addr) }
+        .map_err(|_| "Failed to read the ACPI tables")
 }
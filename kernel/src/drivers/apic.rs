@@ -1,11 +1,38 @@
-use acpi::platform::InterruptModel;
 use spin::{Lazy, Mutex};
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{FrameAllocator, Mapper, PhysFrame, Size4KiB},
 };
 
-use crate::{drivers::acpi::read_acpi_tables, interrupts::InterruptIndex, serial_println};
+use crate::{
+    cpu,
+    drivers::{
+        acpi::read_acpi_tables,
+        madt::{self, MadtInfo},
+        pit,
+    },
+    interrupts::InterruptIndex,
+    serial_println, time,
+};
+
+/// MPS INTI flags of an interrupt source override
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_LEVEL: u16 = 0b11 << 2;
+
+/// I/O APIC redirection entry bits
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+
+/// Rate we run the timer interrupt at
+pub const TIMER_HZ: u64 = time::DEFAULT_TICK_HZ;
+
+/// How long we measure the timer's speed against the PIT
+const CALIBRATION_MICROS: u64 = 10_000;
+
+/// Gives `DEFAULT_TICK_HZ` with QEMU's 1GHz bus clock and a divider of 16
+const FALLBACK_TIMER_COUNT: u32 = 2_500_000;
 
 static LAPIC_ADDR: Lazy<Mutex<LAPICAddress>> = Lazy::new(|| Mutex::new(LAPICAddress::new()));
 
@@ -117,20 +144,58 @@ unsafe fn init_timer(lapic_pointer: *mut u32) {
     let svr = unsafe { lapic_pointer.offset(APICOffset::Svr as isize / 4) };
     unsafe { svr.write_volatile(svr.read_volatile() | 0x100) };
 
-    // Configure timer
-    // Vector 0x20, Periodic Mode (bit 17), Not masked (bit 16 = 0)
     let lvt_timer = unsafe { lapic_pointer.offset(APICOffset::LvtT as isize / 4) };
-    unsafe { lvt_timer.write_volatile(0x20 | (1 << 17)) };
+    let ticr = unsafe { lapic_pointer.offset(APICOffset::Ticr as isize / 4) };
+    let tccr = unsafe { lapic_pointer.offset(APICOffset::Tccr as isize / 4) };
 
     // Set divider to 16
     let tdcr = unsafe { lapic_pointer.offset(APICOffset::Tdcr as isize / 4) };
     unsafe { tdcr.write_volatile(0x3) };
 
-    // Set initial count - smaller value for more frequent interrupts
-    let ticr = unsafe { lapic_pointer.offset(APICOffset::Ticr as isize / 4) };
-    unsafe { ticr.write_volatile(2_500_000) }; // Very slow // TODO: Spead this up when more processes
+    // The APIC timer runs at the bus clock, which we don't know: count down from the top with the
+    // timer masked (bit 16) for a while and see how far it got
+    unsafe {
+        lvt_timer.write_volatile(0x20 | (1 << 16));
+        ticr.write_volatile(u32::MAX);
+    }
+    pit::busy_wait_micros(CALIBRATION_MICROS);
+    let counted = u32::MAX - unsafe { tccr.read_volatile() };
+
+    let (count, hz) = match timer_initial_count(counted as u64, CALIBRATION_MICROS, TIMER_HZ) {
+        Some(calibrated) => calibrated,
+        None => {
+            serial_println!("[WARNING] APIC timer calibration failed, assuming QEMU's bus clock");
+            (FALLBACK_TIMER_COUNT, time::DEFAULT_TICK_HZ)
+        }
+    };
+
+    // Vector 0x20, Periodic Mode (bit 17), Not masked (bit 16 = 0)
+    unsafe {
+        lvt_timer.write_volatile(0x20 | (1 << 17));
+        ticr.write_volatile(count);
+    }
+    time::set_tick_frequency(hz);
+
+    serial_println!(
+        "Local APIC timer initialized: {} counts per {}us, {}Hz",
+        counted,
+        CALIBRATION_MICROS,
+        hz
+    );
+}
 
-    serial_println!("Local APIC timer initialized");
+/// Initial count for the timer to fire `hz` times a second if it counted `counted` times in `micros`
+/// microseconds, and the rate it really fires at with it. None if the timer didn't count at all.
+pub fn timer_initial_count(counted: u64, micros: u64, hz: u64) -> Option<(u32, u64)> {
+    if counted == 0 || micros == 0 || hz == 0 {
+        return None;
+    }
+
+    let per_second = counted as u128 * 1_000_000 / micros as u128;
+    let count = (per_second / hz as u128).clamp(1, u32::MAX as u128);
+    let actual = (per_second / count).max(1);
+
+    Some((count as u32, actual as u64))
 }
 
 unsafe fn init_keyboard(lapic_pointer: *mut u32) {
@@ -153,29 +218,51 @@ unsafe fn init_io_apic(
 
     let ioapic_pointer = virt_addr.as_mut_ptr::<u32>();
 
-    // Configure keyboard interrupt (IRQ 1 -> interrupt vector 33)
-    unsafe {
-        // IRQ 1 uses redirection entry 1: registers 0x12 (low) and 0x13 (high)
-        ioapic_pointer.offset(0).write_volatile(0x12); // Select keyboard redirection entry low
-        ioapic_pointer
-            .offset(4)
-            .write_volatile(InterruptIndex::Keyboard as u8 as u32); // Vector + delivery mode (fixed=000)
-
-        ioapic_pointer.offset(0).write_volatile(0x13); // Select keyboard redirection entry high
-        ioapic_pointer.offset(4).write_volatile(0); // Destination (CPU 0)
+    // Without the MADT every ISA IRQ goes to the GSI with the same number
+    let madt = madt::info().unwrap_or_default();
+    let gsi_base = madt.io_apic_for(0).map_or(0, |io_apic| io_apic.gsi_base);
+
+    let isa_irqs = [
+        (1, InterruptIndex::Keyboard),
+        (4, InterruptIndex::Serial),
+        (12, InterruptIndex::Mouse),
+    ];
+    for (irq, vector) in isa_irqs {
+        let (gsi, flags) = madt.isa_irq(irq);
+        let Some(input) = gsi.checked_sub(gsi_base) else {
+            serial_println!(
+                "[WARNING] IRQ {} is on GSI {}, not on our I/O APIC",
+                irq,
+                gsi
+            );
+            continue;
+        };
+
+        // Redirection entry n: registers 0x10 + n*2 (low) and 0x11 + n*2 (high)
+        let register = 0x10 + input * 2;
+        unsafe {
+            ioapic_pointer.offset(0).write_volatile(register); // Select redirection entry low
+            ioapic_pointer
+                .offset(4)
+                .write_volatile(redirection_entry(vector as u8, flags)); // Vector, delivery mode, polarity, trigger
+
+            ioapic_pointer.offset(0).write_volatile(register + 1); // Select redirection entry high
+            ioapic_pointer.offset(4).write_volatile(0); // Destination (CPU 0)
+        }
     }
+}
 
-    // Configure mouse interrupt (IRQ 12 -> interrupt vector 44)
-    unsafe {
-        // IRQ 12 uses redirection entry 12: registers 0x28 (low) and 0x29 (high)
-        ioapic_pointer.offset(0).write_volatile(0x28); // Select mouse redirection entry low (0x10 + 12*2)
-        ioapic_pointer
-            .offset(4)
-            .write_volatile(InterruptIndex::Mouse as u8 as u32); // Vector + delivery mode (fixed=000)
-
-        ioapic_pointer.offset(0).write_volatile(0x29); // Select mouse redirection entry high
-        ioapic_pointer.offset(4).write_volatile(0); // Destination (CPU 0)
+/// Low half of a redirection entry: fixed delivery of `vector` with the polarity and trigger mode from
+/// MPS INTI `flags`, "conforms to the bus" (0) means active high and edge triggered for ISA
+pub fn redirection_entry(vector: u8, flags: u16) -> u32 {
+    let mut entry = vector as u32;
+    if flags & INTI_POLARITY_MASK == INTI_ACTIVE_LOW {
+        entry |= REDIRECT_ACTIVE_LOW;
+    }
+    if flags & INTI_TRIGGER_MASK == INTI_LEVEL {
+        entry |= REDIRECT_LEVEL;
     }
+    entry
 }
 
 fn map_apic(
@@ -211,40 +298,91 @@ pub fn end_interrupt() {
     }
 }
 
-/// Initializes the APIC (both local and I/O) based on ACPI tables
+/// Check if the local APIC is servicing an interrupt (we're in an interrupt handler that hasn't sent EOI yet)
+/// Always false before the APIC is initialized.
+pub fn in_service() -> bool {
+    let lapic_ptr = LAPIC_ADDR.lock().address;
+    if lapic_ptr.is_null() {
+        return false;
+    }
+
+    let in_service_registers = [
+        APICOffset::Isr1,
+        APICOffset::Isr2,
+        APICOffset::Isr3,
+        APICOffset::Isr4,
+        APICOffset::Isr5,
+        APICOffset::Isr6,
+        APICOffset::Isr7,
+        APICOffset::Isr8,
+    ];
+    in_service_registers
+        .iter()
+        .any(|&register| unsafe { lapic_ptr.offset(register as isize / 4).read_volatile() } != 0)
+}
+
+/// Where the local APIC and the (first) I/O APIC are mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicAddresses {
+    pub local_apic: u64,
+    pub io_apic: u64,
+}
+
+/// Read the APIC topology from the MADT, also records how many CPUs there are
+/// The MADT is kept for routing the IRQs, see `madt::info`.
+pub fn read_apic_addresses(
+    rsdp_addr: usize,
+    physical_memory_offset: VirtAddr,
+) -> Result<ApicAddresses, &'static str> {
+    let tables = read_acpi_tables(rsdp_addr, physical_memory_offset)?;
+    let madt = madt::read_madt(&tables, physical_memory_offset)?;
+
+    cpu::set_count(madt.cpu_count());
+    serial_println!(
+        "CPUs: {} reported, {} online",
+        cpu::count(),
+        cpu::online_count()
+    );
+
+    let addresses = apic_addresses(&madt)?;
+    madt::set_info(madt);
+    Ok(addresses)
+}
+
+/// The local APIC and the I/O APIC for the ISA IRQs
+pub fn apic_addresses(madt: &MadtInfo) -> Result<ApicAddresses, &'static str> {
+    Ok(ApicAddresses {
+        local_apic: madt.local_apic_address,
+        io_apic: madt
+            .io_apic_for(0)
+            .ok_or("No I/O APIC in the MADT")?
+            .address,
+    })
+}
+
+/// Initializes the APIC (both local and I/O) at the addresses from `read_apic_addresses`
 /// Maps the APIC MMIO regions into the kernel's address space
 ///
 /// # Safety
 /// This function performs raw pointer dereferencing and MMIO access, so it must be called with correct parameters and only once during initialization.
 pub unsafe fn init(
-    rsdp_addr: usize,
-    physical_memory_offset: VirtAddr,
+    addresses: ApicAddresses,
     page_table: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) {
-    let tables = read_acpi_tables(rsdp_addr, physical_memory_offset);
-    let (model, _processor_info) = InterruptModel::new(&tables).unwrap();
-
-    match model {
-        InterruptModel::Apic(apic) => {
-            let io_apic_address = apic.io_apics[0].address;
-            unsafe {
-                init_io_apic(
-                    io_apic_address as usize,
-                    &mut *page_table,
-                    &mut *frame_allocator,
-                )
-            };
-
-            let local_apic_address = apic.local_apic_address;
-            unsafe {
-                init_local_apic(
-                    local_apic_address as usize,
-                    &mut *page_table,
-                    &mut *frame_allocator,
-                )
-            };
-        }
-        _ => panic!("Unsupported interrupt model"),
-    }
+    unsafe {
+        init_io_apic(
+            addresses.io_apic as usize,
+            &mut *page_table,
+            &mut *frame_allocator,
+        )
+    };
+
+    unsafe {
+        init_local_apic(
+            addresses.local_apic as usize,
+            &mut *page_table,
+            &mut *frame_allocator,
+        )
+    };
 }
@@ -1,4 +1,4 @@
-use crate::serial_println;
+use crate::{drivers::acpi, serial_println};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
@@ -30,3 +30,16 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
         nop();
     }
 }
+
+/// Turn the machine off, through ACPI if we can and with the QEMU exit device otherwise
+pub fn power_off() -> ! {
+    serial_println!("Powering off...");
+
+    let reason = acpi::shutdown();
+    serial_println!(
+        "[WARNING] ACPI power off failed ({}), trying the QEMU exit device",
+        reason
+    );
+
+    exit_qemu(QemuExitCode::Success)
+}
@@ -1,31 +1,49 @@
 use crate::drivers::apic::end_interrupt;
 use crate::events::{Event, KeyboardEvent, push_event};
-use pc_keyboard::{HandleControl, Keyboard, ScancodeSet1, layouts};
+use pc_keyboard::{DecodedKey, HandleControl, KeyState, Keyboard, ScancodeSet1, layouts};
 use spin::{Lazy, Mutex};
 use x86_64::instructions::port::PortReadOnly;
 use x86_64::structures::idt::InterruptStackFrame;
 
+/// Turns scancodes into key events and characters, keeps track of shift, caps lock etc.
+pub type Decoder = Keyboard<layouts::Azerty, ScancodeSet1>;
+
 // TODO: Do some research on scancode sets
-static KEYBOARD: Lazy<Mutex<Keyboard<layouts::Azerty, ScancodeSet1>>> = Lazy::new(|| {
-    Mutex::new(Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Azerty,
-        HandleControl::Ignore,
-    ))
-});
+static KEYBOARD: Lazy<Mutex<Decoder>> = Lazy::new(|| Mutex::new(new_decoder()));
+
+pub fn new_decoder() -> Decoder {
+    Keyboard::new(ScancodeSet1::new(), layouts::Azerty, HandleControl::Ignore)
+}
+
+/// Feed a scancode to `keyboard`, returns the events it completes: the key event itself and, for a key
+/// press, what it typed (`Char` for a character, `Raw` for keys without one like the arrows)
+pub fn decode(keyboard: &mut Decoder, scancode: u8) -> [Option<KeyboardEvent>; 2] {
+    let Ok(Some(key_event)) = keyboard.add_byte(scancode) else {
+        return [None, None];
+    };
+
+    let event = match key_event.state {
+        KeyState::Down => KeyboardEvent::KeyPressed(key_event.code),
+        KeyState::Up => KeyboardEvent::KeyReleased(key_event.code),
+        KeyState::SingleShot => KeyboardEvent::SingleShot(key_event.code),
+    };
+
+    let typed = keyboard
+        .process_keyevent(key_event)
+        .map(|decoded| match decoded {
+            DecodedKey::Unicode(c) => KeyboardEvent::Char(c),
+            DecodedKey::RawKey(code) => KeyboardEvent::Raw(code),
+        });
+
+    [Some(event), typed]
+}
 
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     let mut port = PortReadOnly::new(0x60);
     let scancode: u8 = unsafe { port.read() };
 
     let mut keyboard = KEYBOARD.lock();
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        let event = match key_event.state {
-            pc_keyboard::KeyState::Down => KeyboardEvent::KeyPressed(key_event.code),
-            pc_keyboard::KeyState::Up => KeyboardEvent::KeyReleased(key_event.code),
-            pc_keyboard::KeyState::SingleShot => KeyboardEvent::SingleShot(key_event.code),
-        };
-
+    for event in decode(&mut keyboard, scancode).into_iter().flatten() {
         push_event(Event::KeyboardEvent(event));
     }
 
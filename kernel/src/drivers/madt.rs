@@ -0,0 +1,182 @@
+// MADT (Multiple APIC Description Table)
+//
+// Lists the local APIC of every CPU, the I/O APICs and how ISA IRQs map onto the I/O APIC inputs
+// (global system interrupts, GSIs). The table is a header followed by variable length entries,
+// each starting with its type and length.
+
+use acpi::{AcpiTables, Handler, sdt::Signature};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Size of the table header, then the local APIC address and flags
+const HEADER_SIZE: usize = 36;
+const ENTRIES_OFFSET: usize = HEADER_SIZE + 8;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+/// Local APIC flags: the CPU is enabled, or can be brought online later
+const LOCAL_APIC_USABLE: u32 = 0b11;
+
+/// What we learned from the MADT, stored at boot for the APIC driver
+static MADT: Mutex<Option<MadtInfo>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    /// First GSI this I/O APIC handles, its input 0
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't wired to the GSI with the same number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MadtInfo {
+    pub local_apic_address: u64,
+    /// APIC IDs of the CPUs we can use
+    pub local_apic_ids: Vec<u32>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<SourceOverride>,
+}
+
+impl MadtInfo {
+    /// Number of CPUs we can use
+    pub fn cpu_count(&self) -> usize {
+        self.local_apic_ids.len()
+    }
+
+    /// The GSI and MPS INTI flags of ISA IRQ `irq`, identity mapped unless there is an override
+    pub fn isa_irq(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .map_or((irq as u32, 0), |o| (o.gsi, o.flags))
+    }
+
+    /// The I/O APIC that handles `gsi`
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        data.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Parse a whole MADT, header included
+pub fn parse_madt(table: &[u8]) -> Result<MadtInfo, &'static str> {
+    if table.get(0..4) != Some(b"APIC") {
+        return Err("Not a MADT");
+    }
+    let length = read_u32(table, 4).ok_or("MADT too small")? as usize;
+    let table = table.get(..length).ok_or("MADT truncated")?;
+
+    let mut info = MadtInfo {
+        local_apic_address: read_u32(table, HEADER_SIZE).ok_or("MADT too small")? as u64,
+        ..Default::default()
+    };
+
+    let mut offset = ENTRIES_OFFSET;
+    while offset < table.len() {
+        let entry_type = table[offset];
+        let entry_length = *table.get(offset + 1).ok_or("MADT entry truncated")? as usize;
+        let entry = table
+            .get(offset..offset + entry_length)
+            .filter(|entry| entry.len() >= 2)
+            .ok_or("Bad MADT entry length")?;
+
+        let malformed = "MADT entry too small";
+        match entry_type {
+            ENTRY_LOCAL_APIC => {
+                let flags = read_u32(entry, 4).ok_or(malformed)?;
+                if flags & LOCAL_APIC_USABLE != 0 {
+                    info.local_apic_ids.push(entry[3] as u32);
+                }
+            }
+            ENTRY_LOCAL_X2APIC => {
+                let flags = read_u32(entry, 8).ok_or(malformed)?;
+                if flags & LOCAL_APIC_USABLE != 0 {
+                    info.local_apic_ids
+                        .push(read_u32(entry, 4).ok_or(malformed)?);
+                }
+            }
+            ENTRY_IO_APIC => info.io_apics.push(IoApic {
+                id: *entry.get(2).ok_or(malformed)?,
+                address: read_u32(entry, 4).ok_or(malformed)? as u64,
+                gsi_base: read_u32(entry, 8).ok_or(malformed)?,
+            }),
+            ENTRY_SOURCE_OVERRIDE => info.overrides.push(SourceOverride {
+                irq: *entry.get(3).ok_or(malformed)?,
+                gsi: read_u32(entry, 4).ok_or(malformed)?,
+                flags: read_u16(entry, 8).ok_or(malformed)?,
+            }),
+            ENTRY_LOCAL_APIC_ADDRESS => {
+                info.local_apic_address = read_u64(entry, 4).ok_or(malformed)?;
+            }
+            // NMIs and whatever else, we don't need them yet
+            _ => {}
+        }
+
+        offset += entry_length;
+    }
+
+    Ok(info)
+}
+
+/// Find and parse the MADT in `tables`
+pub fn read_madt<H: Handler>(
+    tables: &AcpiTables<H>,
+    physical_memory_offset: VirtAddr,
+) -> Result<MadtInfo, &'static str> {
+    let (address, header) = tables
+        .table_headers()
+        .find(|(_, header)| header.signature == Signature::MADT)
+        .ok_or("No MADT")?;
+
+    let table = unsafe {
+        core::slice::from_raw_parts(
+            (physical_memory_offset + address as u64).as_ptr::<u8>(),
+            header.length as usize,
+        )
+    };
+    parse_madt(table)
+}
+
+/// Keep what we learned from the MADT for the APIC driver
+pub fn set_info(info: MadtInfo) {
+    *MADT.lock() = Some(info);
+}
+
+/// What we learned from the MADT, None before the ACPI tables were read
+pub fn info() -> Option<MadtInfo> {
+    MADT.lock().clone()
+}
@@ -1,14 +1,54 @@
+use crate::serial_println;
+
 pub mod acpi;
 pub mod apic;
 pub mod exit;
 pub mod keyboard;
+pub mod madt;
 pub mod mouse;
+pub mod pci;
 pub mod pit;
+pub mod reboot;
+pub mod rtc;
 pub mod serial;
 
-/// Initialize all drivers
-pub fn init() {
+pub use reboot::reboot;
+
+/// Initialize the serial ports, nothing can be logged before this
+pub fn init_serial() {
     serial::init_serial();
+    serial::init_debug_port();
+}
+
+/// Initialize the device drivers, needs the IDT and the APIC to deliver their interrupts
+pub fn init() -> Result<(), &'static str> {
+    serial_println!("RTC: {} UTC", rtc::now());
+    log_pci_devices();
+
+    // The kernel works fine without a mouse, don't fail the boot over it
+    if let Err(e) = mouse::init_mouse() {
+        serial_println!("[WARNING] Mouse init failed: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Print what's on the PCI bus, we don't have drivers for most of it yet
+fn log_pci_devices() {
+    let devices = pci::enumerate();
+    serial_println!("PCI: {} functions", devices.len());
 
-    mouse::init_mouse();
+    for device in devices {
+        serial_println!(
+            "  {:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}:{:02x}:{:02x}",
+            device.function.bus,
+            device.function.device,
+            device.function.function,
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.subclass,
+            device.prog_if
+        );
+    }
 }
@@ -3,10 +3,115 @@ use crate::{
     events::{Event, push_event},
 };
 use ps2_mouse::{Mouse, MouseState};
-use x86_64::{instructions::port::PortReadOnly, structures::idt::InterruptStackFrame};
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, port::PortReadOnly},
+    structures::idt::InterruptStackFrame,
+};
 
 static mut MOUSE: Mouse = Mouse::new();
 
+/// Where the cursor is, kept inside the bounds set with `set_bounds`
+static CURSOR: Mutex<Cursor> = Mutex::new(Cursor::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+}
+
+/// Cursor position and button state, built from the mouse's relative packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub x: usize,
+    pub y: usize,
+    width: usize,
+    height: usize,
+    left: bool,
+    right: bool,
+}
+
+impl Cursor {
+    /// A cursor at (0, 0) that can't move until it gets bounds
+    pub const fn new() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            left: false,
+            right: false,
+        }
+    }
+
+    /// Keep the cursor inside a `width` x `height` screen, it's moved inside if it's outside already
+    pub fn set_bounds(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.x = self.x.min(width.saturating_sub(1));
+        self.y = self.y.min(height.saturating_sub(1));
+    }
+
+    /// Apply a packet from the mouse, `emit` gets a `MouseMove` if it moved and a `MouseButton` for every
+    /// button that changed
+    pub fn update(&mut self, state: &MouseState, mut emit: impl FnMut(Event)) {
+        if state.moved() {
+            let dx = state.get_x();
+            // The mouse counts up going away from the user, the screen counts down
+            let dy = -state.get_y();
+
+            self.x = offset(self.x, dx, self.width);
+            self.y = offset(self.y, dy, self.height);
+            emit(Event::MouseMove {
+                x: self.x,
+                y: self.y,
+                dx,
+                dy,
+            });
+        }
+
+        let buttons = [
+            (MouseButton::Left, &mut self.left, state.left_button_down()),
+            (
+                MouseButton::Right,
+                &mut self.right,
+                state.right_button_down(),
+            ),
+        ];
+        for (button, held, pressed) in buttons {
+            if *held != pressed {
+                *held = pressed;
+                emit(Event::MouseButton { button, pressed });
+            }
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Move `position` by `delta`, clamped to 0..size
+fn offset(position: usize, delta: i16, size: usize) -> usize {
+    let max = size.saturating_sub(1);
+    position.saturating_add_signed(delta as isize).min(max)
+}
+
+/// Set the screen size the cursor stays on
+pub fn set_bounds(width: usize, height: usize) {
+    interrupts::without_interrupts(|| CURSOR.lock().set_bounds(width, height));
+}
+
+/// Get the cursor position
+pub fn position() -> (usize, usize) {
+    interrupts::without_interrupts(|| {
+        let cursor = CURSOR.lock();
+        (cursor.x, cursor.y)
+    })
+}
+
 pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
     let mut port = PortReadOnly::new(0x60);
     let data: u8 = unsafe { port.read() };
@@ -20,16 +125,15 @@ pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptSta
     end_interrupt();
 }
 
-pub fn init_mouse() {
+pub fn init_mouse() -> Result<(), &'static str> {
     #[allow(static_mut_refs)] // Who cares about safety anyway hehehe
-    {
-        unsafe {
-            MOUSE.set_on_complete(handle_on_complete);
-            MOUSE.init().unwrap();
-        };
+    unsafe {
+        MOUSE.set_on_complete(handle_on_complete);
+        MOUSE.init()
     }
 }
 
+/// Called from the interrupt handler, so the cursor lock can't be held by what we interrupted
 fn handle_on_complete(state: MouseState) {
-    push_event(Event::MouseEvent(state));
+    CURSOR.lock().update(&state, push_event);
 }
@@ -0,0 +1,320 @@
+// PCI configuration space and bus enumeration
+//
+// Uses the legacy I/O mechanism: write the address of a config dword to CONFIG_ADDRESS, then read or
+// write it through CONFIG_DATA. It only reaches the first 256 bytes of every function on segment 0,
+// anything else reads as all ones (like a missing device) and writes are dropped.
+
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// Size of the config space the legacy mechanism can reach
+pub const CONFIG_SPACE_SIZE: u16 = 256;
+
+/// Vendor ID a missing function reads as
+const NO_VENDOR: u16 = 0xFFFF;
+
+/// Offsets in the config space header
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0A;
+const CLASS: u16 = 0x0B;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+
+/// Header type bit set on device function 0 if the device has more functions
+const MULTIFUNCTION: u8 = 0x80;
+/// Normal devices have 6 BARs, PCI-to-PCI bridges 2
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// The two config ports, separate so the access logic can be tested without hardware
+pub trait ConfigPorts {
+    fn write_address(&mut self, address: u32);
+    fn read_data(&mut self) -> u32;
+    fn write_data(&mut self, value: u32);
+}
+
+/// The real 0xCF8/0xCFC ports
+pub struct LegacyPorts {
+    address: Port<u32>,
+    data: Port<u32>,
+}
+
+impl LegacyPorts {
+    pub const fn new() -> Self {
+        Self {
+            address: Port::new(CONFIG_ADDRESS),
+            data: Port::new(CONFIG_DATA),
+        }
+    }
+}
+
+impl Default for LegacyPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConfigPorts for LegacyPorts {
+    fn write_address(&mut self, address: u32) {
+        unsafe { self.address.write(address) }
+    }
+
+    fn read_data(&mut self) -> u32 {
+        unsafe { self.data.read() }
+    }
+
+    fn write_data(&mut self, value: u32) {
+        unsafe { self.data.write(value) }
+    }
+}
+
+/// The ports are an address/data pair, nobody may change the address between our two accesses
+static PORTS: Mutex<LegacyPorts> = Mutex::new(LegacyPorts::new());
+
+/// A PCI function on segment 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Function {
+    pub bus: u8,
+    /// 0 - 31
+    pub device: u8,
+    /// 0 - 7
+    pub function: u8,
+}
+
+/// A base address register, where the device's registers are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io { port: u32 },
+    Memory { address: u64, prefetchable: bool },
+}
+
+/// A function found by `enumerate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub function: Function,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    /// Header type without the multifunction bit
+    pub header_type: u8,
+    /// None for unused BARs and the upper half of 64-bit ones
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    pub fn is_class(&self, class: u8, subclass: u8) -> bool {
+        self.class == class && self.subclass == subclass
+    }
+}
+
+/// Value of CONFIG_ADDRESS for the dword containing `offset`
+pub fn config_address(function: Function, offset: u16) -> u32 {
+    1 << 31 // Enable bit
+        | (function.bus as u32) << 16
+        | (function.device as u32 & 0x1F) << 11
+        | (function.function as u32 & 0x7) << 8
+        | (offset as u32 & 0xFC)
+}
+
+fn read_dword(ports: &mut impl ConfigPorts, function: Function, offset: u16) -> u32 {
+    ports.write_address(config_address(function, offset));
+    ports.read_data()
+}
+
+fn write_dword(ports: &mut impl ConfigPorts, function: Function, offset: u16, value: u32) {
+    ports.write_address(config_address(function, offset));
+    ports.write_data(value);
+}
+
+/// Read `size` bytes (1, 2 or 4) at `offset`, little endian
+/// Accesses crossing a dword boundary are split, out of range ones read as all ones.
+pub fn read_config(
+    ports: &mut impl ConfigPorts,
+    function: Function,
+    offset: u16,
+    size: u16,
+) -> u32 {
+    debug_assert!(matches!(size, 1 | 2 | 4), "Bad PCI access size {}", size);
+    if offset.saturating_add(size) > CONFIG_SPACE_SIZE {
+        return u32::MAX >> (32 - size * 8);
+    }
+
+    let shift = (offset & 3) * 8;
+    if (offset & 3) + size <= 4 {
+        let dword = read_dword(ports, function, offset);
+        return (dword >> shift) & (u32::MAX >> (32 - size * 8));
+    }
+
+    // The high bytes are in the next dword
+    let low_size = 4 - (offset & 3);
+    let low = read_config(ports, function, offset, low_size);
+    let high = read_config(ports, function, offset + low_size, size - low_size);
+    low | high << (low_size * 8)
+}
+
+/// Write the low `size` bytes (1, 2 or 4) of `value` at `offset`, leaving the rest of the dword alone
+/// Accesses crossing a dword boundary are split, out of range ones are dropped.
+pub fn write_config(
+    ports: &mut impl ConfigPorts,
+    function: Function,
+    offset: u16,
+    size: u16,
+    value: u32,
+) {
+    debug_assert!(matches!(size, 1 | 2 | 4), "Bad PCI access size {}", size);
+    if offset.saturating_add(size) > CONFIG_SPACE_SIZE {
+        return;
+    }
+
+    if (offset & 3) + size > 4 {
+        let low_size = 4 - (offset & 3);
+        write_config(ports, function, offset, low_size, value);
+        write_config(
+            ports,
+            function,
+            offset + low_size,
+            size - low_size,
+            value >> (low_size * 8),
+        );
+        return;
+    }
+
+    if size == 4 {
+        write_dword(ports, function, offset, value);
+        return;
+    }
+
+    // Read, modify, write the dword around it
+    let shift = (offset & 3) * 8;
+    let mask = (u32::MAX >> (32 - size * 8)) << shift;
+    let dword = read_dword(ports, function, offset);
+    write_dword(
+        ports,
+        function,
+        offset,
+        (dword & !mask) | ((value << shift) & mask),
+    );
+}
+
+/// Read `size` bytes at `offset` of `function`'s config space, see `read_config`
+pub fn read(function: Function, offset: u16, size: u16) -> u32 {
+    interrupts::without_interrupts(|| read_config(&mut *PORTS.lock(), function, offset, size))
+}
+
+/// Write `size` bytes at `offset` of `function`'s config space, see `write_config`
+pub fn write(function: Function, offset: u16, size: u16, value: u32) {
+    interrupts::without_interrupts(|| {
+        write_config(&mut *PORTS.lock(), function, offset, size, value)
+    });
+}
+
+/// Decode the BARs of `function`, `count` of them
+fn read_bars(ports: &mut impl ConfigPorts, function: Function, count: u16) -> [Option<Bar>; 6] {
+    let mut bars = [None; 6];
+
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index * 4;
+        let value = read_config(ports, function, offset, 4);
+
+        if value & 1 == 1 {
+            bars[index as usize] = (value & !0x3 != 0).then_some(Bar::Io { port: value & !0x3 });
+            index += 1;
+            continue;
+        }
+
+        // Bits 1-2: 0 for a 32-bit BAR, 2 for a 64-bit one which takes the next BAR as the upper half
+        let is_64 = (value >> 1) & 0b11 == 0b10 && index + 1 < count;
+        let mut address = (value & !0xF) as u64;
+        if is_64 {
+            address |= (read_config(ports, function, offset + 4, 4) as u64) << 32;
+        }
+
+        if address != 0 {
+            bars[index as usize] = Some(Bar::Memory {
+                address,
+                prefetchable: value & 0x8 != 0,
+            });
+        }
+        index += if is_64 { 2 } else { 1 };
+    }
+
+    bars
+}
+
+/// Read the header of `function`, None if there's nothing there
+fn read_device(ports: &mut impl ConfigPorts, function: Function) -> Option<PciDevice> {
+    let vendor_id = read_config(ports, function, VENDOR_ID, 2) as u16;
+    if vendor_id == NO_VENDOR {
+        return None;
+    }
+
+    let header_type = read_config(ports, function, HEADER_TYPE, 1) as u8 & !MULTIFUNCTION;
+    let bar_count = match header_type {
+        0 => 6,
+        HEADER_TYPE_BRIDGE => 2,
+        // CardBus bridges have no BARs we care about
+        _ => 0,
+    };
+
+    Some(PciDevice {
+        function,
+        vendor_id,
+        device_id: read_config(ports, function, DEVICE_ID, 2) as u16,
+        class: read_config(ports, function, CLASS, 1) as u8,
+        subclass: read_config(ports, function, SUBCLASS, 1) as u8,
+        prog_if: read_config(ports, function, PROG_IF, 1) as u8,
+        header_type,
+        bars: read_bars(ports, function, bar_count),
+    })
+}
+
+/// Find every function on every bus, brute force
+pub fn scan(ports: &mut impl ConfigPorts) -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = Function {
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(found) = read_device(ports, first) else {
+                continue;
+            };
+            devices.push(found);
+
+            // Functions 1 - 7 only exist on multifunction devices
+            if read_config(ports, first, HEADER_TYPE, 1) as u8 & MULTIFUNCTION == 0 {
+                continue;
+            }
+            for function in 1..8 {
+                let function = Function { function, ..first };
+                devices.extend(read_device(ports, function));
+            }
+        }
+    }
+
+    devices
+}
+
+/// Find every PCI function in the machine
+pub fn enumerate() -> Vec<PciDevice> {
+    interrupts::without_interrupts(|| scan(&mut *PORTS.lock()))
+}
+
+/// Find the functions with the given class and subclass, e.g. 0x01, 0x06 for AHCI controllers
+pub fn find_by_class(class: u8, subclass: u8) -> Vec<PciDevice> {
+    enumerate()
+        .into_iter()
+        .filter(|device| device.is_class(class, subclass))
+        .collect()
+}
@@ -1,7 +1,124 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::InterruptStackFrame;
 
 use crate::{drivers::apic::end_interrupt, serial_print};
 
+/// Input clock of the PIT in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+/// Largest count we can load into a PIT channel (~55ms)
+const MAX_COUNT: u64 = 0xFFFF;
+
+/// Smallest divisor that works in mode 3 (square wave)
+const MIN_DIVISOR: u32 = 2;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Port B of the keyboard controller: channel 2 gate (bit 0), speaker (bit 1) and channel 2 output (bit 5)
+const PORT_B: u16 = 0x61;
+const GATE_2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUT_2: u8 = 1 << 5;
+
+/// Rate channel 0 was programmed to by `set_frequency`, 0 while it's still what the firmware left
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Divisor that gets channel 0 closest to `hz`
+/// Fails unless `hz` is between ~19Hz (the largest divisor) and ~600kHz (the smallest one).
+pub fn divisor_for(hz: u32) -> Result<u16, &'static str> {
+    if hz == 0 {
+        return Err("Frequency must be non-zero");
+    }
+
+    let divisor = (PIT_FREQUENCY as u32 + hz / 2) / hz;
+    if !(MIN_DIVISOR..=MAX_COUNT as u32).contains(&divisor) {
+        return Err("Frequency out of the PIT's range");
+    }
+
+    Ok(divisor as u16)
+}
+
+/// Rate a channel actually runs at with `divisor`, the input clock doesn't divide evenly into most rates
+pub fn frequency_for(divisor: u16) -> u32 {
+    PIT_FREQUENCY as u32 / divisor as u32
+}
+
+/// Program channel 0 to fire at (close to) `hz`, returns the rate it really runs at
+pub fn set_frequency(hz: u32) -> Result<u32, &'static str> {
+    let divisor = divisor_for(hz)?;
+
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel = Port::<u8>::new(CHANNEL_0);
+    unsafe {
+        // Channel 0, low byte then high byte, mode 3 (square wave), binary
+        command.write(0b0011_0110);
+        let [low, high] = divisor.to_le_bytes();
+        channel.write(low);
+        channel.write(high);
+    }
+
+    let actual = frequency_for(divisor);
+    FREQUENCY.store(actual, Ordering::Relaxed);
+    Ok(actual)
+}
+
+/// Rate of channel 0 set by `set_frequency`, None if it was never set
+pub fn frequency() -> Option<u32> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Number of PIT cycles in `micros` microseconds, rounded up
+pub fn micros_to_pit_ticks(micros: u64) -> u64 {
+    (micros as u128 * PIT_FREQUENCY as u128).div_ceil(1_000_000) as u64
+}
+
+/// Spin for at least `micros` microseconds, without interrupts or the scheduler
+///
+/// Counts down with PIT channel 2 (the speaker channel, with the speaker off), so it works with interrupts
+/// disabled and doesn't depend on the timer tick. Waits longer than ~55ms are done in several rounds.
+pub fn busy_wait_micros(micros: u64) {
+    let mut remaining = micros_to_pit_ticks(micros);
+
+    while remaining > 0 {
+        let count = remaining.min(MAX_COUNT);
+        unsafe { count_down(count as u16) };
+        remaining -= count;
+    }
+}
+
+/// Run PIT channel 2 once from `count` down to 0 and wait for it
+unsafe fn count_down(count: u16) {
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel = Port::<u8>::new(CHANNEL_2);
+
+    unsafe {
+        // Gate low so the count doesn't start before it's fully loaded
+        let original = port_b.read();
+        port_b.write(original & !(GATE_2 | SPEAKER));
+
+        // Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        let [low, high] = count.to_le_bytes();
+        channel.write(low);
+        channel.write(high);
+
+        // OUT 2 goes high once the count reaches 0
+        port_b.write((original & !SPEAKER) | GATE_2);
+        while port_b.read() & OUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+
+        port_b.write(original);
+    }
+}
+
 pub extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
     // Check if we came from user mode (Ring 3) by looking at the code segment's RPL
     let cs = stack_frame.code_segment.0;
@@ -0,0 +1,101 @@
+// Warm reboot
+//
+// The 8042 keyboard controller can pulse the CPU's reset line, every PC (and QEMU) wires it up.
+// If that doesn't take we triple fault: with an empty IDT the next interrupt can't be delivered,
+// neither can the double fault after it, and the CPU resets.
+
+use x86_64::instructions::port::Port;
+
+use crate::drivers::pit;
+use crate::serial_println;
+
+/// Status and command port of the keyboard controller
+const CONTROLLER_PORT: u16 = 0x64;
+
+/// Status bit that's set while the controller hasn't taken the last byte we wrote
+const INPUT_BUFFER_FULL: u8 = 1 << 1;
+
+/// Command that pulses the reset line
+const RESET_COMMAND: u8 = 0xFE;
+
+/// How many times we poll the status before giving up on the controller
+const MAX_WAIT_POLLS: usize = 100_000;
+
+/// The keyboard controller's status and command register
+pub trait KeyboardController {
+    fn status(&mut self) -> u8;
+    fn write_command(&mut self, command: u8);
+}
+
+/// The real controller at port 0x64
+pub struct ControllerPort(Port<u8>);
+
+impl ControllerPort {
+    pub const fn new() -> Self {
+        Self(Port::new(CONTROLLER_PORT))
+    }
+}
+
+impl Default for ControllerPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyboardController for ControllerPort {
+    fn status(&mut self) -> u8 {
+        unsafe { self.0.read() }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        unsafe { self.0.write(command) };
+    }
+}
+
+/// Poll until the controller can take a command, false if it's still busy after `max_polls` reads
+pub fn wait_input_clear(controller: &mut impl KeyboardController, max_polls: usize) -> bool {
+    (0..max_polls).any(|_| controller.status() & INPUT_BUFFER_FULL == 0)
+}
+
+/// Ask the controller to reset the CPU, false (and nothing written) if it never got ready
+pub fn pulse_reset(controller: &mut impl KeyboardController, max_polls: usize) -> bool {
+    if !wait_input_clear(controller, max_polls) {
+        return false;
+    }
+
+    controller.write_command(RESET_COMMAND);
+    true
+}
+
+/// Reset the machine right away
+///
+/// This is a hard reset, like pressing the reset button: nothing is flushed, synced or shut down
+/// first, so only use it when there's nothing left to save (after a panic, or from a reboot syscall).
+pub fn reboot() -> ! {
+    use x86_64::instructions::{interrupts, tables::lidt};
+    use x86_64::{VirtAddr, structures::DescriptorTablePointer};
+
+    serial_println!("Rebooting...");
+    interrupts::disable();
+
+    if pulse_reset(&mut ControllerPort::new(), MAX_WAIT_POLLS) {
+        // The reset takes a moment
+        pit::busy_wait_micros(50_000);
+    }
+
+    serial_println!("[WARNING] Keyboard controller reset didn't work, triple faulting");
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+
+    // The CPU resets before we get here
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,222 @@
+// Real time clock
+//
+// The CMOS RTC keeps the date and time while the machine is off. Its registers are read by writing
+// the register number to port 0x70 and reading port 0x71. Status register B says whether the values
+// are BCD or binary and whether the hour is in 12 or 24 hour format. The clock updates itself once
+// a second, reads during an update can mix the old and new time.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::{interrupts, port::Port};
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+/// Status A: an update is running, the time registers aren't stable
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: 24 hour format instead of 12 hour
+const HOUR_24: u8 = 1 << 1;
+/// Status B: binary values instead of BCD
+const BINARY: u8 = 1 << 2;
+/// Set in the hour register for PM in 12 hour format
+const HOUR_PM: u8 = 1 << 7;
+
+/// Give up waiting for a stable reading after this many tries, the clock is broken then
+const MAX_READ_ATTEMPTS: usize = 1_000;
+
+/// CMOS register with the century, from the FADT, 0 if there's none
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// The CMOS registers
+pub trait Cmos {
+    fn read(&mut self, register: u8) -> u8;
+}
+
+/// The real CMOS at ports 0x70 and 0x71
+pub struct CmosPorts {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl CmosPorts {
+    pub const fn new() -> Self {
+        Self {
+            index: Port::new(INDEX_PORT),
+            data: Port::new(DATA_PORT),
+        }
+    }
+}
+
+impl Default for CmosPorts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cmos for CmosPorts {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+}
+
+/// A date and time in UTC (the RTC doesn't know about time zones, we assume it's set to UTC)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 - 12
+    pub month: u8,
+    /// 1 - 31
+    pub day: u8,
+    /// 0 - 23
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn unix_timestamp(&self) -> i64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        days * 86_400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+/// Howard Hinnant's `days_from_civil`, years start in March so the leap day is the last day of the year.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The time registers as the RTC has them, before decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub second: u8,
+    pub minute: u8,
+    pub hour: u8,
+    pub day: u8,
+    pub month: u8,
+    pub year: u8,
+    /// None if the machine has no century register
+    pub century: Option<u8>,
+    pub status_b: u8,
+}
+
+impl RtcRegisters {
+    /// Read the registers, once no update is in progress
+    pub fn read(cmos: &mut impl Cmos, century_register: Option<u8>) -> Self {
+        for _ in 0..MAX_READ_ATTEMPTS {
+            if cmos.read(STATUS_A) & UPDATE_IN_PROGRESS == 0 {
+                break;
+            }
+        }
+
+        Self {
+            second: cmos.read(SECONDS),
+            minute: cmos.read(MINUTES),
+            hour: cmos.read(HOURS),
+            day: cmos.read(DAY),
+            month: cmos.read(MONTH),
+            year: cmos.read(YEAR),
+            century: century_register.map(|register| cmos.read(register)),
+            status_b: cmos.read(STATUS_B),
+        }
+    }
+
+    /// Convert to a date, undoing BCD and the 12 hour format if they're used
+    pub fn decode(&self) -> DateTime {
+        let binary = self.status_b & BINARY != 0;
+        let value = |raw: u8| if binary { raw } else { from_bcd(raw) };
+
+        let pm = self.hour & HOUR_PM != 0;
+        let mut hour = value(self.hour & !HOUR_PM);
+        if self.status_b & HOUR_24 == 0 {
+            // 12 AM is midnight, 12 PM is noon
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        // Without a century register we have to guess, this code won't be around in 2100
+        let century = self.century.map_or(20, value) as u16;
+
+        DateTime {
+            year: century * 100 + value(self.year) as u16,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xF)
+}
+
+/// Read the date and time from `cmos`
+/// The registers are read until two reads in a row agree, so an update in the middle can't tear them.
+pub fn read_datetime(cmos: &mut impl Cmos, century_register: Option<u8>) -> DateTime {
+    let mut last = RtcRegisters::read(cmos, century_register);
+
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let current = RtcRegisters::read(cmos, century_register);
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+
+    last.decode()
+}
+
+/// Use the century register the FADT points to, 0 means there's none
+pub fn set_century_register(register: u8) {
+    CENTURY_REGISTER.store(register, Ordering::Relaxed);
+}
+
+/// The current date and time
+pub fn now() -> DateTime {
+    let century_register = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => None,
+        register => Some(register),
+    };
+
+    // An interrupt between selecting a register and reading it could select another one
+    interrupts::without_interrupts(|| read_datetime(&mut CmosPorts::new(), century_register))
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_timestamp() -> i64 {
+    now().unix_timestamp()
+}
@@ -1,14 +1,106 @@
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::{Port, PortReadOnly};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::drivers::apic::end_interrupt;
+use crate::events::{Event, push_event};
 
 static SERIAL1: Mutex<Option<SerialPort>> = Mutex::new(None);
 
+const COM1: u16 = 0x3F8;
+
+/// Line status register bit set when a received byte is waiting in the data register
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+
+/// Second serial port (COM2), reserved for the GDB stub. None if the machine doesn't have one
+static SERIAL2: Mutex<Option<SerialPort>> = Mutex::new(None);
+
+const COM2: u16 = 0x2F8;
+
 pub fn init_serial() {
-    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    // Also enables the interrupt for received bytes (IRQ 4)
+    let mut serial_port = unsafe { SerialPort::new(COM1) };
     serial_port.init();
     *SERIAL1.lock() = Some(serial_port);
 }
 
+/// Initialize the debug port (COM2) if it exists
+pub fn init_debug_port() {
+    // A UART has a scratch register that reads back what we wrote, a missing port reads 0xFF
+    let mut scratch = Port::<u8>::new(COM2 + 7);
+    let present = unsafe {
+        scratch.write(0x5A);
+        scratch.read() == 0x5A
+    };
+    if !present {
+        return;
+    }
+
+    let mut serial_port = unsafe { SerialPort::new(COM2) };
+    serial_port.init();
+    *SERIAL2.lock() = Some(serial_port);
+}
+
+/// Run `f` with the debug port, None if there is no debug port
+pub fn with_debug_port<R>(f: impl FnOnce(&mut SerialPort) -> R) -> Option<R> {
+    SERIAL2.lock().as_mut().map(f)
+}
+
+/// The registers of a UART we need for receiving, so the receive logic can be tested without hardware
+pub trait Uart {
+    fn line_status(&mut self) -> u8;
+    fn read_data(&mut self) -> u8;
+}
+
+/// The receive registers of a UART at an I/O port base
+pub struct UartPorts {
+    data: PortReadOnly<u8>,
+    line_status: PortReadOnly<u8>,
+}
+
+impl UartPorts {
+    pub const fn new(base: u16) -> Self {
+        Self {
+            data: PortReadOnly::new(base),
+            line_status: PortReadOnly::new(base + 5),
+        }
+    }
+}
+
+impl Uart for UartPorts {
+    fn line_status(&mut self) -> u8 {
+        unsafe { self.line_status.read() }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        unsafe { self.data.read() }
+    }
+}
+
+/// Read a byte from `uart` if one is waiting, the data register is only read when it holds one
+pub fn receive(uart: &mut impl Uart) -> Option<u8> {
+    if uart.line_status() & LINE_STATUS_DATA_READY == 0 {
+        return None;
+    }
+    Some(uart.read_data())
+}
+
+/// Read a byte the host sent over COM1, None if there is nothing waiting
+pub fn read_byte() -> Option<u8> {
+    receive(&mut UartPorts::new(COM1))
+}
+
+/// IRQ 4, COM1 received something: turn every waiting byte into a `SerialInput` event
+pub extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    while let Some(byte) = read_byte() {
+        push_event(Event::SerialInput(byte));
+    }
+
+    // Acknowledge the interrupt
+    end_interrupt();
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -1,17 +1,61 @@
 use crossbeam_queue::ArrayQueue;
 use pc_keyboard::KeyCode;
-use spin::Lazy;
+use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
 
+use crate::drivers::mouse::MouseButton;
 use crate::serial_println;
+use crate::tasks::{self, task::BlockReason};
+use crate::time;
 
 const EVENT_QUEUE_SIZE: usize = 128;
 
 static EVENT_QUEUE: Lazy<ArrayQueue<Event>> = Lazy::new(|| ArrayQueue::new(EVENT_QUEUE_SIZE));
 
+/// Function called for every event of the kind it's registered for
+pub type EventHandler = fn(&Event);
+
+/// Maximum number of subscriptions over all event kinds
+const MAX_SUBSCRIBERS: usize = 16;
+
+/// Registered handlers and the kind of event they want
+static SUBSCRIBERS: Mutex<[Option<(EventKind, EventHandler)>; MAX_SUBSCRIBERS]> =
+    Mutex::new([None; MAX_SUBSCRIBERS]);
+
 #[derive(Debug, Clone, Copy)]
 pub enum Event {
     KeyboardEvent(KeyboardEvent),
-    MouseEvent(ps2_mouse::MouseState),
+    /// The cursor moved to (x, y) on the screen, by (dx, dy) (y grows downward)
+    MouseMove {
+        x: usize,
+        y: usize,
+        dx: i16,
+        dy: i16,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+    },
+    /// A byte the host sent over the serial line (COM1)
+    SerialInput(u8),
+}
+
+/// The kind of an event, used to pick a handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Keyboard,
+    Mouse,
+    Serial,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::KeyboardEvent(_) => EventKind::Keyboard,
+            Event::MouseMove { .. } | Event::MouseButton { .. } => EventKind::Mouse,
+            Event::SerialInput(_) => EventKind::Serial,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -19,12 +63,19 @@ pub enum KeyboardEvent {
     KeyPressed(KeyCode),
     KeyReleased(KeyCode),
     SingleShot(KeyCode),
+    /// A key press typed this character, with shift and caps lock applied
+    Char(char),
+    /// A key press that doesn't type a character (arrows, function keys...)
+    Raw(KeyCode),
 }
 
+/// Queue an event and wake the event loop, safe to call from interrupt handlers
 pub fn push_event(event: Event) {
     if EVENT_QUEUE.push(event).is_err() {
         serial_println!("[WARNING] Event queue full, dropping event: {:?}", event);
     }
+
+    tasks::unpark_from_interrupt(BlockReason::Events);
 }
 
 pub fn pop_event() -> Option<Event> {
@@ -33,3 +84,119 @@ pub fn pop_event() -> Option<Event> {
 pub fn has_events() -> bool {
     !EVENT_QUEUE.is_empty()
 }
+
+/// Halt until the next event, enables interrupts
+pub fn wait_event() -> Event {
+    wait_event_with(|| {
+        halt_until_interrupt();
+        true
+    })
+    .expect("wait_event gave up")
+}
+
+/// Like `wait_event`, but gives up after `ticks` timer ticks
+pub fn wait_event_timeout(ticks: u64) -> Option<Event> {
+    let deadline = time::ticks().saturating_add(ticks);
+
+    wait_event_with(|| {
+        if time::ticks() >= deadline {
+            return false;
+        }
+        halt_until_interrupt();
+        true
+    })
+}
+
+/// The loop behind `wait_event`: pop the next event, calling `idle` while the queue is empty
+/// `idle` waits for something to happen and returns false to give up.
+pub fn wait_event_with(mut idle: impl FnMut() -> bool) -> Option<Event> {
+    loop {
+        if let Some(event) = pop_event() {
+            return Some(event);
+        }
+
+        if !idle() {
+            return None;
+        }
+    }
+}
+
+/// Halt until an interrupt unless an event is already queued
+fn halt_until_interrupt() {
+    // An event pushed between the check and the `hlt` would wait for the next interrupt,
+    // `sti; hlt` only lets interrupts in once we're halted
+    interrupts::disable();
+    if has_events() {
+        interrupts::enable();
+    } else {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Register a handler that gets called for every event of the given kind
+/// Fails if the handler table is full
+pub fn subscribe(kind: EventKind, handler: EventHandler) -> Result<(), &'static str> {
+    let mut subscribers = SUBSCRIBERS.lock();
+
+    let slot = subscribers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or("Event handler table full")?;
+    *slot = Some((kind, handler));
+
+    Ok(())
+}
+
+/// Register the handler for a kind of event, replacing every handler subscribed to it before
+/// Fails if the handler table is full
+pub fn on(kind: EventKind, handler: EventHandler) -> Result<(), &'static str> {
+    for slot in SUBSCRIBERS.lock().iter_mut() {
+        if slot.is_some_and(|(k, _)| k == kind) {
+            *slot = None;
+        }
+    }
+
+    subscribe(kind, handler)
+}
+
+/// Call every handler subscribed to the kind of this event
+fn dispatch(event: &Event) {
+    // Copy the table so handlers can subscribe themselves without deadlocking
+    let subscribers = *SUBSCRIBERS.lock();
+    let kind = event.kind();
+
+    for (_, handler) in subscribers.iter().flatten().filter(|(k, _)| *k == kind) {
+        handler(event);
+    }
+}
+
+/// Pop all pending events and hand them to their subscribers
+/// Returns the number of events that were dispatched
+pub fn dispatch_pending() -> usize {
+    let mut count = 0;
+
+    while let Some(event) = pop_event() {
+        dispatch(&event);
+        count += 1;
+    }
+
+    count
+}
+
+/// The kernel's main loop: handle events as they come in and park when there's nothing to do
+/// Runs as a kernel task, so user tasks get the CPU while it's parked
+/// Also frees the tasks that exited on their own kernel stack, those wake it up.
+pub extern "C" fn event_loop() -> ! {
+    loop {
+        dispatch_pending();
+        tasks::reap_exited();
+
+        // Check again with interrupts disabled so we can't miss an event pushed right before we park
+        interrupts::disable();
+        if has_events() {
+            interrupts::enable();
+        } else {
+            tasks::park_on(BlockReason::Events);
+        }
+    }
+}
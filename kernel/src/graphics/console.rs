@@ -0,0 +1,145 @@
+// Text console
+//
+// Renders text to the framebuffer with the built-in 8x16 font. The screen is a grid of glyph
+// cells, text wraps at the right edge and the whole screen scrolls up a line when it's full.
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::graphics::font::{GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::graphics::{Framebuffer, draw_char};
+
+/// The console `kprint!` writes to, None until `init` (and forever when running headless)
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+
+pub struct Console {
+    framebuffer: Framebuffer,
+    /// Cursor position in glyph cells
+    column: usize,
+    row: usize,
+    fg: u32,
+    bg: u32,
+}
+
+impl Console {
+    /// White on black console covering the whole framebuffer, clears the back buffer
+    pub fn new(mut framebuffer: Framebuffer) -> Self {
+        let fg = framebuffer.encode_color(255, 255, 255);
+        let bg = framebuffer.encode_color(0, 0, 0);
+        framebuffer.clear(bg);
+
+        Self {
+            framebuffer,
+            column: 0,
+            row: 0,
+            fg,
+            bg,
+        }
+    }
+
+    /// Number of glyphs that fit on a line
+    pub fn columns(&self) -> usize {
+        self.framebuffer.width / GLYPH_WIDTH
+    }
+
+    /// Number of lines that fit on the screen
+    pub fn rows(&self) -> usize {
+        self.framebuffer.height / GLYPH_HEIGHT
+    }
+
+    /// Cursor position as (column, row)
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.column, self.row)
+    }
+
+    pub fn framebuffer(&mut self) -> &mut Framebuffer {
+        &mut self.framebuffer
+    }
+
+    /// Draw a character at the cursor and advance it
+    pub fn write_char(&mut self, ch: char) {
+        // Too small for a single glyph, nothing to draw to
+        if self.columns() == 0 || self.rows() == 0 {
+            return;
+        }
+
+        match ch {
+            '\n' => self.new_line(),
+            '\r' => self.column = 0,
+            _ => {
+                if self.column >= self.columns() {
+                    self.new_line();
+                }
+
+                draw_char(
+                    &mut self.framebuffer,
+                    self.column * GLYPH_WIDTH,
+                    self.row * GLYPH_HEIGHT,
+                    ch,
+                    self.fg,
+                    self.bg,
+                );
+                self.column += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.column = 0;
+
+        if self.row + 1 < self.rows() {
+            self.row += 1;
+        } else {
+            self.framebuffer.scroll_up(GLYPH_HEIGHT, self.bg);
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            self.write_char(ch);
+        }
+        Ok(())
+    }
+}
+
+/// Hand the framebuffer to the console, `kprint!` draws to it from now on
+pub fn init(framebuffer: Framebuffer) {
+    let mut console = Console::new(framebuffer);
+    console.framebuffer.flip();
+    *CONSOLE.lock() = Some(console);
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    // Same as serial, an interrupt handler printing while we hold the lock would deadlock
+    interrupts::without_interrupts(|| {
+        // Headless, the text only goes to serial
+        if let Some(console) = CONSOLE.lock().as_mut() {
+            let _ = console.write_fmt(args);
+            console.framebuffer.flip();
+        }
+    });
+}
+
+/// Prints to the screen through the text console.
+#[macro_export]
+macro_rules! kprint {
+    ($($arg:tt)*) => {
+        $crate::graphics::console::_print(format_args!($($arg)*))
+    };
+}
+
+/// Prints to the screen through the text console, appending a newline.
+#[macro_export]
+macro_rules! kprintln {
+    () => ($crate::kprint!("\n"));
+    ($fmt:expr) => ($crate::kprint!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::kprint!(
+        concat!($fmt, "\n"), $($arg)*))
+}
@@ -0,0 +1,81 @@
+// Bitmap font
+//
+// An embedded 8x16 PSF1 font (rasterized from Noto Sans Mono, OFL licensed).
+// Only printable ASCII has real glyphs, everything else falls back to glyph 0 (a hollow box).
+
+/// Width of a glyph in pixels
+pub const GLYPH_WIDTH: usize = 8;
+/// Height of a glyph in pixels
+pub const GLYPH_HEIGHT: usize = 16;
+
+/// PSF1 files start with these two bytes
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+/// Size of the PSF1 header (magic, mode, charsize)
+const PSF1_HEADER_SIZE: usize = 4;
+/// Mode bit telling us the font has 512 glyphs instead of 256
+const PSF1_MODE_512: u8 = 0x01;
+
+static FONT_DATA: &[u8] = include_bytes!("../resources/font8x16.psf");
+
+/// The built-in 8x16 font
+pub static FONT: Font = match Font::from_psf1(FONT_DATA) {
+    Some(font) => font,
+    None => panic!("Embedded font is not a valid 8x16 PSF1 font"),
+};
+
+/// A parsed PSF1 bitmap font
+///
+/// Every glyph is `GLYPH_HEIGHT` bytes, one byte per row.
+/// The most significant bit of a row is the leftmost pixel.
+pub struct Font {
+    glyphs: &'static [u8],
+    glyph_count: usize,
+}
+
+impl Font {
+    /// Parse a PSF1 font, returns None if the data isn't an 8x16 PSF1 font
+    pub const fn from_psf1(data: &'static [u8]) -> Option<Self> {
+        if data.len() < PSF1_HEADER_SIZE || data[0] != PSF1_MAGIC[0] || data[1] != PSF1_MAGIC[1] {
+            return None;
+        }
+
+        // We only support 8 pixel wide glyphs, so the charsize is the glyph height
+        if data[3] as usize != GLYPH_HEIGHT {
+            return None;
+        }
+
+        let glyph_count = if data[2] & PSF1_MODE_512 != 0 {
+            512
+        } else {
+            256
+        };
+
+        if data.len() < PSF1_HEADER_SIZE + glyph_count * GLYPH_HEIGHT {
+            return None;
+        }
+
+        let (_, glyphs) = data.split_at(PSF1_HEADER_SIZE);
+
+        Some(Self {
+            glyphs,
+            glyph_count,
+        })
+    }
+
+    /// Get the rows of the glyph for the given character
+    /// Characters without a glyph get the fallback glyph (0)
+    pub fn glyph(&self, ch: char) -> &'static [u8] {
+        let mut index = ch as usize;
+        if index >= self.glyph_count || !(ch.is_ascii_graphic() || ch == ' ') {
+            index = 0;
+        }
+
+        let start = index * GLYPH_HEIGHT;
+        &self.glyphs[start..start + GLYPH_HEIGHT]
+    }
+
+    /// Check if the pixel at (x, y) inside a glyph row set is lit
+    pub fn is_set(glyph: &[u8], x: usize, y: usize) -> bool {
+        glyph[y] & (0x80 >> x) != 0
+    }
+}
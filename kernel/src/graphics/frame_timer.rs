@@ -0,0 +1,87 @@
+// Frame pacing
+//
+// Keeps a render loop from flipping as fast as it can by waiting for the next frame deadline.
+
+use crate::{tasks, time};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Paces a render loop to a target frame rate and measures the frame rate it actually gets
+pub struct FrameTimer {
+    /// Uptime (ns) at which the last frame was due, None before the first frame
+    deadline: Option<u64>,
+    /// Uptime (ns) at which the last frame finished waiting
+    last_frame: Option<u64>,
+    /// Frame rate measured between the last two frames
+    fps: u32,
+}
+
+impl FrameTimer {
+    pub const fn new() -> Self {
+        Self {
+            deadline: None,
+            last_frame: None,
+            fps: 0,
+        }
+    }
+
+    /// Wait until the next frame is due and return the achieved frame rate
+    ///
+    /// Call this once per frame after rendering. A target of 0 disables pacing.
+    /// Only for kernel tasks (or kernel init), like `tasks::sleep_until`.
+    pub fn wait_for_next_frame(&mut self, target_fps: u32) -> u32 {
+        let deadline = self.next_deadline(time::uptime_nanos(), target_fps);
+
+        // Through the scheduler, the other tasks get the CPU until then
+        // The first tick at which the uptime reaches the deadline, rounded up
+        tasks::sleep_until(time::nanos_to_ticks(deadline, time::tick_frequency()));
+
+        self.record_frame(time::uptime_nanos())
+    }
+
+    /// Calculate when the next frame is due, given the current uptime
+    ///
+    /// Deadlines are spaced evenly from the previous one, so small jitter doesn't add up.
+    /// If we're already past the deadline (the frame took too long) we start over from `now`
+    /// instead of trying to catch up with a burst of unpaced frames.
+    pub fn next_deadline(&mut self, now: u64, target_fps: u32) -> u64 {
+        if target_fps == 0 {
+            self.deadline = Some(now);
+            return now;
+        }
+
+        let frame_time = NANOS_PER_SEC / target_fps as u64;
+        let deadline = match self.deadline {
+            Some(previous) if previous + frame_time > now => previous + frame_time,
+            Some(_) => now, // Overran the frame budget, drop the debt
+            None => now + frame_time,
+        };
+
+        self.deadline = Some(deadline);
+        deadline
+    }
+
+    /// Record that a frame was presented at `now` and return the measured frame rate
+    pub fn record_frame(&mut self, now: u64) -> u32 {
+        if let Some(last) = self.last_frame {
+            let elapsed = now.saturating_sub(last);
+            if elapsed > 0 {
+                self.fps = (NANOS_PER_SEC / elapsed) as u32;
+            }
+        }
+
+        self.last_frame = Some(now);
+        self.fps
+    }
+
+    /// Frame rate measured between the last two frames
+    pub fn fps(&self) -> u32 {
+        self.fps
+    }
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
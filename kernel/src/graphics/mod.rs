@@ -0,0 +1,449 @@
+use bootloader_api::info::{FrameBuffer, FrameBufferInfo, Optional, PixelFormat};
+use x86_64::structures::paging::PhysFrame;
+
+use crate::graphics::font::{FONT, Font, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::mm::memory::BootInfoFrameAllocator;
+use crate::serial_println;
+
+pub mod console;
+pub mod font;
+pub mod frame_timer;
+
+pub use frame_timer::FrameTimer;
+
+/// We only draw with 32-bit pixels
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Why a framebuffer from the bootloader can't be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not 32 bits per pixel (holds the bytes per pixel)
+    UnsupportedPixelSize(usize),
+    /// Width or height is zero
+    Empty,
+    /// A line is shorter than the visible width
+    InvalidStride,
+    /// The buffer is too small for `stride * height` pixels
+    BufferTooSmall,
+}
+
+/// Check that we can draw to a framebuffer with this layout
+pub fn validate(info: &FrameBufferInfo) -> Result<(), Error> {
+    if info.bytes_per_pixel != BYTES_PER_PIXEL {
+        return Err(Error::UnsupportedPixelSize(info.bytes_per_pixel));
+    }
+
+    if info.width == 0 || info.height == 0 {
+        return Err(Error::Empty);
+    }
+
+    if info.stride < info.width {
+        return Err(Error::InvalidStride);
+    }
+
+    let required_bytes = info
+        .stride
+        .checked_mul(info.height)
+        .and_then(|pixels| pixels.checked_mul(BYTES_PER_PIXEL))
+        .ok_or(Error::BufferTooSmall)?;
+    if required_bytes > info.byte_len {
+        return Err(Error::BufferTooSmall);
+    }
+
+    Ok(())
+}
+
+/// Take the framebuffer from the boot info (`boot_info.framebuffer`), if there is one we can use
+/// Returns None (and logs why) if there's no framebuffer or its layout isn't supported, the kernel runs headless then
+///
+/// Takes the field instead of the whole `BootInfo` because the memory map stays borrowed by the frame allocator.
+pub fn from_boot_info(
+    framebuffer: &mut Optional<FrameBuffer>,
+    allocator: &mut BootInfoFrameAllocator,
+    phys_mem_offset: u64,
+) -> Option<Framebuffer> {
+    let Some(mut fb) = framebuffer.take() else {
+        serial_println!("No framebuffer from the bootloader");
+        return None;
+    };
+
+    if let Err(e) = validate(&fb.info()) {
+        serial_println!("Unusable framebuffer ({:?}): {:?}", e, fb.info());
+        return None;
+    }
+
+    if !matches!(
+        fb.info().pixel_format,
+        PixelFormat::Rgb | PixelFormat::Bgr | PixelFormat::U8
+    ) {
+        serial_println!(
+            "[WARNING] Unknown pixel format {:?}, everything will be drawn black",
+            fb.info().pixel_format
+        );
+    }
+
+    match Framebuffer::new(&mut fb, allocator, phys_mem_offset) {
+        Ok(framebuffer) => Some(framebuffer),
+        Err(e) => {
+            serial_println!(
+                "[WARNING] Drawing straight to the screen, {}: {} KiB free, largest range {} KiB ({} permille fragmented)",
+                e,
+                allocator.free_memory() / 1024,
+                allocator.largest_contiguous_free() / 1024,
+                allocator.fragmentation_permille()
+            );
+            Some(Framebuffer::single_buffered(&mut fb))
+        }
+    }
+}
+
+/// Part of the back buffer that changed since the last flip, in pixels (`right` and `bottom` are exclusive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DirtyRect {
+    x: usize,
+    y: usize,
+    right: usize,
+    bottom: usize,
+}
+
+impl DirtyRect {
+    /// Smallest rectangle covering both
+    fn union(self, other: DirtyRect) -> DirtyRect {
+        DirtyRect {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            right: self.right.max(other.right),
+            bottom: self.bottom.max(other.bottom),
+        }
+    }
+}
+
+/// Frames the back buffer was allocated from, so `resize` can give them back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BackBufferFrames {
+    start: PhysFrame,
+    pages: usize,
+}
+
+pub struct Framebuffer {
+    front_buffer: *mut u32, // the actual framebuffer
+    back_buffer: *mut u32,
+    /// None if we didn't allocate the back buffer (single buffered or `from_raw_parts`)
+    back_frames: Option<BackBufferFrames>,
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub pixel_format: PixelFormat,
+    /// What `flip` has to copy, None if nothing changed
+    dirty: Option<DirtyRect>,
+}
+
+// The buffers are only reached through `&mut self`, whoever owns the framebuffer owns the pixels
+unsafe impl Send for Framebuffer {}
+
+impl Framebuffer {
+    /// Set up double buffering for the bootloader's framebuffer
+    /// The layout must have been checked with `validate`, the first `flip` clears the screen.
+    /// Fails if there's no contiguous memory for the back buffer, `single_buffered` still works then.
+    pub fn new(
+        fb: &mut FrameBuffer,
+        allocator: &mut BootInfoFrameAllocator,
+        phys_mem_offset: u64,
+    ) -> Result<Self, &'static str> {
+        let info = fb.info();
+        let front_buffer = fb.buffer_mut().as_mut_ptr() as *mut u32;
+        let width = info.width;
+        let height = info.height;
+        let stride = info.stride;
+        let pixel_format = info.pixel_format;
+
+        // Calculate pages needed
+        let buffer_size = stride * height * 4;
+        let pages_needed = (buffer_size + 4095) / 4096;
+
+        // The back buffer has to be contiguous, the boot allocator is the only one that can do that
+        let phys_addr = allocator
+            .allocate_contiguous(pages_needed)
+            .ok_or("no contiguous memory for the back buffer")?;
+        let virt_addr = phys_addr.start_address() + phys_mem_offset;
+        let back_buffer = virt_addr.as_u64() as *mut u32;
+
+        // Zero the buffer
+        unsafe {
+            core::ptr::write_bytes(back_buffer, 0, stride * height);
+        }
+
+        Ok(Self {
+            front_buffer,
+            back_buffer,
+            back_frames: Some(BackBufferFrames {
+                start: phys_addr,
+                pages: pages_needed,
+            }),
+            width,
+            height,
+            stride,
+            pixel_format,
+            dirty: Some(DirtyRect {
+                x: 0,
+                y: 0,
+                right: width,
+                bottom: height,
+            }),
+        })
+    }
+
+    /// Draw straight to the bootloader's framebuffer, without a back buffer
+    /// Drawing shows up right away (and may flicker), `flip` has nothing to do.
+    pub fn single_buffered(fb: &mut FrameBuffer) -> Self {
+        let info = fb.info();
+        let front_buffer = fb.buffer_mut().as_mut_ptr() as *mut u32;
+
+        Self {
+            front_buffer,
+            back_buffer: front_buffer,
+            back_frames: None,
+            width: info.width,
+            height: info.height,
+            stride: info.stride,
+            pixel_format: info.pixel_format,
+            dirty: None,
+        }
+    }
+
+    /// Switch to a new framebuffer (e.g. after a mode change), with a back buffer sized for it
+    /// The old back buffer goes back to `allocator` first, so the new one can reuse its frames. If the
+    /// new one can't be allocated we draw straight to the new framebuffer and return the error.
+    /// Everything is dirty afterwards, the back buffer starts out black.
+    pub fn resize(
+        &mut self,
+        fb: &mut FrameBuffer,
+        allocator: &mut BootInfoFrameAllocator,
+        phys_mem_offset: u64,
+    ) -> Result<(), &'static str> {
+        if allocator.is_handed_off() {
+            return Err("the boot allocator handed its memory to the heap");
+        }
+
+        if let Some(frames) = self.back_frames.take() {
+            unsafe { allocator.free_contiguous(frames.start, frames.pages) };
+        }
+
+        match Framebuffer::new(fb, allocator, phys_mem_offset) {
+            Ok(framebuffer) => {
+                *self = framebuffer;
+                Ok(())
+            }
+            Err(e) => {
+                *self = Framebuffer::single_buffered(fb);
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether drawing goes straight to the screen, see `single_buffered`
+    pub fn is_single_buffered(&self) -> bool {
+        self.back_buffer == self.front_buffer
+    }
+
+    /// Create a framebuffer from already allocated buffers
+    /// Everything is dirty, the first `flip` copies the whole back buffer.
+    ///
+    /// # Safety
+    /// Both buffers must be valid for `stride * height` u32 writes and stay alive as long as the framebuffer.
+    pub unsafe fn from_raw_parts(
+        front_buffer: *mut u32,
+        back_buffer: *mut u32,
+        width: usize,
+        height: usize,
+        stride: usize,
+        pixel_format: PixelFormat,
+    ) -> Self {
+        Self {
+            front_buffer,
+            back_buffer,
+            back_frames: None,
+            width,
+            height,
+            stride,
+            pixel_format,
+            dirty: Some(DirtyRect {
+                x: 0,
+                y: 0,
+                right: width,
+                bottom: height,
+            }),
+        }
+    }
+
+    /// Pack a color into a pixel for this framebuffer's pixel format
+    /// Pixels are little endian, so the first byte in memory is the lowest byte of the u32.
+    /// Unknown formats get black, `from_boot_info` warns about them.
+    pub fn encode_color(&self, r: u8, g: u8, b: u8) -> u32 {
+        let (r, g, b) = (r as u32, g as u32, b as u32);
+        match self.pixel_format {
+            PixelFormat::Rgb => r | (g << 8) | (b << 16),
+            PixelFormat::Bgr => b | (g << 8) | (r << 16),
+            // Rec. 601 luma, weights out of 256
+            PixelFormat::U8 => (77 * r + 150 * g + 29 * b) >> 8,
+            _ => 0,
+        }
+    }
+
+    /// Copy what changed since the last flip to the screen
+    pub fn flip(&mut self) {
+        let Some(dirty) = self.dirty.take() else {
+            return;
+        };
+        if self.is_single_buffered() {
+            return;
+        }
+
+        for row in dirty.y..dirty.bottom {
+            let start = row * self.stride + dirty.x;
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.back_buffer.add(start),
+                    self.front_buffer.add(start),
+                    dirty.right - dirty.x,
+                );
+            }
+        }
+    }
+
+    /// Copy the whole back buffer to the screen, whether it changed or not
+    pub fn flip_full(&mut self) {
+        self.dirty = None;
+        if self.is_single_buffered() {
+            return;
+        }
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.back_buffer,
+                self.front_buffer,
+                self.stride * self.height,
+            );
+        }
+    }
+
+    /// Writes through this pointer aren't tracked, call `mark_dirty` (or use `flip_full`) to show them
+    pub fn get_back_buffer_ptr(&self) -> *mut u32 {
+        self.back_buffer
+    }
+
+    /// Make the next `flip` copy the `w` x `h` rectangle at (x, y), clipped to the framebuffer
+    pub fn mark_dirty(&mut self, x: usize, y: usize, w: usize, h: usize) {
+        let right = x.saturating_add(w).min(self.width);
+        let bottom = y.saturating_add(h).min(self.height);
+        if x >= right || y >= bottom {
+            return;
+        }
+
+        let rect = DirtyRect {
+            x,
+            y,
+            right,
+            bottom,
+        };
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+
+    /// Set the pixel at (x, y) in the back buffer to a color from `encode_color`, pixels outside the framebuffer are ignored
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            unsafe { *self.back_buffer.add(y * self.stride + x) = color };
+            self.mark_dirty(x, y, 1, 1);
+        }
+    }
+
+    /// Fill a `w` x `h` rectangle with its top-left corner at (x, y), clipped to the framebuffer
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32) {
+        let right = x.saturating_add(w).min(self.width);
+        let bottom = y.saturating_add(h).min(self.height);
+        if x >= right {
+            return;
+        }
+
+        for row in y..bottom {
+            let start = row * self.stride + x;
+            unsafe {
+                core::slice::from_raw_parts_mut(self.back_buffer.add(start), right - x).fill(color)
+            };
+        }
+
+        self.mark_dirty(x, y, w, h);
+    }
+
+    /// Fill the whole back buffer with `color`
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Move the back buffer up by `lines` rows of pixels, the rows uncovered at the bottom get `color`
+    pub fn scroll_up(&mut self, lines: usize, color: u32) {
+        let lines = lines.min(self.height);
+        let moved = (self.height - lines) * self.stride;
+
+        // The regions overlap, `copy` handles that
+        unsafe {
+            core::ptr::copy(
+                self.back_buffer.add(lines * self.stride),
+                self.back_buffer,
+                moved,
+            )
+        };
+        self.fill_rect(0, self.height - lines, self.width, lines, color);
+        self.mark_dirty(0, 0, self.width, self.height);
+    }
+}
+
+/// Draw a single character to the back buffer with its top-left corner at (x, y)
+/// `fg` and `bg` come from `Framebuffer::encode_color`, pixels that fall outside the framebuffer are clipped
+pub fn draw_char(fb: &mut Framebuffer, x: usize, y: usize, ch: char, fg: u32, bg: u32) {
+    let glyph = FONT.glyph(ch);
+    let back_buffer = fb.get_back_buffer_ptr();
+
+    for row in 0..GLYPH_HEIGHT {
+        // Rows past the bottom, or past usize::MAX, aren't drawn
+        let Some(py) = y.checked_add(row).filter(|&py| py < fb.height) else {
+            break;
+        };
+
+        for col in 0..GLYPH_WIDTH {
+            let Some(px) = x.checked_add(col).filter(|&px| px < fb.width) else {
+                break;
+            };
+
+            let color = if Font::is_set(glyph, col, row) {
+                fg
+            } else {
+                bg
+            };
+            unsafe { *back_buffer.add(py * fb.stride + px) = color };
+        }
+    }
+
+    fb.mark_dirty(x, y, GLYPH_WIDTH, GLYPH_HEIGHT);
+}
+
+/// Draw a string to the back buffer starting at (x, y)
+/// A newline moves back to `x` on the next row of glyphs
+pub fn draw_string(fb: &mut Framebuffer, x: usize, y: usize, s: &str, fg: u32, bg: u32) {
+    let mut cx = x;
+    let mut cy = y;
+
+    for ch in s.chars() {
+        if ch == '\n' {
+            cx = x;
+            cy += GLYPH_HEIGHT;
+            continue;
+        }
+
+        draw_char(fb, cx, cy, ch, fg, bg);
+        cx += GLYPH_WIDTH;
+    }
+}
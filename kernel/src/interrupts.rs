@@ -1,7 +1,18 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 
+use x86_64::PrivilegeLevel;
+use x86_64::structures::idt::{
+    DescriptorTable, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+    SelectorErrorCode,
+};
+
+use crate::debug;
 use crate::drivers;
+use crate::mm::demand;
+use crate::tasks;
 use crate::tasks::switch::timer_interrupt_entry;
+use crate::tasks::task::TaskContext;
 use crate::{
     drivers::exit::{QemuExitCode, exit_qemu},
     gdt, serial_println,
@@ -14,6 +25,7 @@ use spin::Lazy;
 pub enum InterruptIndex {
     Keyboard = 33,
     Timer = 32,
+    Serial = 36,
     Mouse = 44,
 }
 
@@ -35,6 +47,24 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     }
 
     idt.divide_error.set_handler_fn(divide_by_zero_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    // CR4.MCE is enabled in `init_syscalls` when the CPU supports it
+    idt.machine_check.set_handler_fn(machine_check_handler);
+
+    // Tasks may hit breakpoints too
+    // Naked like the timer entry, it saves the general purpose registers for gdb
+    unsafe {
+        let handler: extern "x86-interrupt" fn(InterruptStackFrame) =
+            core::mem::transmute(breakpoint_entry as *const ());
+        idt.breakpoint
+            .set_handler_fn(handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+    }
 
     // We cast to the expected type since it's a naked function that manages its own frame
     unsafe {
@@ -46,6 +76,7 @@ pub static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     idt[InterruptIndex::Keyboard as u8]
         .set_handler_fn(drivers::keyboard::keyboard_interrupt_handler);
     idt[InterruptIndex::Mouse as u8].set_handler_fn(drivers::mouse::mouse_interrupt_handler);
+    idt[InterruptIndex::Serial as u8].set_handler_fn(drivers::serial::serial_interrupt_handler);
 
     idt
 });
@@ -54,12 +85,315 @@ pub fn init() {
     IDT.load();
 }
 
+/// Human readable breakdown of a page fault error code, e.g. "not present, write, user mode"
+pub struct PageFaultDescription(pub PageFaultErrorCode);
+
+impl fmt::Display for PageFaultDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            write!(f, "protection violation")?;
+        } else {
+            write!(f, "not present")?;
+        }
+
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            write!(f, ", instruction fetch")?;
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            write!(f, ", write")?;
+        } else {
+            write!(f, ", read")?;
+        }
+
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            write!(f, ", user mode")?;
+        } else {
+            write!(f, ", supervisor mode")?;
+        }
+
+        // Rare bits, only mentioned when set
+        let extra = [
+            (PageFaultErrorCode::MALFORMED_TABLE, "reserved bit set"),
+            (PageFaultErrorCode::PROTECTION_KEY, "protection key"),
+            (PageFaultErrorCode::SHADOW_STACK, "shadow stack"),
+            (PageFaultErrorCode::HLAT, "HLAT paging"),
+            (PageFaultErrorCode::SGX, "SGX"),
+            (PageFaultErrorCode::RMP, "RMP"),
+        ];
+        for (flag, name) in extra {
+            if code.contains(flag) {
+                write!(f, ", {}", name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Human readable breakdown of a selector error code (as pushed by a GP fault)
+pub struct SelectorDescription(pub u64);
+
+impl fmt::Display for SelectorDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "none (not caused by a segment selector)");
+        }
+
+        let selector = SelectorErrorCode::new_truncate(self.0);
+        let table = match selector.descriptor_table() {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        };
+        write!(f, "{} index {}", table, selector.index())?;
+
+        if selector.external() {
+            write!(f, ", external event")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Human readable breakdown of a machine check bank's IA32_MCi_STATUS register
+pub struct MachineCheckStatus(pub u64);
+
+impl MachineCheckStatus {
+    /// The bank logged an error (VAL)
+    pub fn is_valid(&self) -> bool {
+        self.0 & (1 << 63) != 0
+    }
+
+    /// IA32_MCi_ADDR holds the address of the error (ADDRV)
+    pub fn has_address(&self) -> bool {
+        self.0 & (1 << 58) != 0
+    }
+
+    /// IA32_MCi_MISC holds more information (MISCV)
+    pub fn has_misc(&self) -> bool {
+        self.0 & (1 << 59) != 0
+    }
+}
+
+impl fmt::Display for MachineCheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // MCA error code in the low 16 bits, model specific code above it
+        write!(
+            f,
+            "MCA error {:#06x}, model specific {:#06x}",
+            self.0 & 0xFFFF,
+            (self.0 >> 16) & 0xFFFF
+        )?;
+
+        let flags = [
+            (62, "overflow"),
+            (61, "uncorrected"),
+            (60, "enabled"),
+            (57, "processor context corrupt"),
+        ];
+        for (bit, name) in flags {
+            if self.0 & (1 << bit) != 0 {
+                write!(f, ", {}", name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if the interrupted code was running in ring 3
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment.rpl() == PrivilegeLevel::Ring3
+}
+
 extern "x86-interrupt" fn divide_by_zero_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
 
     exit_qemu(QemuExitCode::Failed)
 }
 
+/// Kill the running task if the fault came from ring 3, returns if it came from the kernel
+fn kill_faulting_task(stack_frame: &InterruptStackFrame, reason: &str) {
+    if from_user_mode(stack_frame)
+        && let Some(id) = tasks::current_task_id()
+    {
+        serial_println!("Killing task {} for {}", id, reason);
+        tasks::exit_from_fault();
+    }
+}
+
+/// Number of breakpoints hit since boot
+static BREAKPOINTS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of breakpoints (`int3`) hit since boot, the handler always returns to the code after them
+pub fn breakpoint_count() -> u64 {
+    BREAKPOINTS.load(Ordering::Relaxed)
+}
+
+/// #BP entry point, pushes the general purpose registers so the stack holds a full TaskContext
+/// Same layout as `timer_interrupt_entry`, the CPU pushes SS and RSP for ring 0 traps too.
+#[unsafe(naked)]
+extern "C" fn breakpoint_entry() {
+    core::arch::naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+        handler = sym breakpoint_handler,
+    );
+}
+
+extern "C" fn breakpoint_handler(context: &TaskContext) {
+    BREAKPOINTS.fetch_add(1, Ordering::Relaxed);
+
+    // Without a debug port there is nobody to hand control to, just note it and keep going
+    if !debug::enter_debugger(context) {
+        serial_println!(
+            "EXCEPTION: BREAKPOINT at {:#x} (no debug port)",
+            context.rip
+        );
+    }
+}
+
+/// #AC: unaligned access while alignment checking is on
+/// CR0.AM is set, so ring 3 code that sets RFLAGS.AC gets these. The kernel never runs with CPL 3,
+/// so a fault from ring 0 means something is really wrong.
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    serial_println!(
+        "EXCEPTION: ALIGNMENT CHECK at {:#x} (error code {:#x})",
+        stack_frame.instruction_pointer.as_u64(),
+        error_code
+    );
+
+    kill_faulting_task(&stack_frame, "an unaligned access");
+
+    serial_println!("{:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed)
+}
+
+/// #UD: the CPU doesn't know the instruction (or it isn't available, like SSE without enabling it)
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    serial_println!(
+        "EXCEPTION: INVALID OPCODE at {:#x}",
+        stack_frame.instruction_pointer.as_u64()
+    );
+
+    kill_faulting_task(&stack_frame, "an invalid instruction");
+
+    serial_println!("{:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed)
+}
+
+/// #NP: a segment register was loaded with a descriptor that isn't present
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    serial_println!("EXCEPTION: SEGMENT NOT PRESENT");
+    serial_println!(
+        "Error Code: {:#x}, selector: {}",
+        error_code,
+        SelectorDescription(error_code)
+    );
+
+    kill_faulting_task(&stack_frame, "a segment that isn't present");
+
+    serial_println!("{:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed)
+}
+
+/// #SS: a stack access outside of the stack segment, or a non-canonical stack address
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    serial_println!("EXCEPTION: STACK SEGMENT FAULT");
+    serial_println!(
+        "Error Code: {:#x}, selector: {}",
+        error_code,
+        SelectorDescription(error_code)
+    );
+
+    kill_faulting_task(&stack_frame, "a bad stack access");
+
+    serial_println!("{:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed)
+}
+
+/// #MC: the CPU detected a hardware error, log every bank that reported one and stop
+/// Machine checks are generally unrecoverable, so we don't try to continue.
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    use x86_64::registers::model_specific::Msr;
+
+    const IA32_MCG_CAP: u32 = 0x179;
+    const IA32_MCG_STATUS: u32 = 0x17A;
+    const IA32_MC0_STATUS: u32 = 0x401;
+
+    serial_println!("EXCEPTION: MACHINE CHECK");
+
+    unsafe {
+        let bank_count = Msr::new(IA32_MCG_CAP).read() & 0xFF;
+        serial_println!(
+            "MCG_STATUS: {:#x}, {} banks",
+            Msr::new(IA32_MCG_STATUS).read(),
+            bank_count
+        );
+
+        // Every bank has STATUS, ADDR and MISC registers (and CTL before them)
+        for bank in 0..bank_count as u32 {
+            let status_msr = IA32_MC0_STATUS + bank * 4;
+            let status = MachineCheckStatus(Msr::new(status_msr).read());
+            if !status.is_valid() {
+                continue;
+            }
+
+            serial_println!("  Bank {}: {}", bank, status);
+            if status.has_address() {
+                serial_println!("    Address: {:#x}", Msr::new(status_msr + 1).read());
+            }
+            if status.has_misc() {
+                serial_println!("    Misc: {:#x}", Msr::new(status_msr + 2).read());
+            }
+        }
+    }
+
+    serial_println!("{:#?}", stack_frame);
+    exit_qemu(QemuExitCode::Failed)
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
@@ -75,9 +409,34 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let address = Cr2::read();
+
+    // A lazily mapped page being touched for the first time, map it and retry the access
+    let demand_paged = match address {
+        Ok(addr) if from_user_mode(&stack_frame) => tasks::handle_page_fault(addr, error_code),
+        _ => Err(demand::Error::NotDemandPaged),
+    };
+    if demand_paged.is_ok() {
+        return;
+    }
+
     serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
-    serial_println!("Error Code: {:?}", error_code);
+    serial_println!("Accessed Address: {:?}", address);
+    serial_println!(
+        "Error Code: {:?} ({})",
+        error_code,
+        PageFaultDescription(error_code)
+    );
+    serial_println!("From user mode: {}", from_user_mode(&stack_frame));
+    if let Err(e) = demand_paged {
+        serial_println!("Demand paging: {:?}", e);
+    }
+
+    // Don't lock the scheduler here, we might have interrupted code that holds the lock
+    match tasks::current_task_id() {
+        Some(id) => serial_println!("Faulting task: {}", id),
+        None => serial_println!("Faulting task: none (kernel init)"),
+    }
     serial_println!("{:#?}", stack_frame);
 
     exit_qemu(QemuExitCode::Failed)
@@ -88,7 +447,12 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     error_code: u64,
 ) {
     serial_println!("EXCEPTION: GENERAL PROTECTION FAULT");
-    serial_println!("Error Code: {:?}", error_code);
+    serial_println!(
+        "Error Code: {:#x}, selector: {}",
+        error_code,
+        SelectorDescription(error_code)
+    );
+    serial_println!("From user mode: {}", from_user_mode(&stack_frame));
     serial_println!("{:#?}", stack_frame);
 
     exit_qemu(QemuExitCode::Failed)
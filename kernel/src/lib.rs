@@ -3,8 +3,12 @@
 
 extern crate alloc;
 
+use bootloader_api::BootInfo;
 use x86_64::instructions::hlt;
 
+pub mod boot;
+pub mod cpu;
+pub mod debug;
 pub mod drivers;
 pub mod events;
 pub mod gdt;
@@ -12,15 +16,11 @@ pub mod graphics;
 pub mod interrupts;
 pub mod mm;
 pub mod tasks;
+pub mod time;
 
-/// Initialize the kernel
-pub fn init() {
-    drivers::init();
-
-    gdt::init();
-    interrupts::init();
-
-    tasks::init();
+/// Initialize the kernel, see `boot` for the stages
+pub fn init(boot_info: &'static mut BootInfo) -> Result<boot::Kernel, boot::StageError> {
+    boot::init(boot_info)
 }
 
 /// Halt the CPU forever
@@ -1,20 +1,28 @@
 #![no_std]
 #![no_main]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 #[cfg(not(test))]
-use core::panic::PanicInfo;
+use core::{alloc::Layout, panic::PanicInfo};
 
 use bootloader_api::{BootInfo, BootloaderConfig, config::Mapping, entry_point};
 
 use kernel::{
-    graphics::Framebuffer,
-    mm::{allocator, memory::BootInfoFrameAllocator, user::BuddyFrameAllocator},
+    events::{self, EventKind},
+    graphics, kprintln,
+    mm::{allocator, user::BuddyFrameAllocator},
     serial_println,
-    tasks::{SCHEDULER, switch::switch_to_first_task, task::Task},
+    tasks::{
+        self, DEFAULT_KERNEL_STACK_PAGES, SCHEDULER, Scheduler,
+        switch::switch_to_first_task,
+        task::{StackSizes, Task},
+    },
+    time,
 };
 use x86_64::VirtAddr;
 use x86_64::instructions::interrupts;
@@ -28,54 +36,43 @@ static BOOTLOADER_CONFIG: BootloaderConfig = {
 entry_point!(main, config = &BOOTLOADER_CONFIG);
 
 fn main(boot_info: &'static mut BootInfo) -> ! {
-    kernel::init(); // If you dare to call serial_print before this, I'm not responsible for the consequences...
-
-    serial_println!("Hello World!");
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { kernel::mm::memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    let kernel::boot::Kernel {
+        phys_mem_offset,
+        framebuffer,
+        ..
+    } = match kernel::init(boot_info) {
+        Ok(kernel) => kernel,
+        Err(e) => panic!("Boot failed: {}", e),
+    };
 
-    serial_println!("Initializing graphics...");
+    match framebuffer {
+        Some(mut framebuffer) => {
+            serial_println!("Testing graphics...");
 
-    let mut framebuffer = Framebuffer::new(
-        boot_info.framebuffer.take().unwrap(),
-        &mut frame_allocator,
-        phys_mem_offset.as_u64(),
-    );
+            framebuffer.flip();
 
-    serial_println!("Testing graphics...");
+            let green = framebuffer.encode_color(0, 255, 0);
+            framebuffer.clear(green);
 
-    framebuffer.flip();
+            framebuffer.flip();
 
-    // Write some pixels to the back buffer for testing
-    unsafe {
-        let back_buffer = framebuffer.get_back_buffer_ptr();
-        for y in 0..framebuffer.height {
-            for x in 0..framebuffer.width {
-                let offset = y * framebuffer.stride + x;
-                *back_buffer.add(offset) = 0x00FF00; // Green
-            }
+            graphics::console::init(framebuffer);
+            kprintln!("Welcome to lymadOS");
         }
+        None => serial_println!("Running headless"),
     }
 
-    framebuffer.flip();
-
-    serial_println!("Initializing heap...");
-
-    allocator::init_heap(phys_mem_offset.as_u64() as usize);
-
-    // Just grab all frames and add them to the buddy system for testing
-    let mut frame_iter = frame_allocator.usable_frames();
-    for frame in frame_iter.by_ref() {
-        let phys_addr = frame.start_address();
-        let virt_addr = phys_mem_offset + phys_addr.as_u64();
+    if cfg!(feature = "oom_selftest") {
+        exhaust_heap();
+    }
 
-        unsafe { allocator::add_frame(virt_addr.as_mut_ptr()) };
+    if cfg!(feature = "breakpoint_selftest") {
+        breakpoint();
     }
 
-    // drop the iterator to make us able to borrow frame_allocator again later
-    drop(frame_iter);
+    if cfg!(feature = "heap_selftest") {
+        large_vec();
+    }
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -101,38 +98,128 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
         Rc::strong_count(&cloned_reference)
     );
 
-    serial_println!("Initializing APIC...");
+    interrupts::enable();
+
+    if cfg!(feature = "no_user_tasks") {
+        serial_println!("Not creating user tasks (no_user_tasks feature)");
+    } else {
+        create_user_tasks(phys_mem_offset);
+    }
+
+    let keyboard_logger = events::subscribe(EventKind::Keyboard, |event| {
+        serial_println!("Event: {:?}", event);
+    });
+    if let Err(e) = keyboard_logger {
+        serial_println!("[WARNING] Failed to subscribe to keyboard events: {}", e);
+    }
+
+    // Bytes typed into the serial console
+    let serial_logger = events::subscribe(EventKind::Serial, |event| {
+        serial_println!("Event: {:?}", event);
+    });
+    if let Err(e) = serial_logger {
+        serial_println!("[WARNING] Failed to subscribe to serial events: {}", e);
+    }
+
+    // Make sure loading the tasks didn't make any kernel memory reachable from ring 3
+    let user_pages = kernel::mm::audit_user_accessible();
+    serial_println!("User page audit passed ({} user pages)", user_pages);
+
+    {
+        let mut scheduler = SCHEDULER.lock();
+
+        // Without tasks the kernel idles in switch_to_first_task, nothing for these to serve
+        if !cfg!(feature = "no_user_tasks") {
+            add_kernel_tasks(&mut scheduler);
+        }
+
+        if cfg!(feature = "alignment_selftest") {
+            add_alignment_check(&mut scheduler);
+        }
+
+        serial_println!("Total tasks: {}", scheduler.task_count());
+        for info in scheduler.snapshot() {
+            serial_println!(
+                "  Task {}: {:?}, {} resident pages",
+                info.id,
+                info.state,
+                info.resident_pages
+            );
+        }
+
+        // Start the scheduler
+        scheduler.start();
+    }
+
+    serial_println!("Switching to first task...");
+
+    // Switch to the first task (never returns)
     unsafe {
-        kernel::drivers::apic::init(
-            *boot_info.rsdp_addr.as_ref().unwrap() as usize,
-            phys_mem_offset,
-            &mut mapper,
-            &mut frame_allocator,
-        );
-    };
+        switch_to_first_task();
+    }
+}
 
-    interrupts::enable();
+/// Add the event loop and the idle task, they run on the kernel's page table
+fn add_kernel_tasks(scheduler: &mut Scheduler) {
+    // The event loop runs as a kernel task, so the system keeps handling input after the user tasks exit
+    match Task::new_kernel(events::event_loop, DEFAULT_KERNEL_STACK_PAGES) {
+        Ok(task) => {
+            if let Err(e) = scheduler.add_task(task) {
+                serial_println!("[WARNING] Failed to add event loop task: {:?}", e);
+            }
+        }
+        Err(e) => serial_println!("[WARNING] Failed to create event loop task: {}", e),
+    }
+
+    // Runs when everything else is blocked, e.g. while the event loop waits and the user tasks sleep
+    match Task::new_kernel(tasks::idle_loop, DEFAULT_KERNEL_STACK_PAGES) {
+        Ok(task) => {
+            if let Err(e) = scheduler.set_idle_task(task) {
+                serial_println!("[WARNING] Failed to add idle task: {:?}", e);
+            }
+        }
+        Err(e) => serial_println!("[WARNING] Failed to create idle task: {}", e),
+    }
+}
 
+// Embed the user program at compile time, selftests that need one in ring 3 run theirs instead
+#[cfg(not(any(feature = "syscall_selftest", feature = "alignment_selftest")))]
+static USER_PROGRAM: (&str, &[u8]) = ("hello_world", include_bytes!("resources/hello_world.elf"));
+#[cfg(feature = "syscall_selftest")]
+static USER_PROGRAM: (&str, &[u8]) = ("syscall_args", include_bytes!("resources/syscall_args.elf"));
+#[cfg(all(feature = "alignment_selftest", not(feature = "syscall_selftest")))]
+static USER_PROGRAM: (&str, &[u8]) = (
+    "alignment_check",
+    include_bytes!("resources/alignment_check.elf"),
+);
+
+/// ID of the task running USER_PROGRAM, 0 until it's loaded
+static USER_TASK: AtomicU64 = AtomicU64::new(0);
+
+/// Load the embedded user programs and add them to the scheduler
+fn create_user_tasks(phys_mem_offset: VirtAddr) {
     // Create user tasks
     serial_println!("Creating user tasks...");
 
-    // Embed the hello.elf binary at compile time
-    static HELLO_ELF: &[u8] = include_bytes!("resources/hello_world.elf");
-    serial_println!("Embedded hello.elf: {} bytes", HELLO_ELF.len());
+    let (name, elf) = USER_PROGRAM;
+    serial_println!("Embedded {}.elf: {} bytes", name, elf.len());
 
     // Use the buddy allocator for ELF loading
     let mut buddy_frame_alloc = BuddyFrameAllocator;
 
     serial_println!("About to load ELF...");
 
-    let elf_task = match unsafe {
+    let result = unsafe {
         Task::from_elf(
-            HELLO_ELF,
-            &mut mapper,
+            elf,
+            &[name],
+            StackSizes::default(),
             &mut buddy_frame_alloc,
             phys_mem_offset,
         )
-    } {
+    };
+
+    let elf_task = match result {
         Ok(task) => task,
         Err(e) => {
             serial_println!("Failed to load ELF: {:?}", e);
@@ -146,25 +233,141 @@ fn main(boot_info: &'static mut BootInfo) -> ! {
         elf_task.context.rip
     );
 
-    {
-        let mut scheduler = SCHEDULER.lock();
+    USER_TASK.store(elf_task.id, Ordering::Relaxed);
+    if let Err(e) = SCHEDULER.lock().add_task(elf_task) {
+        serial_println!("[WARNING] Failed to add ELF task: {:?}", e);
+    }
+}
 
-        scheduler.add_task(elf_task);
+/// Add the kernel task that checks on the alignment selftest's user program
+fn add_alignment_check(scheduler: &mut Scheduler) {
+    match Task::new_kernel(check_alignment_fault, DEFAULT_KERNEL_STACK_PAGES) {
+        Ok(task) => {
+            if let Err(e) = scheduler.add_task(task) {
+                serial_println!("[WARNING] Failed to add alignment check task: {:?}", e);
+            }
+        }
+        Err(e) => serial_println!("[WARNING] Failed to create alignment check task: {}", e),
+    }
+}
 
-        serial_println!("Total tasks: {}", scheduler.task_count());
+/// Wait for the user program's misaligned load to get it killed, it passes if the task exited with -1
+/// and we still get scheduled afterwards
+extern "C" fn check_alignment_fault() -> ! {
+    use kernel::drivers::exit::{QemuExitCode, exit_qemu};
 
-        // Start the scheduler
-        scheduler.start();
-    }
+    let id = USER_TASK.load(Ordering::Relaxed);
+    let hz = time::tick_frequency();
 
-    serial_println!("Switching to first task...");
+    // Give it 2 seconds to fault
+    let deadline = time::ticks() + 2 * hz;
+    let status = loop {
+        let status = interrupts::without_interrupts(|| SCHEDULER.lock().exit_status(id));
+        if status.is_some() || time::ticks() >= deadline {
+            break status;
+        }
+        tasks::sleep_until(time::ticks() + 1);
+    };
+    serial_println!("Alignment selftest: task {} exited with {:?}", id, status);
 
-    // Switch to the first task (never returns)
-    unsafe {
-        switch_to_first_task();
+    // The scheduler has to wake us up again after the fault
+    let before = time::ticks();
+    tasks::sleep_until(before + hz / 5 + 1);
+    let slept = time::ticks() - before;
+    serial_println!(
+        "Alignment selftest: slept for {} ticks after the fault",
+        slept
+    );
+
+    exit_qemu(if status == Some(-1) && slept > 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    })
+}
+
+/// Fill a Vec bigger than any slab, every byte of it has to hold and go back to the heap
+fn large_vec() -> ! {
+    use kernel::drivers::exit::{QemuExitCode, exit_qemu};
+
+    // 4 MiB, an order 10 buddy block
+    const LEN: usize = 512 * 1024;
+
+    let before = allocator::free_memory();
+    let vec: Vec<u64> = (0..LEN as u64).collect();
+    let intact = vec.iter().enumerate().all(|(i, &value)| value == i as u64);
+    serial_println!(
+        "Heap selftest: {} KiB Vec at {:p}, {} KiB free",
+        LEN * 8 / 1024,
+        vec.as_ptr(),
+        allocator::free_memory() / 1024
+    );
+    drop(vec);
+    let after = allocator::free_memory();
+
+    serial_println!(
+        "Heap selftest: contents {}, {} KiB free before, {} KiB after",
+        if intact { "intact" } else { "corrupted" },
+        before / 1024,
+        after / 1024
+    );
+    exit_qemu(if intact && after == before {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    });
+}
+
+/// Run `int3`, the breakpoint handler has to log it and return right after it
+fn breakpoint() -> ! {
+    use kernel::drivers::exit::{QemuExitCode, exit_qemu};
+
+    let before = kernel::interrupts::breakpoint_count();
+    x86_64::instructions::interrupts::int3();
+    let after = kernel::interrupts::breakpoint_count();
+
+    serial_println!("Breakpoint selftest: {} breakpoints hit", after - before);
+    exit_qemu(if after == before + 1 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    });
+}
+
+/// Leak page sized allocations until the heap runs out, `alloc_error` ends the test
+fn exhaust_heap() -> ! {
+    serial_println!("OOM selftest: exhausting the heap...");
+
+    let mut pages = 0usize;
+    loop {
+        let page: Vec<u8> = Vec::with_capacity(4096);
+        core::mem::forget(page);
+
+        pages += 1;
+        if pages.is_multiple_of(4096) {
+            serial_println!("OOM selftest: {} pages allocated", pages);
+        }
     }
 }
 
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    serial_println!("[ERROR] Out of memory: failed to allocate {:?}", layout);
+    allocator::log_stats();
+
+    if cfg!(feature = "oom_selftest") {
+        serial_println!("OOM selftest: allocation error handler fired");
+        kernel::drivers::exit::exit_qemu(kernel::drivers::exit::QemuExitCode::Success);
+    }
+
+    panic!(
+        "Out of memory: allocation of {} bytes (align {}) failed",
+        layout.size(),
+        layout.align()
+    );
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
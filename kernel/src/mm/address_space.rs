@@ -0,0 +1,168 @@
+// Address spaces
+//
+// Every user task has its own level 4 table. The kernel's mappings are in all of them: the higher
+// half entries point to the kernel's own tables, and the few things the kernel maps in the lower
+// half (the APIC, the bootloader's mappings...) are copied, so user pages mapped next to them stay
+// private. Lower half kernel mappings made after a task was created don't show up in its table.
+// Also lets the kernel look at another task's memory by temporarily running on its page table.
+
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+};
+
+use crate::{drivers::apic, mm::memory, tasks::task::Task};
+
+/// Level 4 entries from here on cover the higher half, they are shared by every address space
+const KERNEL_HALF: usize = 256;
+
+/// Create a new address space with the kernel's mappings from `kernel_table` and no user pages
+/// Returns its level 4 table, nothing is allocated if this fails.
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`, and `kernel_table`
+/// must be a valid level 4 table.
+pub unsafe fn new_address_space(
+    kernel_table: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<PhysFrame, &'static str> {
+    unsafe { copy_table(kernel_table, 4, physical_memory_offset, frame_allocator) }
+}
+
+/// Free the page tables of an address space made by `new_address_space`, and its level 4 table
+/// The pages mapped in it aren't touched, unmap the ones the task owns first.
+///
+/// # Safety
+/// The address space must not be active or used anymore, same requirements as `new_address_space`.
+pub unsafe fn free_address_space(
+    level_4_table: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    unsafe { free_tables(level_4_table, 4, physical_memory_offset, frame_deallocator) };
+}
+
+/// Make `level_4_table` the active address space, if it isn't already
+///
+/// # Safety
+/// The table must map the kernel, see `new_address_space`.
+pub unsafe fn activate(level_4_table: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != level_4_table {
+        unsafe { Cr3::write(level_4_table, flags) };
+    }
+}
+
+fn table_at(frame: PhysFrame, physical_memory_offset: VirtAddr) -> *mut PageTable {
+    (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+/// Copy the `level` table in `source` and every private table below it, skipping user pages
+unsafe fn copy_table(
+    source: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<PhysFrame, &'static str> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or("Failed to allocate page table")?;
+    let table = unsafe { &mut *table_at(frame, physical_memory_offset) };
+    let source = unsafe { &*table_at(source, physical_memory_offset) };
+    *table = PageTable::new();
+
+    for (index, entry) in source.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::USER_ACCESSIBLE)
+        {
+            continue;
+        }
+
+        // Pages and the kernel's half are shared, only tables that user pages could end up in are copied
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) || is_shared(level, index) {
+            table[index] = entry.clone();
+            continue;
+        }
+
+        let next = PhysFrame::containing_address(entry.addr());
+        match unsafe { copy_table(next, level - 1, physical_memory_offset, frame_allocator) } {
+            Ok(copy) => table[index].set_addr(copy.start_address(), flags),
+            Err(e) => {
+                unsafe { free_tables(frame, level, physical_memory_offset, frame_allocator) };
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(frame)
+}
+
+/// Free the `level` table in `frame` and the private tables below it, but not the pages they map
+unsafe fn free_tables(
+    frame: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    if level > 1 {
+        let table = unsafe { &*table_at(frame, physical_memory_offset) };
+        for (index, entry) in table.iter().enumerate() {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT)
+                || flags.contains(PageTableFlags::HUGE_PAGE)
+                || is_shared(level, index)
+            {
+                continue;
+            }
+
+            let next = PhysFrame::containing_address(entry.addr());
+            unsafe { free_tables(next, level - 1, physical_memory_offset, frame_deallocator) };
+        }
+    }
+
+    unsafe { frame_deallocator.deallocate_frame(frame) };
+}
+
+/// Check if an entry points to one of the kernel's tables instead of a copy
+fn is_shared(level: u8, index: usize) -> bool {
+    level == 4 && index >= KERNEL_HALF
+}
+
+/// Run `f` with `task`'s address space active and a mapper for its page table
+///
+/// CR3 is switched to the task's page table (and back afterwards) with interrupts disabled,
+/// so we can't be preempted while running on someone else's tables. Must not be called from an
+/// interrupt handler, the interrupted code expects its own address space when we return.
+pub fn with_address_space<R>(task: &Task, f: impl FnOnce(&mut OffsetPageTable) -> R) -> R {
+    assert!(
+        !apic::in_service(),
+        "with_address_space called from interrupt context"
+    );
+
+    interrupts::without_interrupts(|| {
+        let (current, flags) = Cr3::read();
+        let target = task.page_table;
+        let switch = target != current;
+
+        if switch {
+            unsafe { Cr3::write(target, flags) };
+        }
+
+        let mut mapper = unsafe { memory::mapper_for(target) };
+
+        let result = f(&mut mapper);
+
+        if switch {
+            unsafe { Cr3::write(current, flags) };
+        }
+
+        result
+    })
+}
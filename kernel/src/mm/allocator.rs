@@ -1,7 +1,10 @@
-use crate::mm::buddy::BuddyAllocator;
+use crate::mm::buddy::{self, BuddyAllocator, BuddyFragInfo, MAX_ORDER};
+use crate::mm::emergency::{EMERGENCY_ARENA_SIZE, EmergencyArena};
 use crate::mm::slub::{PAGE_SIZE, PageProvider, SCache};
+use crate::serial_println;
 use core::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 use x86_64::structures::paging::{PhysFrame, Size4KiB};
 
@@ -9,22 +12,37 @@ pub struct GlobalPageAllocator {
     frame_allocator: BuddyAllocator,
 }
 
+impl GlobalPageAllocator {
+    /// Allocate a block of 2^order pages, returns its virtual address
+    fn alloc_pages(&mut self, order: usize) -> Option<*mut u8> {
+        unsafe { self.frame_allocator.alloc(order) }
+    }
+
+    fn free_pages(&mut self, ptr: *mut u8, order: usize) {
+        unsafe { self.frame_allocator.dealloc(ptr, order) };
+    }
+}
+
 impl PageProvider for GlobalPageAllocator {
     fn alloc_page(&mut self) -> Option<*mut u8> {
-        // We only support 4KiB pages for now (order 0)
-        // TODO: support larger pages
-        let frame = unsafe { self.frame_allocator.alloc(0) }?;
-        // This should be a virtual address
-        Some(frame)
+        self.alloc_pages(0)
     }
 
     fn free_page(&mut self, ptr: *mut u8) {
-        unsafe { self.frame_allocator.dealloc(ptr, 0) };
+        self.free_pages(ptr, 0);
     }
 }
 
 static PAGE_ALLOCATOR: Mutex<Option<GlobalPageAllocator>> = Mutex::new(None);
 
+/// Fallback for allocations the heap can't serve (not initialized yet or out of memory)
+static EMERGENCY_ARENA: EmergencyArena<EMERGENCY_ARENA_SIZE> = EmergencyArena::new();
+
+/// Allocations the emergency arena served and couldn't serve, reported by `log_stats`
+/// Only counted here: printing from inside the allocator could allocate or deadlock on the serial port.
+static EMERGENCY_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static EMERGENCY_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
 pub struct SlubAllocator {
     caches: [Mutex<SCache>; 8], // 16, 32, 64, 128, 256, 512, 1024, 2048
 }
@@ -46,104 +64,166 @@ impl SlubAllocator {
     }
 }
 
-unsafe impl GlobalAlloc for SlubAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
+impl SlubAllocator {
+    /// Fall back to the emergency arena when the heap couldn't serve an allocation
+    fn alloc_emergency(&self, layout: Layout) -> *mut u8 {
+        let ptr = EMERGENCY_ARENA.alloc(layout);
 
-        // Handle large allocations (> 2048 bytes)
-        if size > 2048 {
-            // We only support single page allocations for large objects for now
-            // TODO: Implement multi-page allocations
-            if size <= PAGE_SIZE {
+        if ptr.is_null() {
+            EMERGENCY_FAILURES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            EMERGENCY_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    /// Allocate from the slab caches or the buddy allocator, null if the heap can't do it
+    unsafe fn alloc_heap(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = SizeClass::of(layout) else {
+            return ptr::null_mut();
+        };
+
+        let index = match class {
+            SizeClass::Slab(index) => index,
+            SizeClass::Pages(order) => {
                 let mut provider = PAGE_ALLOCATOR.lock();
-                if let Some(p) = provider.as_mut() {
-                    if let Some(ptr) = p.alloc_page() {
-                        return ptr;
-                    }
+                let Some(p) = provider.as_mut() else {
+                    return ptr::null_mut();
+                };
+                let Some(ptr) = p.alloc_pages(order) else {
+                    return ptr::null_mut();
+                };
+
+                // Blocks are aligned in physical memory, the physical memory mapping is only 2 MiB aligned
+                if !(ptr as usize).is_multiple_of(layout.align()) {
+                    p.free_pages(ptr, order);
+                    return ptr::null_mut();
                 }
-            }
-            return ptr::null_mut();
-        }
 
-        // Find index
-        let index = if size <= 16 {
-            0
-        } else if size <= 32 {
-            1
-        } else if size <= 64 {
-            2
-        } else if size <= 128 {
-            3
-        } else if size <= 256 {
-            4
-        } else if size <= 512 {
-            5
-        } else if size <= 1024 {
-            6
-        } else {
-            7
+                return ptr;
+            }
         };
 
         let mut cache = self.caches[index].lock();
         let mut provider = PAGE_ALLOCATOR.lock();
-        if let Some(p) = provider.as_mut() {
-            cache.alloc(p).unwrap_or(ptr::null_mut())
-        } else {
-            ptr::null_mut()
+        let Some(p) = provider.as_mut() else {
+            return ptr::null_mut();
+        };
+        let ptr = cache.alloc(p).unwrap_or(ptr::null_mut());
+
+        // Slots are aligned to the (power of two) cache size, which `SizeClass` made at least the alignment
+        debug_assert!((ptr as usize).is_multiple_of(layout.align()));
+
+        ptr
+    }
+}
+
+/// Where the heap serves an allocation from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClass {
+    /// Index into the slab caches (16, 32, ..., 2048 bytes)
+    Slab(usize),
+    /// Order of a buddy block, for anything bigger than 2048 bytes
+    Pages(usize),
+}
+
+impl SizeClass {
+    /// None if the layout is too big for even the largest buddy block
+    pub fn of(layout: Layout) -> Option<Self> {
+        // Slab objects are aligned to their (power of two) size, so over-aligned layouts use a bigger cache
+        let size = layout.size().max(layout.align());
+
+        if size > 2048 {
+            return buddy::order_for(layout.size(), layout.align()).map(SizeClass::Pages);
         }
+
+        // 16 -> 0, 32 -> 1, ..., 2048 -> 7
+        let index = size.max(16).next_power_of_two().trailing_zeros() as usize - 4;
+        Some(SizeClass::Slab(index))
+    }
+}
+
+// No `alloc_zeroed` override: heap memory is never known to be zero (slab slots are recycled as they
+// are freed and free buddy blocks hold the free list links), so the default `alloc` + memset is all we can do.
+unsafe impl GlobalAlloc for SlubAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.alloc_heap(layout) };
+
+        // Multi-page allocations stay null, the arena is too small to serve them for long
+        if ptr.is_null() && layout.size() <= PAGE_SIZE {
+            return self.alloc_emergency(layout);
+        }
+
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        if size > 2048 {
-            let mut provider = PAGE_ALLOCATOR.lock();
-            if let Some(p) = provider.as_mut() {
-                p.free_page(ptr);
-            }
+        // Emergency memory is never reused
+        if EMERGENCY_ARENA.contains(ptr) {
             return;
         }
 
-        let index = if size <= 16 {
-            0
-        } else if size <= 32 {
-            1
-        } else if size <= 64 {
-            2
-        } else if size <= 128 {
-            3
-        } else if size <= 256 {
-            4
-        } else if size <= 512 {
-            5
-        } else if size <= 1024 {
-            6
-        } else {
-            7
+        // Same class as `alloc_heap` picked, it succeeded so the class exists
+        let Some(class) = SizeClass::of(layout) else {
+            return;
         };
 
-        let mut cache = self.caches[index].lock();
-        let mut provider = PAGE_ALLOCATOR.lock();
-        if let Some(p) = provider.as_mut() {
-            unsafe { cache.dealloc(ptr, p) };
+        match class {
+            SizeClass::Slab(index) => {
+                let mut cache = self.caches[index].lock();
+                let mut provider = PAGE_ALLOCATOR.lock();
+                if let Some(p) = provider.as_mut() {
+                    unsafe { cache.dealloc(ptr, p) };
+                }
+            }
+            SizeClass::Pages(order) => {
+                let mut provider = PAGE_ALLOCATOR.lock();
+                if let Some(p) = provider.as_mut() {
+                    p.free_pages(ptr, order);
+                }
+            }
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+
+        // The block we have already fits, as long as it's heap memory (emergency blocks are exactly sized)
+        let class = SizeClass::of(layout);
+        if class.is_some() && class == SizeClass::of(new_layout) && !EMERGENCY_ARENA.contains(ptr) {
+            return ptr;
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+
+        new_ptr
+    }
 }
 
 #[cfg(not(feature = "no_global_allocator"))] // Fixes issues with tests
 #[global_allocator]
 static ALLOCATOR: SlubAllocator = SlubAllocator::new();
 
-pub fn init_heap(offset: usize) {
-    let mut provider = PAGE_ALLOCATOR.lock();
+/// Set up the buddy allocator behind the heap for the first `max_pages` pages of physical memory
+/// `bitmap` must hold at least `buddy::bitmap_size(max_pages)` bytes.
+pub fn init_heap(
+    offset: usize,
+    max_pages: usize,
+    bitmap: &'static mut [u8],
+) -> Result<(), &'static str> {
+    let mut frame_allocator = BuddyAllocator::with_capacity(max_pages, bitmap)?;
+    frame_allocator.set_offset(offset);
 
-    // Initialize directly in the Option to avoid stack overflow
-    *provider = Some(GlobalPageAllocator {
-        frame_allocator: BuddyAllocator::new(),
-    });
+    *PAGE_ALLOCATOR.lock() = Some(GlobalPageAllocator { frame_allocator });
 
-    if let Some(p) = provider.as_mut() {
-        p.frame_allocator.set_offset(offset);
-    }
+    Ok(())
 }
 
 /// Add a physical frame to the buddy allocator
@@ -154,10 +234,43 @@ pub fn init_heap(offset: usize) {
 pub unsafe fn add_frame(start: *mut u8) {
     let mut provider = PAGE_ALLOCATOR.lock();
     if let Some(p) = provider.as_mut() {
+        // Frames outside of the managed range are ignored by the buddy allocator
         unsafe { p.frame_allocator.add_frame(start) };
     }
 }
 
+/// Total bytes managed by the buddy allocator, 0 if the heap isn't initialized yet
+pub fn total_memory() -> usize {
+    let provider = PAGE_ALLOCATOR.lock();
+    provider
+        .as_ref()
+        .map_or(0, |p| p.frame_allocator.total_bytes())
+}
+
+/// Free bytes in the buddy allocator, 0 if the heap isn't initialized yet
+pub fn free_memory() -> usize {
+    let provider = PAGE_ALLOCATOR.lock();
+    provider
+        .as_ref()
+        .map_or(0, |p| p.frame_allocator.free_bytes())
+}
+
+/// Bytes allocated from the buddy allocator (slabs included), 0 if the heap isn't initialized yet
+pub fn allocated_memory() -> usize {
+    let provider = PAGE_ALLOCATOR.lock();
+    provider
+        .as_ref()
+        .map_or(0, |p| p.frame_allocator.allocated_bytes())
+}
+
+/// Free blocks in each order of the buddy allocator, None if the heap isn't initialized yet
+pub fn free_blocks_per_order() -> Option<[usize; MAX_ORDER]> {
+    let provider = PAGE_ALLOCATOR.lock();
+    provider
+        .as_ref()
+        .map(|p| p.frame_allocator.free_blocks_per_order())
+}
+
 /// Allocate a physical frame from the buddy allocator
 /// Returns the physical frame, or None if no frames are available
 pub fn allocate_frame() -> Option<PhysFrame<Size4KiB>> {
@@ -170,3 +283,64 @@ pub fn allocate_frame() -> Option<PhysFrame<Size4KiB>> {
         None
     }
 }
+
+/// Return a physical frame to the buddy allocator
+///
+/// # Safety
+/// The frame must have been allocated with `allocate_frame` and must not be used anymore.
+pub unsafe fn deallocate_frame(frame: PhysFrame<Size4KiB>) {
+    use x86_64::structures::paging::FrameDeallocator;
+
+    let mut provider = PAGE_ALLOCATOR.lock();
+    if let Some(p) = provider.as_mut() {
+        unsafe { p.frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+/// Fragmentation snapshot of the buddy allocator, None if the heap isn't initialized yet
+pub fn fragmentation() -> Option<BuddyFragInfo> {
+    let provider = PAGE_ALLOCATOR.lock();
+    provider.as_ref().map(|p| p.frame_allocator.fragmentation())
+}
+
+/// Print the heap statistics over serial, used by the boot report and when we run out of memory
+pub fn log_stats() {
+    match fragmentation() {
+        Some(buddy) => serial_println!(
+            "Buddy: {} of {} KiB free, largest block {} KiB, free blocks per order {:?}",
+            buddy.free_bytes / 1024,
+            total_memory() / 1024,
+            buddy.largest_free_block_bytes / 1024,
+            buddy.free_blocks
+        ),
+        None => serial_println!("Buddy: not initialized"),
+    }
+
+    serial_println!(
+        "Emergency arena: {} of {} bytes used",
+        EMERGENCY_ARENA.used(),
+        EMERGENCY_ARENA_SIZE
+    );
+
+    let (allocations, failures) = emergency_allocations();
+    if allocations > 0 {
+        serial_println!(
+            "[WARNING] Heap unavailable {} times, the emergency arena served it",
+            allocations
+        );
+    }
+    if failures > 0 {
+        serial_println!(
+            "[ERROR] Emergency arena exhausted, {} allocations failed",
+            failures
+        );
+    }
+}
+
+/// Number of allocations the emergency arena served and the number it was too full for
+pub fn emergency_allocations() -> (usize, usize) {
+    (
+        EMERGENCY_ALLOCATIONS.load(Ordering::Relaxed),
+        EMERGENCY_FAILURES.load(Ordering::Relaxed),
+    )
+}
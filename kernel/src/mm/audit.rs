@@ -0,0 +1,140 @@
+// Page table auditing
+//
+// Walks the page tables looking for pages that ring 3 can reach, and makes sure
+// every one of them belongs to a task. A page is only reachable from userspace if
+// USER_ACCESSIBLE is set on every level, so a kernel page ending up there means
+// someone marked the wrong thing as user accessible.
+
+use alloc::vec::Vec;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::interrupts,
+    structures::paging::{Page, PageTable, PageTableFlags, PhysFrame},
+};
+
+use crate::{mm::memory, serial_println, tasks::SCHEDULER};
+
+/// A page (or huge page) that userspace can access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserMapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    /// Size of the mapping in bytes (4KiB, 2MiB or 1GiB)
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+/// Collect every mapping reachable from ring 3 in the given level 4 table
+///
+/// # Safety
+/// The complete physical memory must be mapped at `physical_memory_offset`, and the table must be a valid level 4 table.
+pub unsafe fn user_accessible_mappings(
+    level_4_table: &PageTable,
+    physical_memory_offset: VirtAddr,
+) -> Vec<UserMapping> {
+    let mut mappings = Vec::new();
+    unsafe { walk(level_4_table, 4, 0, physical_memory_offset, &mut mappings) };
+    mappings
+}
+
+/// Recursively walk a page table, only following entries that are user accessible
+unsafe fn walk(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    physical_memory_offset: VirtAddr,
+    mappings: &mut Vec<UserMapping>,
+) {
+    let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(required) {
+            continue;
+        }
+
+        // Level 1 covers 4KiB per entry, every level above covers 512 times more
+        let shift = 12 + 9 * (level as u64 - 1);
+        let virt = base | ((index as u64) << shift);
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            mappings.push(UserMapping {
+                virt: VirtAddr::new_truncate(virt),
+                phys: entry.addr(),
+                size: 1 << shift,
+                flags,
+            });
+        } else {
+            let next_virt = physical_memory_offset + entry.addr().as_u64();
+            let next_table = unsafe { &*next_virt.as_ptr::<PageTable>() };
+            unsafe {
+                walk(
+                    next_table,
+                    level - 1,
+                    virt,
+                    physical_memory_offset,
+                    mappings,
+                )
+            };
+        }
+    }
+}
+
+/// Check that every page userspace can reach is owned by the task whose address space it's in
+///
+/// Anything else (kernel heap, page tables, MMIO, the physical memory mapping...) showing up
+/// means kernel memory leaked to ring 3, so we panic. The kernel's own table must have none at all.
+/// Returns the number of user pages that were checked.
+pub fn audit_user_accessible() -> usize {
+    let physical_memory_offset = memory::physical_memory_offset();
+    let kernel_table = memory::kernel_page_table();
+
+    let mappings_in = |level_4_frame: PhysFrame| {
+        let level_4_virt = physical_memory_offset + level_4_frame.start_address().as_u64();
+        let level_4_table = unsafe { &*level_4_virt.as_ptr::<PageTable>() };
+        unsafe { user_accessible_mappings(level_4_table, physical_memory_offset) }
+    };
+
+    // The timer locks the scheduler too, so keep it from firing while we hold the lock
+    let (checked, violations) = interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+
+        let mut checked = 0;
+        let mut violations = mappings_in(kernel_table);
+
+        // Kernel tasks run on the kernel's table, it was checked above
+        for task in scheduler.tasks() {
+            if task.page_table == kernel_table {
+                continue;
+            }
+
+            let mappings = mappings_in(task.page_table);
+            checked += mappings.len();
+            violations.extend(mappings.into_iter().filter(|mapping| {
+                // Tasks only own 4KiB pages, so a user accessible huge page is always wrong
+                mapping.size != 4096
+                    || !(task
+                        .user_pages
+                        .contains(&Page::containing_address(mapping.virt))
+                        || task
+                            .shm_mappings
+                            .iter()
+                            .any(|shm| shm.contains(mapping.virt)))
+            }));
+        }
+
+        (checked, violations)
+    });
+
+    if !violations.is_empty() {
+        for mapping in &violations {
+            serial_println!("  user accessible: {:?}", mapping);
+        }
+        panic!(
+            "{} user accessible mappings are not owned by their task",
+            violations.len()
+        );
+    }
+
+    checked
+}
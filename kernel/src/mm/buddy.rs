@@ -1,32 +1,77 @@
 use core::ptr::NonNull;
 
-use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
 
-const MAX_ORDER: usize = 12;
+pub const MAX_ORDER: usize = 12;
+/// Blocks of the top order have no buddy to merge with, so their pairs get no bit
+const TOP_ORDER: usize = MAX_ORDER - 1;
 const PAGE_SIZE: usize = 4096;
-// 1GB RAM / 4KiB pages = 262,144 pages
-const MAX_PAGES: usize = 262_144;
+// Default capacity of `new`: 1GB RAM / 4KiB pages = 262,144 pages
+const DEFAULT_MAX_PAGES: usize = 262_144;
+const BITMAP_SIZE: usize = bitmap_size(DEFAULT_MAX_PAGES);
+
+static mut BITMAP_STORAGE: [u8; BITMAP_SIZE] = [0; BITMAP_SIZE];
+
+/// Snapshot of how fragmented the buddy allocator's free memory is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuddyFragInfo {
+    /// Number of free blocks in each order's free list
+    pub free_blocks: [usize; MAX_ORDER],
+    /// Size of the biggest free block in bytes
+    pub largest_free_block_bytes: usize,
+    /// Total free bytes over all orders
+    pub free_bytes: usize,
+}
+
+/// Number of pairs of buddies of an order, rounded up so a leftover block still gets a bit
+const fn pairs(max_pages: usize, order: usize) -> usize {
+    max_pages.div_ceil(1 << (order + 1))
+}
+
+/// Bytes of bitmap needed to manage `max_pages` pages
 // We need 1 bit per pair of buddies.
-// Order 0: 131,072 pairs (131,072 bits)
-// Order 1: 65,536 pairs (65,536 bits)
+// Order 0: max_pages / 2 pairs
+// Order 1: max_pages / 4 pairs
 // ...
-// Total bits < MAX_PAGES.
-// 262,144 bits / 8 = 32,768 bytes.
-const BITMAP_SIZE: usize = MAX_PAGES / 8;
+// Total bits < max_pages (plus one per order for the rounding).
+pub const fn bitmap_size(max_pages: usize) -> usize {
+    let mut bits = 0;
+    let mut order = 0;
+    while order < TOP_ORDER {
+        bits += pairs(max_pages, order);
+        order += 1;
+    }
+    bits.div_ceil(8)
+}
 
-static mut BITMAP_STORAGE: [u8; BITMAP_SIZE] = [0; BITMAP_SIZE];
+/// Smallest order with a block that fits `size` bytes aligned to `align`
+/// Blocks are aligned to their own size, so a big enough block is also aligned enough.
+/// Returns None if even the largest block is too small.
+pub fn order_for(size: usize, align: usize) -> Option<usize> {
+    let pages = size.max(align).div_ceil(PAGE_SIZE).max(1);
+    let order = pages.next_power_of_two().trailing_zeros() as usize;
+    (order < MAX_ORDER).then_some(order)
+}
 
 pub struct BuddyAllocator {
     // Heads of the free lists for each order
     // free_lists[0] -> order 0 (4KiB)
     // free_lists[1] -> order 1 (8KiB), etc.
     free_lists: [Option<NonNull<FreeFrame>>; MAX_ORDER],
-    // Bitmap to track the state of buddy pairs
-    // 0: Both buddies are in the same state (both free or both used)
-    // 1: One buddy is free, one is used
+    // Length of each free list, kept up to date by `push_free` and `remove_frame`
+    free_counts: [usize; MAX_ORDER],
+    // Bytes fed in with `add_frame`
+    total_bytes: usize,
+    // One bit per pair of buddies of every order below TOP_ORDER, the invariant is
+    // bit == 1 exactly when one of the two buddies is on the free list of that order.
+    // Both buddies are never free at once (they'd have merged), so 0 means neither is: both
+    // allocated, or both part of a bigger block that's free or allocated as a whole.
     bitmap: &'static mut [u8],
     // Virtual memory offset (phys_mem_offset)
     offset: usize,
+    // Number of pages starting at physical address 0 this allocator manages
+    max_pages: usize,
 }
 
 #[repr(C)]
@@ -36,26 +81,119 @@ struct FreeFrame {
 }
 
 impl BuddyAllocator {
+    /// An allocator for the first 1GB of physical memory, using the static bitmap
     pub fn new() -> Self {
-        Self {
+        let bitmap = unsafe { &mut *core::ptr::addr_of_mut!(BITMAP_STORAGE) };
+
+        Self::with_capacity(DEFAULT_MAX_PAGES, bitmap).expect("Static bitmap is too small")
+    }
+
+    /// An allocator for the first `max_pages` pages of physical memory
+    /// `bitmap` must hold at least `bitmap_size(max_pages)` bytes.
+    pub fn with_capacity(
+        max_pages: usize,
+        bitmap: &'static mut [u8],
+    ) -> Result<Self, &'static str> {
+        if bitmap.len() < bitmap_size(max_pages) {
+            return Err("Bitmap too small for the number of pages");
+        }
+
+        // Start from a clean state, the storage may be shared with an allocator created before
+        bitmap.fill(0);
+
+        Ok(Self {
             free_lists: [None; MAX_ORDER],
-            bitmap: unsafe { &mut *core::ptr::addr_of_mut!(BITMAP_STORAGE) },
+            free_counts: [0; MAX_ORDER],
+            total_bytes: 0,
+            bitmap,
             offset: 0,
-        }
+            max_pages,
+        })
     }
 
     pub fn set_offset(&mut self, offset: usize) {
         self.offset = offset;
     }
 
+    /// Returns the physical frame a pointer into the managed memory points into
+    /// Returns None if the pointer is outside of the managed range
+    pub fn ptr_to_frame(&self, ptr: *const u8) -> Option<PhysFrame<Size4KiB>> {
+        if !self.contains(ptr) {
+            return None;
+        }
+
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            self.phys_addr(ptr),
+        )))
+    }
+
+    /// Returns the pointer to the start of a physical frame (through the `offset` mapping)
+    pub fn frame_to_ptr(&self, frame: PhysFrame<Size4KiB>) -> *mut u8 {
+        (frame.start_address().as_u64() as usize + self.offset) as *mut u8
+    }
+
+    /// Check if a pointer is inside the memory this allocator can manage
+    fn contains(&self, ptr: *const u8) -> bool {
+        let addr = ptr as usize;
+        addr >= self.offset && addr - self.offset < self.max_pages * PAGE_SIZE
+    }
+
+    /// Physical address of a pointer, the pointer must be in the managed range
+    fn phys_addr(&self, ptr: *const u8) -> u64 {
+        debug_assert!(self.contains(ptr), "Pointer outside of the managed range");
+        (ptr as usize - self.offset) as u64
+    }
+
+    /// Index of the page a pointer points into, the pointer must be in the managed range
+    fn page_index(&self, ptr: *const u8) -> usize {
+        self.phys_addr(ptr) as usize / PAGE_SIZE
+    }
+
+    /// Bytes of memory fed in with `add_frame`
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Bytes in free blocks of any order
+    pub fn free_bytes(&self) -> usize {
+        self.free_counts
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count * (1 << order) * PAGE_SIZE)
+            .sum()
+    }
+
+    /// Bytes handed out by `alloc` and not freed yet
+    pub fn allocated_bytes(&self) -> usize {
+        self.total_bytes - self.free_bytes()
+    }
+
+    /// Number of free blocks in each order's free list
+    pub fn free_blocks_per_order(&self) -> [usize; MAX_ORDER] {
+        self.free_counts
+    }
+
+    /// Snapshot of the free blocks of every order
+    pub fn fragmentation(&self) -> BuddyFragInfo {
+        let largest_order = self.free_counts.iter().rposition(|&count| count > 0);
+
+        BuddyFragInfo {
+            free_blocks: self.free_counts,
+            largest_free_block_bytes: largest_order.map_or(0, |order| (1 << order) * PAGE_SIZE),
+            free_bytes: self.free_bytes(),
+        }
+    }
+
     /// Calculates the index of the bit corresponding to the pair of buddies
     /// for a given page index and order.
     fn get_bit_index(&self, page_idx: usize, order: usize) -> usize {
+        debug_assert!(order < TOP_ORDER, "Top order blocks have no buddy bit");
+
         // Calculate offset for this order in the bitmap
-        // Offset = Sum(N / 2^(i+1)) for i from 0 to order-1
+        // Offset = Sum(ceil(N / 2^(i+1))) for i from 0 to order-1
         let mut offset = 0;
         for i in 0..order {
-            offset += MAX_PAGES >> (i + 1);
+            offset += pairs(self.max_pages, i);
         }
 
         // The pair index within this order is page_idx / 2^(order+1)
@@ -76,13 +214,11 @@ impl BuddyAllocator {
 
     fn calculate_buddy_address(&self, ptr: *mut u8, order: usize) -> *mut u8 {
         let block_size = 1 << order; // Size in pages
-        let addr = ptr as usize;
-        // Convert to relative address (physical-like)
-        let relative_addr = addr - self.offset;
         // XOR toggles the bit corresponding to the block size
-        let buddy_relative_addr = relative_addr ^ (block_size * PAGE_SIZE);
-        // Convert back to virtual address
-        (buddy_relative_addr + self.offset) as *mut u8
+        let buddy_phys_addr = self.phys_addr(ptr) ^ (block_size * PAGE_SIZE) as u64;
+        self.frame_to_ptr(PhysFrame::containing_address(PhysAddr::new(
+            buddy_phys_addr,
+        )))
     }
 
     // Allocates a block of memory
@@ -100,12 +236,11 @@ impl BuddyAllocator {
             // Remove from free list
             unsafe { self.remove_frame(frame_ptr.as_ptr() as *mut u8, order) };
 
-            // Toggle bit. Since we are allocating one of a pair, and the other is presumably used
-            // (otherwise they would be merged), the bit should go from 1 -> 0.
-            // We only track bits for orders < MAX_ORDER - 1
-            if order < MAX_ORDER - 1 {
-                let page_idx = (frame_ptr.as_ptr() as usize - self.offset) / PAGE_SIZE;
-                self.toggle_bit(page_idx, order);
+            // The block was the free one of its pair, now neither is: 1 -> 0
+            if order < TOP_ORDER {
+                let page_idx = self.page_index(frame_ptr.as_ptr() as *const u8);
+                let is_now_one = self.toggle_bit(page_idx, order);
+                debug_assert!(!is_now_one, "Free block's buddy bit was clear");
             }
 
             return Some(frame_ptr.as_ptr() as *mut u8);
@@ -115,14 +250,12 @@ impl BuddyAllocator {
         if let Some(ptr) = unsafe { self.alloc(order + 1) } {
             let buddy_addr = self.calculate_buddy_address(ptr, order);
 
-            // We have a block of order+1. We split it into two blocks of order.
-            // We return `ptr` and free `buddy_addr`.
-            // The pair (ptr, buddy) is now "One used, one free".
-            // The bit should become 1.
-            if order < MAX_ORDER - 1 {
-                let page_idx = (ptr as usize - self.offset) / PAGE_SIZE;
-                self.toggle_bit(page_idx, order);
-            }
+            // We split a block of order+1 into `ptr` (returned) and `buddy_addr` (freed), the pair
+            // goes from neither free to one free: 0 -> 1. `order + 1` was allocated above, so
+            // `order` is below TOP_ORDER and always has a bit.
+            let page_idx = self.page_index(ptr);
+            let is_now_one = self.toggle_bit(page_idx, order);
+            debug_assert!(is_now_one, "Split block's buddy bit was set");
 
             // Add the buddy to the free list
             unsafe { self.push_free(buddy_addr, order) };
@@ -138,19 +271,18 @@ impl BuddyAllocator {
     // # Safety
     // The caller must ensure that the pointer and order are valid and that the block was previously allocated, as misuse can lead to memory corruption.
     pub unsafe fn dealloc(&mut self, ptr: *mut u8, order: usize) {
-        let addr = ptr as usize;
-        if addr < self.offset || addr >= self.offset + MAX_PAGES * PAGE_SIZE {
+        if !self.contains(ptr) || order >= MAX_ORDER {
             // Address out of managed range
             return;
         }
 
-        // If we are at the max order, we can't merge further
-        if order >= MAX_ORDER - 1 {
+        // If we are at the top order, we can't merge further
+        if order == TOP_ORDER {
             unsafe { self.push_free(ptr, order) };
             return;
         }
 
-        let page_idx = (ptr as usize - self.offset) / PAGE_SIZE;
+        let page_idx = self.page_index(ptr);
 
         // Toggle bit for this pair
         let is_now_one = self.toggle_bit(page_idx, order);
@@ -160,8 +292,7 @@ impl BuddyAllocator {
             // So we cannot merge. Just add to free list.
             unsafe { self.push_free(ptr, order) };
         } else {
-            // Bit became 0. This means the state is now "Both free" (since we just freed one).
-            // We must merge.
+            // Bit became 0. The buddy was the free one, so now both are and we must merge.
             let buddy_addr = self.calculate_buddy_address(ptr, order);
 
             // Remove buddy from free list
@@ -180,10 +311,10 @@ impl BuddyAllocator {
     /// # Safety
     /// The caller must ensure that the provided frame is valid and not already in use, as this can lead to memory corruption if misused.
     pub unsafe fn add_frame(&mut self, frame: *mut u8) {
-        let addr = frame as usize;
-        if addr < self.offset || addr >= self.offset + MAX_PAGES * PAGE_SIZE {
+        if !self.contains(frame) {
             return;
         }
+        self.total_bytes += PAGE_SIZE;
         unsafe { self.dealloc(frame, 0) };
     }
 
@@ -199,6 +330,7 @@ impl BuddyAllocator {
         }
 
         self.free_lists[order] = NonNull::new(frame_ptr);
+        self.free_counts[order] += 1;
     }
 
     unsafe fn remove_frame(&mut self, ptr: *mut u8, order: usize) {
@@ -219,6 +351,7 @@ impl BuddyAllocator {
         // Clean up pointers
         frame.next = None;
         frame.prev = None;
+        self.free_counts[order] -= 1;
     }
 }
 
@@ -226,9 +359,12 @@ unsafe impl Send for BuddyAllocator {}
 
 unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        unsafe { self.alloc(0) }.map(|ptr| {
-            let phys_addr = (ptr as usize - self.offset) as u64;
-            PhysFrame::containing_address(x86_64::PhysAddr::new(phys_addr))
-        })
+        unsafe { self.alloc(0) }.and_then(|ptr| self.ptr_to_frame(ptr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { self.dealloc(self.frame_to_ptr(frame), 0) };
     }
 }
@@ -0,0 +1,158 @@
+// Demand paging
+//
+// A task can reserve parts of its address space that only get memory once they're touched (a heap
+// or a stack that grows lazily). The first access to a page in one of them page faults, the fault
+// handler maps a zeroed frame there and the faulting instruction runs again.
+
+use alloc::vec::Vec;
+use x86_64::{
+    VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+            Size4KiB, mapper::MapperFlush,
+        },
+    },
+};
+
+use crate::mm::user::USER_SPACE_LIMIT;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Not page aligned, empty or reaching into kernel space
+    InvalidRegion,
+    /// Overlaps a region that's already registered
+    Overlap,
+    /// The address isn't in any region
+    NotDemandPaged,
+    /// The page is there but the access isn't allowed (e.g. a write to a read-only region)
+    ProtectionViolation,
+    /// No frame left, or the task is at its memory limit
+    OutOfMemory,
+    /// The page tables couldn't be updated
+    MapFailed,
+}
+
+/// A range of user pages that's mapped one page at a time, on first access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DemandRegion {
+    pub start: VirtAddr,
+    pub pages: usize,
+    /// Flags for the pages once they're mapped, always present and user accessible
+    pub flags: PageTableFlags,
+}
+
+impl DemandRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages as u64 * PAGE_SIZE
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// The demand paged regions of one address space
+#[derive(Debug, Default)]
+pub struct DemandRegions {
+    regions: Vec<DemandRegion>,
+}
+
+impl DemandRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    /// Reserve `pages` pages at `start`, nothing is mapped until they're touched
+    pub fn register(
+        &mut self,
+        start: VirtAddr,
+        pages: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), Error> {
+        let end = (pages as u64)
+            .checked_mul(PAGE_SIZE)
+            .and_then(|size| start.as_u64().checked_add(size));
+        match end {
+            Some(end) if pages > 0 && start.is_aligned(PAGE_SIZE) && end <= USER_SPACE_LIMIT => {}
+            _ => return Err(Error::InvalidRegion),
+        }
+
+        let region = DemandRegion {
+            start,
+            pages,
+            flags: flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        };
+        if self
+            .regions
+            .iter()
+            .any(|r| r.start < region.end() && region.start < r.end())
+        {
+            return Err(Error::Overlap);
+        }
+
+        self.regions.push(region);
+        Ok(())
+    }
+
+    /// Forget the region starting at `start`, the pages that were mapped in it stay mapped
+    pub fn unregister(&mut self, start: VirtAddr) -> Option<DemandRegion> {
+        let index = self.regions.iter().position(|r| r.start == start)?;
+        Some(self.regions.swap_remove(index))
+    }
+
+    /// The region `addr` is in
+    pub fn find(&self, addr: VirtAddr) -> Option<&DemandRegion> {
+        self.regions.iter().find(|r| r.contains(addr))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DemandRegion> {
+        self.regions.iter()
+    }
+}
+
+/// Map a zeroed frame for a page fault at `addr`, if it's in one of `regions`
+/// Returns the flush for the new page, flush it and return from the fault to retry the access.
+pub fn resolve_fault(
+    regions: &DemandRegions,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<MapperFlush<Size4KiB>, Error> {
+    // The page is mapped already, mapping it again won't help
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(Error::ProtectionViolation);
+    }
+
+    let region = regions.find(addr).ok_or(Error::NotDemandPaged)?;
+
+    // Mapping the page would only fault again
+    let writable = region.flags.contains(PageTableFlags::WRITABLE);
+    let executable = !region.flags.contains(PageTableFlags::NO_EXECUTE);
+    if (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !writable)
+        || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && !executable)
+    {
+        return Err(Error::ProtectionViolation);
+    }
+
+    let frame = frame_allocator.allocate_frame().ok_or(Error::OutOfMemory)?;
+
+    // The frame could hold anything, like another task's data
+    let frame_addr = mapper.phys_offset() + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(frame_addr.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    match unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) } {
+        Ok(flush) => Ok(flush),
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(Error::MapFailed)
+        }
+    }
+}
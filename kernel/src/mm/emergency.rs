@@ -0,0 +1,72 @@
+// Emergency allocator
+//
+// A small bump allocator over a fixed static arena. The global allocator falls back to it
+// when the real heap isn't initialized yet or runs out, so an early `Box::new` gets real
+// memory instead of a null pointer. Memory from the arena is never reused.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Size of the arena used by the global allocator
+pub const EMERGENCY_ARENA_SIZE: usize = 64 * 1024;
+
+/// A bump allocator over `SIZE` bytes of static memory
+#[repr(C, align(4096))]
+pub struct EmergencyArena<const SIZE: usize> {
+    memory: UnsafeCell<[u8; SIZE]>,
+    /// Offset of the first unused byte
+    next: AtomicUsize,
+}
+
+// Every allocation gets its own bytes of the arena, handed out atomically
+unsafe impl<const SIZE: usize> Sync for EmergencyArena<SIZE> {}
+
+impl<const SIZE: usize> EmergencyArena<SIZE> {
+    pub const fn new() -> Self {
+        Self {
+            memory: UnsafeCell::new([0; SIZE]),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Allocate memory for `layout`, returns null if the arena is full
+    pub fn alloc(&self, layout: Layout) -> *mut u8 {
+        let base = self.memory.get() as usize;
+
+        let mut next = self.next.load(Ordering::Relaxed);
+        loop {
+            let start = (base + next).next_multiple_of(layout.align()) - base;
+            let end = match start.checked_add(layout.size()) {
+                Some(end) if end <= SIZE => end,
+                _ => return ptr::null_mut(),
+            };
+
+            match self
+                .next
+                .compare_exchange_weak(next, end, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return (base + start) as *mut u8,
+                Err(current) => next = current,
+            }
+        }
+    }
+
+    /// Check if a pointer was handed out by this arena
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        let base = self.memory.get() as usize;
+        (base..base + SIZE).contains(&(ptr as usize))
+    }
+
+    /// Number of bytes used so far (including alignment padding)
+    pub fn used(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+}
+
+impl<const SIZE: usize> Default for EmergencyArena<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
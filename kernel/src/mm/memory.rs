@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use x86_64::PhysAddr;
 use x86_64::registers::control::Cr3;
@@ -5,6 +7,8 @@ use x86_64::structures::paging::page_table::FrameError;
 use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size2MiB, Size4KiB};
 use x86_64::{VirtAddr, structures::paging::PageTable};
 
+use crate::serial_println;
+
 /// Size constants
 pub const PAGE_SIZE: u64 = 4096;
 pub const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024; // 2 MiB
@@ -15,6 +19,12 @@ pub const GIANT_PAGE_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
 /// but can grow as allocations split ranges. 256 is very generous.
 const MAX_RANGES: usize = 256;
 
+/// Where the bootloader mapped the physical memory, set by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the level 4 table the bootloader left us, set by `init`
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -25,12 +35,50 @@ const MAX_RANGES: usize = 256;
 /// # Safety
 /// The caller must ensure that the complete physical memory is mapped to virtual memory at the passed `physical_memory_offset`, and that this function is only called once during initialization to avoid undefined behavior.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_PAGE_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
     }
 }
 
+/// Returns the offset at which the physical memory is mapped
+/// Panics if `init` hasn't been called yet
+pub fn physical_memory_offset() -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "Memory not initialized");
+    VirtAddr::new(offset)
+}
+
+/// The kernel's own level 4 table, used by kernel tasks and copied into every new address space
+/// Frame 0 before `init`, no real page table lives there.
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::Relaxed)))
+}
+
+/// Create a new OffsetPageTable for the active level 4 table.
+/// Used by code that has no access to the mapper created at boot (e.g. in syscalls).
+///
+/// # Safety
+/// `init` must have been called, and the caller must make sure nobody else is modifying the
+/// page tables while the returned mapper is alive.
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    unsafe { mapper_for(Cr3::read().0) }
+}
+
+/// Create a new OffsetPageTable for the level 4 table in `level_4_table_frame`, active or not
+///
+/// # Safety
+/// Same as `active_mapper`, and the frame must hold a valid level 4 table.
+pub unsafe fn mapper_for(level_4_table_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let physical_memory_offset = physical_memory_offset();
+    let virt = physical_memory_offset + level_4_table_frame.start_address().as_u64();
+
+    unsafe { OffsetPageTable::new(&mut *virt.as_mut_ptr::<PageTable>(), physical_memory_offset) }
+}
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -54,18 +102,36 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 /// The caller must ensure that the complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`.
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    translate_addr_inner(addr, physical_memory_offset)
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    translate_addr_inner(level_4_table_frame, addr, physical_memory_offset)
+}
+
+/// Like `translate_addr`, but walks the page tables starting at `level_4_table_frame`
+/// instead of the active ones.
+///
+/// # Safety
+/// The caller must ensure that the complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`, and that `level_4_table_frame` holds a valid page table hierarchy.
+pub unsafe fn translate_addr_in(
+    level_4_table_frame: PhysFrame,
+    addr: VirtAddr,
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysAddr> {
+    translate_addr_inner(level_4_table_frame, addr, physical_memory_offset)
 }
 
-/// Private function that is called by `translate_addr`.
+/// Private function that is called by `translate_addr` and `translate_addr_in`.
 ///
 /// This function is safe to limit the scope of `unsafe` because Rust treats
 /// the whole body of unsafe functions as an unsafe block. This function must
 /// only be reachable through `unsafe fn` from outside of this module.
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    // read the active level 4 frame from the CR3 register
-    let (level_4_table_frame, _) = Cr3::read();
-
+fn translate_addr_inner(
+    level_4_table_frame: PhysFrame,
+    addr: VirtAddr,
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysAddr> {
     let table_indexes = [
         addr.p4_index(),
         addr.p3_index(),
@@ -75,7 +141,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let mut frame = level_4_table_frame;
 
     // traverse the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -86,7 +152,18 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            // A huge page ends the walk, the rest of the address is the offset into it
+            Err(FrameError::HugeFrame) => {
+                let page_size = match level {
+                    1 => GIANT_PAGE_SIZE,
+                    2 => HUGE_PAGE_SIZE,
+                    // The same bit means PAT in level 1 entries, that's still a normal 4KiB page
+                    3 => PAGE_SIZE,
+                    // and it's reserved in level 4 entries
+                    _ => return None,
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -111,6 +188,24 @@ impl PhysRange {
     }
 }
 
+/// Snapshot of how fragmented the free physical memory is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragInfo {
+    /// Number of separate free ranges
+    pub range_count: usize,
+    /// Size of the biggest free range in bytes
+    pub largest_contiguous_bytes: u64,
+    /// Total free bytes over all ranges
+    pub free_bytes: u64,
+}
+
+/// Why freeing into the `BootInfoFrameAllocator` failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreeError {
+    /// Every slot of the free range table is used and the range doesn't touch a free one
+    TooManyRanges,
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
 /// Supports contiguous allocation and deallocation.
 /// Uses a fixed-size array instead of Vec since this runs before the heap exists.
@@ -123,6 +218,8 @@ pub struct BootInfoFrameAllocator {
     allocated_bytes: u64,
     /// Total bytes available at init
     total_bytes: u64,
+    /// Set by `hand_off`, the free memory belongs to the buddy allocator from then on
+    handed_off: bool,
 }
 
 impl BootInfoFrameAllocator {
@@ -168,6 +265,7 @@ impl BootInfoFrameAllocator {
             range_count: count,
             allocated_bytes: 0,
             total_bytes,
+            handed_off: false,
         }
     }
 
@@ -181,11 +279,51 @@ impl BootInfoFrameAllocator {
         self.allocated_bytes
     }
 
+    /// Returns the address right after the highest free frame, 0 if there's no free memory
+    pub fn end_of_free_memory(&self) -> u64 {
+        self.free_ranges[..self.range_count]
+            .iter()
+            .map(|range| range.end)
+            .max()
+            .unwrap_or(0)
+    }
+
     /// Returns the number of tracked free ranges
     pub fn range_count(&self) -> usize {
         self.range_count
     }
 
+    /// Returns the size of the biggest free range in bytes, the most `allocate_contiguous` can get
+    pub fn largest_contiguous_free(&self) -> u64 {
+        self.free_ranges[..self.range_count]
+            .iter()
+            .map(|range| range.end - range.start)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns how much of the free memory is outside of the biggest range, in thousandths
+    /// 0 when it's all one range (or nothing is free), close to 1000 when it's scattered in small pieces.
+    pub fn fragmentation_permille(&self) -> u32 {
+        let free = self.free_memory();
+        if free == 0 {
+            return 0;
+        }
+
+        (1000 - self.largest_contiguous_free() * 1000 / free) as u32
+    }
+
+    /// Returns how fragmented the free memory currently is
+    pub fn fragmentation(&self) -> FragInfo {
+        let ranges = &self.free_ranges[..self.range_count];
+
+        FragInfo {
+            range_count: self.range_count,
+            largest_contiguous_bytes: self.largest_contiguous_free(),
+            free_bytes: ranges.iter().map(|range| range.end - range.start).sum(),
+        }
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
         self.free_ranges[..self.range_count]
@@ -197,6 +335,33 @@ impl BootInfoFrameAllocator {
             })
     }
 
+    /// Give all free frames to another allocator (the buddy allocator) by calling `add_frame` for each
+    ///
+    /// Frames allocated before the handoff (e.g. the framebuffer's back buffer) stay with their owner.
+    /// Afterwards this allocator owns nothing, so allocating or freeing through it panics instead of
+    /// handing out memory the buddy allocator also owns.
+    /// Returns the number of bytes handed off.
+    pub fn hand_off(&mut self, mut add_frame: impl FnMut(PhysFrame)) -> u64 {
+        assert!(!self.handed_off, "BootInfoFrameAllocator handed off twice");
+
+        for frame in self.usable_frames() {
+            add_frame(frame);
+        }
+
+        let handed_off_bytes = self.free_memory();
+        self.allocated_bytes = self.total_bytes;
+        self.free_ranges = [PhysRange::empty(); MAX_RANGES];
+        self.range_count = 0;
+        self.handed_off = true;
+
+        handed_off_bytes
+    }
+
+    /// Returns true once the free memory was handed to the buddy allocator
+    pub fn is_handed_off(&self) -> bool {
+        self.handed_off
+    }
+
     /// Allocate `count` contiguous 4KiB frames.
     /// Returns the starting physical frame, or None if not enough contiguous memory.
     pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
@@ -210,6 +375,11 @@ impl BootInfoFrameAllocator {
         count: usize,
         alignment: u64,
     ) -> Option<PhysFrame> {
+        assert!(
+            !self.handed_off,
+            "BootInfoFrameAllocator used after handing its memory to the buddy allocator"
+        );
+
         if count == 0 {
             return None;
         }
@@ -267,25 +437,68 @@ impl BootInfoFrameAllocator {
     }
 
     /// Free `count` contiguous 4KiB frames starting at `frame`.
+    /// If the free range table is full the frames are leaked, with a warning.
     ///
     /// # Safety
     /// The caller must ensure that the frames were previously allocated by this allocator
     /// and are no longer in use.
     pub unsafe fn free_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        if let Err(e) = unsafe { self.try_free_contiguous(frame, count) } {
+            serial_println!(
+                "[WARNING] Leaking {} frames at {:?}: {:?}",
+                count,
+                frame.start_address(),
+                e
+            );
+        }
+    }
+
+    /// Free `count` contiguous 4KiB frames starting at `frame`.
+    /// Fails if the range can't be tracked, the frames stay allocated then.
+    ///
+    /// # Safety
+    /// The caller must ensure that the frames were previously allocated by this allocator
+    /// and are no longer in use.
+    pub unsafe fn try_free_contiguous(
+        &mut self,
+        frame: PhysFrame,
+        count: usize,
+    ) -> Result<(), FreeError> {
+        assert!(
+            !self.handed_off,
+            "Freeing into the BootInfoFrameAllocator after the handoff, the frame would be lost"
+        );
+
         if count == 0 {
-            return;
+            return Ok(());
         }
 
         let start = frame.start_address().as_u64();
         let end = start + count as u64 * PAGE_SIZE;
 
+        // Growing a free neighbour doesn't need a new slot, so this works even with a full table
+        let neighbour = self.free_ranges[..self.range_count]
+            .iter()
+            .position(|range| range.end == start || range.start == end);
+
+        match neighbour {
+            Some(i) => {
+                let range = &mut self.free_ranges[i];
+                range.start = range.start.min(start);
+                range.end = range.end.max(end);
+            }
+            None if self.range_count >= MAX_RANGES => return Err(FreeError::TooManyRanges),
+            None => self.insert_range_sorted(PhysRange::new(start, end)),
+        }
+
         self.allocated_bytes = self
             .allocated_bytes
             .saturating_sub(count as u64 * PAGE_SIZE);
 
-        // Insert the freed range and coalesce
-        self.insert_range_sorted(PhysRange::new(start, end));
+        // The range may touch a neighbour on the other side too
         self.coalesce_ranges();
+
+        Ok(())
     }
 
     /// Free a single frame
@@ -308,10 +521,12 @@ impl BootInfoFrameAllocator {
     }
 
     /// Insert a range in sorted order by start address
+    /// The callers make sure there's a free slot.
     fn insert_range_sorted(&mut self, range: PhysRange) {
-        if self.range_count >= MAX_RANGES {
-            panic!("BootInfoFrameAllocator: exceeded maximum number of free ranges");
-        }
+        assert!(
+            self.range_count < MAX_RANGES,
+            "BootInfoFrameAllocator: exceeded maximum number of free ranges"
+        );
 
         // Find insertion point
         let mut pos = self.range_count;
@@ -1,5 +1,48 @@
+pub mod address_space;
 pub mod allocator;
+pub mod audit;
 pub mod buddy;
+pub mod demand;
+pub mod emergency;
 pub mod memory;
+pub mod shm;
 pub mod slub;
 pub mod user;
+
+pub use address_space::with_address_space;
+pub use audit::audit_user_accessible;
+
+use core::slice;
+
+use x86_64::VirtAddr;
+
+use memory::BootInfoFrameAllocator;
+
+/// Bring up the kernel heap: the buddy allocator gets every frame the boot allocator has left
+/// Returns the bytes handed over. The boot allocator must not allocate after this.
+pub fn init(
+    frame_allocator: &mut BootInfoFrameAllocator,
+    phys_mem_offset: VirtAddr,
+) -> Result<u64, &'static str> {
+    // The buddy allocator tracks every page up to the end of free memory, its bitmap comes from the boot allocator
+    let max_pages = (frame_allocator.end_of_free_memory() / memory::PAGE_SIZE) as usize;
+    let bitmap_bytes = buddy::bitmap_size(max_pages);
+    let bitmap_frame = frame_allocator
+        .allocate_contiguous(bitmap_bytes.div_ceil(memory::PAGE_SIZE as usize).max(1))
+        .ok_or("no free memory for the buddy allocator bitmap")?;
+    let bitmap_addr = phys_mem_offset + bitmap_frame.start_address().as_u64();
+    let bitmap = unsafe { slice::from_raw_parts_mut(bitmap_addr.as_mut_ptr(), bitmap_bytes) };
+
+    allocator::init_heap(phys_mem_offset.as_u64() as usize, max_pages, bitmap)?;
+
+    let handed_off = frame_allocator.hand_off(|frame| {
+        let virt_addr = phys_mem_offset + frame.start_address().as_u64();
+        unsafe { allocator::add_frame(virt_addr.as_mut_ptr()) };
+    });
+
+    if handed_off == 0 {
+        return Err("no free memory for the heap");
+    }
+
+    Ok(handed_off)
+}
@@ -0,0 +1,208 @@
+// Shared memory
+//
+// Anonymous shared memory objects: a set of physical frames that several tasks can map at once.
+// The frames are freed once the object is destroyed and its last mapping is gone.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Biggest shared memory object we hand out: 1024 pages = 4 MiB
+pub const SHM_MAX_PAGES: usize = 1024;
+
+/// Virtual address window shared memory gets mapped into, far away from ELF segments and stacks
+pub const SHM_BASE: u64 = 0x1000_0000_0000;
+pub const SHM_END: u64 = 0x2000_0000_0000;
+
+/// Global shared memory objects, used by the shm syscalls
+pub static SHM: Mutex<ShmRegistry> = Mutex::new(ShmRegistry::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Size is 0 or bigger than `SHM_MAX_PAGES`
+    InvalidSize,
+    /// No (live) object with that ID
+    NotFound,
+    /// The shared memory window is used up
+    NoAddressSpace,
+}
+
+/// A shared memory object mapped into a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShmMapping {
+    pub id: u64,
+    pub base: VirtAddr,
+    pub pages: usize,
+}
+
+impl ShmMapping {
+    /// Check if an address lies inside this mapping
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.base && addr < self.base + self.pages as u64 * PAGE_SIZE
+    }
+}
+
+struct SharedObject {
+    frames: Vec<PhysFrame<Size4KiB>>,
+    /// Number of live mappings, the frames stay allocated while this isn't 0
+    mappings: usize,
+    /// Set by `destroy`, the object can't be mapped anymore and is freed with its last mapping
+    destroyed: bool,
+}
+
+pub struct ShmRegistry {
+    objects: BTreeMap<u64, SharedObject>,
+    next_id: u64,
+    /// Next free address in the shared memory window, addresses are never reused
+    next_addr: u64,
+}
+
+impl ShmRegistry {
+    pub const fn new() -> Self {
+        Self {
+            objects: BTreeMap::new(),
+            next_id: 1,
+            next_addr: SHM_BASE,
+        }
+    }
+
+    /// Number of pages needed for an object of `size` bytes
+    pub fn pages_for(size: u64) -> Result<usize, Error> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        if pages == 0 || pages > SHM_MAX_PAGES as u64 {
+            return Err(Error::InvalidSize);
+        }
+        Ok(pages as usize)
+    }
+
+    /// Register a new object backed by `frames` and return its ID
+    pub fn insert(&mut self, frames: Vec<PhysFrame<Size4KiB>>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.objects.insert(
+            id,
+            SharedObject {
+                frames,
+                mappings: 0,
+                destroyed: false,
+            },
+        );
+
+        id
+    }
+
+    /// Size of an object in pages
+    pub fn pages(&self, id: u64) -> Result<usize, Error> {
+        match self.objects.get(&id) {
+            Some(object) if !object.destroyed => Ok(object.frames.len()),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Number of live mappings of an object, None if it's gone
+    pub fn mapping_count(&self, id: u64) -> Option<usize> {
+        self.objects.get(&id).map(|object| object.mappings)
+    }
+
+    /// Take a reference to an object for a new mapping
+    ///
+    /// Reserves a range in the shared memory window (with a guard page after it) and
+    /// returns where to map the object and the frames to map there.
+    pub fn attach(&mut self, id: u64) -> Result<(VirtAddr, &[PhysFrame<Size4KiB>]), Error> {
+        let pages = self.pages(id)? as u64;
+
+        let base = self.next_addr;
+        let end = base + (pages + 1) * PAGE_SIZE;
+        if end > SHM_END {
+            return Err(Error::NoAddressSpace);
+        }
+        self.next_addr = end;
+
+        let object = self.objects.get_mut(&id).ok_or(Error::NotFound)?;
+        object.mappings += 1;
+
+        Ok((VirtAddr::new(base), &object.frames))
+    }
+
+    /// Drop the reference a mapping held
+    ///
+    /// Returns the frames if that was the last mapping of a destroyed object, they have to be freed.
+    pub fn detach(&mut self, id: u64) -> Option<Vec<PhysFrame<Size4KiB>>> {
+        let object = self.objects.get_mut(&id)?;
+        object.mappings = object.mappings.saturating_sub(1);
+
+        self.remove_if_unused(id)
+    }
+
+    /// Mark an object as destroyed, no new mappings can be made
+    ///
+    /// Returns the frames if nothing maps the object anymore, they have to be freed.
+    pub fn destroy(&mut self, id: u64) -> Result<Option<Vec<PhysFrame<Size4KiB>>>, Error> {
+        match self.objects.get_mut(&id) {
+            Some(object) if !object.destroyed => object.destroyed = true,
+            _ => return Err(Error::NotFound),
+        }
+
+        Ok(self.remove_if_unused(id))
+    }
+
+    fn remove_if_unused(&mut self, id: u64) -> Option<Vec<PhysFrame<Size4KiB>>> {
+        let object = self.objects.get(&id)?;
+        if !object.destroyed || object.mappings > 0 {
+            return None;
+        }
+
+        self.objects.remove(&id).map(|object| object.frames)
+    }
+}
+
+impl Default for ShmRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Map shared frames at `base` for userspace: writable, but never executable
+/// Nothing stays mapped if one of the pages fails.
+pub fn map_frames(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    base: VirtAddr,
+    frames: &[PhysFrame<Size4KiB>],
+) -> Result<(), &'static str> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+
+    for (i, &frame) in frames.iter().enumerate() {
+        let page = Page::containing_address(base + i as u64 * PAGE_SIZE);
+
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            // The page wasn't mapped, so the TLB can't have an entry for it
+            Ok(flush) => flush.ignore(),
+            Err(_) => {
+                unmap_frames(mapper, base, i);
+                return Err("Failed to map shared memory page");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmap `pages` shared pages starting at `base`, the frames are left alone
+pub fn unmap_frames(mapper: &mut impl Mapper<Size4KiB>, base: VirtAddr, pages: usize) {
+    for i in 0..pages {
+        let page = Page::<Size4KiB>::containing_address(base + i as u64 * PAGE_SIZE);
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
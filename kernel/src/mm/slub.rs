@@ -17,6 +17,10 @@ pub struct SlabHeader {
     freelist: Option<NonNull<FreeObject>>,
     /// Number of objects currently in use in this slab.
     in_use: usize,
+    /// Object size of the cache this slab belongs to, to catch frees into the wrong cache.
+    size: usize,
+    /// Whether the slab is linked into the partial list, so it's never linked twice.
+    in_partial: bool,
 }
 
 /// A node in the free list, embedded in the free memory slots.
@@ -57,6 +61,7 @@ impl SCache {
                 if slab.freelist.is_none() {
                     self.partial = slab.next_slab;
                     slab.next_slab = None;
+                    slab.in_partial = false;
                 }
 
                 return Some(obj_ptr.as_ptr() as *mut u8);
@@ -64,6 +69,8 @@ impl SCache {
                 // Should not happen if it's in partial list, unless logic error.
                 // Remove from partial and try next.
                 self.partial = slab.next_slab;
+                slab.next_slab = None;
+                slab.in_partial = false;
                 return self.alloc(provider);
             }
         }
@@ -119,6 +126,8 @@ impl SCache {
             next_slab: None,
             freelist: next_ptr,
             in_use: 0,
+            size: self.size,
+            in_partial: false,
         };
 
         // We immediately allocate one object (the first one)
@@ -130,6 +139,7 @@ impl SCache {
         // If there are still free objects, add to partial
         if slab.freelist.is_some() {
             slab.next_slab = self.partial;
+            slab.in_partial = true;
             self.partial = NonNull::new(slab_ptr);
         }
 
@@ -144,6 +154,17 @@ impl SCache {
         let slab_ptr = page_ptr as *mut SlabHeader;
         let slab = unsafe { &mut *slab_ptr };
 
+        // A pointer from another cache would corrupt that slab's freelist, leak it instead
+        if slab.size != self.size {
+            if cfg!(debug_assertions) {
+                panic!(
+                    "SCache: freeing {:p} from a {} byte slab into the {} byte cache",
+                    ptr, slab.size, self.size
+                );
+            }
+            return;
+        }
+
         // Create FreeObject at ptr
         let obj_ptr = ptr as *mut FreeObject;
         unsafe { (*obj_ptr).next = slab.freelist };
@@ -152,17 +173,27 @@ impl SCache {
 
         if slab.in_use == 0 {
             // Free the page
-            self.remove_slab_from_partial(slab_ptr);
-            provider.free_page(page_ptr);
-        } else {
-            // If it was full (not in partial) and now has 1 free, add to partial.
-            // We check if it's in partial by checking if we just transitioned from full.
-            // If `(*obj_ptr).next` (old freelist head) was None, it was full.
-            if unsafe { (*obj_ptr).next.is_none() } {
-                slab.next_slab = self.partial;
-                self.partial = NonNull::new(slab_ptr);
+            if slab.in_partial {
+                self.remove_slab_from_partial(slab_ptr);
             }
+            provider.free_page(page_ptr);
+        } else if !slab.in_partial {
+            // It was full and now has a free object, it goes back on the partial list
+            slab.next_slab = self.partial;
+            slab.in_partial = true;
+            self.partial = NonNull::new(slab_ptr);
+        }
+    }
+
+    /// Number of slabs on the partial list (slabs with both used and free objects)
+    pub fn partial_slabs(&self) -> usize {
+        let mut count = 0;
+        let mut cur = self.partial;
+        while let Some(node) = cur {
+            count += 1;
+            cur = unsafe { node.as_ref().next_slab };
         }
+        count
     }
 
     fn remove_slab_from_partial(&mut self, slab_ptr: *mut SlabHeader) {
@@ -172,6 +203,8 @@ impl SCache {
                 // Found it
                 unsafe {
                     *cur = node.as_mut().next_slab;
+                    node.as_mut().next_slab = None;
+                    node.as_mut().in_partial = false;
                 }
                 return;
             }
@@ -1,9 +1,13 @@
 use x86_64::{
     PhysAddr, VirtAddr,
-    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+        Translate,
+        mapper::{MappedFrame, TranslateResult},
+    },
 };
 
-use crate::mm::allocator;
+use crate::mm::{allocator, memory};
 
 /// A wrapper that provides frames from the global buddy allocator
 pub struct BuddyFrameAllocator;
@@ -14,9 +18,15 @@ unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        unsafe { allocator::deallocate_frame(frame) };
+    }
+}
+
 /// Maps a new page at the given virtual address for userspace
 ///
-/// Uses the buddy allocator to get a physical frame, then maps it
+/// Uses the buddy allocator to get a physical frame (never a kernel heap object), then maps it
 /// at the specified virtual address with the given flags.
 ///
 /// Returns the physical address of the allocated frame so the caller
@@ -37,12 +47,203 @@ pub fn map_user_page(
     let phys_addr = frame.start_address();
 
     // 2. Map the page to the frame
+    // The page wasn't mapped, so the TLB can't have an entry for it
     unsafe {
         mapper
             .map_to(page, frame, flags, frame_allocator)
             .map_err(|_| "Failed to map page")?
-            .flush();
+            .ignore();
     }
 
     Ok(phys_addr)
 }
+
+/// Check that every byte of `len` bytes at `addr` is mapped and accessible from ring 3
+/// An empty buffer is only fine at a canonical address.
+pub fn is_user_accessible(mapper: &impl Translate, addr: u64, len: u64) -> bool {
+    has_user_flags(mapper, addr, len, PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Like `is_user_accessible`, but every page must also be writable
+/// CR0.WP is set, so the kernel faults on read-only user pages just like userspace does.
+pub fn is_user_writable(mapper: &impl Translate, addr: u64, len: u64) -> bool {
+    has_user_flags(
+        mapper,
+        addr,
+        len,
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+    )
+}
+
+/// Check that every page of `len` bytes at `addr` is mapped with all of `required`
+fn has_user_flags(mapper: &impl Translate, addr: u64, len: u64, required: PageTableFlags) -> bool {
+    let Some(end) = addr.checked_add(len) else {
+        return false;
+    };
+    let (Ok(start), Ok(_)) = (VirtAddr::try_new(addr), VirtAddr::try_new(end)) else {
+        return false;
+    };
+
+    // One check per page is enough, the flags are per page
+    let mut page = start.align_down(4096u64);
+    while page.as_u64() < end {
+        match mapper.translate(page) {
+            TranslateResult::Mapped { flags, .. } if flags.contains(required) => {}
+            _ => return false,
+        }
+        page += 4096u64;
+    }
+
+    true
+}
+
+/// User space address limit - addresses above this are kernel space
+/// Our kernel is mapped in the higher half, so user addresses should be below this
+pub const USER_SPACE_LIMIT: u64 = 0x0000_8000_0000_0000;
+
+/// Why a buffer passed to a syscall was rejected (EFAULT for userspace)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// Null, wraps around or reaches into kernel space
+    BadRange,
+    /// Part of the buffer isn't mapped or isn't accessible from ring 3
+    NotMapped,
+    /// The kernel was going to write to the buffer, but part of it is read-only
+    ReadOnly,
+}
+
+/// Check that `len` bytes at `ptr` are user memory: below `kernel_start`, mapped and user accessible
+pub fn check_user_buffer(
+    mapper: &impl Translate,
+    ptr: u64,
+    len: u64,
+    kernel_start: u64,
+) -> Result<(), SyscallError> {
+    match ptr.checked_add(len) {
+        Some(end) if ptr != 0 && end <= kernel_start => {}
+        _ => return Err(SyscallError::BadRange),
+    }
+
+    if !is_user_accessible(mapper, ptr, len) {
+        return Err(SyscallError::NotMapped);
+    }
+
+    Ok(())
+}
+
+/// Like `check_user_buffer`, for buffers the kernel writes to: they also have to be writable
+pub fn check_user_buffer_mut(
+    mapper: &impl Translate,
+    ptr: u64,
+    len: u64,
+    kernel_start: u64,
+) -> Result<(), SyscallError> {
+    check_user_buffer(mapper, ptr, len, kernel_start)?;
+
+    if !is_user_writable(mapper, ptr, len) {
+        return Err(SyscallError::ReadOnly);
+    }
+
+    Ok(())
+}
+
+/// Where kernel space starts as far as syscall buffers are concerned
+fn kernel_start() -> u64 {
+    // Everything from the physical memory mapping or the higher half up is kernel space
+    memory::physical_memory_offset()
+        .as_u64()
+        .min(USER_SPACE_LIMIT)
+}
+
+/// Borrow a buffer userspace passed to a syscall, after checking that it's user memory
+///
+/// Only use the slice during the syscall, the task could unmap the memory afterwards.
+pub fn validate_user_buffer<'a>(ptr: u64, len: u64) -> Result<&'a [u8], SyscallError> {
+    // Only reads the page tables, nobody changes them while we're in a syscall
+    let mapper = unsafe { memory::active_mapper() };
+    check_user_buffer(&mapper, ptr, len, kernel_start())?;
+
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+/// Borrow a buffer the kernel fills for a syscall, after checking that it's writable user memory
+///
+/// Writing to a read-only user page (the task's own code, say) would page fault in ring 0.
+/// Only use the slice during the syscall, the task could unmap the memory afterwards.
+pub fn validate_user_buffer_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], SyscallError> {
+    let mapper = unsafe { memory::active_mapper() };
+    check_user_buffer_mut(&mapper, ptr, len, kernel_start())?;
+
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize) })
+}
+
+/// Makes a mapped user page writable while `f` runs, then restores its original flags
+///
+/// `f` gets a pointer to the page's frame through the physical memory mapping, so the kernel can fill
+/// pages that userspace may only read (code, rodata) without leaving them writable afterwards.
+/// Returns what `f` returns, or an error if the page isn't mapped as a 4KiB page.
+pub fn with_writable<R>(
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    page: Page<Size4KiB>,
+    phys_mem_offset: VirtAddr,
+    f: impl FnOnce(*mut u8) -> R,
+) -> Result<R, &'static str> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err("Page is part of a huge page"),
+        _ => return Err("Page is not mapped"),
+    };
+
+    let was_writable = flags.contains(PageTableFlags::WRITABLE);
+    if !was_writable {
+        unsafe {
+            mapper
+                .update_flags(page, flags | PageTableFlags::WRITABLE)
+                .map_err(|_| "Failed to make page writable")?
+                .flush();
+        }
+    }
+
+    let kernel_ptr = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+    let result = f(kernel_ptr);
+
+    if !was_writable {
+        unsafe {
+            mapper
+                .update_flags(page, flags)
+                .map_err(|_| "Failed to restore page flags")?
+                .flush();
+        }
+    }
+
+    Ok(result)
+}
+
+/// Unmaps a user page and gives its frame back to the frame allocator
+///
+/// This is the counterpart of `map_user_page`.
+/// The frame is zeroed first, the buddy allocator also feeds the kernel heap and we don't
+/// want a task's data to show up in kernel memory (or in the next task) after it's gone.
+/// Page tables that become empty are not freed (yet).
+///
+/// # Safety
+/// Nothing may access the page after it is unmapped, and the frame must not be mapped anywhere else.
+pub unsafe fn unmap_user_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    page: Page<Size4KiB>,
+) -> Result<(), &'static str> {
+    let (frame, flush) = mapper.unmap(page).map_err(|_| "Failed to unmap page")?;
+    flush.flush();
+
+    let kernel_ptr = memory::physical_memory_offset() + frame.start_address().as_u64();
+    unsafe { core::ptr::write_bytes(kernel_ptr.as_mut_ptr::<u8>(), 0, 4096) };
+
+    unsafe { frame_deallocator.deallocate_frame(frame) };
+
+    Ok(())
+}
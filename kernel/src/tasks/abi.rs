@@ -0,0 +1,128 @@
+// Syscall ABI
+//
+// Structs and constants shared with userspace. The layouts match Linux on x86_64 so a
+// userspace library can use the same definitions (and existing C code works unchanged).
+//
+// Syscalls that return a struct take a user pointer to it, the kernel fills it with
+// `copy_to_user` and returns 0 on success or -errno on failure.
+// Every struct here must be #[repr(C)] without implicit padding, padding bytes would leak
+// kernel stack contents to userspace.
+
+/// No such process
+pub const ESRCH: i64 = 3;
+/// I/O error
+pub const EIO: i64 = 5;
+/// Try again
+pub const EAGAIN: i64 = 11;
+/// Out of memory
+pub const ENOMEM: i64 = 12;
+/// Bad address
+pub const EFAULT: i64 = 14;
+/// Device or resource busy
+pub const EBUSY: i64 = 16;
+/// Invalid argument
+pub const EINVAL: i64 = 22;
+/// Function not implemented
+pub const ENOSYS: i64 = 38;
+
+/// Turn an errno into a syscall return value (-errno)
+pub const fn error(errno: i64) -> u64 {
+    (-errno) as u64
+}
+
+/// Turn a syscall result into a return value, 0 on success and -errno on failure
+pub fn result(result: Result<(), i64>) -> u64 {
+    match result {
+        Ok(()) => 0,
+        Err(errno) => error(errno),
+    }
+}
+
+/// Turn the result of a syscall that returns a value (an ID, an address...) into a return value
+pub fn value(result: Result<u64, i64>) -> u64 {
+    match result {
+        Ok(value) => value,
+        Err(errno) => error(errno),
+    }
+}
+
+/// Same as `struct timespec`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    /// The duration in nanoseconds, None if it's negative or tv_nsec isn't below a second
+    /// Durations too long for a u64 (over 500 years) are clamped.
+    pub fn as_nanos(&self) -> Option<u64> {
+        if self.tv_sec < 0 || !(0..1_000_000_000).contains(&self.tv_nsec) {
+            return None;
+        }
+
+        Some(
+            (self.tv_sec as u64)
+                .saturating_mul(1_000_000_000)
+                .saturating_add(self.tv_nsec as u64),
+        )
+    }
+
+    /// A duration of `nanos` nanoseconds
+    pub fn from_nanos(nanos: u64) -> Self {
+        Self {
+            tv_sec: (nanos / 1_000_000_000) as i64,
+            tv_nsec: (nanos % 1_000_000_000) as i64,
+        }
+    }
+}
+
+/// Time since boot, the only clock `clock_gettime` supports (same number as Linux)
+pub const CLOCK_MONOTONIC: u64 = 1;
+
+/// Signals `kill` supports, same numbers as Linux
+pub const SIGCONT: u64 = 18;
+pub const SIGSTOP: u64 = 19;
+
+/// Address space limit, the only resource `setrlimit` supports
+pub const RLIMIT_AS: u64 = 9;
+/// No limit
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+/// Same as `struct rlimit`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Rlimit {
+    /// Soft limit
+    pub rlim_cur: u64,
+    /// Hard limit
+    pub rlim_max: u64,
+}
+
+/// Same as `struct sysinfo`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SysInfo {
+    /// Seconds since boot
+    pub uptime: i64,
+    /// 1, 5 and 15 minute load averages
+    pub loads: [u64; 3],
+    /// Total usable memory, in `mem_unit` units
+    pub totalram: u64,
+    /// Free memory, in `mem_unit` units
+    pub freeram: u64,
+    pub sharedram: u64,
+    pub bufferram: u64,
+    pub totalswap: u64,
+    pub freeswap: u64,
+    /// Number of tasks
+    pub procs: u16,
+    pub pad: u16,
+    pub _pad: u32,
+    pub totalhigh: u64,
+    pub freehigh: u64,
+    /// Size of the memory unit in bytes
+    pub mem_unit: u32,
+    pub _reserved: u32,
+}
@@ -1,187 +1,492 @@
 // Elf parser and loader
 
+use alloc::vec::Vec;
+use core::ops::Range;
 use goblin::elf64::header::Header;
 use goblin::elf64::program_header::ProgramHeader;
 use x86_64::{
     VirtAddr,
-    structures::paging::{FrameAllocator, Mapper, PageTableFlags, Size4KiB},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB, Translate,
+    },
 };
 
-use crate::{mm::user::map_user_page, serial_println};
+use crate::{
+    mm::user::{USER_SPACE_LIMIT, map_user_page, with_writable},
+    serial_println,
+};
 
 /// User stack is placed at a fixed address below the kernel
 /// Stack grows downward, so this is the top of the stack
 pub const USER_STACK_TOP: u64 = 0x7FFFFF000;
-/// Size of user stack: 16 pages = 64 KiB
+/// Default size of user stacks: 16 pages = 64 KiB
 pub const USER_STACK_PAGES: u64 = 16;
 pub const USER_STACK_SIZE: u64 = USER_STACK_PAGES * 4096;
 
+/// Largest heap a task can grow with brk: 16384 pages = 64 MiB
+pub const USER_HEAP_MAX_PAGES: u64 = 16384;
+
 #[derive(Debug)]
 pub enum Error {
     MappingFailed(&'static str),
     InvalidElf(goblin::error::Error),
 }
 
-/// Result of loading an ELF: entry point and stack pointer
+/// Result of loading an ELF: entry point, stack pointer and every page we mapped
 pub struct ElfLoadResult {
     pub entry_point: u64,
     pub stack_top: u64,
+    /// First page after the highest segment, where the heap starts
+    pub heap_base: u64,
+    pub mapped_pages: Vec<Page<Size4KiB>>,
 }
 
-/// Load an ELF binary into memory and allocate a user stack
-///
-/// `phys_mem_offset` is used to write to physical frames through the kernel's
-/// identity-mapped physical memory region.
+/// Segment permission flags (p_flags)
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// The parts of a PT_LOAD program header the loader needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub memsz: u64,
+    pub filesz: u64,
+    pub offset: u64,
+    pub flags: u32,
+}
+
+impl From<&ProgramHeader> for Segment {
+    fn from(ph: &ProgramHeader) -> Self {
+        Self {
+            vaddr: ph.p_vaddr,
+            memsz: ph.p_memsz,
+            filesz: ph.p_filesz,
+            offset: ph.p_offset,
+            flags: ph.p_flags,
+        }
+    }
+}
+
+impl Segment {
+    /// Check that the segment fits in memory and its file data lies inside the ELF
+    pub fn validate(&self, data_len: usize) -> Result<(), Error> {
+        if self.filesz > self.memsz {
+            return Err(malformed("Segment file size larger than memory size"));
+        }
+        if self.vaddr.checked_add(self.memsz).is_none() {
+            return Err(malformed("Segment wraps around the address space"));
+        }
+        // BSS only segments never read the file, so their offset doesn't matter
+        if self.filesz == 0 {
+            return Ok(());
+        }
+        match self.offset.checked_add(self.filesz) {
+            Some(end) if end <= data_len as u64 => Ok(()),
+            _ => Err(malformed("Segment data out of bounds")),
+        }
+    }
+
+    /// Final flags of the segment's pages: writable only with PF_W, executable only with PF_X
+    pub fn page_flags(&self) -> PageTableFlags {
+        let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.flags & PF_W != 0 {
+            page_flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            page_flags |= PageTableFlags::NO_EXECUTE;
+        }
+        page_flags
+    }
+
+    /// Start addresses of the pages the segment covers, empty if it takes no memory
+    /// The segment must be valid, see `validate`.
+    pub fn pages(&self) -> Range<u64> {
+        if self.memsz == 0 {
+            return 0..0;
+        }
+
+        let start_page = self.vaddr & !0xFFF;
+        let end_page = (self.vaddr + self.memsz).div_ceil(4096) * 4096;
+        start_page..end_page
+    }
+
+    /// Part of the file data that belongs in the page at `page_vaddr`
+    /// Returns the offset in the page and the range in the ELF file, None if there is nothing to copy
+    /// (BSS only segments never have anything to copy).
+    pub fn file_bytes_in_page(&self, page_vaddr: u64) -> Option<(usize, Range<usize>)> {
+        let page_end = page_vaddr + 4096;
+        let file_end = self.vaddr + self.filesz;
+
+        let copy_start = self.vaddr.max(page_vaddr);
+        let copy_end = file_end.min(page_end);
+        if copy_start >= copy_end {
+            return None;
+        }
+
+        let file_offset = (self.offset + (copy_start - self.vaddr)) as usize;
+        let len = (copy_end - copy_start) as usize;
+        Some((
+            (copy_start - page_vaddr) as usize,
+            file_offset..file_offset + len,
+        ))
+    }
+}
+
+/// The loadable part of an ELF, checked by `parse`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ElfImage {
+    pub entry: u64,
+    /// PT_LOAD segments that take memory, they don't share any pages
+    pub segments: Vec<Segment>,
+}
+
+/// PT_LOAD program header type
+const PT_LOAD: u32 = 1;
+
+/// Turn a reason into `Error::InvalidElf`
+fn malformed(reason: &str) -> Error {
+    Error::InvalidElf(goblin::error::Error::Malformed(reason.into()))
+}
+
+/// Parse and check the ELF header and program headers, before anything is mapped
 ///
-/// Returns the entry point address and stack top pointer
-pub fn load_elf(
-    data: &[u8],
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    phys_mem_offset: VirtAddr,
-) -> Result<ElfLoadResult, Error> {
-    // Parse ELF header directly (no allocation)
+/// Every segment must lie inside the file and in user space, segments can't share pages
+/// (the second mapping would fail halfway through loading), and the entry point has to be
+/// in an executable segment.
+pub fn parse(data: &[u8]) -> Result<ElfImage, Error> {
     if data.len() < core::mem::size_of::<Header>() {
-        return Err(Error::MappingFailed("ELF too small for header"));
+        return Err(malformed("ELF too small for header"));
     }
 
-    let header: &Header = unsafe { &*(data.as_ptr() as *const Header) };
+    // The data doesn't have to be aligned
+    let header = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Header) };
 
     // Validate ELF magic
     if &header.e_ident[0..4] != b"\x7fELF" {
-        return Err(Error::MappingFailed("Invalid ELF magic"));
+        return Err(malformed("Invalid ELF magic"));
     }
 
     // Check 64-bit
     if header.e_ident[4] != 2 {
-        return Err(Error::MappingFailed("Not a 64-bit ELF"));
+        return Err(malformed("Not a 64-bit ELF"));
     }
 
-    let entry = header.e_entry;
     let ph_offset = header.e_phoff as usize;
     let ph_count = header.e_phnum as usize;
     let ph_size = header.e_phentsize as usize;
 
-    serial_println!(
-        "Loading ELF: entry=0x{:x}, {} program headers",
-        entry,
-        ph_count
-    );
+    if ph_count > 0 && ph_size != core::mem::size_of::<ProgramHeader>() {
+        return Err(malformed("Unexpected program header size"));
+    }
+    match ph_count
+        .checked_mul(ph_size)
+        .and_then(|size| size.checked_add(ph_offset))
+    {
+        Some(end) if end <= data.len() => {}
+        _ => return Err(malformed("Program headers out of bounds")),
+    }
 
+    let mut segments = Vec::new();
     for i in 0..ph_count {
-        let ph_start = ph_offset + i * ph_size;
+        let ph_ptr = unsafe { data.as_ptr().add(ph_offset + i * ph_size) };
+        let ph = unsafe { core::ptr::read_unaligned(ph_ptr as *const ProgramHeader) };
 
-        if ph_start + core::mem::size_of::<ProgramHeader>() > data.len() {
-            return Err(Error::MappingFailed("Program header out of bounds"));
+        if ph.p_type != PT_LOAD {
+            continue;
         }
 
-        let ph_ptr = data.as_ptr();
-        let ph_ptr_offset = unsafe { ph_ptr.add(ph_start) };
-        let ph: &ProgramHeader = unsafe { &*(ph_ptr_offset as *const ProgramHeader) };
-
-        // PT_LOAD = 1
-        if ph.p_type == 1 {
-            let vaddr_start = ph.p_vaddr;
-            let memsz = ph.p_memsz;
-            let filesz = ph.p_filesz;
-            let offset = ph.p_offset;
-            let flags = ph.p_flags;
-
-            serial_println!(
-                "  LOAD: vaddr=0x{:x}, memsz=0x{:x}, filesz=0x{:x}, flags=0x{:x}",
-                vaddr_start,
-                memsz,
-                filesz,
-                flags
-            );
-
-            // Determine page flags
-            // PF_W = 2, PF_X = 1
-            // NOTE: We always map as writable initially so we can copy data,
-            // then we'll need to remap with proper flags later // TODO
-            let mut page_flags = PageTableFlags::PRESENT
-                | PageTableFlags::USER_ACCESSIBLE
-                | PageTableFlags::WRITABLE; // Always writable for now to allow copy
-            if flags & 1 == 0 {
-                page_flags |= PageTableFlags::NO_EXECUTE;
-            }
+        let segment = Segment::from(&ph);
+        segment.validate(data.len())?;
 
-            // Map all pages for this segment and copy data through physical memory mapping
-            let start_page = vaddr_start & !0xFFF;
-            let end_page = (vaddr_start + memsz + 0xFFF) & !0xFFF;
+        // Nothing to map, the page math would still map the page at vaddr
+        if segment.memsz == 0 {
+            continue;
+        }
+        if segment.pages().end > USER_SPACE_LIMIT {
+            return Err(malformed("Segment outside of user space"));
+        }
+
+        segments.push(segment);
+    }
+
+    // Sorted by address, every segment has to end before the next one starts
+    let mut by_address: Vec<_> = segments.iter().map(Segment::pages).collect();
+    by_address.sort_by_key(|pages| pages.start);
+    if by_address
+        .windows(2)
+        .any(|pair| pair[0].end > pair[1].start)
+    {
+        return Err(malformed("Segments overlap"));
+    }
+
+    let entry = header.e_entry;
+    let entry_is_code = segments.iter().any(|segment| {
+        segment.flags & PF_X != 0 && entry >= segment.vaddr && entry - segment.vaddr < segment.memsz
+    });
+    if !entry_is_code {
+        return Err(malformed("Entry point not in an executable segment"));
+    }
+
+    Ok(ElfImage { entry, segments })
+}
+
+/// Most of the stack the arguments may take, the program needs some of it too
+pub const MAX_ARGS_SIZE: u64 = USER_STACK_SIZE / 4;
 
-            // For each page, map it and copy the relevant portion of the segment
-            for page_vaddr in (start_page..end_page).step_by(4096) {
-                // Map the page and get its physical address
-                let phys_addr = map_user_page(
-                    mapper,
-                    frame_allocator,
-                    VirtAddr::new(page_vaddr),
-                    page_flags,
-                )
-                .map_err(|e| Error::MappingFailed(e))?;
+/// Where a stack of `stack_pages` pages goes for `image`, it ends at `USER_STACK_TOP`
+///
+/// The page below the stack is a guard page: it stays unmapped so an overflow faults instead of
+/// running into other memory. Fails if a segment or the heap (at its biggest) could end up there.
+pub fn stack_range(image: &ElfImage, stack_pages: u64) -> Result<Range<u64>, Error> {
+    if stack_pages == 0 {
+        return Err(Error::MappingFailed("Stack needs at least one page"));
+    }
+    // Stack and guard page
+    let guard = stack_pages
+        .checked_add(1)
+        .and_then(|pages| pages.checked_mul(4096))
+        .and_then(|size| USER_STACK_TOP.checked_sub(size))
+        .ok_or(Error::MappingFailed("Stack too big"))?;
+
+    let heap_base = image
+        .segments
+        .iter()
+        .map(|segment| segment.pages().end)
+        .max()
+        .unwrap_or(0);
+    let heap = heap_base..heap_base + USER_HEAP_MAX_PAGES * 4096;
+
+    let overlaps_stack = |range: &Range<u64>| range.start < USER_STACK_TOP && guard < range.end;
+    if overlaps_stack(&heap) || image.segments.iter().any(|s| overlaps_stack(&s.pages())) {
+        return Err(malformed("Segments or heap overlap the stack"));
+    }
 
-                // Calculate kernel-accessible address for this physical frame
-                let kernel_ptr = (phys_mem_offset.as_u64() + phys_addr.as_u64()) as *mut u8;
+    Ok(guard + 4096..USER_STACK_TOP)
+}
 
+/// Build the top of the initial stack (System V layout) for a program started with `args`
+///
+/// From the stack pointer up: argc, the argv pointers and a null pointer, an empty envp (a null
+/// pointer), an empty auxv (AT_NULL) and then the argument strings, each NUL terminated.
+/// Returns the bytes that go between the stack pointer and `stack_top`, and the stack pointer,
+/// which is 16-byte aligned.
+pub fn initial_stack(args: &[&str], stack_top: u64) -> Result<(Vec<u8>, u64), Error> {
+    let strings_size: u64 = args.iter().map(|arg| arg.len() as u64 + 1).sum();
+    // argc, argv, null, envp null, AT_NULL (type and value)
+    let pointers_size = (args.len() as u64 + 5) * 8;
+
+    let size = (strings_size + pointers_size).next_multiple_of(16);
+    if size > MAX_ARGS_SIZE {
+        return Err(Error::MappingFailed("Arguments don't fit on the stack"));
+    }
+    let stack_pointer = stack_top - size;
+
+    let mut image = alloc::vec![0u8; size as usize];
+    let mut write_word = |offset: usize, value: u64| {
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    };
+
+    write_word(0, args.len() as u64);
+
+    // Strings go right below stack_top, in order
+    let mut string_addr = stack_top - strings_size;
+    for (i, arg) in args.iter().enumerate() {
+        write_word(8 + i * 8, string_addr);
+        string_addr += arg.len() as u64 + 1;
+    }
+    // The null pointers after argv and envp and AT_NULL are already zero
+
+    let mut offset = (size - strings_size) as usize;
+    for arg in args {
+        image[offset..offset + arg.len()].copy_from_slice(arg.as_bytes());
+        offset += arg.len() + 1;
+    }
+
+    Ok((image, stack_pointer))
+}
+
+/// Load an ELF binary into memory and allocate a user stack
+///
+/// `phys_mem_offset` is used to write to physical frames through the kernel's
+/// identity-mapped physical memory region.
+/// `args` are put on the stack for the program as argc and argv, see `initial_stack`.
+/// The stack is `stack_pages` pages, see `stack_range`.
+///
+/// Returns the entry point address and stack top pointer. If loading fails halfway through, the
+/// pages mapped so far are unmapped and their frames freed before the error is returned.
+pub fn load_elf(
+    data: &[u8],
+    args: &[&str],
+    stack_pages: u64,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    phys_mem_offset: VirtAddr,
+) -> Result<ElfLoadResult, Error> {
+    let image = parse(data)?;
+
+    serial_println!(
+        "Loading ELF: entry=0x{:x}, {} loadable segments",
+        image.entry,
+        image.segments.len()
+    );
+    for segment in &image.segments {
+        serial_println!(
+            "  LOAD: vaddr=0x{:x}, memsz=0x{:x}, filesz=0x{:x}, flags=0x{:x}",
+            segment.vaddr,
+            segment.memsz,
+            segment.filesz,
+            segment.flags
+        );
+    }
+
+    let loaded = map_image(
+        &image,
+        data,
+        args,
+        stack_pages,
+        mapper,
+        frame_allocator,
+        phys_mem_offset,
+    )?;
+
+    serial_println!(
+        "  Stack: {} pages below 0x{:x}, stack pointer 0x{:x}",
+        stack_pages,
+        USER_STACK_TOP,
+        loaded.stack_top
+    );
+    serial_println!(
+        "  ELF loaded successfully, entry=0x{:x}",
+        loaded.entry_point
+    );
+
+    Ok(loaded)
+}
+
+/// Map the segments of `image` (parsed from `data`) and a stack with `args` on it, see `load_elf`
+/// If it fails halfway through, the pages mapped so far are unmapped and their frames freed.
+pub fn map_image(
+    image: &ElfImage,
+    data: &[u8],
+    args: &[&str],
+    stack_pages: u64,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    phys_mem_offset: VirtAddr,
+) -> Result<ElfLoadResult, Error> {
+    let mut mapped_pages = Vec::new();
+
+    let result = map_into(
+        image,
+        data,
+        args,
+        stack_pages,
+        mapper,
+        frame_allocator,
+        phys_mem_offset,
+        &mut mapped_pages,
+    );
+    if result.is_err() {
+        unmap_loaded(mapper, frame_allocator, &mapped_pages, phys_mem_offset);
+    }
+
+    result
+}
+
+/// Unmap the pages `map_into` mapped before it failed, zero their frames and free them
+///
+/// Nothing used the pages through their user addresses yet (we fill them through the physical
+/// memory mapping), so the TLB can't have entries for them and there's nothing to flush.
+fn unmap_loaded(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    pages: &[Page<Size4KiB>],
+    phys_mem_offset: VirtAddr,
+) {
+    for &page in pages {
+        let Ok((frame, flush)) = mapper.unmap(page) else {
+            continue;
+        };
+        flush.ignore();
+
+        let kernel_ptr = phys_mem_offset + frame.start_address().as_u64();
+        unsafe { core::ptr::write_bytes(kernel_ptr.as_mut_ptr::<u8>(), 0, 4096) };
+        unsafe { frame_deallocator.deallocate_frame(frame) };
+    }
+}
+
+/// `map_image` without the cleanup, every page it maps goes into `mapped_pages` right away
+#[allow(clippy::too_many_arguments)]
+fn map_into(
+    image: &ElfImage,
+    data: &[u8],
+    args: &[&str],
+    stack_pages: u64,
+    mapper: &mut (impl Mapper<Size4KiB> + Translate),
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    phys_mem_offset: VirtAddr,
+    mapped_pages: &mut Vec<Page<Size4KiB>>,
+) -> Result<ElfLoadResult, Error> {
+    let stack = stack_range(image, stack_pages)?;
+    let (stack_image, stack_pointer) = initial_stack(args, USER_STACK_TOP)?;
+    if stack_image.len() as u64 > (stack.end - stack.start) / 4 {
+        return Err(Error::MappingFailed("Arguments don't fit on the stack"));
+    }
+
+    // Keep track of the pages so the task can unmap them when it's dropped
+    let mut heap_base = 0;
+
+    for segment in &image.segments {
+        heap_base = heap_base.max(segment.pages().end);
+
+        // Pages are only writable while we copy the data in, see `with_writable`
+        let page_flags = segment.page_flags();
+
+        // For each page, map it and copy the relevant portion of the segment
+        for page_vaddr in segment.pages().step_by(4096) {
+            // Map the page with the segment's final flags
+            map_user_page(
+                mapper,
+                frame_allocator,
+                VirtAddr::new(page_vaddr),
+                page_flags,
+            )
+            .map_err(Error::MappingFailed)?;
+            let page = Page::containing_address(VirtAddr::new(page_vaddr));
+            mapped_pages.push(page);
+
+            // Fill the page through the kernel's physical memory mapping
+            with_writable(mapper, page, phys_mem_offset, |kernel_ptr| {
                 // Zero the entire page first (for BSS and partial pages)
                 unsafe {
                     core::ptr::write_bytes(kernel_ptr, 0, 4096);
                 }
 
-                // Calculate what portion of the segment falls in this page
-                let page_start = page_vaddr;
-                let page_end = page_vaddr + 4096;
-
-                // Calculate the range of the segment that overlaps with this page
-                let seg_start = vaddr_start;
-                let seg_file_end = vaddr_start + filesz; // End of file data
-
-                // Only copy if this page contains file data
-                if seg_file_end > page_start && seg_start < page_end {
-                    // Calculate the overlap between segment file data and this page
-                    let copy_start = seg_start.max(page_start);
-                    let copy_end = seg_file_end.min(page_end);
-                    let copy_len = (copy_end - copy_start) as usize;
-
-                    if copy_len > 0 {
-                        // Calculate source offset in ELF file
-                        let file_offset = offset + (copy_start - vaddr_start);
-                        let src = &data[file_offset as usize..(file_offset as usize + copy_len)];
-
-                        // Calculate destination offset within the page
-                        let page_offset = (copy_start - page_vaddr) as usize;
-                        let dest = unsafe { kernel_ptr.add(page_offset) };
-
-                        serial_println!(
-                            "      Copying {} bytes at offset {} in page",
-                            copy_len,
-                            page_offset
+                // BSS only pages have no file data and stay zeroed
+                if let Some((page_offset, file_range)) = segment.file_bytes_in_page(page_vaddr) {
+                    // In bounds, the segment was validated
+                    let src = &data[file_range];
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            src.as_ptr(),
+                            kernel_ptr.add(page_offset),
+                            src.len(),
                         );
-                        unsafe {
-                            core::ptr::copy_nonoverlapping(src.as_ptr(), dest, copy_len);
-                        }
                     }
                 }
-            }
+            })
+            .map_err(Error::MappingFailed)?;
         }
     }
 
     // Allocate user stack pages
-    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
     let stack_flags = PageTableFlags::PRESENT
         | PageTableFlags::WRITABLE
         | PageTableFlags::USER_ACCESSIBLE
         | PageTableFlags::NO_EXECUTE;
 
-    serial_println!(
-        "  Allocating stack: 0x{:x} - 0x{:x} ({} pages)",
-        stack_bottom,
-        USER_STACK_TOP,
-        USER_STACK_PAGES
-    );
-
-    for page_addr in (stack_bottom..USER_STACK_TOP).step_by(4096) {
+    for page_addr in stack.step_by(4096) {
         // Map the stack page and get physical address
         let phys_addr = map_user_page(
             mapper,
@@ -189,19 +494,34 @@ pub fn load_elf(
             VirtAddr::new(page_addr),
             stack_flags,
         )
-        .map_err(|e| Error::MappingFailed(e))?;
+        .map_err(Error::MappingFailed)?;
+        mapped_pages.push(Page::containing_address(VirtAddr::new(page_addr)));
 
         // Zero the stack page through kernel's physical memory mapping
         let kernel_ptr = (phys_mem_offset.as_u64() + phys_addr.as_u64()) as *mut u8;
         unsafe {
             core::ptr::write_bytes(kernel_ptr, 0, 4096);
         }
-    }
 
-    serial_println!("  ELF loaded successfully, entry=0x{:x}", entry);
+        // Copy the part of the arguments that lands in this page
+        let copy_start = stack_pointer.max(page_addr);
+        let copy_end = USER_STACK_TOP.min(page_addr + 4096);
+        if copy_start < copy_end {
+            let src = &stack_image[(copy_start - stack_pointer) as usize..];
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    src.as_ptr(),
+                    kernel_ptr.add((copy_start - page_addr) as usize),
+                    (copy_end - copy_start) as usize,
+                );
+            }
+        }
+    }
 
     Ok(ElfLoadResult {
-        entry_point: entry,
-        stack_top: USER_STACK_TOP,
+        entry_point: image.entry,
+        stack_top: stack_pointer,
+        heap_base,
+        mapped_pages: core::mem::take(mapped_pages),
     })
 }
@@ -0,0 +1,98 @@
+// Task IDs
+//
+// IDs are handed out lowest-free-first and can be reused once their task is dropped, so they
+// stay small in long running spawn/exit loops. ID 0 is never handed out, it means "no task".
+// The allocator is a lock-free bitmap because tasks can be dropped from interrupt context.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of 64 bit words in the global allocator's bitmap
+const TASK_ID_WORDS: usize = 64;
+
+/// Largest number of task IDs that can be in use at once (minus the reserved 0)
+pub const MAX_TASK_IDS: usize = TASK_ID_WORDS * 64;
+
+/// IDs of all tasks that currently exist
+static TASK_IDS: TaskIdAllocator<TASK_ID_WORDS> = TaskIdAllocator::new();
+
+/// Hands out IDs 1..WORDS * 64, one bit per ID
+pub struct TaskIdAllocator<const WORDS: usize> {
+    words: [AtomicU64; WORDS],
+}
+
+impl<const WORDS: usize> TaskIdAllocator<WORDS> {
+    pub const fn new() -> Self {
+        let mut words = [const { AtomicU64::new(0) }; WORDS];
+        // Reserve ID 0
+        words[0] = AtomicU64::new(1);
+
+        Self { words }
+    }
+
+    /// Get the lowest free ID, None if all of them are in use
+    pub fn allocate(&self) -> Option<u64> {
+        for (index, word) in self.words.iter().enumerate() {
+            let mut current = word.load(Ordering::Relaxed);
+
+            while current != u64::MAX {
+                let bit = (!current).trailing_zeros();
+                match word.compare_exchange_weak(
+                    current,
+                    current | (1 << bit),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some((index * 64) as u64 + bit as u64),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Give an ID back so it can be reused
+    /// Returns false (and does nothing) if the ID wasn't allocated
+    pub fn release(&self, id: u64) -> bool {
+        let Some((word, mask)) = self.position(id) else {
+            return false;
+        };
+
+        self.words[word].fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+
+    /// Check if an ID is in use
+    pub fn is_allocated(&self, id: u64) -> bool {
+        match self.position(id) {
+            Some((word, mask)) => self.words[word].load(Ordering::Relaxed) & mask != 0,
+            None => false,
+        }
+    }
+
+    /// Word index and bit mask of an ID, None for the reserved ID 0 and IDs out of range
+    fn position(&self, id: u64) -> Option<(usize, u64)> {
+        let word = (id / 64) as usize;
+        if id == 0 || word >= WORDS {
+            return None;
+        }
+
+        Some((word, 1 << (id % 64)))
+    }
+}
+
+impl<const WORDS: usize> Default for TaskIdAllocator<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Get an ID for a new task
+/// Panics if `MAX_TASK_IDS` tasks already exist, the scheduler's task limit is far below that
+pub fn allocate() -> u64 {
+    TASK_IDS.allocate().expect("Out of task IDs")
+}
+
+/// Give the ID of a dropped task back
+pub fn release(id: u64) {
+    TASK_IDS.release(id);
+}
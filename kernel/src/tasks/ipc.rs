@@ -0,0 +1,72 @@
+// Message passing
+//
+// Every task has a bounded mailbox of byte messages. `send` copies a message into the receiver's
+// mailbox and wakes it if it's waiting, `recv` takes the oldest one out. The syscalls copy the bytes
+// between user buffers through kernel space, so the two tasks never share memory.
+
+use alloc::vec::Vec;
+use crossbeam_queue::ArrayQueue;
+
+/// Biggest message, one page
+pub const MAX_MESSAGE_SIZE: usize = 4096;
+
+/// Messages a mailbox holds before `send` fails
+pub const MAILBOX_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Bigger than `MAX_MESSAGE_SIZE` (EINVAL for userspace)
+    TooLarge,
+    /// The receiver's mailbox is full (EAGAIN for userspace)
+    Full,
+    /// There is no task with that ID (ESRCH for userspace)
+    NoSuchTask,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// ID of the task that sent it
+    pub sender: u64,
+    pub data: Vec<u8>,
+}
+
+/// The messages sent to one task, oldest first
+pub struct Mailbox {
+    queue: ArrayQueue<Message>,
+}
+
+impl Mailbox {
+    pub fn new() -> Self {
+        Self {
+            queue: ArrayQueue::new(MAILBOX_CAPACITY),
+        }
+    }
+
+    /// Queue a message, fails if it's too big or the mailbox is full
+    pub fn send(&self, message: Message) -> Result<(), Error> {
+        if message.data.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::TooLarge);
+        }
+
+        self.queue.push(message).map_err(|_| Error::Full)
+    }
+
+    /// Take the oldest message, None if there is none
+    pub fn recv(&self) -> Option<Message> {
+        self.queue.pop()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl Default for Mailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -5,22 +5,309 @@
 // - Task: Individual task/process representation
 // - Scheduler: Round-robin task scheduling
 
-use spin::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
 
-use crate::tasks::scheduler::Scheduler;
+use crossbeam_queue::ArrayQueue;
+use spin::{Lazy, Mutex};
+use x86_64::{VirtAddr, instructions::interrupts, structures::idt::PageFaultErrorCode};
 
+use crate::mm::{demand, memory, user::BuddyFrameAllocator};
+use crate::serial_println;
+use crate::tasks::task::BlockReason;
+use crate::time;
+
+pub mod abi;
 pub mod elf;
+pub mod id;
+pub mod ipc;
+pub mod ptrace;
 pub mod scheduler;
+pub mod stack;
 pub mod switch;
 pub mod syscall;
 pub mod task;
 
-/// Size of each task's kernel stack (1 page = 4KiB)  
-const KERNEL_STACK_SIZE: usize = 4096;
+pub use scheduler::Scheduler;
+pub use task::{Task, TaskContext};
+
+/// Default size of a task's kernel stack (8 pages = 32KiB)
+/// Kernel tasks run their whole life on this stack, so it can't be too small
+pub const DEFAULT_KERNEL_STACK_PAGES: usize = 8;
 
 /// Global scheduler instance
 pub static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler::new());
 
+/// ID of the task running on the CPU, 0 before the first task starts
+///
+/// Written by the context switch code (with interrupts disabled) right before it jumps to the new task,
+/// so anything that interrupts a task reads that task's ID. It doesn't need the scheduler lock,
+/// which makes it safe to use from interrupt handlers (page faults, timer...) that might interrupt
+/// code holding that lock. Once we have SMP this has to become per-CPU.
+static CURRENT_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Get the ID of the running task without locking the scheduler
+/// Returns None if no task has been started yet
+pub fn current_task_id() -> Option<u64> {
+    match CURRENT_TASK_ID.load(Ordering::Relaxed) {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+/// Record which task is about to run, called on every context switch
+fn set_current_task_id(id: u64) {
+    CURRENT_TASK_ID.store(id, Ordering::Relaxed);
+}
+
+/// Wake-ups requested by interrupt handlers that couldn't take the scheduler lock
+const PENDING_UNPARKS_SIZE: usize = 32;
+
+static PENDING_UNPARKS: Lazy<ArrayQueue<BlockReason>> =
+    Lazy::new(|| ArrayQueue::new(PENDING_UNPARKS_SIZE));
+
+/// Block the running kernel task until it's unparked with a matching reason
+///
+/// To not miss a wake-up, check the condition you're waiting for with interrupts disabled and call this
+/// before enabling them again, the interrupt that unparks us can then only arrive once we're blocked.
+/// Interrupts are enabled when this returns.
+///
+/// Only for kernel tasks, syscalls share a single kernel stack so a user task can't block inside one.
+pub fn park_on(reason: BlockReason) {
+    let Some(id) = current_task_id() else {
+        // Nothing to block during kernel init
+        interrupts::enable();
+        return;
+    };
+
+    interrupts::without_interrupts(|| SCHEDULER.lock().block_current(reason));
+
+    // The timer switches away from us on the next tick, we only get past this once we're unparked
+    loop {
+        interrupts::disable();
+        if !SCHEDULER.lock().is_blocked(id) {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Block the running kernel task until the tick counter reaches `until`
+///
+/// Before the first task runs (during kernel init) we halt until then instead. Either way interrupts
+/// have to be enabled, otherwise the ticks don't come. They are enabled when this returns.
+/// Only for kernel tasks, like `park_on`.
+pub fn sleep_until(until: u64) {
+    if time::ticks() >= until {
+        return;
+    }
+
+    if current_task_id().is_none() {
+        while time::ticks() < until {
+            interrupts::enable_and_hlt();
+        }
+        return;
+    }
+
+    park_on(BlockReason::Sleep(until));
+}
+
+/// Wake every task blocked for a reason matching `predicate`
+/// Returns the number of tasks that were woken up
+///
+/// Don't call this from interrupt handlers, the interrupted code might hold the scheduler lock.
+/// Use `unpark_from_interrupt` there.
+pub fn unpark(predicate: impl Fn(BlockReason) -> bool) -> usize {
+    interrupts::without_interrupts(|| SCHEDULER.lock().unpark(predicate))
+}
+
+/// Wake every task blocked for `reason`, safe to call from interrupt handlers
+///
+/// If the interrupted code holds the scheduler lock we can't take it (we would spin forever),
+/// so the wake-up is queued instead and the timer interrupt applies it on the next tick.
+pub fn unpark_from_interrupt(reason: BlockReason) {
+    if let Some(mut scheduler) = SCHEDULER.try_lock() {
+        scheduler.unpark(|r| r == reason);
+        return;
+    }
+
+    if PENDING_UNPARKS.push(reason).is_err() {
+        serial_println!("[WARNING] Pending unpark queue full, dropping {:?}", reason);
+    }
+}
+
+/// Stop the task with ID `id` until `continue_task` is called for it
+///
+/// If a kernel task stops itself, this waits until it's continued (like `park_on`, with interrupts enabled
+/// on return). User tasks stop themselves with the kill syscall, which switches away right after this
+/// returns (see `yield_from_syscall`).
+pub fn stop_task(id: u64) -> Result<(), scheduler::Error> {
+    let stopped_kernel_task = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let stopped_self = scheduler.stop(id)?;
+
+        // Kernel tasks never map user pages (and never make syscalls)
+        Ok(stopped_self
+            && scheduler
+                .task(id)
+                .is_some_and(|task| task.user_pages.is_empty()))
+    })?;
+
+    if stopped_kernel_task {
+        // The timer switches away from us on the next tick, we only get past this once we're continued
+        loop {
+            interrupts::disable();
+            if !SCHEDULER.lock().is_stopped(id) {
+                interrupts::enable();
+                break;
+            }
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    Ok(())
+}
+
+/// Kill the task with ID `id`, it's freed right away unless it's the running one
+///
+/// Only for kernel code, a user task ends itself with `exit_from_syscall`. A kernel task that kills itself
+/// never returns from this, the timer switches away on the next tick and the idle task or the event loop
+/// frees it.
+pub fn kill_task(id: u64) -> Result<(), scheduler::Error> {
+    let killed_self = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let killed_self = scheduler.kill_task(id)?;
+        if killed_self {
+            wake_reaper(&mut scheduler);
+        }
+        Ok(killed_self)
+    })?;
+
+    if killed_self {
+        loop {
+            interrupts::enable_and_hlt();
+        }
+    }
+
+    reap_exited();
+    Ok(())
+}
+
+/// Map the page the running task faulted on if it's demand paged, see `Task::handle_page_fault`
+///
+/// Only call this from the page fault handler for a fault in ring 3, userspace can't hold the scheduler lock.
+/// The faulting task's address space is the active one.
+pub fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), demand::Error> {
+    let mut scheduler = SCHEDULER.lock();
+    let task = scheduler
+        .current_task_mut()
+        .ok_or(demand::Error::NotDemandPaged)?;
+
+    let mut mapper = unsafe { memory::mapper_for(task.page_table) };
+    task.handle_page_fault(&mut mapper, &mut BuddyFrameAllocator, addr, error_code)?
+        .flush();
+
+    Ok(())
+}
+
+/// Exit code of a task killed by a fault
+const FAULT_EXIT_CODE: i32 = -1;
+
+/// Kill the running task after it faulted in user mode, and never return to it
+///
+/// Only call this from an exception handler for a fault in ring 3: we're on the task's own kernel stack,
+/// and userspace can't hold the scheduler lock. The timer switches to another task on the next tick.
+/// We can't free the stack we're running on, the idle task or the event loop frees the task afterwards.
+pub fn exit_from_fault() -> ! {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.exit_current(FAULT_EXIT_CODE);
+        wake_reaper(&mut scheduler);
+    }
+
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// End the running user task with `code` from a syscall, and never return to it
+///
+/// Syscalls have their own stack, so unlike `exit_from_fault` the task is freed right away.
+/// The timer switches to another task on the next tick, if this was the last one we halt.
+pub fn exit_from_syscall(code: i32) -> ! {
+    // The timer must not switch away before the task is freed, we would never come back to finish
+    interrupts::disable();
+
+    let (id, remaining) = {
+        let mut scheduler = SCHEDULER.lock();
+        let id = scheduler.current_task_id();
+        scheduler.exit_current(code);
+        (id, scheduler.task_count())
+    };
+    reap_exited();
+
+    if let Some(id) = id {
+        serial_println!("[kernel] Task {} exited with code {}", id, code);
+    }
+
+    if remaining == 0 {
+        serial_println!("[kernel] Last task exited, halting");
+        interrupts::enable();
+        crate::hlt_loop();
+    }
+
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Free the tasks that exited
+///
+/// Don't call this from interrupt handlers (dropping a task takes the allocator and SHM locks),
+/// or from the kernel stack of a task that exited.
+pub fn reap_exited() {
+    interrupts::without_interrupts(|| {
+        let exited = SCHEDULER.lock().take_exited();
+        drop(exited);
+    });
+}
+
+/// Wake the event loop so it frees the tasks that exited on their own kernel stack
+/// The idle task frees them too, but it doesn't get to run while other tasks keep the CPU busy.
+fn wake_reaper(scheduler: &mut Scheduler) {
+    scheduler.unpark(|reason| reason == BlockReason::Events);
+}
+
+/// The idle task, the scheduler runs it when every other task is blocked or stopped
+/// Frees the tasks that exited without being freed (faulted or killed themselves), then halts until
+/// the next interrupt, the timer switches to a task as soon as one can run again.
+pub extern "C" fn idle_loop() -> ! {
+    loop {
+        reap_exited();
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Let a task stopped with `stop_task` run again
+pub fn continue_task(id: u64) -> Result<(), scheduler::Error> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().resume(id))
+}
+
+/// Set the priority of the task with ID `id`, see `SchedPolicy::Priority`
+pub fn set_priority(id: u64, priority: u8) -> Result<(), scheduler::Error> {
+    interrupts::without_interrupts(|| SCHEDULER.lock().set_priority(id, priority))
+}
+
+/// Apply the wake-ups queued by `unpark_from_interrupt`, called by the timer interrupt
+fn apply_pending_unparks(scheduler: &mut Scheduler) {
+    while let Some(reason) = PENDING_UNPARKS.pop() {
+        scheduler.unpark(|r| r == reason);
+    }
+}
+
 pub fn init() {
     syscall::init_syscalls();
 }
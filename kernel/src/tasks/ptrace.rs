@@ -0,0 +1,104 @@
+// Process inspection
+//
+// A minimal ptrace: read and write another task's memory and read its registers.
+// The target must not be running, otherwise its memory and saved registers could change under us.
+
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        PageTableFlags, Translate,
+        mapper::{MappedFrame, TranslateResult},
+    },
+};
+
+use crate::{
+    mm::{memory, with_address_space},
+    tasks::{
+        SCHEDULER,
+        abi::{EBUSY, EIO, ESRCH},
+        scheduler::Scheduler,
+        task::{Task, TaskContext, TaskState},
+    },
+};
+
+/// Request numbers, same as Linux
+pub const PTRACE_PEEKDATA: u64 = 2;
+pub const PTRACE_POKEDATA: u64 = 5;
+pub const PTRACE_GETREGS: u64 = 12;
+
+/// Find a task that can be inspected
+///
+/// Fails with ESRCH if there is no such task and EBUSY if it's running (that's the caller itself).
+// TODO: check that the caller is allowed to inspect the target, for now everyone may inspect everyone
+pub fn target(scheduler: &Scheduler, pid: u64) -> Result<&Task, i64> {
+    let task = scheduler.task(pid).ok_or(ESRCH)?;
+    if task.state == TaskState::Running {
+        return Err(EBUSY);
+    }
+    Ok(task)
+}
+
+/// Kernel pointer to a byte of user memory in the active address space, None if it isn't user accessible
+/// Goes through the physical memory mapping, so read-only pages (e.g. code for breakpoints) can be written.
+fn user_byte(mapper: &impl Translate, addr: u64) -> Option<*mut u8> {
+    let addr = VirtAddr::try_new(addr).ok()?;
+
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            offset,
+            flags,
+        } if flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+            let phys = frame.start_address() + offset;
+            Some((memory::physical_memory_offset() + phys.as_u64()).as_mut_ptr())
+        }
+        _ => None,
+    }
+}
+
+/// Read the word at `addr` in task `pid`'s memory
+pub fn peek(pid: u64, addr: u64) -> Result<u64, i64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let task = target(&scheduler, pid)?;
+
+        with_address_space(task, |mapper| {
+            let mut bytes = [0u8; 8];
+            // Byte by byte, the word may cross into another page
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let ptr = user_byte(mapper, addr.wrapping_add(i as u64)).ok_or(EIO)?;
+                *byte = unsafe { ptr.read_volatile() };
+            }
+            Ok(u64::from_ne_bytes(bytes))
+        })
+    })
+}
+
+/// Write `value` to the word at `addr` in task `pid`'s memory
+/// Nothing is written if part of the word isn't mapped.
+pub fn poke(pid: u64, addr: u64, value: u64) -> Result<(), i64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let task = target(&scheduler, pid)?;
+
+        with_address_space(task, |mapper| {
+            let mut ptrs = [core::ptr::null_mut(); 8];
+            for (i, ptr) in ptrs.iter_mut().enumerate() {
+                *ptr = user_byte(mapper, addr.wrapping_add(i as u64)).ok_or(EIO)?;
+            }
+
+            for (ptr, byte) in ptrs.iter().zip(value.to_ne_bytes()) {
+                unsafe { ptr.write_volatile(byte) };
+            }
+            Ok(())
+        })
+    })
+}
+
+/// Get the registers task `pid` saved when it was switched out
+pub fn getregs(pid: u64) -> Result<TaskContext, i64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        target(&scheduler, pid).map(|task| task.context)
+    })
+}
@@ -1,12 +1,78 @@
-use crate::tasks::task::{Task, TaskContext, TaskState};
+use crate::tasks::ipc::{self, Message};
+use crate::tasks::task::{BlockReason, Task, TaskContext, TaskState};
+use alloc::collections::VecDeque;
 use alloc::vec::Vec;
+use x86_64::structures::paging::PhysFrame;
 
-/// Simple round-robin scheduler
-// TODO: More advanced scheduling algorithms, task sleeping/waking, inter-task communication, etc.
+/// Default limit on the number of tasks, every task owns a kernel stack so we can't have infinitely many
+pub const DEFAULT_MAX_TASKS: usize = 64;
+
+/// Default number of timer ticks a task runs before the next one gets the CPU
+pub const DEFAULT_TIME_SLICE: u64 = 10;
+
+/// Exit code of a task removed with `Scheduler::kill_task`, like SIGKILL
+pub const KILL_EXIT_CODE: i32 = -9;
+
+/// Number of exit codes `Scheduler::exit_status` remembers
+pub const RECENT_EXITS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The scheduler already holds `max_tasks` tasks (EAGAIN for userspace)
+    TooManyTasks,
+    /// There is no task with that ID (ESRCH for userspace)
+    NoSuchTask,
+    /// The task is the only one left, something has to run
+    LastTask,
+}
+
+/// What `Scheduler::snapshot` reports about a task (like a line of `ps`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub state: TaskState,
+    pub resident_pages: usize,
+}
+
+/// How `Scheduler::schedule` picks the next task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SchedPolicy {
+    /// Every runnable task gets a turn in order
+    #[default]
+    RoundRobin,
+    /// The runnable task with the highest priority runs, tasks with the same priority take turns
+    /// Lower priority tasks only run while every higher priority task is blocked or stopped.
+    Priority,
+}
+
+/// How much CPU time a task got so far, see `Scheduler::task_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Number of times the task was switched to
+    pub run_count: u64,
+    /// Timer ticks the task was running for
+    pub total_ticks: u64,
+}
+
+/// Round-robin scheduler, optionally by priority (see `SchedPolicy`)
 pub struct Scheduler {
     tasks: Vec<Task>,
     current: usize,
+    /// The running task exited, it's gone from `tasks` and `current` is the task to try next
+    current_exited: bool,
+    /// Tasks that exited, freed by `tasks::reap_exited` once nothing runs on their kernel stack anymore
+    exited: Vec<Task>,
+    /// ID and exit code of the last `RECENT_EXITS` tasks that exited, oldest first
+    recent_exits: VecDeque<(u64, i32)>,
     initialized: bool,
+    max_tasks: usize,
+    /// Ticks a task runs for before it's switched out
+    time_slice: u64,
+    /// Ticks left in the running task's time slice
+    ticks_remaining: u64,
+    /// ID of the task that runs when no other task can
+    idle: Option<u64>,
+    policy: SchedPolicy,
 }
 
 impl Scheduler {
@@ -14,13 +80,105 @@ impl Scheduler {
         Self {
             tasks: Vec::new(),
             current: 0,
+            current_exited: false,
+            exited: Vec::new(),
+            recent_exits: VecDeque::new(),
             initialized: false,
+            max_tasks: DEFAULT_MAX_TASKS,
+            time_slice: DEFAULT_TIME_SLICE,
+            ticks_remaining: DEFAULT_TIME_SLICE,
+            idle: None,
+            policy: SchedPolicy::RoundRobin,
         }
     }
 
     /// Add a task to the scheduler
-    pub fn add_task(&mut self, task: Task) {
+    /// Fails if the scheduler is already at its task limit, the task is dropped in that case
+    pub fn add_task(&mut self, task: Task) -> Result<(), Error> {
+        if self.tasks.len() >= self.max_tasks {
+            return Err(Error::TooManyTasks);
+        }
+
         self.tasks.push(task);
+        Ok(())
+    }
+
+    /// Add the task that runs when every other task is blocked or stopped
+    /// The round-robin skips it as long as there is something else to run.
+    pub fn set_idle_task(&mut self, task: Task) -> Result<(), Error> {
+        let id = task.id;
+        self.add_task(task)?;
+        self.idle = Some(id);
+        Ok(())
+    }
+
+    /// Get the maximum number of tasks
+    pub fn max_tasks(&self) -> usize {
+        self.max_tasks
+    }
+
+    /// Set the maximum number of tasks
+    /// Tasks that are already running are kept even if there are more of them than the new limit
+    pub fn set_max_tasks(&mut self, max_tasks: usize) {
+        self.max_tasks = max_tasks;
+    }
+
+    /// Get the number of ticks a task runs before it's switched out
+    pub fn time_slice(&self) -> u64 {
+        self.time_slice
+    }
+
+    /// Set the number of ticks a task runs before it's switched out (at least 1)
+    /// The running task starts a new time slice of that length.
+    pub fn set_time_slice(&mut self, ticks: u64) {
+        self.time_slice = ticks.max(1);
+        self.ticks_remaining = self.time_slice;
+    }
+
+    /// Get how the next task is picked
+    pub fn policy(&self) -> SchedPolicy {
+        self.policy
+    }
+
+    /// Set how the next task is picked, takes effect on the next `schedule`
+    pub fn set_policy(&mut self, policy: SchedPolicy) {
+        self.policy = policy;
+    }
+
+    /// Set the priority of the task with ID `id`, only used with `SchedPolicy::Priority`
+    pub fn set_priority(&mut self, id: u64, priority: u8) -> Result<(), Error> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.id == id)
+            .ok_or(Error::NoSuchTask)?;
+        task.priority = priority;
+        Ok(())
+    }
+
+    /// Count a timer tick against the running task's time slice
+    /// Returns true if it's time to `schedule`: the slice ran out, or the task can't run anymore.
+    /// The idle task is switched out on every tick, in case a task woke up.
+    pub fn tick(&mut self) -> bool {
+        // The tick was spent in whatever was running, even if it can't go on
+        if let Some(index) = self.running() {
+            self.tasks[index].total_ticks += 1;
+        }
+
+        if self
+            .running()
+            .is_none_or(|index| !self.tasks[index].state.is_runnable() || self.is_idle(index))
+        {
+            return true;
+        }
+
+        self.ticks_remaining = self.ticks_remaining.saturating_sub(1);
+        if self.ticks_remaining > 0 {
+            return false;
+        }
+
+        self.ticks_remaining = self.time_slice;
+        true
     }
 
     /// Get the number of tasks
@@ -28,10 +186,41 @@ impl Scheduler {
         self.tasks.len()
     }
 
+    /// Get all tasks
+    pub fn tasks(&self) -> &[Task] {
+        &self.tasks
+    }
+
+    /// Get the task with the given ID
+    pub fn task(&self, id: u64) -> Option<&Task> {
+        self.tasks.iter().find(|task| task.id == id)
+    }
+
+    /// Get how often the task with the given ID ran and for how long
+    pub fn task_stats(&self, id: u64) -> Option<TaskStats> {
+        self.task(id).map(|task| TaskStats {
+            run_count: task.run_count,
+            total_ticks: task.total_ticks,
+        })
+    }
+
+    /// Get a copy of the interesting bits of every task
+    pub fn snapshot(&self) -> Vec<TaskInfo> {
+        self.tasks
+            .iter()
+            .map(|task| TaskInfo {
+                id: task.id,
+                state: task.state,
+                resident_pages: task.resident_pages(),
+            })
+            .collect()
+    }
+
     /// Mark scheduler as initialized and set first task as running
     pub fn start(&mut self) {
         if !self.tasks.is_empty() {
             self.tasks[0].state = TaskState::Running;
+            self.tasks[0].run_count += 1;
             self.initialized = true;
         }
     }
@@ -41,58 +230,310 @@ impl Scheduler {
         self.initialized
     }
 
-    /// Get the current task's context (for initial switch)
-    pub fn current_context(&self) -> Option<&TaskContext> {
-        if self.tasks.is_empty() {
+    /// Index of the running task, None if there is none or it exited
+    fn running(&self) -> Option<usize> {
+        if self.current_exited || self.current >= self.tasks.len() {
             None
         } else {
-            Some(&self.tasks[self.current].context)
+            Some(self.current)
         }
     }
 
+    /// Get the current task's context (for initial switch)
+    pub fn current_context(&self) -> Option<&TaskContext> {
+        self.running().map(|index| &self.tasks[index].context)
+    }
+
     /// Get mutable reference to current task's context
     pub fn current_context_mut(&mut self) -> Option<&mut TaskContext> {
-        if self.tasks.is_empty() {
-            None
-        } else {
-            Some(&mut self.tasks[self.current].context)
-        }
+        self.running().map(|index| &mut self.tasks[index].context)
     }
 
     /// Get current task's kernel stack top (for TSS RSP0)
     pub fn current_kernel_stack_top(&self) -> Option<u64> {
-        if self.tasks.is_empty() {
-            None
-        } else {
-            Some(self.tasks[self.current].kernel_stack_top())
+        self.running()
+            .map(|index| self.tasks[index].kernel_stack_top())
+    }
+
+    /// Get the level 4 table of the running task's address space
+    pub fn current_page_table(&self) -> Option<PhysFrame> {
+        self.running().map(|index| self.tasks[index].page_table)
+    }
+
+    /// Get a mutable reference to the running task
+    pub fn current_task_mut(&mut self) -> Option<&mut Task> {
+        self.running().map(|index| &mut self.tasks[index])
+    }
+
+    /// Block the running task, it won't be scheduled again until it's unparked
+    /// It keeps running until the next switch, see `tasks::park_on`
+    pub fn block_current(&mut self, reason: BlockReason) {
+        if let Some(task) = self.current_task_mut() {
+            task.state = TaskState::Blocked(reason);
         }
     }
 
+    /// Block the running task until the tick counter reaches `until`, see `wake_sleepers`
+    pub fn sleep_current(&mut self, until: u64) {
+        self.block_current(BlockReason::Sleep(until));
+    }
+
+    /// Wake the tasks that sleep until `now` or earlier, called on every timer tick
+    /// Returns the number of tasks that were woken up
+    pub fn wake_sleepers(&mut self, now: u64) -> usize {
+        self.unpark(|reason| matches!(reason, BlockReason::Sleep(until) if until <= now))
+    }
+
+    /// Make every task blocked for a reason matching `predicate` ready again
+    /// Returns the number of tasks that were woken up
+    pub fn unpark(&mut self, predicate: impl Fn(BlockReason) -> bool) -> usize {
+        let mut count = 0;
+
+        for task in &mut self.tasks {
+            if let TaskState::Blocked(reason) = task.state
+                && predicate(reason)
+            {
+                task.state = TaskState::Ready;
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Put a message in the mailbox of the task with ID `to`, wakes it if it waits for one
+    pub fn send_message(&mut self, to: u64, message: Message) -> Result<(), ipc::Error> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.id == to)
+            .ok_or(ipc::Error::NoSuchTask)?;
+        task.mailbox.send(message)?;
+
+        if task.state == TaskState::Blocked(BlockReason::Message) {
+            task.state = TaskState::Ready;
+        }
+        Ok(())
+    }
+
+    /// Take the oldest message from the running task's mailbox
+    /// If it's empty the task blocks until `send_message` gives it one, and None is returned.
+    pub fn recv_or_block(&mut self) -> Option<Message> {
+        let task = self.current_task_mut()?;
+        let message = task.mailbox.recv();
+        if message.is_none() {
+            task.state = TaskState::Blocked(BlockReason::Message);
+        }
+        message
+    }
+
+    /// Remove the running task from the scheduler, it's never scheduled again
+    ///
+    /// It keeps running until the next switch (see `tasks::exit_from_syscall`), the next switch doesn't
+    /// save its context. The task is kept in the exited list until `take_exited` hands it out to be freed.
+    pub fn exit_current(&mut self, code: i32) {
+        let Some(index) = self.running() else {
+            return;
+        };
+
+        let task = self.detach(index);
+        self.retire(task, code);
+    }
+
+    /// Remove the task with ID `id` from the scheduler and hand it out
+    /// None if there is no such task or it's the last one.
+    ///
+    /// Removing the running task works like `exit_current`: it keeps running until the next switch, which
+    /// doesn't save its context, so don't drop it before then (it may be running on its kernel stack).
+    pub fn remove_task(&mut self, id: u64) -> Option<Task> {
+        let index = self.tasks.iter().position(|task| task.id == id)?;
+        if self.tasks.len() == 1 {
+            return None;
+        }
+
+        if self.idle == Some(id) {
+            self.idle = None;
+        }
+        Some(self.detach(index))
+    }
+
+    /// Kill the task with ID `id`, it exits with `KILL_EXIT_CODE` and is freed by `tasks::reap_exited`
+    /// Returns true if it's the running task, the caller should switch away from it.
+    pub fn kill_task(&mut self, id: u64) -> Result<bool, Error> {
+        if self.task(id).is_none() {
+            return Err(Error::NoSuchTask);
+        }
+        let running = self.current_task_id() == Some(id);
+
+        let task = self.remove_task(id).ok_or(Error::LastTask)?;
+        self.retire(task, KILL_EXIT_CODE);
+        Ok(running)
+    }
+
+    /// Mark a detached task as exited with `code` and keep it until it's freed
+    fn retire(&mut self, mut task: Task, code: i32) {
+        task.state = TaskState::Exited(code);
+        if self.recent_exits.len() == RECENT_EXITS {
+            self.recent_exits.pop_front();
+        }
+        self.recent_exits.push_back((task.id, code));
+        self.exited.push(task);
+    }
+
+    /// Exit code of the task with ID `id`, if it's one of the last `RECENT_EXITS` tasks that exited
+    /// Still there after the task was freed, but IDs are reused so only ask right after it exits.
+    pub fn exit_status(&self, id: u64) -> Option<i32> {
+        self.recent_exits
+            .iter()
+            .rev()
+            .find(|&&(exited, _)| exited == id)
+            .map(|&(_, code)| code)
+    }
+
+    /// Take the task at `index` out of `tasks`, keeping `current` on the same task
+    /// If it's the running task, `current` becomes the task to try next and the next switch doesn't save it.
+    fn detach(&mut self, index: usize) -> Task {
+        let running = self.running() == Some(index);
+        let task = self.tasks.remove(index);
+
+        if index < self.current {
+            self.current -= 1;
+        }
+        // The task after the removed one moved into its slot
+        if self.current >= self.tasks.len() {
+            self.current = 0;
+        }
+        if running {
+            self.current_exited = true;
+        }
+
+        task
+    }
+
+    /// Take the tasks that exited so they can be freed
+    ///
+    /// Don't drop them while one of them may still be running on its kernel stack, a task that exited from
+    /// an exception handler runs on it until the next switch.
+    pub fn take_exited(&mut self) -> Vec<Task> {
+        core::mem::take(&mut self.exited)
+    }
+
+    /// Stop a task, it isn't scheduled again until it's continued
+    /// A blocked task forgets what it was waiting for, it has to check again once it's continued.
+    /// Returns true if the task is the running one, the caller should switch away from it.
+    pub fn stop(&mut self, id: u64) -> Result<bool, Error> {
+        let index = self
+            .tasks
+            .iter()
+            .position(|task| task.id == id)
+            .ok_or(Error::NoSuchTask)?;
+
+        self.tasks[index].state = TaskState::Stopped;
+        Ok(self.running() == Some(index))
+    }
+
+    /// Let a stopped task run again, tasks that aren't stopped are left alone
+    pub fn resume(&mut self, id: u64) -> Result<(), Error> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.id == id)
+            .ok_or(Error::NoSuchTask)?;
+
+        if task.state == TaskState::Stopped {
+            task.state = TaskState::Ready;
+        }
+        Ok(())
+    }
+
+    /// Check if the task with the given ID is stopped
+    pub fn is_stopped(&self, id: u64) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task.id == id && task.state == TaskState::Stopped)
+    }
+
+    /// Check if the task with the given ID is blocked
+    pub fn is_blocked(&self, id: u64) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task.id == id && matches!(task.state, TaskState::Blocked(_)))
+    }
+
+    fn is_idle(&self, index: usize) -> bool {
+        self.idle == Some(self.tasks[index].id)
+    }
+
     /// Get current task ID
+    /// None if the running task exited
     pub fn current_task_id(&self) -> Option<u64> {
-        if self.tasks.is_empty() {
-            None
-        } else {
-            Some(self.tasks[self.current].id)
-        }
+        self.running().map(|index| self.tasks[index].id)
     }
 
-    /// Schedule the next task (round-robin)
-    /// Returns (old_context_ptr, new_context_ptr, new_kernel_stack_top)
+    /// Schedule the next task (round-robin or by priority), blocked and stopped tasks are skipped
+    /// Returns (old_context_ptr, new_context_ptr, new_kernel_stack_top), old_context_ptr is null if the
+    /// running task exited (there is nothing to save it to)
+    /// If nothing else can run, the current task keeps running if it can and the idle task runs otherwise.
+    /// Returns None if there is nothing to switch to, the current task keeps running then (even if it's blocked)
     pub fn schedule(&mut self) -> Option<(*mut TaskContext, *const TaskContext, u64)> {
-        if self.tasks.len() < 2 {
+        // After an exit `current` already is the next task to try, otherwise we switch away from it
+        let first = if self.current_exited { 0 } else { 1 };
+        if self.tasks.len() <= first {
             return None; // Nothing to switch to
         }
 
-        // Save current task as Ready
-        self.tasks[self.current].state = TaskState::Ready;
-        let old_context = &mut self.tasks[self.current].context as *mut TaskContext;
+        // Find the next task that isn't blocked or stopped
+        let count = self.tasks.len();
+        let next = match self.policy {
+            SchedPolicy::RoundRobin => (first..count)
+                .map(|offset| (self.current + offset) % count)
+                .find(|&index| self.tasks[index].state.is_runnable() && !self.is_idle(index)),
+            // The running task is a candidate too (last, so its equals go first), `min_by_key`
+            // keeps the first of the highest priority tasks
+            SchedPolicy::Priority => (first..count + first)
+                .map(|offset| (self.current + offset) % count)
+                .filter(|&index| self.tasks[index].state.is_runnable() && !self.is_idle(index))
+                .min_by_key(|&index| core::cmp::Reverse(self.tasks[index].priority)),
+        };
+
+        // Nothing outranks the running task, it keeps the CPU
+        if next.is_some() && next == self.running() {
+            return None;
+        }
+
+        let next = match next {
+            Some(next) => next,
+            None if self
+                .running()
+                .is_some_and(|index| self.tasks[index].state.is_runnable()) =>
+            {
+                return None;
+            }
+            None => (0..count).find(|&index| {
+                self.is_idle(index)
+                    && Some(index) != self.running()
+                    && self.tasks[index].state.is_runnable()
+            })?,
+        };
+
+        let old_context = if self.current_exited {
+            self.current_exited = false;
+            core::ptr::null_mut()
+        } else {
+            // Save current task as Ready, unless it blocked or stopped itself
+            if self.tasks[self.current].state == TaskState::Running {
+                self.tasks[self.current].state = TaskState::Ready;
+            }
+            &mut self.tasks[self.current].context as *mut TaskContext
+        };
 
-        // Move to next task (round-robin)
-        self.current = (self.current + 1) % self.tasks.len();
+        // Move to next task (round-robin), with a full time slice
+        self.current = next;
+        self.ticks_remaining = self.time_slice;
 
         // Mark new task as Running
         self.tasks[self.current].state = TaskState::Running;
+        self.tasks[self.current].run_count += 1;
         let new_context = &self.tasks[self.current].context as *const TaskContext;
         let new_kernel_stack = self.tasks[self.current].kernel_stack_top();
 
@@ -0,0 +1,302 @@
+// Kernel stacks
+//
+// Every task has a kernel stack for the interrupts it takes, kernel tasks run on it all the time.
+// Stacks live in a virtual range of their own: one higher half level 4 entry, split in slots of
+// SLOT_PAGES pages. A stack is mapped at the top of its slot and the rest of the slot stays
+// unmapped, so there's at least one guard page below every stack and an overflow page faults
+// instead of silently corrupting whatever is below it.
+//
+// The level 4 entry is set up by `init` before any address space is created, every address space
+// shares the kernel's half, so stacks mapped later show up in all of them.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::{interrupts, tlb},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+};
+
+use crate::mm::{memory, user::BuddyFrameAllocator};
+
+const PAGE_SIZE: usize = 4096;
+
+/// Pages in a slot, a stack can have all of them but the guard page
+pub const SLOT_PAGES: usize = 64;
+
+/// Bytes covered by one level 4 entry
+const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+/// Level 4 entries from here on are the kernel's half
+const KERNEL_HALF: usize = 256;
+
+/// The kernel's stack area, empty until `init`
+static KERNEL_STACKS: Mutex<StackArea> = Mutex::new(StackArea::empty());
+
+/// Where the kernel's stack area starts and ends, so dropping a stack can tell without the lock
+static KERNEL_STACKS_START: AtomicU64 = AtomicU64::new(0);
+static KERNEL_STACKS_END: AtomicU64 = AtomicU64::new(0);
+
+/// Reserve the kernel's stack area in the kernel's page table
+/// Must run before any task (and its address space) is created.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let phys_mem_offset = memory::physical_memory_offset();
+    let area = unsafe { StackArea::reserve(mapper, frame_allocator, phys_mem_offset) }?;
+
+    KERNEL_STACKS_START.store(area.start, Ordering::Relaxed);
+    KERNEL_STACKS_END.store(area.end(), Ordering::Relaxed);
+    interrupts::without_interrupts(|| *KERNEL_STACKS.lock() = area);
+    Ok(())
+}
+
+/// A range of slots for kernel stacks, each slot is used by one stack at a time
+pub struct StackArea {
+    start: u64,
+    slots: usize,
+    /// Slots below this have been handed out before
+    next: usize,
+    /// Slots that were handed out and given back
+    free: Vec<usize>,
+}
+
+impl StackArea {
+    /// An area without any slots
+    pub const fn empty() -> Self {
+        Self {
+            start: 0,
+            slots: 0,
+            next: 0,
+            free: Vec::new(),
+        }
+    }
+
+    /// Take an unused higher half level 4 entry of `mapper` for kernel stacks
+    /// The entry gets an empty level 3 table, so address spaces copied from `mapper`'s table
+    /// afterwards share it.
+    ///
+    /// # Safety
+    /// The complete physical memory must be mapped at `phys_mem_offset`.
+    pub unsafe fn reserve(
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        phys_mem_offset: VirtAddr,
+    ) -> Result<Self, &'static str> {
+        let level_4_table = mapper.level_4_table_mut();
+        let index = (KERNEL_HALF..512)
+            .find(|&index| level_4_table[index].is_unused())
+            .ok_or("No free level 4 entry for the kernel stacks")?;
+
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or("Failed to allocate page table")?;
+        let table: *mut PageTable = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { *table = PageTable::new() };
+        level_4_table[index].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+        let start = VirtAddr::new_truncate(index as u64 * LEVEL_4_ENTRY_SIZE);
+        let slots = (LEVEL_4_ENTRY_SIZE / (SLOT_PAGES * PAGE_SIZE) as u64) as usize;
+        Ok(Self {
+            start: start.as_u64(),
+            slots,
+            next: 0,
+            free: Vec::new(),
+        })
+    }
+
+    /// Check if `addr` is in one of the area's slots
+    pub fn contains(&self, addr: u64) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    fn end(&self) -> u64 {
+        self.start + (self.slots * SLOT_PAGES * PAGE_SIZE) as u64
+    }
+
+    /// Map a zeroed stack of `pages` pages at the top of a free slot
+    /// Fails if `pages` is 0 or doesn't leave room for the guard page, or we run out of memory.
+    ///
+    /// # Safety
+    /// `mapper` must be the table the area was reserved in, with the complete physical memory
+    /// mapped at `phys_mem_offset`.
+    pub unsafe fn map_stack(
+        &mut self,
+        pages: usize,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+        phys_mem_offset: VirtAddr,
+    ) -> Result<KernelStack, &'static str> {
+        if pages == 0 || pages >= SLOT_PAGES {
+            return Err("Kernel stack size out of range");
+        }
+
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None if self.next < self.slots => {
+                self.next += 1;
+                self.next - 1
+            }
+            None => return Err("Out of kernel stack slots"),
+        };
+
+        let slot_start = self.start + (slot * SLOT_PAGES * PAGE_SIZE) as u64;
+        let base = slot_start + ((SLOT_PAGES - pages) * PAGE_SIZE) as u64;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for (mapped, page) in page_range(base, pages).enumerate() {
+            if let Err(e) =
+                unsafe { map_zeroed(page, flags, mapper, frame_allocator, phys_mem_offset) }
+            {
+                unsafe { unmap_pages(page_range(base, mapped), mapper, frame_allocator) };
+                self.free.push(slot);
+                return Err(e);
+            }
+        }
+
+        Ok(KernelStack { base, pages })
+    }
+
+    /// Unmap `stack`, give its frames back and free its slot
+    /// Doesn't flush the TLB, the caller has to if the table is active.
+    ///
+    /// # Safety
+    /// `stack` must come from `map_stack` on this area with the same `mapper`, and nothing may
+    /// run on it anymore.
+    pub unsafe fn unmap_stack(
+        &mut self,
+        stack: &KernelStack,
+        mapper: &mut OffsetPageTable,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        unsafe { unmap_pages(stack.page_range(), mapper, frame_deallocator) };
+        self.free
+            .push((stack.bottom() - self.start) as usize / (SLOT_PAGES * PAGE_SIZE));
+    }
+}
+
+/// The `pages` pages from `base` up
+fn page_range(base: u64, pages: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    let bottom = Page::containing_address(VirtAddr::new(base));
+    (0..pages as u64).map(move |i| bottom + i)
+}
+
+/// Map `page` to a new zeroed frame, nothing is left allocated if this fails
+unsafe fn map_zeroed(
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+    phys_mem_offset: VirtAddr,
+) -> Result<(), &'static str> {
+    let frame: PhysFrame = frame_allocator
+        .allocate_frame()
+        .ok_or("Failed to allocate kernel stack")?;
+    let memory: *mut u8 = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+    unsafe { memory.write_bytes(0, PAGE_SIZE) };
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        // The page wasn't mapped, so the TLB can't have an entry for it
+        Ok(flush) => {
+            flush.ignore();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err("Failed to map kernel stack")
+        }
+    }
+}
+
+/// Unmap `pages` and give their frames back, without flushing the TLB
+unsafe fn unmap_pages(
+    pages: impl Iterator<Item = Page<Size4KiB>>,
+    mapper: &mut OffsetPageTable,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.ignore();
+            unsafe { frame_deallocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+pub struct KernelStack {
+    base: u64,
+    pages: usize,
+}
+
+impl KernelStack {
+    /// Map a zeroed stack of `pages` pages in the kernel's stack area, None if `pages` is 0 or
+    /// too big, we're out of memory or the area isn't set up
+    pub fn new(pages: usize) -> Option<Self> {
+        interrupts::without_interrupts(|| {
+            let mut area = KERNEL_STACKS.lock();
+            if area.slots == 0 {
+                return None;
+            }
+
+            let mut mapper = unsafe { memory::mapper_for(memory::kernel_page_table()) };
+            unsafe {
+                area.map_stack(
+                    pages,
+                    &mut mapper,
+                    &mut BuddyFrameAllocator,
+                    memory::physical_memory_offset(),
+                )
+            }
+            .ok()
+        })
+    }
+
+    /// Size of the stack in pages
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Lowest address of the stack
+    pub fn bottom(&self) -> u64 {
+        self.base
+    }
+
+    /// Address right after the stack (stacks grow down), page aligned
+    pub fn top(&self) -> u64 {
+        self.bottom() + (self.pages * PAGE_SIZE) as u64
+    }
+
+    /// The unmapped page right below the stack
+    pub fn guard_page(&self) -> Page<Size4KiB> {
+        Page::containing_address(VirtAddr::new(self.bottom() - PAGE_SIZE as u64))
+    }
+
+    fn page_range(&self) -> impl Iterator<Item = Page<Size4KiB>> + use<> {
+        page_range(self.base, self.pages)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        // Stacks mapped in some other table aren't ours to free
+        let kernel_stacks =
+            KERNEL_STACKS_START.load(Ordering::Relaxed)..KERNEL_STACKS_END.load(Ordering::Relaxed);
+        if !kernel_stacks.contains(&self.bottom()) {
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut area = KERNEL_STACKS.lock();
+            let mut mapper = unsafe { memory::mapper_for(memory::kernel_page_table()) };
+            unsafe { area.unmap_stack(self, &mut mapper, &mut BuddyFrameAllocator) };
+            for page in self.page_range() {
+                tlb::flush(page.start_address());
+            }
+        });
+    }
+}
@@ -6,8 +6,16 @@
 use core::arch::asm;
 
 use crate::drivers::apic::end_interrupt;
-use crate::serial_print;
-use crate::tasks::{SCHEDULER, task::TaskContext};
+use crate::gdt::GDT;
+use crate::mm::address_space;
+use crate::tasks::{
+    SCHEDULER, abi, apply_pending_unparks,
+    scheduler::Scheduler,
+    set_current_task_id,
+    syscall::{self, Syscall},
+    task::TaskContext,
+};
+use crate::{serial_print, serial_println, time};
 
 /// Pointer to where we should store the current RSP0 value for TSS updates
 /// This is set by the GDT module to point to the TSS's RSP0 field
@@ -19,6 +27,8 @@ pub static mut TSS_RSP0_PTR: *mut u64 = core::ptr::null_mut();
 pub extern "C" fn timer_tick(context_ptr: *mut TaskContext) {
     let context = unsafe { &mut *context_ptr };
 
+    time::tick();
+
     // Check if we came from user mode
     let from_usermode = (context.cs & 3) == 3;
 
@@ -43,28 +53,124 @@ pub extern "C" fn timer_tick(context_ptr: *mut TaskContext) {
         serial_print!("{}", task_id);
     }
 
-    // Try to schedule next task
-    if let Some((old_ctx, new_ctx, new_kernel_stack)) = scheduler.schedule() {
-        // Copy the current context to the old task
+    // Wake tasks that interrupt handlers unparked while we held the lock
+    apply_pending_unparks(&mut scheduler);
+    scheduler.wake_sleepers(time::ticks());
+
+    // Switch to the next task once this one's time slice is used up
+    if scheduler.tick() {
+        switch_context(&mut scheduler, context);
+        activate_address_space(&scheduler);
+    }
+
+    drop(scheduler); // prevent deadlock
+
+    // Acknowledge interrupt
+    end_interrupt();
+}
+
+/// Switch to the next task if there is one to switch to
+///
+/// `context` holds the registers of the running task and is restored when we return to it,
+/// so it's saved to the running task and overwritten with the next task's registers.
+pub fn switch_context(scheduler: &mut Scheduler, context: &mut TaskContext) {
+    let Some((old_ctx, new_ctx, new_kernel_stack)) = scheduler.schedule() else {
+        return;
+    };
+
+    if let Some(id) = scheduler.current_task_id() {
+        set_current_task_id(id);
+    }
+
+    // Copy the current context to the old task, unless it exited
+    if !old_ctx.is_null() {
         unsafe {
             *old_ctx = *context;
         }
+    }
 
-        // Load the new task's context
-        unsafe {
-            *context = *new_ctx;
+    // Load the new task's context
+    unsafe {
+        *context = *new_ctx;
+
+        // Update TSS RSP0 to point to the new task's kernel stack
+        if !TSS_RSP0_PTR.is_null() {
+            *TSS_RSP0_PTR = new_kernel_stack;
+        }
+    }
+}
+
+/// Length of the `syscall` instruction, to run it again after a blocking recv
+const SYSCALL_INSTRUCTION_LEN: u64 = 2;
+
+/// sched_yield, nanosleep, recv and kill, called by the syscall handler with the calling task's user registers
+/// rax holds the syscall number and gets the return value. The syscall handler doesn't know the user selectors.
+#[unsafe(no_mangle)]
+pub(crate) extern "C" fn yield_from_syscall(context_ptr: *mut TaskContext) {
+    let context = unsafe { &mut *context_ptr };
+    context.cs = (GDT.1.user_code.0 | 3) as u64;
+    context.ss = (GDT.1.user_data.0 | 3) as u64;
 
-            // Update TSS RSP0 to point to the new task's kernel stack
-            if !TSS_RSP0_PTR.is_null() {
-                *TSS_RSP0_PTR = new_kernel_stack;
+    if context.rax == Syscall::Recv as u64 {
+        match syscall::recv(context.rdi, context.rsi, true) {
+            // Blocked until a message arrives, the task makes the syscall again when it runs next
+            Err(abi::EAGAIN) => {
+                context.rip -= SYSCALL_INSTRUCTION_LEN;
+                let mut scheduler = SCHEDULER.lock();
+                if scheduler.is_initialized() {
+                    switch_context(&mut scheduler, context);
+                    activate_address_space(&scheduler);
+                }
             }
+            result => context.rax = abi::value(result),
         }
+        return;
     }
 
-    drop(scheduler); // prevent deadlock
+    if context.rax == Syscall::Kill as u64 {
+        context.rax = abi::result(syscall::kill(context.rdi, context.rsi));
 
-    // Acknowledge interrupt
-    end_interrupt();
+        // A task that stopped itself gives up the CPU now instead of at the next tick
+        let mut scheduler = SCHEDULER.lock();
+        let stopped_self = scheduler
+            .current_task_id()
+            .is_some_and(|id| scheduler.is_stopped(id));
+        if stopped_self && scheduler.is_initialized() {
+            switch_context(&mut scheduler, context);
+            activate_address_space(&scheduler);
+        }
+        return;
+    }
+
+    let sleep_ticks = if context.rax == Syscall::Nanosleep as u64 {
+        match syscall::sleep_ticks(context.rdi) {
+            Ok(ticks) => ticks,
+            Err(errno) => {
+                context.rax = abi::error(errno);
+                return;
+            }
+        }
+    } else {
+        0
+    };
+    context.rax = 0;
+
+    // Interrupts are masked during syscalls, so the timer can't hold the lock
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.is_initialized() {
+        if sleep_ticks > 0 {
+            scheduler.sleep_current(time::ticks().saturating_add(sleep_ticks));
+        }
+        switch_context(&mut scheduler, context);
+        activate_address_space(&scheduler);
+    }
+}
+
+/// Switch to the running task's page table, we're on the kernel's mappings so that's fine anywhere
+fn activate_address_space(scheduler: &Scheduler) {
+    if let Some(page_table) = scheduler.current_page_table() {
+        unsafe { address_space::activate(page_table) };
+    }
 }
 
 /// The actual timer interrupt handler entry point
@@ -125,10 +231,21 @@ pub extern "C" fn timer_interrupt_entry() {
 pub unsafe fn switch_to_first_task() -> ! {
     let scheduler = SCHEDULER.lock();
 
-    let context = scheduler.current_context().expect("No tasks to run");
-    let kernel_stack = scheduler
-        .current_kernel_stack_top()
-        .expect("No kernel stack");
+    let (Some(context), Some(kernel_stack)) = (
+        scheduler.current_context(),
+        scheduler.current_kernel_stack_top(),
+    ) else {
+        drop(scheduler);
+
+        serial_println!("[WARNING] No tasks to run, idling");
+        idle();
+    };
+
+    if let Some(id) = scheduler.current_task_id() {
+        set_current_task_id(id);
+    }
+
+    activate_address_space(&scheduler);
 
     // Update TSS RSP0
     unsafe {
@@ -178,3 +295,18 @@ pub unsafe fn switch_to_first_task() -> ! {
         );
     }
 }
+
+/// Idle forever when there is nothing to run
+/// Interrupts stay enabled so the timer and drivers keep working
+fn idle() -> ! {
+    x86_64::instructions::interrupts::enable();
+
+    // Wait for one tick so we know the system is still alive
+    let start = time::ticks();
+    while time::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    serial_println!("Timer is still ticking while idle");
+
+    crate::hlt_loop();
+}
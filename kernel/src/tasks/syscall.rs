@@ -14,42 +14,300 @@ use x86_64::{
     },
 };
 
-use crate::{gdt::GDT, serial_println};
+use crate::{
+    cpu,
+    gdt::GDT,
+    mm::{
+        allocator, memory,
+        shm::{self, SHM, ShmRegistry},
+        user::{self, BuddyFrameAllocator},
+    },
+    serial_print, serial_println,
+    tasks::{
+        SCHEDULER,
+        abi::{
+            self, CLOCK_MONOTONIC, EAGAIN, EFAULT, EINVAL, EIO, ENOMEM, ENOSYS, ESRCH,
+            RLIM_INFINITY, RLIMIT_AS, Rlimit, SIGCONT, SIGSTOP, SysInfo, Timespec,
+        },
+        continue_task, exit_from_syscall,
+        ipc::{self, MAX_MESSAGE_SIZE, Message},
+        ptrace::{self, PTRACE_GETREGS, PTRACE_PEEKDATA, PTRACE_POKEDATA},
+        scheduler::{self, Scheduler},
+        set_priority, stop_task,
+        switch::yield_from_syscall,
+        task::MemoryError,
+    },
+    time,
+};
 
 const SYSCALL_STACK_SIZE: usize = 4096 * 4; // 16 KiB
 
-/// User space address limit - addresses above this are kernel space
-/// Our kernel is mapped in the higher half, so user addresses should be below this
-const USER_SPACE_LIMIT: u64 = 0x0000_8000_0000_0000;
+/// sysconf names, same values as glibc
+const SC_NPROCESSORS_CONF: u64 = 83;
+const SC_NPROCESSORS_ONLN: u64 = 84;
 
-/// Safely read a fixed-length buffer from user memory
+/// Write a struct to user memory, used by syscalls that return more than a register
 ///
-/// Returns None if:
-/// - The pointer is in kernel space
-/// - The buffer would extend into kernel space
-fn read_user_bytes(ptr: u64, len: u64) -> Option<alloc::vec::Vec<u8>> {
-    use alloc::vec::Vec;
-
-    // Validate pointer is in user space
-    if ptr >= USER_SPACE_LIMIT || ptr == 0 {
-        return None;
+/// `T` should be one of the structs from `abi`, they don't have padding that could leak kernel data.
+/// Returns EFAULT if the destination isn't writable user memory.
+fn copy_to_user<T: Copy>(ptr: u64, value: &T) -> Result<(), i64> {
+    user::validate_user_buffer_mut(ptr, core::mem::size_of::<T>() as u64).map_err(|_| EFAULT)?;
+
+    // User pointers don't have to be aligned
+    unsafe { core::ptr::write_unaligned(ptr as *mut T, *value) };
+    Ok(())
+}
+
+/// Write bytes to user memory, `copy_to_user` for buffers of any length
+/// Returns EFAULT if the destination isn't writable user memory.
+fn copy_bytes_to_user(ptr: u64, data: &[u8]) -> Result<(), i64> {
+    user::validate_user_buffer_mut(ptr, data.len() as u64)
+        .map_err(|_| EFAULT)?
+        .copy_from_slice(data);
+    Ok(())
+}
+
+/// Read a struct from user memory, the counterpart of `copy_to_user`
+/// Returns EFAULT if the source isn't user memory.
+fn copy_from_user<T: Copy>(ptr: u64) -> Result<T, i64> {
+    user::validate_user_buffer(ptr, core::mem::size_of::<T>() as u64).map_err(|_| EFAULT)?;
+
+    Ok(unsafe { core::ptr::read_unaligned(ptr as *const T) })
+}
+
+/// Get the running task's address space limit (RLIMIT_AS), in bytes
+fn getrlimit(resource: u64, rlim_ptr: u64) -> Result<(), i64> {
+    if resource != RLIMIT_AS {
+        return Err(EINVAL);
+    }
+
+    // Don't let the timer interrupt us while we hold the scheduler lock, it needs it too
+    let limit_pages = x86_64::instructions::interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .current_task_mut()
+            .map(|task| task.memory_limit_pages)
+    })
+    .ok_or(EINVAL)?;
+
+    let limit_bytes = (limit_pages as u64).saturating_mul(4096);
+    let limit = Rlimit {
+        rlim_cur: limit_bytes,
+        rlim_max: limit_bytes,
+    };
+    copy_to_user(rlim_ptr, &limit)
+}
+
+/// Set the running task's address space limit (RLIMIT_AS), in bytes
+/// We only have one limit per task, so the soft limit is what counts
+fn setrlimit(resource: u64, rlim_ptr: u64) -> Result<(), i64> {
+    if resource != RLIMIT_AS {
+        return Err(EINVAL);
+    }
+
+    let limit: Rlimit = copy_from_user(rlim_ptr)?;
+    if limit.rlim_cur > limit.rlim_max {
+        return Err(EINVAL);
     }
 
-    // Check that the entire buffer is in user space
-    let end_addr = ptr.checked_add(len)?;
-    if end_addr > USER_SPACE_LIMIT {
-        return None;
+    let limit_pages = if limit.rlim_cur == RLIM_INFINITY {
+        usize::MAX
+    } else {
+        (limit.rlim_cur / 4096) as usize
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.current_task_mut().ok_or(EINVAL)?;
+        task.memory_limit_pages = limit_pages;
+        Ok(())
+    })
+}
+
+/// Move the running task's program break, returns the break it ends up with
+fn brk(addr: u64) -> u64 {
+    let physical_memory_offset = memory::physical_memory_offset();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(task) = scheduler.current_task_mut() else {
+            return 0;
+        };
+        let current = task.brk.as_u64();
+
+        let Ok(new_brk) = VirtAddr::try_new(addr) else {
+            return current;
+        };
+        let mut mapper = unsafe { memory::active_mapper() };
+
+        match unsafe {
+            task.set_brk(
+                &mut mapper,
+                &mut BuddyFrameAllocator,
+                physical_memory_offset,
+                new_brk,
+            )
+        } {
+            Ok(new_brk) => new_brk.as_u64(),
+            Err(_) => current,
+        }
+    })
+}
+
+/// Errno for a failed shared memory operation
+fn shm_errno(error: MemoryError) -> i64 {
+    match error {
+        MemoryError::Shm(shm::Error::InvalidSize | shm::Error::NotFound)
+        | MemoryError::InvalidBreak => EINVAL,
+        MemoryError::Shm(shm::Error::NoAddressSpace)
+        | MemoryError::LimitExceeded
+        | MemoryError::MapFailed(_) => ENOMEM,
     }
+}
 
-    let mut result = Vec::with_capacity(len as usize);
+/// Create a zeroed shared memory object of at least `size` bytes and return its ID
+fn shm_create(size: u64) -> Result<u64, i64> {
+    let pages = ShmRegistry::pages_for(size).map_err(|_| EINVAL)?;
+    let physical_memory_offset = memory::physical_memory_offset();
 
-    for i in 0..len {
-        let addr = ptr + i as u64;
-        let byte = unsafe { *(addr as *const u8) };
-        result.push(byte);
+    let mut frames = alloc::vec::Vec::with_capacity(pages);
+    for _ in 0..pages {
+        let Some(frame) = allocator::allocate_frame() else {
+            for frame in frames {
+                unsafe { allocator::deallocate_frame(frame) };
+            }
+            return Err(ENOMEM);
+        };
+
+        // The frame may still hold another task's data
+        let ptr = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+        unsafe { ptr.write_bytes(0, 4096) };
+        frames.push(frame);
     }
 
-    Some(result)
+    Ok(x86_64::instructions::interrupts::without_interrupts(|| {
+        SHM.lock().insert(frames)
+    }))
+}
+
+/// Map a shared memory object into the running task and return its address
+fn shm_map(id: u64) -> Result<u64, i64> {
+    // The timer needs the scheduler lock, and the scheduler lock is always taken before SHM
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.current_task_mut().ok_or(EINVAL)?;
+        let mut mapper = unsafe { memory::active_mapper() };
+
+        task.map_shared(&mut SHM.lock(), &mut mapper, &mut BuddyFrameAllocator, id)
+            .map(|base| base.as_u64())
+            .map_err(shm_errno)
+    })
+}
+
+/// Unmap the shared memory the running task mapped at `addr`
+fn shm_unmap(addr: u64) -> Result<(), i64> {
+    let base = VirtAddr::try_new(addr).map_err(|_| EINVAL)?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let task = scheduler.current_task_mut().ok_or(EINVAL)?;
+        let mut mapper = unsafe { memory::active_mapper() };
+
+        unsafe { task.unmap_shared(&mut SHM.lock(), &mut mapper, &mut BuddyFrameAllocator, base) }
+            .map_err(|_| EINVAL)
+    })
+}
+
+/// Destroy a shared memory object, its frames are freed once the last mapping is gone
+fn shm_destroy(id: u64) -> Result<(), i64> {
+    let frames = x86_64::instructions::interrupts::without_interrupts(|| SHM.lock().destroy(id))
+        .map_err(|_| EINVAL)?;
+
+    for frame in frames.into_iter().flatten() {
+        unsafe { allocator::deallocate_frame(frame) };
+    }
+
+    Ok(())
+}
+
+/// Inspect another task, see `tasks::ptrace`
+fn ptrace(request: u64, pid: u64, addr: u64, data: u64) -> Result<(), i64> {
+    match request {
+        PTRACE_PEEKDATA => copy_to_user(data, &ptrace::peek(pid, addr)?),
+        PTRACE_POKEDATA => ptrace::poke(pid, addr, data),
+        PTRACE_GETREGS => copy_to_user(data, &ptrace::getregs(pid)?),
+        _ => Err(EIO),
+    }
+}
+
+/// Stop or continue a task, the only signals we have
+pub fn kill(pid: u64, signal: u64) -> Result<(), i64> {
+    let result = match signal {
+        SIGSTOP => stop_task(pid),
+        SIGCONT => continue_task(pid),
+        _ => return Err(EINVAL),
+    };
+
+    result.map_err(|e| match e {
+        scheduler::Error::NoSuchTask => ESRCH,
+        scheduler::Error::TooManyTasks | scheduler::Error::LastTask => EINVAL,
+    })
+}
+
+/// Copy a message from user memory into the mailbox of task `pid`
+fn send(pid: u64, ptr: u64, len: u64) -> Result<(), i64> {
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(EINVAL);
+    }
+    let data = user::validate_user_buffer(ptr, len)
+        .map_err(|_| EFAULT)?
+        .to_vec();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let sender = getpid(&scheduler);
+        scheduler.send_message(pid, Message { sender, data })
+    })
+    .map_err(|e| match e {
+        ipc::Error::TooLarge => EINVAL,
+        ipc::Error::Full => EAGAIN,
+        ipc::Error::NoSuchTask => ESRCH,
+    })
+}
+
+/// Copy the oldest message in the calling task's mailbox to `len` bytes at `ptr`
+/// Returns the number of bytes copied, or EAGAIN if the mailbox is empty. With `block` the task is
+/// blocked until a message arrives in that case.
+pub fn recv(ptr: u64, len: u64, block: bool) -> Result<u64, i64> {
+    // Check the buffer first, a bad pointer must not cost the message
+    user::validate_user_buffer_mut(ptr, len).map_err(|_| EFAULT)?;
+
+    let message = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if block {
+            scheduler.recv_or_block()
+        } else {
+            scheduler
+                .current_task_mut()
+                .and_then(|task| task.mailbox.recv())
+        }
+    })
+    .ok_or(EAGAIN)?;
+
+    let copied = message.data.len().min(len as usize);
+    copy_bytes_to_user(ptr, &message.data[..copied])?;
+    Ok(copied as u64)
+}
+
+/// Set a task's priority, pid 0 is the calling task
+fn setpriority(pid: u64, priority: u64) -> Result<(), i64> {
+    let priority = u8::try_from(priority).map_err(|_| EINVAL)?;
+    let pid = match pid {
+        0 => x86_64::instructions::interrupts::without_interrupts(|| getpid(&SCHEDULER.lock())),
+        pid => pid,
+    };
+
+    set_priority(pid, priority).map_err(|_| ESRCH)
 }
 
 /// Kernel stack for syscall handler
@@ -69,6 +327,7 @@ pub fn init_syscalls() {
     // First, enable the necessary CPU features for syscalls
     unsafe {
         Cr0::update(|cr0| {
+            // Ring 3 code that sets RFLAGS.AC gets #AC for unaligned accesses, see `interrupts.rs`
             *cr0 |= Cr0Flags::ALIGNMENT_MASK;
             *cr0 |= Cr0Flags::NUMERIC_ERROR;
             *cr0 |= Cr0Flags::MONITOR_COPROCESSOR;
@@ -193,6 +452,16 @@ extern "C" fn syscall_handler() {
         // Load kernel stack using RIP-relative addressing for PIE compatibility
         "lea rsp, [rip + {kernel_stack} + {stack_size}]",
 
+        // sched_yield, nanosleep, recv and kill switch tasks, they need all the user registers (see below)
+        "cmp rax, {sched_yield}",
+        "je 2f",
+        "cmp rax, {nanosleep}",
+        "je 2f",
+        "cmp rax, {recv}",
+        "je 2f",
+        "cmp rax, {kill}",
+        "je 2f",
+
         // Now we're on kernel stack - save everything
         // First save RCX and R11 since we need them for sysret
         "push rcx",         // return RIP
@@ -216,22 +485,30 @@ extern "C" fn syscall_handler() {
         "sti",
 
         // Set up arguments for syscall_entry according to System V ABI:
-        // syscall_entry(syscall_num, arg1, arg2, arg3, arg4, arg5)
-        // TODO: arg6
-        //
-        // Stack layout: [rsp+0]=r9, [rsp+8]=r8, [rsp+16]=r10, [rsp+24]=rdx,
-        //               [rsp+32]=rsi, [rsp+40]=rdi, [rsp+48]=rax,
-        //               [rsp+56]=user_rsp, [rsp+64]=r11, [rsp+72]=rcx
-        "mov rdi, [rsp + 48]",  // syscall_num = saved rax
-        "mov rsi, [rsp + 40]",  // arg1 = saved rdi
-        "mov rdx, [rsp + 32]",  // arg2 = saved rsi
-        "mov rcx, [rsp + 24]",  // arg3 = saved rdx
-        "mov r8,  [rsp + 16]",  // arg4 = saved r10
-        "mov r9,  [rsp + 8]",   // arg5 = saved r8
+        // syscall_entry(syscall_num, arg1, arg2, arg3, arg4, arg5, arg6)
+        // The first six go in registers, arg6 is the seventh argument so it's passed on the stack.
+        // The callee owns that stack slot and may overwrite it, so it gets a copy of the saved r9
+        // (plus 8 bytes of padding to keep the stack 16-byte aligned for the call).
+        "sub rsp, 8",
+        "push r9",
+
+        // Stack layout: [rsp+0]=arg6 (copy of r9), [rsp+8]=padding,
+        //               [rsp+16]=r9, [rsp+24]=r8, [rsp+32]=r10, [rsp+40]=rdx,
+        //               [rsp+48]=rsi, [rsp+56]=rdi, [rsp+64]=rax,
+        //               [rsp+72]=user_rsp, [rsp+80]=r11, [rsp+88]=rcx
+        "mov rdi, [rsp + 64]",  // syscall_num = saved rax
+        "mov rsi, [rsp + 56]",  // arg1 = saved rdi
+        "mov rdx, [rsp + 48]",  // arg2 = saved rsi
+        "mov rcx, [rsp + 40]",  // arg3 = saved rdx
+        "mov r8,  [rsp + 32]",  // arg4 = saved r10
+        "mov r9,  [rsp + 24]",  // arg5 = saved r8
 
         "lea rax, [rip + {syscall_entry}]",
         "call rax",
 
+        // Drop arg6 and the padding
+        "add rsp, 16",
+
         // Return value is in RAX - save it temporarily
         "mov [rsp + 48], rax",  // Store return value where rax was saved
 
@@ -269,18 +546,386 @@ extern "C" fn syscall_handler() {
         // Return to user mode
         "sysretq",
 
+        // sched_yield, nanosleep, recv and kill: build a TaskContext like the timer interrupt does, so we can switch
+        // to another task and come back to this one with iretq. Interrupts stay masked until the iretq.
+        "2:",
+        // iretq frame, yield_from_syscall fills in the user selectors
+        "push 0",           // ss
+        "push qword ptr [rip + {user_rsp_temp}]", // rsp
+        "push r11",         // rflags
+        "push 0",           // cs
+        "push rcx",         // rip
+        // General purpose registers in TaskContext order, rax is the syscall number until
+        // yield_from_syscall replaces it with the return value
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "lea rax, [rip + {yield_from_syscall}]",
+        "call rax",
+        // Restore whichever task we switched to
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "iretq",
+
         kernel_stack = sym SYSCALL_KERNEL_STACK,
+        sched_yield = const Syscall::SchedYield as u64,
+        nanosleep = const Syscall::Nanosleep as u64,
+        recv = const Syscall::Recv as u64,
+        kill = const Syscall::Kill as u64,
+        yield_from_syscall = sym yield_from_syscall,
         stack_size = const SYSCALL_STACK_SIZE,
         syscall_entry = sym syscall_entry,
         user_rsp_temp = sym USER_RSP_TEMP,
     );
 }
 
+/// Syscall numbers, the same as Linux where we have an equivalent
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syscall {
+    Write = 1,
+    WriteBytes = 2,
+    Brk = 12,
+    SchedYield = 24,
+    Nanosleep = 35,
+    ShmCreate = 29,
+    ShmMap = 30,
+    ShmDestroy = 31,
+    GetPid = 39,
+    Exit = 60,
+    Kill = 62,
+    ShmUnmap = 67,
+    Send = 69,
+    Recv = 70,
+    GetRlimit = 97,
+    SysInfo = 99,
+    Ptrace = 101,
+    SetPriority = 141,
+    SetRlimit = 160,
+    ClockGettime = 228,
+    Sysconf = 500,
+}
+
+/// Every syscall handler takes the six arguments, unused ones are ignored
+pub type Handler = fn(u64, u64, u64, u64, u64, u64) -> u64;
+
+impl Syscall {
+    /// None for numbers we don't implement
+    pub fn from_number(number: u64) -> Option<Self> {
+        Some(match number {
+            1 => Syscall::Write,
+            2 => Syscall::WriteBytes,
+            12 => Syscall::Brk,
+            24 => Syscall::SchedYield,
+            35 => Syscall::Nanosleep,
+            29 => Syscall::ShmCreate,
+            30 => Syscall::ShmMap,
+            31 => Syscall::ShmDestroy,
+            39 => Syscall::GetPid,
+            60 => Syscall::Exit,
+            62 => Syscall::Kill,
+            67 => Syscall::ShmUnmap,
+            69 => Syscall::Send,
+            70 => Syscall::Recv,
+            97 => Syscall::GetRlimit,
+            99 => Syscall::SysInfo,
+            101 => Syscall::Ptrace,
+            141 => Syscall::SetPriority,
+            160 => Syscall::SetRlimit,
+            228 => Syscall::ClockGettime,
+            500 => Syscall::Sysconf,
+            _ => return None,
+        })
+    }
+
+    pub fn handler(self) -> Handler {
+        match self {
+            // write - write a buffer to fd
+            // arg1 = fd (1 = stdout, which goes to the serial port)
+            // arg2 = pointer to the buffer in user space
+            // arg3 = length of the buffer
+            // Returns: the number of bytes written, -1 for other fds or invalid pointers
+            Syscall::Write => |fd, ptr, len, _, _, _| write(fd, ptr, len),
+
+            // write_bytes - write buffer with explicit length
+            // arg1 = fd (1 = stdout)
+            // arg2 = pointer to the buffer in user space
+            // arg3 = length of the buffer
+            // Returns: bytes written on success, -1 on failure
+            Syscall::WriteBytes => |fd, ptr, len, _, _, _| write_bytes(fd, ptr, len),
+
+            // brk - move the end of the calling task's heap
+            // arg1 = the new program break, 0 to ask for the current one
+            // Returns: the new break on success, the current break on failure (like Linux)
+            Syscall::Brk => |addr, _, _, _, _, _| brk(addr),
+
+            // sched_yield - let the next task run, this one runs again on its next turn
+            // Returns: 0 once the task runs again
+            // syscall_handler switches tasks itself, we only get here if someone calls the table directly
+            Syscall::SchedYield => |_, _, _, _, _, _| 0,
+
+            // nanosleep - block the calling task for a while, other tasks run in the meantime
+            // arg1 = pointer to a struct timespec with the duration, rounded up to whole ticks
+            // arg2 = remaining time if interrupted, never written since nothing interrupts a sleep
+            // Returns: 0 once the time has passed, -EINVAL for bad durations, -EFAULT for invalid pointers
+            // syscall_handler sleeps and switches tasks itself, we only get here if someone calls the table directly
+            Syscall::Nanosleep => |_, _, _, _, _, _| 0,
+
+            // shm_create - create an anonymous shared memory object (shmget's number)
+            // arg1 = size in bytes, rounded up to whole pages
+            // Returns: the object's ID, -EINVAL for a bad size, -ENOMEM if we're out of frames
+            Syscall::ShmCreate => |size, _, _, _, _, _| abi::value(shm_create(size)),
+
+            // shm_map - map a shared memory object into the calling task (shmat's number)
+            // arg1 = ID from shm_create
+            // Returns: the address it's mapped at, -EINVAL for unknown IDs, -ENOMEM over the memory limit
+            Syscall::ShmMap => |id, _, _, _, _, _| abi::value(shm_map(id)),
+
+            // shm_destroy - destroy a shared memory object once it's unmapped everywhere
+            // arg1 = ID from shm_create
+            // Returns: 0 on success, -EINVAL for unknown IDs
+            Syscall::ShmDestroy => |id, _, _, _, _, _| abi::result(shm_destroy(id)),
+
+            // getpid - get the ID of the calling task
+            // Returns: the task ID, 0 if no task is running
+            Syscall::GetPid => |_, _, _, _, _, _| {
+                // Don't let the timer interrupt us while we hold the scheduler lock, it needs it too
+                x86_64::instructions::interrupts::without_interrupts(|| getpid(&SCHEDULER.lock()))
+            },
+
+            // exit - end the calling task
+            // arg1 = exit code
+            // Never returns
+            Syscall::Exit => |code, _, _, _, _, _| exit_from_syscall(code as i32),
+
+            // kill - stop or continue a task
+            // arg1 = ID of the task
+            // arg2 = signal, only SIGSTOP and SIGCONT
+            // Returns: 0 on success, -ESRCH for unknown tasks, -EINVAL for other signals
+            // A task that stops itself is switched away from right away, syscall_handler does that itself
+            Syscall::Kill => |pid, signal, _, _, _, _| abi::result(kill(pid, signal)),
+
+            // shm_unmap - unmap shared memory (shmdt's number)
+            // arg1 = address returned by shm_map
+            // Returns: 0 on success, -EINVAL if nothing is mapped there
+            Syscall::ShmUnmap => |addr, _, _, _, _, _| abi::result(shm_unmap(addr)),
+
+            // send - copy a message into another task's mailbox (msgsnd's number, our own arguments)
+            // arg1 = ID of the receiving task
+            // arg2 = pointer to the message in user space
+            // arg3 = length of the message, at most MAX_MESSAGE_SIZE
+            // Returns: 0 on success, -ESRCH for unknown tasks, -EINVAL for messages that are too big,
+            //          -EAGAIN if the receiver's mailbox is full, -EFAULT for invalid pointers
+            Syscall::Send => |pid, ptr, len, _, _, _| abi::result(send(pid, ptr, len)),
+
+            // recv - take the oldest message out of the calling task's mailbox (msgrcv's number, our own arguments)
+            // arg1 = pointer to the buffer in user space
+            // arg2 = length of the buffer, the rest of a longer message is dropped
+            // Returns: the number of bytes copied, -EFAULT for invalid pointers
+            // The task blocks until a message arrives. syscall_handler does that itself, if someone calls
+            // the table directly an empty mailbox returns -EAGAIN instead.
+            Syscall::Recv => |ptr, len, _, _, _, _| abi::value(recv(ptr, len, false)),
+
+            // getrlimit - get a resource limit
+            // arg1 = resource (only RLIMIT_AS)
+            // arg2 = pointer to a struct rlimit in user space
+            // Returns: 0 on success, -EINVAL for unsupported resources, -EFAULT for invalid pointers
+            Syscall::GetRlimit => |resource, ptr, _, _, _, _| abi::result(getrlimit(resource, ptr)),
+
+            // sysinfo - get memory and uptime statistics
+            // arg1 = pointer to a struct sysinfo in user space
+            // Returns: 0 on success, -EFAULT if the pointer is invalid
+            Syscall::SysInfo => |ptr, _, _, _, _, _| abi::result(sysinfo(ptr)),
+
+            // ptrace - read/write another task's memory and read its registers
+            // arg1 = request (PTRACE_PEEKDATA, PTRACE_POKEDATA or PTRACE_GETREGS)
+            // arg2 = ID of the task to inspect, it must not be running
+            // arg3 = address in the target's memory (PEEKDATA and POKEDATA)
+            // arg4 = PEEKDATA: pointer to a u64 for the word, POKEDATA: the word to write,
+            //        GETREGS: pointer to a TaskContext (our layout, not struct user_regs_struct)
+            // Returns: 0 on success, -ESRCH for unknown tasks, -EBUSY for the calling task,
+            //          -EIO for unmapped target addresses or unknown requests, -EFAULT for invalid pointers
+            Syscall::Ptrace => {
+                |request, pid, addr, data, _, _| abi::result(ptrace(request, pid, addr, data))
+            }
+
+            // setpriority - set how important a task is to the scheduler (Linux's number, our own arguments)
+            // arg1 = ID of the task, 0 for the calling task
+            // arg2 = priority from 0 to 255, higher runs first (only while the scheduler runs by priority)
+            // Returns: 0 on success, -ESRCH for unknown tasks, -EINVAL for priorities over 255
+            Syscall::SetPriority => {
+                |pid, priority, _, _, _, _| abi::result(setpriority(pid, priority))
+            }
+
+            // setrlimit - set a resource limit
+            // arg1 = resource (only RLIMIT_AS, limits the task's mapped memory)
+            // arg2 = pointer to a struct rlimit in user space
+            // Returns: 0 on success, -EINVAL for unsupported resources, -EFAULT for invalid pointers
+            Syscall::SetRlimit => |resource, ptr, _, _, _, _| abi::result(setrlimit(resource, ptr)),
+
+            // clock_gettime - read a clock
+            // arg1 = clock ID (only CLOCK_MONOTONIC, the time since boot)
+            // arg2 = pointer to a struct timespec in user space
+            // Returns: 0 on success, -EINVAL for unknown clocks, -EFAULT for invalid pointers
+            // The clock only moves once per timer tick.
+            Syscall::ClockGettime => {
+                |clock, ptr, _, _, _, _| abi::result(clock_gettime(clock, ptr))
+            }
+
+            // sysconf - query system configuration (Linux does this in libc, so we pick our own number)
+            // arg1 = name (SC_NPROCESSORS_CONF or SC_NPROCESSORS_ONLN)
+            // Returns: the value on success, -1 for unknown names
+            Syscall::Sysconf => |name, _, _, _, _, _| sysconf(name),
+        }
+    }
+}
+
+/// How many ticks nanosleep should sleep for the struct timespec at `req`
+pub fn sleep_ticks(req: u64) -> Result<u64, i64> {
+    let duration: Timespec = copy_from_user(req)?;
+    let nanos = duration.as_nanos().ok_or(EINVAL)?;
+
+    Ok(time::nanos_to_ticks(nanos, time::tick_frequency()))
+}
+
+/// Run the handler for syscall `number`, -ENOSYS for numbers we don't implement
+pub fn dispatch(number: u64, args: [u64; 6]) -> u64 {
+    match Syscall::from_number(number) {
+        Some(syscall) => {
+            let [arg1, arg2, arg3, arg4, arg5, arg6] = args;
+            syscall.handler()(arg1, arg2, arg3, arg4, arg5, arg6)
+        }
+        None => abi::error(ENOSYS),
+    }
+}
+
+fn write(fd: u64, ptr: u64, len: u64) -> u64 {
+    if fd != 1 {
+        // Only stdout (fd=1) is supported for now
+        return u64::MAX;
+    }
+
+    match user::validate_user_buffer(ptr, len) {
+        Ok(bytes) => {
+            serial_print!("{}", alloc::string::String::from_utf8_lossy(bytes));
+            len
+        }
+        Err(_) => {
+            serial_println!(
+                "[kernel] write: invalid user pointer {:#x} or length {}",
+                ptr,
+                len
+            );
+            u64::MAX
+        }
+    }
+}
+
+fn write_bytes(fd: u64, ptr: u64, len: u64) -> u64 {
+    if fd != 1 {
+        return u64::MAX; // EBADF
+    }
+
+    match user::validate_user_buffer(ptr, len) {
+        Ok(bytes) => {
+            // Try to interpret as UTF-8, fall back to lossy conversion
+            let msg = alloc::string::String::from_utf8_lossy(bytes);
+            serial_println!("[user] {}", msg);
+            len // Return bytes written
+        }
+        Err(_) => {
+            serial_println!(
+                "[kernel] write_bytes: invalid user pointer {:#x} or length {}",
+                ptr,
+                len
+            );
+            u64::MAX // Error
+        }
+    }
+}
+
+/// ID of the running task, 0 if there is none (the scheduler isn't started yet or the task exited)
+pub fn getpid(scheduler: &Scheduler) -> u64 {
+    scheduler.current_task_id().unwrap_or(0)
+}
+
+fn sysinfo(info_ptr: u64) -> Result<(), i64> {
+    // Don't let the timer interrupt us while we hold the scheduler lock, it needs it too
+    let procs =
+        x86_64::instructions::interrupts::without_interrupts(|| SCHEDULER.lock().task_count());
+
+    let info = SysInfo {
+        uptime: (time::uptime_nanos() / 1_000_000_000) as i64,
+        totalram: allocator::total_memory() as u64,
+        freeram: allocator::free_memory() as u64,
+        procs: procs as u16,
+        mem_unit: 1,
+        ..Default::default()
+    };
+
+    copy_to_user(info_ptr, &info)
+}
+
+/// Current time of `clock`, EINVAL for clocks we don't have
+pub fn clock_now(clock: u64) -> Result<Timespec, i64> {
+    clock_at(clock, time::ticks(), time::tick_frequency())
+}
+
+/// Time of `clock` after `ticks` timer ticks at `hz`, EINVAL for clocks we don't have
+pub fn clock_at(clock: u64, ticks: u64, hz: u64) -> Result<Timespec, i64> {
+    match clock {
+        CLOCK_MONOTONIC => Ok(Timespec::from_nanos(time::ticks_to_nanos(ticks, hz))),
+        _ => Err(EINVAL),
+    }
+}
+
+fn clock_gettime(clock: u64, ts_ptr: u64) -> Result<(), i64> {
+    let now = clock_now(clock)?;
+    copy_to_user(ts_ptr, &now)
+}
+
+fn sysconf(name: u64) -> u64 {
+    match name {
+        SC_NPROCESSORS_CONF => cpu::count() as u64,
+        SC_NPROCESSORS_ONLN => cpu::online_count() as u64,
+        _ => {
+            serial_println!("[kernel] sysconf: unknown name {}", name);
+            u64::MAX
+        }
+    }
+}
+
 /// Actual syscall handler - called by syscall_handler after saving context
 ///
 /// Arguments (remapped from syscall convention to System V ABI):
 ///     syscall_num: syscall number (was in rax)
-///     arg1-arg5: syscall arguments (were in rdi, rsi, rdx, r10, r8)
+///     arg1-arg6: syscall arguments (were in rdi, rsi, rdx, r10, r8, r9)
 /// Returns:
 ///     rax: return value
 extern "C" fn syscall_entry(
@@ -288,72 +933,50 @@ extern "C" fn syscall_entry(
     arg1: u64,
     arg2: u64,
     arg3: u64,
-    _arg4: u64,
-    _arg5: u64,
+    arg4: u64,
+    arg5: u64,
+    arg6: u64,
 ) -> u64 {
-    match syscall_num {
-        // Syscall 1: write - write string to fd
-        // arg1 = fd (1 = stdout)
-        // arg2 = pointer to the null-terminated string in user space
-        // arg3 = length of the string
-        // Returns: 0 on success, error code on failure
-        1 => {
-            if arg1 != 1 {
-                // Only stdout (fd=1) is supported for now
-                return 1; // EBADF - bad file descriptor
-            }
+    let args = [arg1, arg2, arg3, arg4, arg5, arg6];
+    if cfg!(feature = "syscall_selftest") && syscall_num == SELFTEST_ARGS {
+        check_selftest_args(args);
+    }
 
-            match read_user_bytes(arg2, arg3) {
-                Some(msg) => {
-                    // Convert to string
-                    let msg = alloc::string::String::from_utf8_lossy(&msg);
-
-                    serial_println!("[user] {}", msg);
-                    0 // Success
-                }
-                None => {
-                    serial_println!(
-                        "[kernel] write: invalid user pointer {:#x} or length {}",
-                        arg2,
-                        arg3
-                    );
-                    14 // EFAULT - bad address
-                }
-            }
-        }
+    if Syscall::from_number(syscall_num).is_none() {
+        serial_println!("[kernel] Unknown syscall: num={}", syscall_num);
+    }
 
-        // Syscall 2: write_bytes - write buffer with explicit length
-        // arg1 = fd (1 = stdout)
-        // arg2 = pointer to the buffer in user space
-        // arg3 = length of the buffer
-        // Returns: bytes written on success, -1 on failure
-        2 => {
-            if arg1 != 1 {
-                return u64::MAX; // EBADF
-            }
+    dispatch(syscall_num, args)
+}
 
-            match read_user_bytes(arg2, arg3) {
-                Some(bytes) => {
-                    // Try to interpret as UTF-8, fall back to lossy conversion
-                    let msg = alloc::string::String::from_utf8_lossy(&bytes);
-                    serial_println!("[user] {}", msg);
-                    arg3 // Return bytes written
-                }
-                None => {
-                    serial_println!(
-                        "[kernel] write_bytes: invalid user pointer {:#x} or length {}",
-                        arg2,
-                        arg3
-                    );
-                    u64::MAX // Error
-                }
-            }
-        }
+/// Syscall number the `syscall_selftest` user program makes with [`SELFTEST_ARG_VALUES`]
+pub const SELFTEST_ARGS: u64 = 0x5E1F;
 
-        // Unknown syscall
-        _ => {
-            serial_println!("[kernel] Unknown syscall: num={}", syscall_num);
-            u64::MAX // Return error
-        }
+/// Arguments the `syscall_selftest` user program passes, all different so a swapped pair shows up
+pub const SELFTEST_ARG_VALUES: [u64; 6] = [
+    0x1111_1111_1111_1111,
+    0x2222_2222_2222_2222,
+    0x3333_3333_3333_3333,
+    0x4444_4444_4444_4444,
+    0x5555_5555_5555_5555,
+    0x6666_6666_6666_6666,
+];
+
+/// End the syscall selftest, it passes if every argument made it from ring 3 to its place
+fn check_selftest_args(args: [u64; 6]) -> ! {
+    use crate::drivers::exit::{QemuExitCode, exit_qemu};
+
+    for (i, (got, expected)) in args.iter().zip(SELFTEST_ARG_VALUES).enumerate() {
+        serial_println!(
+            "Syscall selftest: arg{} = 0x{:x}, expected 0x{:x}",
+            i + 1,
+            got,
+            expected
+        );
     }
+    exit_qemu(if args == SELFTEST_ARG_VALUES {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    })
 }
@@ -1,15 +1,32 @@
-use crate::tasks::{KERNEL_STACK_SIZE, elf};
-use alloc::boxed::Box;
-use core::sync::atomic::{AtomicU64, Ordering};
+use crate::tasks::{DEFAULT_KERNEL_STACK_PAGES, elf, id, ipc::Mailbox, stack::KernelStack};
+use alloc::vec::Vec;
 use x86_64::{
-    VirtAddr,
-    structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB},
+    PhysAddr, VirtAddr,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+            PhysFrame, Size4KiB, mapper::MapperFlush,
+        },
+    },
 };
 
 use crate::gdt::GDT;
+use crate::mm::{
+    address_space,
+    demand::{self, DemandRegions},
+    memory,
+    shm::{self, SHM, ShmMapping, ShmRegistry},
+    user::{self, BuddyFrameAllocator, unmap_user_page},
+};
+use crate::serial_println;
+
+/// Default per-task memory limit: 4096 pages = 16 MiB
+pub const DEFAULT_MEMORY_LIMIT_PAGES: usize = 4096;
 
-/// Counter for generating unique task IDs
-static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+/// Priority tasks start with, in the middle so a task can be made more or less important
+pub const DEFAULT_PRIORITY: u8 = 128;
 
 /// CPU register state saved during context switch
 /// This struct is used by the assembly context switch code
@@ -74,6 +91,18 @@ impl TaskContext {
             ss: user_data,
         }
     }
+
+    /// Create a new context for a kernel-mode task
+    pub fn new_kernel(entry_point: u64, stack_top: u64) -> Self {
+        Self {
+            rip: entry_point,
+            cs: GDT.1.code.0 as u64,
+            rflags: 0x200, // IF (Interrupt Flag) enabled
+            rsp: stack_top,
+            ss: GDT.1.data.0 as u64,
+            ..Default::default()
+        }
+    }
 }
 
 /// Task state
@@ -81,7 +110,68 @@ impl TaskContext {
 pub enum TaskState {
     Ready,
     Running,
-    Blocked,
+    /// Not scheduled until it's unparked with a matching reason
+    Blocked(BlockReason),
+    /// Frozen (e.g. by a debugger), not scheduled until it's continued
+    Stopped,
+    /// Exited with this code, the scheduler removed it and it's freed by `tasks::reap_exited`
+    Exited(i32),
+}
+
+impl TaskState {
+    /// Check if the scheduler may run a task in this state
+    pub fn is_runnable(self) -> bool {
+        matches!(self, TaskState::Ready | TaskState::Running)
+    }
+}
+
+/// What a blocked task is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockReason {
+    /// An event was pushed to the event queue
+    Events,
+    /// The interrupt with this vector fired
+    Interrupt(u8),
+    /// Sleeping until the tick counter reaches this value
+    Sleep(u64),
+    /// Waiting for a message in its mailbox
+    Message,
+}
+
+/// Why mapping memory for a task failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    /// The task would go over its memory limit (ENOMEM for userspace)
+    LimitExceeded,
+    /// Mapping the page itself failed (out of frames, already mapped...)
+    MapFailed(&'static str),
+    /// The shared memory object can't be mapped
+    Shm(shm::Error),
+    /// The program break would go below the heap base or past the biggest heap we allow
+    InvalidBreak,
+}
+
+/// Heap pages to map or unmap when the program break moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BrkChange {
+    Grow { start: VirtAddr, pages: usize },
+    Shrink { start: VirtAddr, pages: usize },
+}
+
+/// Stack sizes of a new task, in pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackSizes {
+    pub user_pages: u64,
+    pub kernel_pages: usize,
+}
+
+impl Default for StackSizes {
+    fn default() -> Self {
+        Self {
+            user_pages: elf::USER_STACK_PAGES,
+            kernel_pages: DEFAULT_KERNEL_STACK_PAGES,
+        }
+    }
 }
 
 /// A single task/process
@@ -91,31 +181,93 @@ pub struct Task {
     pub context: TaskContext,
 
     /// Kernel-mode stack for this task (used when handling interrupts from this task)
-    pub kernel_stack: Box<[u8; KERNEL_STACK_SIZE]>,
+    pub kernel_stack: KernelStack,
+
+    /// User pages (code, data and stack) mapped for this task, unmapped when the task is dropped
+    pub user_pages: Vec<Page<Size4KiB>>,
+
+    /// Maximum number of user pages this task may have mapped
+    pub memory_limit_pages: usize,
+
+    /// Shared memory objects mapped by this task, their frames aren't owned by the task
+    pub shm_mappings: Vec<ShmMapping>,
+
+    /// Parts of the address space that are mapped on first access, see `handle_page_fault`
+    pub demand_regions: DemandRegions,
+
+    /// Start of the heap, the program break never goes below it
+    pub heap_base: VirtAddr,
+
+    /// Current program break (end of the heap), the pages up to it are mapped
+    pub brk: VirtAddr,
+
+    /// Level 4 page table of the task's address space, the kernel's own table for kernel tasks
+    pub page_table: PhysFrame,
+
+    /// Number of times the scheduler switched to this task
+    pub run_count: u64,
+
+    /// Timer ticks this task was running for
+    pub total_ticks: u64,
+
+    /// Higher runs first under `SchedPolicy::Priority`, ignored by the round-robin
+    pub priority: u8,
+
+    /// Messages other tasks sent to this one, see `tasks::ipc`
+    pub mailbox: Mailbox,
 }
 
 impl Task {
     /// Create a new task from an ELF binary
     ///
-    /// Loads the ELF into memory at its specified virtual addresses,
-    /// allocates a user stack, and creates the task context.
+    /// Creates a new address space for the task, loads the ELF into it at its specified virtual addresses,
+    /// allocates a user stack with `args` on it (argv[0] is the program name), and creates the task context.
     pub unsafe fn from_elf(
         elf_data: &[u8],
-        mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        args: &[&str],
+        stacks: StackSizes,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
         phys_mem_offset: VirtAddr,
     ) -> Result<Self, elf::Error> {
-        let id = NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst);
+        let kernel_stack = KernelStack::new(stacks.kernel_pages)
+            .ok_or(elf::Error::MappingFailed("Failed to allocate kernel stack"))?;
+
+        let page_table = unsafe {
+            address_space::new_address_space(
+                memory::kernel_page_table(),
+                phys_mem_offset,
+                frame_allocator,
+            )
+        }
+        .map_err(elf::Error::MappingFailed)?;
+        let mut mapper = unsafe { memory::mapper_for(page_table) };
 
         // Load ELF and allocate user stack
+        let loaded = elf::load_elf(
+            elf_data,
+            args,
+            stacks.user_pages,
+            &mut mapper,
+            frame_allocator,
+            phys_mem_offset,
+        );
         let elf::ElfLoadResult {
             entry_point,
             stack_top,
-        } = elf::load_elf(elf_data, mapper, frame_allocator, phys_mem_offset)?;
+            heap_base,
+            mapped_pages,
+        } = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // load_elf freed the pages it mapped, only the tables are left
+                unsafe {
+                    address_space::free_address_space(page_table, phys_mem_offset, frame_allocator)
+                };
+                return Err(e);
+            }
+        };
 
-        // Allocate kernel stack for this task (used during interrupts)
-        // TODO: Consider something better
-        let kernel_stack = Box::new([0u8; KERNEL_STACK_SIZE]);
+        let id = id::allocate();
 
         // Create context with ELF entry point and mapped stack
         let context = TaskContext::new_user(entry_point, stack_top);
@@ -125,11 +277,375 @@ impl Task {
             state: TaskState::Ready,
             context,
             kernel_stack,
+            user_pages: mapped_pages,
+            memory_limit_pages: DEFAULT_MEMORY_LIMIT_PAGES,
+            shm_mappings: Vec::new(),
+            demand_regions: DemandRegions::new(),
+            heap_base: VirtAddr::new(heap_base),
+            brk: VirtAddr::new(heap_base),
+            page_table,
+            run_count: 0,
+            total_ticks: 0,
+            priority: DEFAULT_PRIORITY,
+            mailbox: Mailbox::new(),
         })
     }
 
+    /// Create a new task that runs `entry` in kernel mode (ring 0)
+    ///
+    /// The task runs on its own kernel stack of `stack_pages` pages, and must never return.
+    /// Fails if the stack can't be allocated.
+    pub fn new_kernel(
+        entry: extern "C" fn() -> !,
+        stack_pages: usize,
+    ) -> Result<Self, &'static str> {
+        let kernel_stack =
+            KernelStack::new(stack_pages).ok_or("Failed to allocate kernel stack")?;
+        let id = id::allocate();
+
+        let mut task = Task {
+            id,
+            state: TaskState::Ready,
+            context: TaskContext::default(),
+            kernel_stack,
+            user_pages: Vec::new(),
+            memory_limit_pages: DEFAULT_MEMORY_LIMIT_PAGES,
+            shm_mappings: Vec::new(),
+            demand_regions: DemandRegions::new(),
+            heap_base: VirtAddr::zero(),
+            brk: VirtAddr::zero(),
+            page_table: memory::kernel_page_table(),
+            run_count: 0,
+            total_ticks: 0,
+            priority: DEFAULT_PRIORITY,
+            mailbox: Mailbox::new(),
+        };
+
+        // The ABI expects rsp + 8 to be 16-byte aligned on function entry (like after a `call`)
+        let stack_top = (task.kernel_stack_top() & !0xF) - 8;
+        task.context = TaskContext::new_kernel(entry as usize as u64, stack_top);
+
+        Ok(task)
+    }
+
+    /// Number of user pages currently mapped for this task (its resident set size), shared memory included
+    pub fn resident_pages(&self) -> usize {
+        let shared: usize = self.shm_mappings.iter().map(|mapping| mapping.pages).sum();
+        self.user_pages.len() + shared
+    }
+
+    /// Check that this task can map `pages` more pages without going over its memory limit
+    pub fn check_memory_limit(&self, pages: usize) -> Result<(), MemoryError> {
+        match self.resident_pages().checked_add(pages) {
+            Some(total) if total <= self.memory_limit_pages => Ok(()),
+            _ => Err(MemoryError::LimitExceeded),
+        }
+    }
+
+    /// Map a new user page for this task and account for it
+    /// Fails without mapping anything if the task is at its memory limit.
+    /// Returns the physical address of the new frame, see `mm::user::map_user_page`
+    pub fn map_user_page(
+        &mut self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        vaddr: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<PhysAddr, MemoryError> {
+        self.check_memory_limit(1)?;
+
+        let phys_addr = user::map_user_page(mapper, frame_allocator, vaddr, flags)
+            .map_err(MemoryError::MapFailed)?;
+
+        self.user_pages.push(Page::containing_address(vaddr));
+
+        Ok(phys_addr)
+    }
+
+    /// Map the page at `addr` on first access if it's in one of this task's demand paged regions
+    /// The page becomes one of the task's user pages, flush it and retry the faulting access.
+    pub fn handle_page_fault(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<MapperFlush<Size4KiB>, demand::Error> {
+        if self.demand_regions.find(addr).is_none() {
+            return Err(demand::Error::NotDemandPaged);
+        }
+        self.check_memory_limit(1)
+            .map_err(|_| demand::Error::OutOfMemory)?;
+
+        let flush = demand::resolve_fault(
+            &self.demand_regions,
+            mapper,
+            frame_allocator,
+            addr,
+            error_code,
+        )?;
+
+        self.user_pages.push(Page::containing_address(addr));
+
+        Ok(flush)
+    }
+
+    /// Unmap one of this task's user pages and free its frame
+    ///
+    /// # Safety
+    /// Same as `mm::user::unmap_user_page`, and `mapper` must be this task's address space.
+    pub unsafe fn unmap_user_page(
+        &mut self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+        page: Page<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        let index = self
+            .user_pages
+            .iter()
+            .position(|&p| p == page)
+            .ok_or("Page doesn't belong to this task")?;
+
+        unsafe { unmap_user_page(mapper, frame_deallocator, page)? };
+
+        self.user_pages.swap_remove(index);
+
+        Ok(())
+    }
+
+    /// Work out which heap pages moving the program break to `new_brk` maps or unmaps
+    /// Fails if the break is out of range or the new pages would go over the memory limit.
+    pub fn brk_change(&self, new_brk: VirtAddr) -> Result<BrkChange, MemoryError> {
+        let max_brk = self.heap_base.as_u64() + elf::USER_HEAP_MAX_PAGES * 4096;
+        if new_brk < self.heap_base || new_brk.as_u64() > max_brk {
+            return Err(MemoryError::InvalidBreak);
+        }
+
+        // The pages up to the old break are mapped, the ones up to the new break should be
+        let old_end = self.brk.align_up(4096u64);
+        let new_end = new_brk.align_up(4096u64);
+
+        if new_end >= old_end {
+            let pages = ((new_end - old_end) / 4096) as usize;
+            self.check_memory_limit(pages)?;
+            Ok(BrkChange::Grow {
+                start: old_end,
+                pages,
+            })
+        } else {
+            Ok(BrkChange::Shrink {
+                start: new_end,
+                pages: ((old_end - new_end) / 4096) as usize,
+            })
+        }
+    }
+
+    /// Move the program break to `new_brk`, mapping zeroed pages or unmapping pages as needed
+    /// Nothing changes if this fails. Returns the new break.
+    ///
+    /// # Safety
+    /// `mapper` must be this task's address space, and nothing may use the heap memory past `new_brk`.
+    pub unsafe fn set_brk(
+        &mut self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+        phys_mem_offset: VirtAddr,
+        new_brk: VirtAddr,
+    ) -> Result<VirtAddr, MemoryError> {
+        match self.brk_change(new_brk)? {
+            BrkChange::Grow { start, pages } => {
+                let flags = PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::NO_EXECUTE;
+
+                for i in 0..pages {
+                    let addr = start + i as u64 * 4096;
+                    match self.map_user_page(mapper, frame_allocator, addr, flags) {
+                        Ok(phys_addr) => {
+                            // The frame may still hold another task's data
+                            let ptr = (phys_mem_offset + phys_addr.as_u64()).as_mut_ptr::<u8>();
+                            unsafe { ptr.write_bytes(0, 4096) };
+                        }
+                        Err(e) => {
+                            unsafe { self.unmap_heap(mapper, frame_allocator, start, i) };
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            BrkChange::Shrink { start, pages } => unsafe {
+                self.unmap_heap(mapper, frame_allocator, start, pages)
+            },
+        }
+
+        self.brk = new_brk;
+        Ok(new_brk)
+    }
+
+    /// Unmap `pages` heap pages starting at `start`
+    unsafe fn unmap_heap(
+        &mut self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+        start: VirtAddr,
+        pages: usize,
+    ) {
+        for i in 0..pages {
+            let page = Page::containing_address(start + i as u64 * 4096);
+            if let Err(e) = unsafe { self.unmap_user_page(mapper, frame_deallocator, page) } {
+                serial_println!(
+                    "[WARNING] Task {}: {} at {:?}",
+                    self.id,
+                    e,
+                    page.start_address()
+                );
+            }
+        }
+    }
+
+    /// Map a shared memory object into this task and account for its pages
+    /// Returns the address the object was mapped at.
+    pub fn map_shared(
+        &mut self,
+        registry: &mut ShmRegistry,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        id: u64,
+    ) -> Result<VirtAddr, MemoryError> {
+        let pages = registry.pages(id).map_err(MemoryError::Shm)?;
+        self.check_memory_limit(pages)?;
+
+        let (base, frames) = registry.attach(id).map_err(MemoryError::Shm)?;
+        if let Err(e) = shm::map_frames(mapper, frame_allocator, base, frames) {
+            // Can't be the last reference, the object wasn't destroyed since we attached
+            registry.detach(id);
+            return Err(MemoryError::MapFailed(e));
+        }
+
+        self.shm_mappings.push(ShmMapping { id, base, pages });
+
+        Ok(base)
+    }
+
+    /// Unmap the shared memory mapped at `base`
+    /// The frames are freed if this was the last mapping of a destroyed object.
+    ///
+    /// # Safety
+    /// `mapper` must be this task's address space and nothing may use the mapping anymore.
+    pub unsafe fn unmap_shared(
+        &mut self,
+        registry: &mut ShmRegistry,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+        base: VirtAddr,
+    ) -> Result<(), &'static str> {
+        let index = self
+            .shm_mappings
+            .iter()
+            .position(|mapping| mapping.base == base)
+            .ok_or("No shared memory mapped at this address")?;
+        let mapping = self.shm_mappings.swap_remove(index);
+
+        shm::unmap_frames(mapper, mapping.base, mapping.pages);
+
+        for frame in registry.detach(mapping.id).into_iter().flatten() {
+            unsafe { frame_deallocator.deallocate_frame(frame) };
+        }
+
+        Ok(())
+    }
+
     /// Get the top of this task's kernel stack
     pub fn kernel_stack_top(&self) -> u64 {
-        self.kernel_stack.as_ptr() as u64 + KERNEL_STACK_SIZE as u64
+        self.kernel_stack.top()
+    }
+
+    /// Unmap and zero the task's own pages and free its address space, every frame goes to `frame_deallocator`
+    /// Shared memory isn't touched, unmap it first. Afterwards the task is left on the kernel's table.
+    ///
+    /// # Safety
+    /// The task's address space must not be active, its pages are unmapped without flushing the TLB.
+    /// The complete physical memory must be mapped at `physical_memory_offset`.
+    pub unsafe fn free_address_space(
+        &mut self,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+        physical_memory_offset: VirtAddr,
+    ) {
+        let table =
+            (physical_memory_offset + self.page_table.start_address().as_u64()).as_mut_ptr();
+        let mut mapper = unsafe { OffsetPageTable::new(&mut *table, physical_memory_offset) };
+
+        for page in self.user_pages.drain(..) {
+            let Ok((frame, flush)) = mapper.unmap(page) else {
+                serial_println!(
+                    "[WARNING] Task {}: page at {:?} wasn't mapped",
+                    self.id,
+                    page.start_address()
+                );
+                continue;
+            };
+            // Not the active address space, so none of it is in the TLB
+            flush.ignore();
+
+            // Same as `unmap_user_page`, the next owner of the frame mustn't see the task's data
+            let kernel_ptr = physical_memory_offset + frame.start_address().as_u64();
+            unsafe { core::ptr::write_bytes(kernel_ptr.as_mut_ptr::<u8>(), 0, 4096) };
+            unsafe { frame_deallocator.deallocate_frame(frame) };
+        }
+
+        unsafe {
+            address_space::free_address_space(
+                self.page_table,
+                physical_memory_offset,
+                frame_deallocator,
+            )
+        };
+
+        // Nothing of its own is left, dropping the task leaves the kernel's table alone
+        self.page_table = memory::kernel_page_table();
+    }
+}
+
+impl Drop for Task {
+    /// Unmap the user pages and shared memory, free the address space, give the frames back to the
+    /// buddy allocator and free the ID
+    /// The kernel stack frees itself
+    fn drop(&mut self) {
+        id::release(self.id);
+
+        // Kernel tasks run on the kernel's table, none of it is theirs
+        if self.page_table == memory::kernel_page_table() {
+            return;
+        }
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            // A task that exited from a syscall is still the active address space
+            if Cr3::read().0 == self.page_table {
+                unsafe { address_space::activate(memory::kernel_page_table()) };
+            }
+
+            let mut mapper = unsafe { memory::mapper_for(self.page_table) };
+            let mut frame_deallocator = BuddyFrameAllocator;
+
+            // Shared frames are only freed with the last mapping, never by one of the tasks
+            if !self.shm_mappings.is_empty() {
+                let mut registry = SHM.lock();
+                while let Some(mapping) = self.shm_mappings.last().copied() {
+                    let _ = unsafe {
+                        self.unmap_shared(
+                            &mut registry,
+                            &mut mapper,
+                            &mut frame_deallocator,
+                            mapping.base,
+                        )
+                    };
+                }
+            }
+
+            unsafe {
+                self.free_address_space(&mut frame_deallocator, memory::physical_memory_offset())
+            };
+        });
     }
 }
@@ -0,0 +1,67 @@
+// Monotonic time
+//
+// Counts timer interrupts since boot and converts them to real time using the
+// configured tick frequency.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Tick rate we assume until someone tells us the real one
+/// The APIC timer is calibrated to run at this rate, see `apic::TIMER_HZ`
+pub const DEFAULT_TICK_HZ: u64 = 25;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Number of timer ticks since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the timer interrupt in Hz
+static TICK_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TICK_HZ);
+
+/// Advance the tick counter, called once per timer interrupt
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer ticks since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Frequency of the timer interrupt in Hz
+pub fn tick_frequency() -> u64 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// Tell the time module how fast the timer interrupt fires
+pub fn set_tick_frequency(hz: u64) {
+    assert!(hz > 0, "Tick frequency must be non-zero");
+    TICK_HZ.store(hz, Ordering::Relaxed);
+}
+
+/// Convert a number of ticks to nanoseconds at the given frequency
+pub fn ticks_to_nanos(ticks: u64, hz: u64) -> u64 {
+    // u128 so we don't overflow after a few hours of uptime
+    (ticks as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64
+}
+
+/// Convert nanoseconds to ticks at the given frequency, rounding up to a whole tick
+pub fn nanos_to_ticks(nanos: u64, hz: u64) -> u64 {
+    (nanos as u128 * hz as u128).div_ceil(NANOS_PER_SEC as u128) as u64
+}
+
+/// Tick at which a wait of `nanos` nanoseconds that starts at tick `now` is over
+///
+/// Part of the current tick is already gone, waiting for the counter to go up `n` times can be up to a
+/// tick short. One more tick makes sure we never wait less than asked.
+pub fn deadline_after(now: u64, nanos: u64, hz: u64) -> u64 {
+    if nanos == 0 {
+        return now;
+    }
+    now.saturating_add(nanos_to_ticks(nanos, hz))
+        .saturating_add(1)
+}
+
+/// Nanoseconds since boot (with tick resolution)
+pub fn uptime_nanos() -> u64 {
+    ticks_to_nanos(ticks(), tick_frequency())
+}
@@ -0,0 +1,56 @@
+use core::mem::{offset_of, size_of};
+use kernel::tasks::abi::{self, EFAULT, EINVAL, Rlimit, SysInfo, Timespec};
+
+#[test]
+fn test_abi_struct_layouts_match_linux() {
+    assert_eq!(size_of::<Timespec>(), 16);
+    assert_eq!(offset_of!(Timespec, tv_nsec), 8);
+
+    assert_eq!(size_of::<Rlimit>(), 16);
+    assert_eq!(offset_of!(Rlimit, rlim_max), 8);
+
+    assert_eq!(size_of::<SysInfo>(), 112);
+    assert_eq!(offset_of!(SysInfo, totalram), 32);
+    assert_eq!(offset_of!(SysInfo, procs), 80);
+    assert_eq!(offset_of!(SysInfo, totalhigh), 88);
+    assert_eq!(offset_of!(SysInfo, mem_unit), 104);
+}
+
+#[test]
+fn test_syscall_results() {
+    assert_eq!(abi::result(Ok(())), 0);
+    assert_eq!(abi::result(Err(EFAULT)) as i64, -14);
+    assert_eq!(abi::error(EFAULT), u64::MAX - 13);
+    assert_eq!(abi::value(Ok(0x1000)), 0x1000);
+    assert_eq!(abi::value(Err(EINVAL)) as i64, -22);
+}
+
+#[test]
+fn test_timespec_as_nanos() {
+    let timespec = |tv_sec, tv_nsec| Timespec { tv_sec, tv_nsec };
+
+    assert_eq!(timespec(0, 0).as_nanos(), Some(0));
+    assert_eq!(timespec(2, 500).as_nanos(), Some(2_000_000_500));
+    assert_eq!(timespec(0, 999_999_999).as_nanos(), Some(999_999_999));
+    assert_eq!(timespec(i64::MAX, 0).as_nanos(), Some(u64::MAX));
+
+    assert_eq!(timespec(-1, 0).as_nanos(), None);
+    assert_eq!(timespec(0, -1).as_nanos(), None);
+    assert_eq!(timespec(0, 1_000_000_000).as_nanos(), None);
+}
+
+#[test]
+fn test_timespec_from_nanos_round_trips() {
+    assert_eq!(Timespec::from_nanos(0), Timespec::default());
+    assert_eq!(
+        Timespec::from_nanos(2_000_000_500),
+        Timespec {
+            tv_sec: 2,
+            tv_nsec: 500
+        }
+    );
+
+    for nanos in [1, 999_999_999, 1_000_000_000, 123_456_789_012, u64::MAX] {
+        assert_eq!(Timespec::from_nanos(nanos).as_nanos(), Some(nanos));
+    }
+}
@@ -0,0 +1,46 @@
+use kernel::drivers::acpi::{SleepType, parse_s5, sleep_control_value};
+
+/// `Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })` in a DSDT, between other definitions
+const DSDT_S5: &[u8] = &[
+    0x08, b'_', b'S', b'4', b'_', 0x12, 0x08, 0x04, 0x0A, 0x06, 0x0A, 0x06, 0x00, 0x00, // _S4
+    0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x0A, 0x05, 0x00, 0x00, // _S5
+    0x14, 0x08, b'_', b'P', b'T', b'S', 0x01, 0xA3, // Method (_PTS, 1) { Noop }
+];
+
+#[test]
+fn test_parse_s5_byte_values() {
+    assert_eq!(parse_s5(DSDT_S5), Some(SleepType { a: 5, b: 5 }));
+}
+
+#[test]
+fn test_parse_s5_with_constants_and_root_prefix() {
+    // QEMU: `Name (\_S5, Package (0x04) { Zero, Zero, Zero, Zero })`
+    let aml = [
+        0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x06, 0x04, 0x00, 0x00, 0x00, 0x00,
+    ];
+    assert_eq!(parse_s5(&aml), Some(SleepType { a: 0, b: 0 }));
+
+    // Word constants and a two byte PkgLength
+    let aml = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x01, 0x02, 0x0B, 0x07, 0x00, 0x01,
+    ];
+    assert_eq!(parse_s5(&aml), Some(SleepType { a: 7, b: 1 }));
+}
+
+#[test]
+fn test_parse_s5_ignores_references_and_garbage() {
+    // A reference to _S5 in a method isn't its definition
+    assert_eq!(parse_s5(&[0xA4, b'_', b'S', b'5', b'_']), None);
+    // Cut off in the middle of the package
+    assert_eq!(parse_s5(&DSDT_S5[14..24]), None);
+    assert_eq!(parse_s5(&[]), None);
+    assert_eq!(parse_s5(b"_S5_"), None);
+}
+
+#[test]
+fn test_sleep_control_value() {
+    // SLP_TYP in bits 10-12 plus SLP_EN, SCI_EN stays set
+    assert_eq!(sleep_control_value(0x0001, 5), 0x3401);
+    // The old SLP_TYP is replaced
+    assert_eq!(sleep_control_value(0x1C00, 0), 0x2000);
+}
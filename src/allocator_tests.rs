@@ -1,6 +1,13 @@
-use kernel::mm::buddy::BuddyAllocator;
+use kernel::mm::allocator::{self, SizeClass, SlubAllocator};
+use kernel::mm::buddy::{BuddyAllocator, MAX_ORDER, bitmap_size, order_for};
 use kernel::mm::slub::{PAGE_SIZE, PageProvider, SCache};
-use std::alloc::{Layout, alloc, dealloc};
+use std::alloc::{GlobalAlloc, Layout, alloc, dealloc};
+use std::sync::Mutex;
+use x86_64::PhysAddr;
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+
+/// Every `BuddyAllocator::new` shares one static bitmap and the heap is global, tests that feed them memory can't run at the same time
+static BUDDY_BITMAP: Mutex<()> = Mutex::new(());
 
 struct TestPageProvider {
     allocated_pages: Vec<*mut u8>,
@@ -56,6 +63,7 @@ impl Drop for TestPageProvider {
 
 #[test]
 fn test_buddy_allocator() {
+    let _bitmap = BUDDY_BITMAP.lock().unwrap();
     let mut buddy = BuddyAllocator::new();
 
     // Allocate 4MB of memory to feed the buddy allocator
@@ -101,6 +109,316 @@ fn test_buddy_allocator() {
     unsafe { dealloc(memory, layout) };
 }
 
+#[test]
+fn test_buddy_fragmentation() {
+    let _bitmap = BUDDY_BITMAP.lock().unwrap();
+    let mut buddy = BuddyAllocator::new();
+
+    // 4MB = 1024 pages = exactly one order 10 block
+    let memory_size = 4 * 1024 * 1024;
+    let layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(layout) };
+
+    // Pretend the block starts at physical address 0 so it's inside the managed range
+    buddy.set_offset(memory as usize);
+
+    for i in (0..memory_size).step_by(4096) {
+        unsafe { buddy.add_frame(memory.add(i)) };
+    }
+
+    let info = buddy.fragmentation();
+    assert_eq!(info.free_blocks[10], 1);
+    assert_eq!(info.free_blocks.iter().sum::<usize>(), 1);
+    assert_eq!(info.largest_free_block_bytes, memory_size);
+    assert_eq!(info.free_bytes, memory_size);
+
+    // Splitting the block down to order 0 leaves one free buddy on every lower order
+    let ptr = unsafe { buddy.alloc(0) }.expect("Failed to alloc order 0");
+
+    let info = buddy.fragmentation();
+    assert_eq!(info.free_blocks[..10], [1; 10]);
+    assert_eq!(info.free_blocks[10], 0);
+    assert_eq!(info.largest_free_block_bytes, memory_size / 2);
+    assert_eq!(info.free_bytes, memory_size - 4096);
+
+    unsafe { buddy.dealloc(ptr, 0) };
+
+    let info = buddy.fragmentation();
+    assert_eq!(info.free_blocks[10], 1);
+    assert_eq!(info.free_bytes, memory_size);
+
+    unsafe { dealloc(memory, layout) };
+}
+
+#[test]
+fn test_buddy_usage_statistics() {
+    // 1024 pages = exactly one order 10 block, with its own bitmap
+    let pages = 1024;
+    let bitmap: &'static mut [u8] = Box::leak(vec![0u8; bitmap_size(pages)].into_boxed_slice());
+    let mut buddy = BuddyAllocator::with_capacity(pages, bitmap).unwrap();
+    assert_eq!(buddy.total_bytes(), 0);
+    assert_eq!(buddy.free_bytes(), 0);
+    assert_eq!(buddy.free_blocks_per_order(), [0; MAX_ORDER]);
+
+    let memory_size = pages * PAGE_SIZE;
+    let layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(layout) };
+    buddy.set_offset(memory as usize);
+
+    for i in (0..memory_size).step_by(PAGE_SIZE) {
+        unsafe { buddy.add_frame(memory.add(i)) };
+    }
+    // Frames outside of the managed range don't count
+    unsafe { buddy.add_frame(memory.add(memory_size)) };
+
+    assert_eq!(buddy.total_bytes(), memory_size);
+    assert_eq!(buddy.free_bytes(), memory_size);
+    assert_eq!(buddy.allocated_bytes(), 0);
+    let mut expected = [0; MAX_ORDER];
+    expected[10] = 1;
+    assert_eq!(buddy.free_blocks_per_order(), expected);
+
+    // Splitting the order 10 block for an order 8 one leaves a free order 9 and order 8 buddy
+    let a = unsafe { buddy.alloc(8) }.unwrap();
+    expected[10] = 0;
+    expected[9] = 1;
+    expected[8] = 1;
+    assert_eq!(buddy.free_blocks_per_order(), expected);
+    assert_eq!(buddy.allocated_bytes(), 256 * PAGE_SIZE);
+
+    // The free order 8 buddy is taken without splitting anything
+    let b = unsafe { buddy.alloc(8) }.unwrap();
+    expected[8] = 0;
+    assert_eq!(buddy.free_blocks_per_order(), expected);
+
+    // An order 0 page splits the order 9 block all the way down
+    let c = unsafe { buddy.alloc(0) }.unwrap();
+    expected[9] = 0;
+    expected[..9].copy_from_slice(&[1; 9]);
+    assert_eq!(buddy.free_blocks_per_order(), expected);
+    assert_eq!(buddy.allocated_bytes(), 513 * PAGE_SIZE);
+    assert_eq!(buddy.free_bytes(), memory_size - 513 * PAGE_SIZE);
+
+    // Freeing `a` can't merge while `b` is allocated
+    unsafe { buddy.dealloc(a, 8) };
+    expected[8] = 2;
+    assert_eq!(buddy.free_blocks_per_order(), expected);
+
+    unsafe {
+        buddy.dealloc(c, 0);
+        buddy.dealloc(b, 8);
+    }
+    let mut coalesced = [0; MAX_ORDER];
+    coalesced[10] = 1;
+    assert_eq!(buddy.free_blocks_per_order(), coalesced);
+    assert_eq!(buddy.free_bytes(), memory_size);
+    assert_eq!(buddy.allocated_bytes(), 0);
+
+    unsafe { dealloc(memory, layout) };
+}
+
+#[test]
+fn test_buddy_order_for() {
+    // Up to a page is a single page
+    assert_eq!(order_for(1, 1), Some(0));
+    assert_eq!(order_for(PAGE_SIZE, 8), Some(0));
+
+    // Page counts round up to the next power of two
+    assert_eq!(order_for(PAGE_SIZE + 1, 8), Some(1));
+    assert_eq!(order_for(3 * PAGE_SIZE, 8), Some(2));
+    assert_eq!(order_for(40 * PAGE_SIZE, 8), Some(6));
+
+    // Blocks are aligned to their size, so big alignments need big blocks
+    assert_eq!(order_for(64, 4 * PAGE_SIZE), Some(2));
+
+    // The largest block is 2^11 pages = 8 MiB
+    assert_eq!(order_for(2048 * PAGE_SIZE, 8), Some(11));
+    assert_eq!(order_for(2048 * PAGE_SIZE + 1, 8), None);
+}
+
+#[test]
+fn test_buddy_multi_page_allocations() {
+    let _bitmap = BUDDY_BITMAP.lock().unwrap();
+    let mut buddy = BuddyAllocator::new();
+
+    // 4MB = 1024 pages = exactly one order 10 block
+    let memory_size = 4 * 1024 * 1024;
+    let layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(layout) };
+    buddy.set_offset(memory as usize);
+
+    for i in (0..memory_size).step_by(4096) {
+        unsafe { buddy.add_frame(memory.add(i)) };
+    }
+
+    for pages in [3, 40] {
+        let size = pages * PAGE_SIZE;
+        let order = order_for(size, 8).unwrap();
+        let ptr = unsafe { buddy.alloc(order) }.expect("Failed to alloc multi-page block");
+        assert_eq!(ptr as usize % ((1 << order) * PAGE_SIZE), 0);
+
+        // Every byte of the buffer is ours
+        let buffer = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        assert!(buffer.iter().enumerate().all(|(i, &byte)| byte == i as u8));
+
+        unsafe { buddy.dealloc(ptr, order_for(size, 8).unwrap()) };
+
+        // Freeing merges everything back into the one big block
+        let info = buddy.fragmentation();
+        assert_eq!(info.free_blocks[10], 1);
+        assert_eq!(info.free_bytes, memory_size);
+    }
+
+    unsafe { dealloc(memory, layout) };
+}
+
+#[test]
+fn test_buddy_with_capacity() {
+    // Just under one bit per page: 1GB needs a bit less than 32KiB, 4GB four times that
+    assert_eq!(bitmap_size(262_144), 32_752);
+    assert_eq!(bitmap_size(1 << 20), 131_008);
+
+    let too_small: &'static mut [u8] = Box::leak(vec![0u8; 16].into_boxed_slice());
+    assert!(BuddyAllocator::with_capacity(1 << 20, too_small).is_err());
+
+    // 4GB of pages, fed frames from 3GB up (outside of what `new` can track)
+    let bitmap: &'static mut [u8] = Box::leak(vec![0u8; bitmap_size(1 << 20)].into_boxed_slice());
+    let mut buddy = BuddyAllocator::with_capacity(1 << 20, bitmap).unwrap();
+
+    let memory_size = 4 * 1024 * 1024;
+    let layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(layout) };
+    let high = 3 * 1024 * 1024 * 1024usize;
+    let Some(offset) = (memory as usize).checked_sub(high) else {
+        println!("Skipping test_buddy_with_capacity: allocated memory is below 3GB");
+        unsafe { dealloc(memory, layout) };
+        return;
+    };
+    buddy.set_offset(offset);
+
+    let frame = buddy
+        .ptr_to_frame(memory)
+        .expect("High frame isn't tracked");
+    assert_eq!(frame.start_address().as_u64(), high as u64);
+
+    for i in (0..memory_size).step_by(4096) {
+        unsafe { buddy.add_frame(memory.add(i)) };
+    }
+    let info = buddy.fragmentation();
+    assert_eq!(info.free_blocks[10], 1);
+    assert_eq!(info.free_bytes, memory_size);
+
+    let ptr = unsafe { buddy.alloc(3) }.expect("Failed to alloc order 3");
+    assert!(ptr >= memory && ptr < unsafe { memory.add(memory_size) });
+    unsafe { buddy.dealloc(ptr, 3) };
+    assert_eq!(buddy.fragmentation().free_blocks[10], 1);
+
+    // Past the end of the 4GB is still out of range
+    assert_eq!(buddy.ptr_to_frame((offset + (4 << 30)) as *const u8), None);
+
+    unsafe { dealloc(memory, layout) };
+}
+
+#[test]
+fn test_buddy_exhaustive_alloc_and_coalesce() {
+    // 2^11 pages = exactly one top order block, with its own bitmap
+    let top = MAX_ORDER - 1;
+    let pages = 1 << top;
+    let bitmap: &'static mut [u8] = Box::leak(vec![0u8; bitmap_size(pages)].into_boxed_slice());
+    let mut buddy = BuddyAllocator::with_capacity(pages, bitmap).unwrap();
+
+    let memory_size = pages * PAGE_SIZE;
+    let layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(layout) };
+    buddy.set_offset(memory as usize);
+
+    for i in (0..memory_size).step_by(PAGE_SIZE) {
+        unsafe { buddy.add_frame(memory.add(i)) };
+    }
+
+    let assert_coalesced = |buddy: &BuddyAllocator| {
+        let info = buddy.fragmentation();
+        assert_eq!(info.free_blocks[top], 1);
+        assert_eq!(info.free_blocks.iter().sum::<usize>(), 1);
+        assert_eq!(info.free_bytes, memory_size);
+    };
+    assert_coalesced(&buddy);
+
+    for order in 0..MAX_ORDER {
+        let count = 1 << (top - order);
+        let block_size = (1 << order) * PAGE_SIZE;
+
+        // Forward, reverse, evens then odds, and a scramble (7 is coprime with every count)
+        let orders: [Box<dyn Fn(usize) -> usize>; 4] = [
+            Box::new(|i| i),
+            Box::new(move |i| count - 1 - i),
+            Box::new(move |i| {
+                if i < count.div_ceil(2) {
+                    i * 2
+                } else {
+                    (i - count.div_ceil(2)) * 2 + 1
+                }
+            }),
+            Box::new(move |i| (i * 7 + 3) % count),
+        ];
+
+        for free_order in &orders {
+            let mut blocks: Vec<*mut u8> = (0..count)
+                .map(|_| unsafe { buddy.alloc(order) }.expect("Failed to alloc a block"))
+                .collect();
+            assert_eq!(unsafe { buddy.alloc(order) }, None);
+            assert_eq!(buddy.fragmentation().free_bytes, 0);
+
+            // Every block is distinct, aligned and inside the memory
+            blocks.sort();
+            blocks.dedup();
+            assert_eq!(blocks.len(), count);
+            for &block in &blocks {
+                assert_eq!((block as usize - memory as usize) % block_size, 0);
+                assert!(block >= memory && block < unsafe { memory.add(memory_size) });
+            }
+
+            for i in 0..count {
+                unsafe { buddy.dealloc(blocks[free_order(i)], order) };
+            }
+            assert_coalesced(&buddy);
+        }
+    }
+
+    unsafe { dealloc(memory, layout) };
+}
+
+#[test]
+fn test_buddy_address_conversions() {
+    let mut buddy = BuddyAllocator::new();
+    let offset = 0xffff_8000_0000_0000usize;
+    buddy.set_offset(offset);
+
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(0x12_3000));
+    let ptr = buddy.frame_to_ptr(frame);
+    assert_eq!(ptr as usize, offset + 0x12_3000);
+    assert_eq!(buddy.ptr_to_frame(ptr), Some(frame));
+
+    // Pointers in the middle of a frame belong to that frame
+    assert_eq!(buddy.ptr_to_frame(ptr.wrapping_add(0x7ff)), Some(frame));
+
+    // Frame 0 sits right at the offset
+    let first = PhysFrame::containing_address(PhysAddr::new(0));
+    assert_eq!(buddy.frame_to_ptr(first) as usize, offset);
+    assert_eq!(buddy.ptr_to_frame(offset as *const u8), Some(first));
+
+    // Anything outside of the managed range has no frame
+    assert_eq!(buddy.ptr_to_frame((offset - 1) as *const u8), None);
+    assert_eq!(buddy.ptr_to_frame(core::ptr::null()), None);
+    assert_eq!(
+        buddy.ptr_to_frame((offset + 1024 * 1024 * 1024) as *const u8),
+        None
+    );
+}
+
 #[test]
 fn test_slub_allocator() {
     let mut provider = TestPageProvider::new();
@@ -153,3 +471,363 @@ fn test_slub_allocator_exhaustion() {
         unsafe { cache.dealloc(ptr, &mut provider) };
     }
 }
+
+#[test]
+fn test_slub_many_small_objects() {
+    let mut provider = TestPageProvider::new();
+    let mut cache = SCache::new(32);
+
+    let mut ptrs = Vec::new();
+    for i in 0..10_000u32 {
+        let ptr = cache.alloc(&mut provider).expect("Failed to alloc 32B");
+        unsafe { (ptr as *mut u32).write(i) };
+        ptrs.push(ptr);
+    }
+
+    // New slabs came from the provider as needed and no object was handed out twice
+    assert!(provider.allocated_pages.len() > 1);
+    for (i, &ptr) in ptrs.iter().enumerate() {
+        assert_eq!(unsafe { (ptr as *const u32).read() }, i as u32);
+    }
+
+    for ptr in ptrs {
+        unsafe { cache.dealloc(ptr, &mut provider) };
+    }
+    assert_eq!(cache.partial_slabs(), 0);
+    assert!(provider.allocated_pages.is_empty());
+}
+
+#[test]
+fn test_slub_reclaims_empty_slab_in_middle_of_partial_list() {
+    let mut provider = TestPageProvider::new();
+    // 3 objects per slab: the header pushes the first one to offset 1024
+    let mut cache = SCache::new(1024);
+
+    let ptrs: Vec<_> = (0..9)
+        .map(|_| cache.alloc(&mut provider).unwrap())
+        .collect();
+    assert_eq!(provider.allocated_pages.len(), 3);
+    assert_eq!(cache.partial_slabs(), 0);
+
+    // Free one object from each slab, all three end up on the partial list
+    let slabs: Vec<_> = ptrs.chunks(3).collect();
+    for slab in &slabs {
+        unsafe { cache.dealloc(slab[0], &mut provider) };
+    }
+    assert_eq!(cache.partial_slabs(), 3);
+
+    // Emptying the slab in the middle of the list gives its page back and keeps the others linked
+    unsafe {
+        cache.dealloc(slabs[1][1], &mut provider);
+        cache.dealloc(slabs[1][2], &mut provider);
+    }
+    assert_eq!(cache.partial_slabs(), 2);
+    assert_eq!(provider.allocated_pages.len(), 2);
+
+    for slab in [slabs[0], slabs[2]] {
+        unsafe {
+            cache.dealloc(slab[1], &mut provider);
+            cache.dealloc(slab[2], &mut provider);
+        }
+    }
+    assert_eq!(cache.partial_slabs(), 0);
+    assert!(provider.allocated_pages.is_empty());
+}
+
+#[test]
+fn test_slub_refilled_slab_is_linked_once() {
+    let mut provider = TestPageProvider::new();
+    // 3 objects per slab: the header pushes the first one to offset 1024
+    let mut cache = SCache::new(1024);
+
+    let ptrs: Vec<_> = (0..3)
+        .map(|_| cache.alloc(&mut provider).unwrap())
+        .collect();
+    assert_eq!(provider.allocated_pages.len(), 1);
+    assert_eq!(cache.partial_slabs(), 0);
+
+    // The first free puts the full slab back on the partial list, the second must not add it again
+    unsafe {
+        cache.dealloc(ptrs[0], &mut provider);
+        cache.dealloc(ptrs[2], &mut provider);
+    }
+    assert_eq!(cache.partial_slabs(), 1);
+
+    // Both freed objects come from that slab before a new page is needed
+    let again: Vec<_> = (0..2)
+        .map(|_| cache.alloc(&mut provider).unwrap())
+        .collect();
+    assert_eq!(provider.allocated_pages.len(), 1);
+    assert_eq!(cache.partial_slabs(), 0);
+
+    // Refilling and draining it again keeps the list consistent
+    unsafe {
+        cache.dealloc(again[0], &mut provider);
+        cache.dealloc(again[1], &mut provider);
+    }
+    assert_eq!(cache.partial_slabs(), 1);
+    unsafe { cache.dealloc(ptrs[1], &mut provider) };
+    assert_eq!(cache.partial_slabs(), 0);
+    assert!(provider.allocated_pages.is_empty());
+}
+
+#[test]
+fn test_slub_one_object_per_slab() {
+    let mut provider = TestPageProvider::new();
+    // The header pushes the first 2048-aligned object to the middle of the page, so only one fits
+    let mut cache = SCache::new(2048);
+
+    let mut ptrs = Vec::new();
+    for _ in 0..3 {
+        let ptr = cache.alloc(&mut provider).expect("Failed to alloc 2048B");
+        assert_eq!(ptr as usize % PAGE_SIZE, 2048);
+        ptrs.push(ptr);
+
+        // Full right after the first allocation, so never on the partial list
+        assert_eq!(cache.partial_slabs(), 0);
+    }
+    assert_eq!(provider.allocated_pages.len(), 3);
+
+    // Freeing the only object gives the page back instead of making the slab partial
+    unsafe { cache.dealloc(ptrs.pop().unwrap(), &mut provider) };
+    assert_eq!(cache.partial_slabs(), 0);
+    assert_eq!(provider.allocated_pages.len(), 2);
+
+    for ptr in ptrs {
+        unsafe { cache.dealloc(ptr, &mut provider) };
+    }
+    assert!(provider.allocated_pages.is_empty());
+}
+
+#[test]
+fn test_slub_non_power_of_two_size() {
+    let mut provider = TestPageProvider::new();
+    let mut cache = SCache::new(96);
+
+    // 40 byte header, objects are only 8 byte aligned: (4096 - 40) / 96 = 42 per slab
+    let per_slab = 42;
+
+    let mut ptrs = Vec::new();
+    for i in 0..per_slab {
+        let ptr = cache.alloc(&mut provider).expect("Failed to alloc 96B");
+        assert_eq!(ptr as usize % 8, 0);
+        if let Some(&previous) = ptrs.last() {
+            assert_eq!(ptr as usize, previous as usize + 96);
+        }
+        ptrs.push(ptr);
+
+        // Partial until the last object is handed out
+        let expected_partial = if i + 1 < per_slab { 1 } else { 0 };
+        assert_eq!(cache.partial_slabs(), expected_partial);
+    }
+
+    // All objects fit in the page after the header
+    let page = ptrs[0] as usize & !(PAGE_SIZE - 1);
+    assert!(
+        ptrs.iter()
+            .all(|&ptr| ptr as usize & !(PAGE_SIZE - 1) == page)
+    );
+    assert!(ptrs[per_slab - 1] as usize + 96 <= page + PAGE_SIZE);
+    assert_eq!(provider.allocated_pages.len(), 1);
+
+    // The next object needs a new slab, which stays partial
+    let extra = cache.alloc(&mut provider).unwrap();
+    assert_eq!(provider.allocated_pages.len(), 2);
+    assert_eq!(cache.partial_slabs(), 1);
+
+    // Freeing from the full slab puts it back on the partial list
+    unsafe { cache.dealloc(ptrs.pop().unwrap(), &mut provider) };
+    assert_eq!(cache.partial_slabs(), 2);
+
+    unsafe { cache.dealloc(extra, &mut provider) };
+    for ptr in ptrs {
+        unsafe { cache.dealloc(ptr, &mut provider) };
+    }
+    assert_eq!(cache.partial_slabs(), 0);
+    assert!(provider.allocated_pages.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "into the 64 byte cache")]
+fn test_slub_dealloc_into_wrong_cache() {
+    let mut provider = TestPageProvider::new();
+    let mut small = SCache::new(32);
+    let mut wrong = SCache::new(64);
+
+    let ptr = small.alloc(&mut provider).unwrap();
+    unsafe { wrong.dealloc(ptr, &mut provider) };
+}
+
+#[test]
+fn test_size_class() {
+    let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+
+    assert_eq!(SizeClass::of(layout(1, 1)), Some(SizeClass::Slab(0)));
+    assert_eq!(SizeClass::of(layout(16, 8)), Some(SizeClass::Slab(0)));
+    assert_eq!(SizeClass::of(layout(17, 8)), Some(SizeClass::Slab(1)));
+    assert_eq!(SizeClass::of(layout(100, 8)), Some(SizeClass::Slab(3)));
+    assert_eq!(SizeClass::of(layout(2048, 8)), Some(SizeClass::Slab(7)));
+
+    // Over-aligned layouts go to a cache whose objects are aligned enough
+    assert_eq!(SizeClass::of(layout(8, 256)), Some(SizeClass::Slab(4)));
+    assert_eq!(SizeClass::of(layout(8, 2048)), Some(SizeClass::Slab(7)));
+
+    // Past the biggest cache they get a block aligned to its size
+    assert_eq!(SizeClass::of(layout(8, 4096)), Some(SizeClass::Pages(0)));
+    assert_eq!(
+        SizeClass::of(layout(8, 4 * PAGE_SIZE)),
+        Some(SizeClass::Pages(2))
+    );
+
+    assert_eq!(SizeClass::of(layout(2049, 8)), Some(SizeClass::Pages(0)));
+    assert_eq!(
+        SizeClass::of(layout(3 * PAGE_SIZE, 8)),
+        Some(SizeClass::Pages(2))
+    );
+    assert_eq!(SizeClass::of(layout(64 * 1024 * 1024, 8)), None);
+}
+
+/// Run `f` with a SLUB allocator whose global page allocator is backed by 4MB of host memory
+/// Checks that everything went back to the buddy allocator afterwards.
+fn with_test_heap(f: impl FnOnce(&SlubAllocator)) {
+    let _bitmap = BUDDY_BITMAP.lock().unwrap();
+
+    let memory_size = 4 * 1024 * 1024;
+    let memory_layout = Layout::from_size_align(memory_size, memory_size).unwrap();
+    let memory = unsafe { alloc(memory_layout) };
+    let pages = memory_size / PAGE_SIZE;
+    let bitmap: &'static mut [u8] = Box::leak(vec![0u8; bitmap_size(pages)].into_boxed_slice());
+    allocator::init_heap(memory as usize, pages, bitmap).unwrap();
+    for i in (0..memory_size).step_by(4096) {
+        unsafe { allocator::add_frame(memory.add(i)) };
+    }
+
+    f(&SlubAllocator::new());
+
+    let info = allocator::fragmentation().unwrap();
+    assert_eq!(info.free_bytes, memory_size);
+
+    unsafe { dealloc(memory, memory_layout) };
+}
+
+#[test]
+fn test_slub_realloc() {
+    with_test_heap(|slub| unsafe {
+        // Grow like a Vec<u32> does, doubling the capacity from 16 to over 2000 elements
+        let mut capacity = 16;
+        let mut layout = Layout::array::<u32>(capacity).unwrap();
+        let mut ptr = slub.alloc(layout) as *mut u32;
+        assert!(!ptr.is_null());
+
+        let mut len = 0;
+        while len < 2000 {
+            if len == capacity {
+                capacity *= 2;
+                let new_size = capacity * size_of::<u32>();
+                ptr = slub.realloc(ptr as *mut u8, layout, new_size) as *mut u32;
+                assert!(!ptr.is_null());
+                layout = Layout::array::<u32>(capacity).unwrap();
+            }
+            ptr.add(len).write(len as u32);
+            len += 1;
+        }
+        assert!((0..len).all(|i| ptr.add(i).read() == i as u32));
+        slub.dealloc(ptr as *mut u8, layout);
+
+        // Shrinking (or growing) within the same size class keeps the block
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = slub.alloc(layout);
+        ptr.write_bytes(0xAB, 100);
+        assert_eq!(slub.realloc(ptr, layout, 80), ptr);
+        assert_eq!(
+            slub.realloc(ptr, Layout::from_size_align(80, 8).unwrap(), 128),
+            ptr
+        );
+
+        // Moving to another class copies what fits
+        let moved = slub.realloc(ptr, Layout::from_size_align(128, 8).unwrap(), 40);
+        assert_ne!(moved, ptr);
+        assert!((0..40).all(|i| moved.add(i).read() == 0xAB));
+        slub.dealloc(moved, Layout::from_size_align(40, 8).unwrap());
+    });
+}
+
+#[test]
+fn test_slub_alloc_zeroed() {
+    with_test_heap(|slub| unsafe {
+        for size in [48, 3 * PAGE_SIZE] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+
+            // Dirty a block and give it back
+            let dirty = slub.alloc(layout);
+            dirty.write_bytes(0xFF, size);
+            slub.dealloc(dirty, layout);
+
+            // Slots and blocks are reused right away, so this is the same memory
+            let ptr = slub.alloc_zeroed(layout);
+            assert_eq!(ptr, dirty);
+            assert!((0..size).all(|i| ptr.add(i).read() == 0));
+            slub.dealloc(ptr, layout);
+        }
+    });
+}
+
+#[test]
+fn test_slub_over_aligned_allocations() {
+    #[repr(align(256))]
+    struct Aligned([u8; 24]);
+
+    with_test_heap(|slub| unsafe {
+        // Enough of them to fill a couple of slabs, every slot has to be aligned
+        let layout = Layout::new::<Aligned>();
+        let ptrs: Vec<_> = (0..40).map(|_| slub.alloc(layout)).collect();
+        for &ptr in &ptrs {
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 256, 0);
+            (ptr as *mut Aligned).write(Aligned([0xAB; 24]));
+        }
+        for ptr in ptrs {
+            assert_eq!((*(ptr as *const Aligned)).0, [0xAB; 24]);
+            slub.dealloc(ptr, layout);
+        }
+
+        // Alignments past the biggest cache come from page aligned buddy blocks
+        for align in [4096, 4 * PAGE_SIZE] {
+            let layout = Layout::from_size_align(64, align).unwrap();
+            let ptr = slub.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            slub.dealloc(ptr, layout);
+        }
+    });
+}
+
+#[test]
+fn test_slub_falls_back_to_the_emergency_arena() {
+    with_test_heap(|slub| unsafe {
+        // One block takes the whole 4MB heap
+        let all = Layout::from_size_align(4 * 1024 * 1024, 8).unwrap();
+        let heap = slub.alloc(all);
+        assert!(!heap.is_null());
+
+        let (allocations, failures) = allocator::emergency_allocations();
+        let small = Layout::from_size_align(64, 8).unwrap();
+        assert!(!slub.alloc(small).is_null());
+        assert_eq!(
+            allocator::emergency_allocations(),
+            (allocations + 1, failures)
+        );
+
+        // Multi-page allocations don't fall back
+        assert!(
+            slub.alloc(Layout::from_size_align(8192, 8).unwrap())
+                .is_null()
+        );
+        assert_eq!(
+            allocator::emergency_allocations(),
+            (allocations + 1, failures)
+        );
+
+        slub.dealloc(heap, all);
+    });
+}
@@ -0,0 +1,51 @@
+use kernel::boot::{Stage, StageError, run};
+
+/// Records which stages ran
+type Log = Vec<&'static str>;
+
+fn first(log: &mut Log) -> Result<(), &'static str> {
+    log.push("first");
+    Ok(())
+}
+
+fn second(log: &mut Log) -> Result<(), &'static str> {
+    log.push("second");
+    Ok(())
+}
+
+fn broken(log: &mut Log) -> Result<(), &'static str> {
+    log.push("broken");
+    Err("out of cheese")
+}
+
+#[test]
+fn test_boot_stages_run_in_order() {
+    let mut log = Log::new();
+    let stages = [Stage::new("first", first), Stage::new("second", second)];
+
+    assert_eq!(run(&mut log, &stages), Ok(()));
+    assert_eq!(log, ["first", "second"]);
+}
+
+#[test]
+fn test_boot_stops_at_failed_stage() {
+    let mut log = Log::new();
+    let stages = [
+        Stage::new("first", first),
+        Stage::new("broken", broken),
+        Stage::new("second", second),
+    ];
+
+    let error = run(&mut log, &stages).unwrap_err();
+    assert_eq!(
+        error,
+        StageError {
+            stage: "broken",
+            error: "out of cheese"
+        }
+    );
+    assert_eq!(error.to_string(), "boot stage broken failed: out of cheese");
+
+    // Nothing after the broken stage ran
+    assert_eq!(log, ["first", "broken"]);
+}
@@ -0,0 +1,106 @@
+use std::fmt::Write;
+
+use bootloader_api::info::PixelFormat;
+use kernel::graphics::Framebuffer;
+use kernel::graphics::console::Console;
+use kernel::graphics::font::{FONT, Font, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+/// 4 columns and 3 rows of text, with some padding on every line
+const WIDTH: usize = 4 * GLYPH_WIDTH;
+const HEIGHT: usize = 3 * GLYPH_HEIGHT;
+const STRIDE: usize = WIDTH + 8;
+
+const WHITE: u32 = 0xFFFFFF;
+const BLACK: u32 = 0x000000;
+
+struct TestScreen {
+    front: Vec<u32>,
+    back: Vec<u32>,
+}
+
+impl TestScreen {
+    fn new() -> Self {
+        Self {
+            front: vec![0; STRIDE * HEIGHT],
+            back: vec![0xDEADBEEF; STRIDE * HEIGHT],
+        }
+    }
+
+    fn console(&mut self) -> Console {
+        let framebuffer = unsafe {
+            Framebuffer::from_raw_parts(
+                self.front.as_mut_ptr(),
+                self.back.as_mut_ptr(),
+                WIDTH,
+                HEIGHT,
+                STRIDE,
+                PixelFormat::Bgr,
+            )
+        };
+        Console::new(framebuffer)
+    }
+
+    /// Whether the cell at (column, row) shows `ch` in white on black
+    fn shows(&self, column: usize, row: usize, ch: char) -> bool {
+        let glyph = FONT.glyph(ch);
+        (0..GLYPH_HEIGHT).all(|y| {
+            (0..GLYPH_WIDTH).all(|x| {
+                let expected = if Font::is_set(glyph, x, y) {
+                    WHITE
+                } else {
+                    BLACK
+                };
+                let pixel_y = row * GLYPH_HEIGHT + y;
+                self.back[pixel_y * STRIDE + column * GLYPH_WIDTH + x] == expected
+            })
+        })
+    }
+}
+
+#[test]
+fn test_console_renders_text_at_the_origin() {
+    let mut screen = TestScreen::new();
+    let mut console = screen.console();
+
+    write!(console, "Hi").unwrap();
+    assert_eq!(console.cursor(), (2, 0));
+
+    assert!(screen.shows(0, 0, 'H'));
+    assert!(screen.shows(1, 0, 'i'));
+    assert!(screen.shows(2, 0, ' '));
+}
+
+#[test]
+fn test_console_wraps_and_handles_line_breaks() {
+    let mut screen = TestScreen::new();
+    let mut console = screen.console();
+    assert_eq!((console.columns(), console.rows()), (4, 3));
+
+    // The fifth character wraps to the next line
+    write!(console, "abcde").unwrap();
+    assert_eq!(console.cursor(), (1, 1));
+
+    write!(console, "\nxy\rZ").unwrap();
+    assert_eq!(console.cursor(), (1, 2));
+
+    assert!(screen.shows(3, 0, 'd'));
+    assert!(screen.shows(0, 1, 'e'));
+    // '\r' went back to the start of the line, 'Z' replaced 'x'
+    assert!(screen.shows(0, 2, 'Z'));
+    assert!(screen.shows(1, 2, 'y'));
+}
+
+#[test]
+fn test_console_scrolls_when_full() {
+    let mut screen = TestScreen::new();
+    let mut console = screen.console();
+
+    write!(console, "1\n2\n3\n4").unwrap();
+    assert_eq!(console.cursor(), (1, 2));
+
+    // The first line scrolled off the top and the new bottom line was cleared
+    assert!(screen.shows(0, 0, '2'));
+    assert!(screen.shows(0, 1, '3'));
+    assert!(screen.shows(0, 2, '4'));
+    assert!(screen.shows(1, 2, ' '));
+}
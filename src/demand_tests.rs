@@ -0,0 +1,174 @@
+use kernel::mm::demand::{DemandRegions, Error, resolve_fault};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags as Flags,
+            PhysFrame, Size4KiB, Translate, mapper::TranslateResult,
+        },
+    },
+};
+
+const PAGE_SIZE: usize = 4096;
+
+#[repr(C, align(4096))]
+struct Frame([u8; PAGE_SIZE]);
+
+/// Hands out heap allocated frames full of junk, with the physical memory mapped at offset 0
+#[derive(Default)]
+struct TestFrames {
+    frames: Vec<Box<Frame>>,
+    freed: Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for TestFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = Box::new(Frame([0xAA; PAGE_SIZE]));
+        let addr = PhysAddr::new(frame.0.as_ptr() as u64);
+        self.frames.push(frame);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for TestFrames {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.freed.push(frame);
+    }
+}
+
+const HEAP: u64 = 0x4000_0000;
+
+fn not_present(write: bool) -> PageFaultErrorCode {
+    let mut code = PageFaultErrorCode::USER_MODE;
+    if write {
+        code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+    }
+    code
+}
+
+#[test]
+fn test_demand_fault_maps_a_zeroed_page() {
+    let mut l4 = Box::new(PageTable::new());
+    let mut mapper = unsafe { OffsetPageTable::new(&mut l4, VirtAddr::new(0)) };
+    let mut frames = TestFrames::default();
+
+    let mut regions = DemandRegions::new();
+    regions
+        .register(VirtAddr::new(HEAP), 4, Flags::WRITABLE | Flags::NO_EXECUTE)
+        .unwrap();
+
+    let addr = VirtAddr::new(HEAP + 0x1234);
+    assert_eq!(mapper.translate_addr(addr), None);
+
+    // A page that wasn't mapped can't be in the TLB, and flushing would need ring 0
+    resolve_fault(&regions, &mut mapper, &mut frames, addr, not_present(true))
+        .unwrap()
+        .ignore();
+
+    let phys = mapper.translate_addr(addr).unwrap();
+    let page =
+        unsafe { std::slice::from_raw_parts((phys.as_u64() & !0xFFF) as *const u8, PAGE_SIZE) };
+    assert!(page.iter().all(|&byte| byte == 0));
+
+    // Only the page that was touched is mapped, as a user page with the region's flags
+    assert_eq!(mapper.translate_addr(addr + 0x1000u64), None);
+    let flags = match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("page isn't mapped"),
+    };
+    assert!(
+        flags.contains(
+            Flags::PRESENT | Flags::USER_ACCESSIBLE | Flags::WRITABLE | Flags::NO_EXECUTE
+        )
+    );
+    assert!(frames.freed.is_empty());
+}
+
+#[test]
+fn test_illegal_faults_are_not_resolved() {
+    let mut l4 = Box::new(PageTable::new());
+    let mut mapper = unsafe { OffsetPageTable::new(&mut l4, VirtAddr::new(0)) };
+    let mut frames = TestFrames::default();
+
+    let mut regions = DemandRegions::new();
+    regions
+        .register(VirtAddr::new(HEAP), 1, Flags::NO_EXECUTE)
+        .unwrap();
+    let addr = VirtAddr::new(HEAP);
+
+    let mut resolve = |addr: VirtAddr, code: PageFaultErrorCode| {
+        resolve_fault(&regions, &mut mapper, &mut frames, addr, code).map(|flush| flush.ignore())
+    };
+
+    // Outside of every region
+    assert_eq!(
+        resolve(VirtAddr::new(HEAP + 0x1000), not_present(false)),
+        Err(Error::NotDemandPaged)
+    );
+    // The page is there, the access just isn't allowed
+    assert_eq!(
+        resolve(
+            addr,
+            not_present(false) | PageFaultErrorCode::PROTECTION_VIOLATION
+        ),
+        Err(Error::ProtectionViolation)
+    );
+    // Read-only and not executable regions
+    assert_eq!(
+        resolve(addr, not_present(true)),
+        Err(Error::ProtectionViolation)
+    );
+    assert_eq!(
+        resolve(
+            addr,
+            not_present(false) | PageFaultErrorCode::INSTRUCTION_FETCH
+        ),
+        Err(Error::ProtectionViolation)
+    );
+
+    // Nothing was allocated for any of them
+    assert!(frames.frames.is_empty());
+    assert_eq!(mapper.translate_addr(addr), None);
+}
+
+#[test]
+fn test_demand_region_registration() {
+    let mut regions = DemandRegions::new();
+    let flags = Flags::WRITABLE;
+
+    assert_eq!(
+        regions.register(VirtAddr::new(HEAP + 8), 1, flags),
+        Err(Error::InvalidRegion)
+    );
+    assert_eq!(
+        regions.register(VirtAddr::new(HEAP), 0, flags),
+        Err(Error::InvalidRegion)
+    );
+    // Kernel space can't be demand paged
+    assert_eq!(
+        regions.register(VirtAddr::new(0x7FFF_FFFF_F000), 2, flags),
+        Err(Error::InvalidRegion)
+    );
+
+    regions.register(VirtAddr::new(HEAP), 4, flags).unwrap();
+    assert_eq!(
+        regions.register(VirtAddr::new(HEAP + 0x3000), 1, flags),
+        Err(Error::Overlap)
+    );
+    regions
+        .register(VirtAddr::new(HEAP + 0x4000), 1, flags)
+        .unwrap();
+
+    let region = regions.find(VirtAddr::new(HEAP + 0x3FFF)).unwrap();
+    assert_eq!(region.start, VirtAddr::new(HEAP));
+    assert!(
+        region
+            .flags
+            .contains(Flags::PRESENT | Flags::USER_ACCESSIBLE)
+    );
+
+    assert!(regions.unregister(VirtAddr::new(HEAP)).is_some());
+    assert!(regions.find(VirtAddr::new(HEAP + 0x3FFF)).is_none());
+    assert_eq!(regions.iter().count(), 1);
+}
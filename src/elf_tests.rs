@@ -0,0 +1,395 @@
+use kernel::mm::address_space::new_address_space;
+use kernel::mm::memory::translate_addr_in;
+use kernel::tasks::elf::{self, Error, PF_R, PF_W, PF_X, Segment};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+    PageTableFlags as Flags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+fn segment(vaddr: u64, memsz: u64, filesz: u64, offset: u64) -> Segment {
+    Segment {
+        vaddr,
+        memsz,
+        filesz,
+        offset,
+        flags: PF_R,
+    }
+}
+
+#[test]
+fn test_empty_segment_maps_nothing() {
+    let empty = segment(0x401234, 0, 0, 0x1000);
+    assert!(empty.validate(0x1000).is_ok());
+    assert!(empty.pages().is_empty());
+}
+
+#[test]
+fn test_bss_only_segment_is_only_zeroed() {
+    // Starts in the middle of a page and spills into the next one
+    let bss = segment(0x402800, 0x1000, 0, 0x5000);
+    assert!(bss.validate(0x100).is_ok());
+
+    let pages: Vec<u64> = bss.pages().step_by(4096).collect();
+    assert_eq!(pages, vec![0x402000, 0x403000]);
+
+    // Nothing is copied, even though the file offset is past the end of the data
+    for page in pages {
+        assert_eq!(bss.file_bytes_in_page(page), None);
+    }
+}
+
+#[test]
+fn test_segment_file_data_split_over_pages() {
+    // 0x1800 bytes of data followed by BSS, starting 0x100 into a page
+    let data = segment(0x400100, 0x3000, 0x1800, 0x100);
+    assert!(data.validate(0x1900).is_ok());
+
+    let pages: Vec<u64> = data.pages().step_by(4096).collect();
+    assert_eq!(pages, vec![0x400000, 0x401000, 0x402000, 0x403000]);
+
+    assert_eq!(
+        data.file_bytes_in_page(0x400000),
+        Some((0x100, 0x100..0x1000))
+    );
+    assert_eq!(data.file_bytes_in_page(0x401000), Some((0, 0x1000..0x1900)));
+    assert_eq!(data.file_bytes_in_page(0x402000), None);
+    assert_eq!(data.file_bytes_in_page(0x403000), None);
+}
+
+#[test]
+fn test_invalid_segments_are_rejected() {
+    // File data past the end of the ELF
+    assert!(
+        segment(0x400000, 0x1000, 0x1000, 0x800)
+            .validate(0x1000)
+            .is_err()
+    );
+    // More file data than memory
+    assert!(segment(0x400000, 0x10, 0x20, 0).validate(0x1000).is_err());
+    // Wraps around the address space
+    assert!(
+        segment(u64::MAX - 0xFFF, 0x2000, 0, 0)
+            .validate(0x1000)
+            .is_err()
+    );
+    // Offset + size overflows
+    assert!(
+        segment(0x400000, 0x10, 0x10, u64::MAX)
+            .validate(0x1000)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_segment_page_flags_follow_segment_flags() {
+    let with_flags = |flags| Segment {
+        flags,
+        ..segment(0x400000, 0x1000, 0x1000, 0)
+    };
+    let user = Flags::PRESENT | Flags::USER_ACCESSIBLE;
+
+    // Read-only data: neither writable nor executable
+    assert_eq!(with_flags(PF_R).page_flags(), user | Flags::NO_EXECUTE);
+    // Code: executable but never writable
+    assert_eq!(with_flags(PF_R | PF_X).page_flags(), user);
+    // Data and BSS: writable but never executable
+    assert_eq!(
+        with_flags(PF_R | PF_W).page_flags(),
+        user | Flags::WRITABLE | Flags::NO_EXECUTE
+    );
+}
+
+/// Program header for `build_elf`: (flags, file offset, vaddr, filesz, memsz)
+type Phdr = (u32, u64, u64, u64, u64);
+
+/// Build a minimal ELF64 image: the header, then the program headers, then `body`
+fn build_elf(entry: u64, phdrs: &[Phdr], body: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 64];
+    data[0..4].copy_from_slice(b"\x7fELF");
+    data[4] = 2; // 64-bit
+    data[5] = 1; // little endian
+    data[6] = 1; // version
+    data[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    data[18..20].copy_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    data[24..32].copy_from_slice(&entry.to_le_bytes());
+    data[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+    data[52..54].copy_from_slice(&64u16.to_le_bytes()); // e_ehsize
+    data[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+    data[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+
+    for &(flags, offset, vaddr, filesz, memsz) in phdrs {
+        let mut ph = vec![0u8; 56];
+        ph[0..4].copy_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        ph[4..8].copy_from_slice(&flags.to_le_bytes());
+        ph[8..16].copy_from_slice(&offset.to_le_bytes());
+        ph[16..24].copy_from_slice(&vaddr.to_le_bytes());
+        ph[24..32].copy_from_slice(&vaddr.to_le_bytes());
+        ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+        ph[40..48].copy_from_slice(&memsz.to_le_bytes());
+        ph[48..56].copy_from_slice(&0x1000u64.to_le_bytes());
+        data.extend_from_slice(&ph);
+    }
+
+    data.extend_from_slice(body);
+    data
+}
+
+/// Where `build_elf` puts the body with two program headers
+const BODY: u64 = 64 + 2 * 56;
+
+/// Code at 0x400000 and read-only data at 0x401000, both from the body
+fn valid_phdrs() -> [Phdr; 2] {
+    [
+        (PF_R | PF_X, BODY, 0x400000, 0x20, 0x20),
+        (PF_R, BODY + 0x20, 0x401000, 0x10, 0x10),
+    ]
+}
+
+fn assert_invalid(data: &[u8]) {
+    let result = elf::parse(data);
+    assert!(
+        matches!(result, Err(Error::InvalidElf(_))),
+        "expected InvalidElf, got {:?}",
+        result
+    );
+}
+
+#[test]
+fn test_parse_valid_elf() {
+    let data = build_elf(0x400010, &valid_phdrs(), &[0xCC; 0x30]);
+
+    let image = elf::parse(&data).unwrap();
+    assert_eq!(image.entry, 0x400010);
+    assert_eq!(image.segments.len(), 2);
+    assert_eq!(image.segments[1].vaddr, 0x401000);
+    assert_eq!(image.segments[1].flags, PF_R);
+}
+
+#[test]
+fn test_parse_rejects_truncated_elfs() {
+    let data = build_elf(0x400010, &valid_phdrs(), &[0xCC; 0x30]);
+
+    // Cut off in the header, in the program headers and in the segment data
+    for len in [0, 10, 63, 64, 100, 64 + 56 + 10, BODY as usize + 0x28] {
+        assert_invalid(&data[..len]);
+    }
+    assert!(elf::parse(&data).is_ok());
+
+    // Not an ELF at all
+    assert_invalid(&[0u8; 200]);
+}
+
+#[test]
+fn test_parse_rejects_bad_segments() {
+    let body = [0xCC; 0x100];
+
+    // Segments sharing a page
+    let overlapping = [
+        (PF_R | PF_X, BODY, 0x400000, 0x20, 0x20),
+        (PF_R | PF_W, BODY + 0x20, 0x400800, 0x10, 0x1000),
+    ];
+    assert_invalid(&build_elf(0x400010, &overlapping, &body));
+
+    // More file data than memory
+    assert_invalid(&build_elf(
+        0x400010,
+        &[(PF_R | PF_X, 0x100, 0x400000, 0x20, 0x10)],
+        &body,
+    ));
+
+    // Wraps around the address space
+    assert_invalid(&build_elf(
+        u64::MAX - 0x10,
+        &[(PF_R | PF_X, 0x100, u64::MAX - 0xFFF, 0x20, 0x2000)],
+        &body,
+    ));
+
+    // Mapped into kernel space
+    assert_invalid(&build_elf(
+        0xFFFF_8000_0000_0010,
+        &[(PF_R | PF_X, 0x100, 0xFFFF_8000_0000_0000, 0x20, 0x20)],
+        &body,
+    ));
+
+    // Program header table offset overflowing
+    let mut data = build_elf(0x400010, &valid_phdrs(), &body);
+    data[32..40].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_invalid(&data);
+}
+
+#[test]
+fn test_parse_rejects_entry_outside_of_code() {
+    let body = [0xCC; 0x100];
+
+    // In the read-only data segment, past the end of the code and nowhere at all
+    for entry in [0x401000, 0x400020, 0x900000] {
+        assert_invalid(&build_elf(entry, &valid_phdrs(), &body));
+    }
+}
+
+#[test]
+fn test_parse_embedded_hello_world() {
+    let data = include_bytes!("../kernel/src/resources/hello_world.elf");
+    let image = elf::parse(data).unwrap();
+    assert!(!image.segments.is_empty());
+}
+
+#[test]
+fn test_initial_stack_layout() {
+    let top = 0x7FFFFF000;
+    let (image, sp) = elf::initial_stack(&["hello", "world!"], top).unwrap();
+
+    assert_eq!(sp % 16, 0);
+    assert_eq!(sp + image.len() as u64, top);
+
+    let word = |addr: u64| {
+        let offset = (addr - sp) as usize;
+        u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+    };
+    let string = |addr: u64| {
+        let bytes = &image[(addr - sp) as usize..];
+        let len = bytes.iter().position(|&b| b == 0).unwrap();
+        std::str::from_utf8(&bytes[..len]).unwrap().to_string()
+    };
+
+    // argc, argv[0], argv[1], null, envp null, AT_NULL
+    assert_eq!(word(sp), 2);
+    assert_eq!(string(word(sp + 8)), "hello");
+    assert_eq!(string(word(sp + 16)), "world!");
+    assert_eq!(word(sp + 24), 0);
+    assert_eq!(word(sp + 32), 0);
+    assert_eq!(word(sp + 40), 0);
+    assert_eq!(word(sp + 48), 0);
+
+    // The strings are at the very top
+    assert_eq!(word(sp + 16) + "world!".len() as u64 + 1, top);
+}
+
+#[test]
+fn test_initial_stack_without_args() {
+    let (image, sp) = elf::initial_stack(&[], 0x800000).unwrap();
+    assert_eq!(sp % 16, 0);
+    assert!(image.iter().all(|&b| b == 0));
+    assert_eq!(image.len(), 48);
+}
+
+#[test]
+fn test_initial_stack_rejects_huge_args() {
+    let huge = "x".repeat(elf::MAX_ARGS_SIZE as usize);
+    assert!(matches!(
+        elf::initial_stack(&[&huge], 0x800000),
+        Err(Error::MappingFailed(_))
+    ));
+}
+
+#[test]
+fn test_stack_range_ends_at_the_stack_top() {
+    let data = build_elf(0x400010, &valid_phdrs(), &[0xCC; 0x30]);
+    let image = elf::parse(&data).unwrap();
+
+    let stack = elf::stack_range(&image, elf::USER_STACK_PAGES).unwrap();
+    assert_eq!(stack.end, elf::USER_STACK_TOP);
+    assert_eq!(stack.end - stack.start, elf::USER_STACK_SIZE);
+    assert_eq!(stack.start % 4096, 0);
+
+    let small = elf::stack_range(&image, 8).unwrap();
+    assert_eq!(small, elf::USER_STACK_TOP - 8 * 4096..elf::USER_STACK_TOP);
+
+    assert!(elf::stack_range(&image, 0).is_err());
+    assert!(elf::stack_range(&image, u64::MAX).is_err());
+}
+
+#[test]
+fn test_stack_range_keeps_the_guard_page_free() {
+    let data = build_elf(0x400010, &valid_phdrs(), &[0xCC; 0x30]);
+    let image = elf::parse(&data).unwrap();
+
+    // The heap can grow right up to the guard page, but not into it
+    let heap_end = 0x402000 + elf::USER_HEAP_MAX_PAGES * 4096;
+    let largest = (elf::USER_STACK_TOP - heap_end) / 4096 - 1;
+
+    let stack = elf::stack_range(&image, largest).unwrap();
+    assert_eq!(stack.start, heap_end + 4096);
+    assert!(matches!(
+        elf::stack_range(&image, largest + 1),
+        Err(Error::InvalidElf(_))
+    ));
+}
+
+#[repr(C, align(4096))]
+struct Frame([u8; 4096]);
+
+/// Hands out heap allocated frames until `budget` runs out, the physical memory is "mapped" at offset 0
+#[derive(Default)]
+struct LimitedFrames {
+    frames: Vec<Box<Frame>>,
+    budget: usize,
+    in_use: usize,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for LimitedFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.budget = self.budget.checked_sub(1)?;
+        self.in_use += 1;
+
+        let frame = Box::new(Frame([0xAA; 4096]));
+        let addr = PhysAddr::new(frame.0.as_ptr() as u64);
+        self.frames.push(frame);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for LimitedFrames {
+    unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {
+        self.in_use -= 1;
+    }
+}
+
+#[test]
+fn test_failed_load_frees_the_pages_it_mapped() {
+    let offset = VirtAddr::new(0);
+    let mut frames = LimitedFrames {
+        budget: usize::MAX,
+        ..Default::default()
+    };
+
+    let kernel = frames.allocate_frame().unwrap();
+    let space = unsafe { new_address_space(kernel, offset, &mut frames) }.unwrap();
+    let table = space.start_address().as_u64() as *mut PageTable;
+    let mut mapper = unsafe { OffsetPageTable::new(&mut *table, offset) };
+
+    // Something else next to the segments, so the loader needs no new page tables
+    let other = Page::<Size4KiB>::containing_address(VirtAddr::new(0x5ff000));
+    let other_frame = frames.allocate_frame().unwrap();
+    unsafe { mapper.map_to(other, other_frame, Flags::PRESENT, &mut frames) }
+        .unwrap()
+        .ignore();
+
+    // Data at 0x400000 and code at 0x401000, there's only a frame left for the data
+    let phdrs = [
+        (PF_R | PF_W, BODY, 0x400000, 0x20, 0x20),
+        (PF_R | PF_X, BODY + 0x20, 0x401000, 0x10, 0x10),
+    ];
+    let data = build_elf(0x401000, &phdrs, &[0xCC; 0x30]);
+    let in_use = frames.in_use;
+    frames.budget = 1;
+
+    let image = elf::parse(&data).unwrap();
+    let result = elf::map_image(
+        &image,
+        &data,
+        &["test"],
+        4,
+        &mut mapper,
+        &mut frames,
+        offset,
+    );
+    assert!(matches!(result, Err(Error::MappingFailed(_))));
+
+    // The data page was mapped and given back, the page tables are still the address space's
+    assert_eq!(frames.budget, 0);
+    assert_eq!(frames.in_use, in_use);
+    assert!(unsafe { translate_addr_in(space, VirtAddr::new(0x400000), offset) }.is_none());
+    assert!(unsafe { translate_addr_in(space, VirtAddr::new(0x5ff000), offset) }.is_some());
+}
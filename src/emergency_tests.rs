@@ -0,0 +1,37 @@
+use std::alloc::Layout;
+
+use kernel::mm::emergency::EmergencyArena;
+
+#[test]
+fn test_emergency_arena() {
+    let arena = Box::new(EmergencyArena::<1024>::new());
+
+    let a = arena.alloc(Layout::from_size_align(3, 1).unwrap());
+    let b = arena.alloc(Layout::from_size_align(64, 16).unwrap());
+    assert!(!a.is_null() && !b.is_null());
+    assert_eq!(b as usize % 16, 0);
+    assert!(b as usize >= a as usize + 3);
+    assert!(arena.contains(a) && arena.contains(b));
+    assert_eq!(arena.used(), 16 + 64);
+
+    // The memory is really usable
+    unsafe { b.write_bytes(0xAB, 64) };
+
+    // Anything that doesn't fit anymore fails instead of overflowing the arena
+    assert!(
+        arena
+            .alloc(Layout::from_size_align(1024, 1).unwrap())
+            .is_null()
+    );
+    let rest = arena.alloc(Layout::from_size_align(1024 - 80, 1).unwrap());
+    assert!(!rest.is_null());
+    assert!(
+        arena
+            .alloc(Layout::from_size_align(1, 1).unwrap())
+            .is_null()
+    );
+    assert_eq!(arena.used(), 1024);
+
+    let outside = 0u8;
+    assert!(!arena.contains(&outside));
+}
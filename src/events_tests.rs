@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel::events::{
+    Event, EventKind, KeyboardEvent, dispatch_pending, has_events, on, pop_event, push_event,
+    subscribe, wait_event_with,
+};
+use pc_keyboard::KeyCode;
+
+static KEYBOARD_A: AtomicUsize = AtomicUsize::new(0);
+static KEYBOARD_B: AtomicUsize = AtomicUsize::new(0);
+static MOUSE: AtomicUsize = AtomicUsize::new(0);
+static MOUSE_ONLY: AtomicUsize = AtomicUsize::new(0);
+
+fn key_event(code: KeyCode) -> Event {
+    Event::KeyboardEvent(KeyboardEvent::KeyPressed(code))
+}
+
+// The event queue and handler table are global, so everything is checked in a single test
+#[test]
+fn test_subscribe_and_dispatch() {
+    subscribe(EventKind::Keyboard, |_| {
+        KEYBOARD_A.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    subscribe(EventKind::Keyboard, |_| {
+        KEYBOARD_B.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    subscribe(EventKind::Mouse, |_| {
+        MOUSE.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+
+    push_event(key_event(KeyCode::A));
+    push_event(key_event(KeyCode::B));
+
+    // Every keyboard handler sees every keyboard event, the mouse handler sees nothing
+    assert_eq!(dispatch_pending(), 2);
+    assert!(!has_events());
+    assert_eq!(KEYBOARD_A.load(Ordering::SeqCst), 2);
+    assert_eq!(KEYBOARD_B.load(Ordering::SeqCst), 2);
+    assert_eq!(MOUSE.load(Ordering::SeqCst), 0);
+
+    // Manual popping still works and bypasses the handlers
+    push_event(key_event(KeyCode::C));
+    assert!(matches!(
+        pop_event(),
+        Some(Event::KeyboardEvent(KeyboardEvent::KeyPressed(KeyCode::C)))
+    ));
+    assert_eq!(dispatch_pending(), 0);
+    assert_eq!(KEYBOARD_A.load(Ordering::SeqCst), 2);
+
+    // Waiting drains queued events in order without idling
+    push_event(key_event(KeyCode::D));
+    push_event(key_event(KeyCode::E));
+    let no_idle = || -> bool { panic!("idled with events queued") };
+    assert!(matches!(
+        wait_event_with(no_idle),
+        Some(Event::KeyboardEvent(KeyboardEvent::KeyPressed(KeyCode::D)))
+    ));
+    assert!(matches!(
+        wait_event_with(no_idle),
+        Some(Event::KeyboardEvent(KeyboardEvent::KeyPressed(KeyCode::E)))
+    ));
+
+    // With an empty queue it idles until an event shows up (here: on the third wakeup)
+    let mut wakeups = 0;
+    let event = wait_event_with(|| {
+        wakeups += 1;
+        if wakeups == 3 {
+            push_event(key_event(KeyCode::F));
+        }
+        true
+    });
+    assert_eq!(wakeups, 3);
+    assert!(matches!(
+        event,
+        Some(Event::KeyboardEvent(KeyboardEvent::KeyPressed(KeyCode::F)))
+    ));
+
+    // Giving up (a timeout) returns nothing and leaves the queue alone
+    assert!(wait_event_with(|| false).is_none());
+    assert!(!has_events());
+
+    // The table is small and rejects handlers once it's full
+    let mut rejected = false;
+    for _ in 0..32 {
+        if subscribe(EventKind::Mouse, |_| {}).is_err() {
+            rejected = true;
+            break;
+        }
+    }
+    assert!(rejected);
+
+    // `on` replaces every mouse handler with its own, which also frees up their slots
+    on(EventKind::Mouse, |_| {
+        MOUSE_ONLY.fetch_add(1, Ordering::SeqCst);
+    })
+    .unwrap();
+    push_event(Event::MouseMove {
+        x: 1,
+        y: 2,
+        dx: 1,
+        dy: 2,
+    });
+    push_event(key_event(KeyCode::G));
+    assert_eq!(dispatch_pending(), 2);
+    assert_eq!(MOUSE_ONLY.load(Ordering::SeqCst), 1);
+    assert_eq!(MOUSE.load(Ordering::SeqCst), 0);
+    assert_eq!(KEYBOARD_A.load(Ordering::SeqCst), 3);
+    assert!(subscribe(EventKind::Serial, |_| {}).is_ok());
+}
@@ -0,0 +1,108 @@
+use kernel::debug::gdbstub::{self, Action, PacketReader, Received, Registers, Target};
+use kernel::tasks::task::TaskContext;
+
+/// 16 bytes of memory at 0x1000, nothing else is mapped
+struct FakeTarget {
+    registers: Registers,
+}
+
+impl Target for FakeTarget {
+    fn registers(&self) -> Registers {
+        self.registers
+    }
+
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> bool {
+        if addr < 0x1000 || addr + buf.len() as u64 > 0x1010 {
+            return false;
+        }
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (addr - 0x1000) as u8 + i as u8;
+        }
+        true
+    }
+}
+
+fn target() -> FakeTarget {
+    let context = TaskContext {
+        rax: 1,
+        rsp: 0x7FFF_F000,
+        r15: 0x1122_3344_5566_7788,
+        rip: 0xFFFF_8000_0000_1234,
+        rflags: 0x202,
+        cs: 0x8,
+        ss: 0x10,
+        ..Default::default()
+    };
+    FakeTarget {
+        registers: Registers::from(&context),
+    }
+}
+
+fn reply(packet: &[u8]) -> Vec<u8> {
+    match gdbstub::handle(packet, &target()) {
+        Action::Reply(reply) => reply,
+        action => panic!("Expected a reply, got {:?}", action),
+    }
+}
+
+#[test]
+fn test_gdb_packet_framing() {
+    assert_eq!(gdbstub::frame(b"OK"), b"$OK#9a");
+    assert_eq!(gdbstub::frame(b""), b"$#00");
+
+    let mut reader = PacketReader::new();
+    let mut received = Vec::new();
+    // Acks and noise before the packet are skipped
+    for &byte in b"+-x$m1000,4#8e" {
+        if let Some(packet) = reader.feed(byte) {
+            received.push(packet);
+        }
+    }
+    assert_eq!(received, vec![Received::Packet(b"m1000,4".to_vec())]);
+
+    let bad: Vec<_> = b"$g#00".iter().filter_map(|&b| reader.feed(b)).collect();
+    assert_eq!(bad, vec![Received::BadChecksum]);
+
+    // A cut off packet is dropped when the next one starts
+    let restarted: Vec<_> = b"$m10$g#67"
+        .iter()
+        .filter_map(|&b| reader.feed(b))
+        .collect();
+    assert_eq!(restarted, vec![Received::Packet(b"g".to_vec())]);
+}
+
+#[test]
+fn test_gdb_register_read() {
+    let registers = reply(b"g");
+    // 17 64 bit registers and 7 32 bit ones, two hex digits per byte
+    assert_eq!(registers.len(), (17 * 8 + 7 * 4) * 2);
+
+    let register = |offset: usize, len: usize| &registers[offset * 2..(offset + len) * 2];
+    assert_eq!(register(0, 8), b"0100000000000000"); // rax
+    assert_eq!(register(7 * 8, 8), b"00f0ff7f00000000"); // rsp
+    assert_eq!(register(15 * 8, 8), b"8877665544332211"); // r15
+    assert_eq!(register(16 * 8, 8), b"341200000080ffff"); // rip
+    assert_eq!(register(17 * 8, 4), b"02020000"); // eflags
+    assert_eq!(register(17 * 8 + 4, 4), b"08000000"); // cs
+}
+
+#[test]
+fn test_gdb_memory_read() {
+    assert_eq!(reply(b"m1002,4"), b"02030405");
+    assert_eq!(reply(b"m100e,4"), b"E14");
+    assert_eq!(reply(b"m1000"), b"E22");
+    assert_eq!(reply(b"mzz,1"), b"E22");
+}
+
+#[test]
+fn test_gdb_commands() {
+    assert_eq!(reply(b"?"), b"S05");
+    assert_eq!(reply(b"Hg0"), b"OK");
+    assert_eq!(reply(b"qSupported:multiprocess+"), b"PacketSize=1000");
+    assert_eq!(reply(b"qAttached"), b"1");
+    // Unsupported packets get an empty reply
+    assert_eq!(reply(b"Z0,1000,1"), b"");
+
+    assert_eq!(gdbstub::handle(b"c", &target()), Action::Continue);
+    assert_eq!(gdbstub::handle(b"D", &target()), Action::Detach);
+}
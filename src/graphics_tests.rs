@@ -0,0 +1,501 @@
+use bootloader_api::info::{
+    FrameBuffer, FrameBufferInfo, MemoryRegion, MemoryRegionKind, MemoryRegions, PixelFormat,
+};
+use kernel::graphics::font::{FONT, Font, GLYPH_HEIGHT, GLYPH_WIDTH};
+use kernel::graphics::{self, Error, FrameTimer, Framebuffer, draw_char, draw_string};
+use kernel::mm::memory::BootInfoFrameAllocator;
+
+const WIDTH: usize = 32;
+const HEIGHT: usize = 24;
+const STRIDE: usize = 40;
+/// Extra pixels after the buffer to catch out of bounds writes
+const GUARD: usize = 64;
+const GUARD_VALUE: u32 = 0xDEADBEEF;
+
+const FG: u32 = 0xFFFFFF;
+const BG: u32 = 0x123456;
+
+struct TestBuffers {
+    front: Vec<u32>,
+    back: Vec<u32>,
+}
+
+impl TestBuffers {
+    fn new() -> Self {
+        Self {
+            front: vec![0; STRIDE * HEIGHT],
+            back: vec![GUARD_VALUE; STRIDE * HEIGHT + GUARD],
+        }
+    }
+
+    fn framebuffer(&mut self) -> Framebuffer {
+        self.framebuffer_with_format(PixelFormat::Bgr)
+    }
+
+    fn framebuffer_with_format(&mut self, pixel_format: PixelFormat) -> Framebuffer {
+        unsafe {
+            Framebuffer::from_raw_parts(
+                self.front.as_mut_ptr(),
+                self.back.as_mut_ptr(),
+                WIDTH,
+                HEIGHT,
+                STRIDE,
+                pixel_format,
+            )
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> u32 {
+        self.back[y * STRIDE + x]
+    }
+}
+
+#[test]
+fn test_draw_char_matches_glyph() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    draw_char(&mut fb, 3, 2, 'A', FG, BG);
+
+    let glyph = FONT.glyph('A');
+    for row in 0..GLYPH_HEIGHT {
+        for col in 0..GLYPH_WIDTH {
+            let expected = if Font::is_set(glyph, col, row) {
+                FG
+            } else {
+                BG
+            };
+            assert_eq!(buffers.pixel(3 + col, 2 + row), expected);
+        }
+    }
+
+    // The crossbar of the 'A' is lit, the first rows are empty padding
+    assert_eq!(buffers.pixel(3 + 3, 2 + 9), FG);
+    assert_eq!(buffers.pixel(3, 2), BG);
+
+    // Pixels around the glyph are untouched
+    assert_eq!(buffers.pixel(2, 2), GUARD_VALUE);
+    assert_eq!(buffers.pixel(3 + GLYPH_WIDTH, 2), GUARD_VALUE);
+}
+
+#[test]
+fn test_draw_char_clips_at_edges() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    draw_char(&mut fb, WIDTH - 4, HEIGHT - 4, '#', FG, BG);
+    draw_char(&mut fb, WIDTH + 10, HEIGHT + 10, '#', FG, BG);
+    // Doesn't overflow computing the pixel's position either
+    draw_char(&mut fb, usize::MAX - 2, usize::MAX - 2, '#', FG, BG);
+
+    // Nothing was written past the visible width or below the last row
+    for y in 0..HEIGHT {
+        for x in WIDTH..STRIDE {
+            assert_eq!(buffers.pixel(x, y), GUARD_VALUE);
+        }
+    }
+    assert!(
+        buffers.back[STRIDE * HEIGHT..]
+            .iter()
+            .all(|&p| p == GUARD_VALUE)
+    );
+}
+
+#[test]
+fn test_draw_string_newline() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    draw_string(&mut fb, 0, 0, "ab\nc", FG, BG);
+
+    // 'a' and 'b' are next to each other, 'c' starts on the next glyph row
+    assert_eq!(buffers.pixel(0, 0), BG);
+    assert_eq!(buffers.pixel(GLYPH_WIDTH, 0), BG);
+    assert_eq!(buffers.pixel(2 * GLYPH_WIDTH, 0), GUARD_VALUE);
+    assert_eq!(buffers.pixel(0, GLYPH_HEIGHT), BG);
+    assert_eq!(buffers.pixel(GLYPH_WIDTH, GLYPH_HEIGHT), GUARD_VALUE);
+}
+
+impl TestBuffers {
+    /// Check that nothing was written to the stride padding or past the last row
+    fn assert_guards_intact(&self) {
+        for y in 0..HEIGHT {
+            for x in WIDTH..STRIDE {
+                assert_eq!(self.pixel(x, y), GUARD_VALUE, "padding at ({}, {})", x, y);
+            }
+        }
+        assert!(
+            self.back[STRIDE * HEIGHT..]
+                .iter()
+                .all(|&p| p == GUARD_VALUE)
+        );
+    }
+
+    /// Forget what's on the screen, then count the pixels the next flips copy
+    fn reset_front(&mut self) {
+        self.front.fill(0);
+    }
+
+    /// Screen pixels written since `reset_front`, as (count, rows that were touched)
+    fn copied(&self) -> (usize, Vec<usize>) {
+        let count = self.front.iter().filter(|&&p| p != 0).count();
+        let rows = (0..HEIGHT)
+            .filter(|&y| {
+                self.front[y * STRIDE..(y + 1) * STRIDE]
+                    .iter()
+                    .any(|&p| p != 0)
+            })
+            .collect();
+        (count, rows)
+    }
+}
+
+#[test]
+fn test_fill_rect_sets_exactly_its_pixels() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    fb.fill_rect(2, 3, 5, 4, FG);
+
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let inside = (2..7).contains(&x) && (3..7).contains(&y);
+            let expected = if inside { FG } else { GUARD_VALUE };
+            assert_eq!(buffers.pixel(x, y), expected, "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_drawing_clips_at_the_edges() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    fb.put_pixel(WIDTH, 0, FG);
+    fb.put_pixel(0, HEIGHT, FG);
+    fb.put_pixel(usize::MAX, usize::MAX, FG);
+    fb.fill_rect(WIDTH - 2, HEIGHT - 2, 10, 10, FG);
+    fb.fill_rect(WIDTH + 5, 0, 3, 3, FG);
+    fb.fill_rect(usize::MAX - 1, usize::MAX - 1, usize::MAX, usize::MAX, FG);
+
+    // Only the part of the rect on the screen was drawn
+    assert_eq!(buffers.pixel(WIDTH - 1, HEIGHT - 1), FG);
+    assert_eq!(buffers.pixel(WIDTH - 2, HEIGHT - 2), FG);
+    assert_eq!(buffers.pixel(WIDTH - 3, HEIGHT - 2), GUARD_VALUE);
+    assert_eq!(buffers.pixel(0, 0), GUARD_VALUE);
+    buffers.assert_guards_intact();
+}
+
+#[test]
+fn test_put_pixel_and_clear() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    fb.clear(BG);
+    fb.put_pixel(5, 7, FG);
+
+    assert_eq!(buffers.pixel(5, 7), FG);
+    assert_eq!(buffers.pixel(6, 7), BG);
+    assert_eq!(buffers.pixel(WIDTH - 1, HEIGHT - 1), BG);
+    buffers.assert_guards_intact();
+}
+
+#[test]
+fn test_unknown_char_uses_fallback_glyph() {
+    assert_eq!(FONT.glyph('\u{1F600}'), FONT.glyph('\0'));
+    assert_ne!(FONT.glyph('A'), FONT.glyph('\0'));
+}
+
+const FRAME_60FPS: u64 = 1_000_000_000 / 60;
+
+#[test]
+fn test_frame_timer_paces_evenly() {
+    let mut timer = FrameTimer::new();
+
+    let first = timer.next_deadline(0, 60);
+    assert_eq!(first, FRAME_60FPS);
+
+    // Rendering finished early, the next deadline is one frame after the previous one
+    let second = timer.next_deadline(first + 1_000_000, 60);
+    assert_eq!(second, 2 * FRAME_60FPS);
+}
+
+#[test]
+fn test_frame_timer_drops_debt_on_overrun() {
+    let mut timer = FrameTimer::new();
+    timer.next_deadline(0, 60);
+
+    // A frame took way longer than its budget, don't try to catch up
+    let late = 10 * FRAME_60FPS;
+    assert_eq!(timer.next_deadline(late, 60), late);
+
+    // And the frame after that is paced normally again
+    assert_eq!(timer.next_deadline(late + 1_000, 60), late + FRAME_60FPS);
+}
+
+#[test]
+fn test_frame_timer_reports_fps() {
+    let mut timer = FrameTimer::new();
+
+    assert_eq!(timer.record_frame(0), 0);
+    assert_eq!(timer.record_frame(20_000_000), 50);
+    assert_eq!(timer.record_frame(40_000_000), 50);
+    assert_eq!(timer.fps(), 50);
+}
+
+fn framebuffer_info(
+    width: usize,
+    height: usize,
+    stride: usize,
+    bytes_per_pixel: usize,
+) -> FrameBufferInfo {
+    FrameBufferInfo {
+        byte_len: stride * height * bytes_per_pixel,
+        width,
+        height,
+        pixel_format: PixelFormat::Bgr,
+        bytes_per_pixel,
+        stride,
+    }
+}
+
+#[test]
+fn test_framebuffer_validation() {
+    assert_eq!(
+        graphics::validate(&framebuffer_info(1280, 720, 1280, 4)),
+        Ok(())
+    );
+    // Padded lines are fine
+    assert_eq!(
+        graphics::validate(&framebuffer_info(1270, 720, 1280, 4)),
+        Ok(())
+    );
+
+    assert_eq!(
+        graphics::validate(&framebuffer_info(1280, 720, 1280, 3)),
+        Err(Error::UnsupportedPixelSize(3))
+    );
+    assert_eq!(
+        graphics::validate(&framebuffer_info(0, 720, 1280, 4)),
+        Err(Error::Empty)
+    );
+    assert_eq!(
+        graphics::validate(&framebuffer_info(1280, 0, 1280, 4)),
+        Err(Error::Empty)
+    );
+    assert_eq!(
+        graphics::validate(&framebuffer_info(1280, 720, 1000, 4)),
+        Err(Error::InvalidStride)
+    );
+
+    let mut short = framebuffer_info(1280, 720, 1280, 4);
+    short.byte_len -= 4;
+    assert_eq!(graphics::validate(&short), Err(Error::BufferTooSmall));
+
+    let mut huge = framebuffer_info(1, 1, 1, 4);
+    huge.stride = usize::MAX;
+    huge.height = 2;
+    assert_eq!(graphics::validate(&huge), Err(Error::BufferTooSmall));
+}
+
+#[test]
+fn test_encode_color_follows_the_pixel_format() {
+    let mut buffers = TestBuffers::new();
+
+    let rgb = buffers.framebuffer_with_format(PixelFormat::Rgb);
+    assert_eq!(rgb.encode_color(255, 0, 0), 0x0000FF);
+    assert_eq!(rgb.encode_color(0x12, 0x34, 0x56), 0x563412);
+
+    let bgr = buffers.framebuffer_with_format(PixelFormat::Bgr);
+    assert_eq!(bgr.encode_color(255, 0, 0), 0xFF0000);
+    assert_eq!(bgr.encode_color(0x12, 0x34, 0x56), 0x123456);
+    assert_ne!(rgb.encode_color(255, 0, 0), bgr.encode_color(255, 0, 0));
+
+    let gray = buffers.framebuffer_with_format(PixelFormat::U8);
+    assert_eq!(gray.encode_color(255, 255, 255), 0xFF);
+    assert_eq!(gray.encode_color(0, 0, 0), 0);
+
+    let unknown = buffers.framebuffer_with_format(PixelFormat::Unknown {
+        red_position: 0,
+        green_position: 8,
+        blue_position: 16,
+    });
+    assert_eq!(unknown.encode_color(255, 255, 255), 0);
+}
+
+#[test]
+fn test_flip_only_copies_the_dirty_rect() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+
+    // A new framebuffer is all dirty
+    fb.flip();
+    assert_eq!(buffers.copied().0, WIDTH * HEIGHT);
+    buffers.reset_front();
+
+    fb.fill_rect(4, 5, 3, 2, FG);
+    fb.flip();
+    assert_eq!(buffers.copied(), (3 * 2, vec![5, 6]));
+
+    // Nothing changed since, the next flip copies nothing
+    buffers.reset_front();
+    fb.flip();
+    assert_eq!(buffers.copied(), (0, vec![]));
+}
+
+#[test]
+fn test_dirty_rects_union() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+    fb.flip();
+    buffers.reset_front();
+
+    // Overlapping rects plus a pixel off to the side, the flip covers (2, 3) to (10, 8)
+    fb.fill_rect(2, 3, 4, 4, FG);
+    fb.fill_rect(4, 5, 3, 3, FG);
+    fb.put_pixel(9, 4, FG);
+    // Out of bounds, doesn't grow the dirty rect
+    fb.put_pixel(WIDTH, HEIGHT, FG);
+    fb.flip();
+
+    assert_eq!(buffers.copied(), (8 * 5, vec![3, 4, 5, 6, 7]));
+}
+
+#[test]
+fn test_flip_full_copies_everything() {
+    let mut buffers = TestBuffers::new();
+    let mut fb = buffers.framebuffer();
+    fb.flip();
+    buffers.reset_front();
+
+    fb.flip_full();
+    // The stride padding is copied too
+    assert_eq!(buffers.copied().0, STRIDE * HEIGHT);
+
+    buffers.reset_front();
+    fb.flip();
+    assert_eq!(buffers.copied().0, 0);
+}
+
+/// A boot frame allocator with `usable` bytes of memory at physical address 0x1000
+fn boot_allocator(usable: u64) -> BootInfoFrameAllocator {
+    let regions: &'static mut [MemoryRegion] = Box::leak(
+        vec![MemoryRegion {
+            start: 0x1000,
+            end: 0x1000 + usable,
+            kind: MemoryRegionKind::Usable,
+        }]
+        .into_boxed_slice(),
+    );
+    let memory_map: &'static MemoryRegions = Box::leak(Box::new(MemoryRegions::from(regions)));
+
+    unsafe { BootInfoFrameAllocator::init(memory_map) }
+}
+
+#[test]
+fn test_framebuffer_without_room_for_a_back_buffer() {
+    let mut screen = vec![0u32; STRIDE * HEIGHT];
+    let info = framebuffer_info(WIDTH, HEIGHT, STRIDE, 4);
+    let mut fb = unsafe { FrameBuffer::new(screen.as_mut_ptr() as u64, info) };
+
+    // Nothing free, the back buffer can't be allocated
+    let mut allocator = boot_allocator(0);
+    assert!(Framebuffer::new(&mut fb, &mut allocator, 0).is_err());
+
+    // Drawing goes straight to the screen and flipping has nothing to do
+    let mut framebuffer = Framebuffer::single_buffered(&mut fb);
+    assert!(framebuffer.is_single_buffered());
+    assert_eq!(framebuffer.width, WIDTH);
+    assert_eq!(framebuffer.stride, STRIDE);
+    framebuffer.fill_rect(2, 3, 4, 5, FG);
+    framebuffer.flip();
+    framebuffer.flip_full();
+
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let expected = if (2..6).contains(&x) && (3..8).contains(&y) {
+                FG
+            } else {
+                0
+            };
+            assert_eq!(screen[y * STRIDE + x], expected, "pixel ({}, {})", x, y);
+        }
+    }
+}
+
+#[test]
+fn test_framebuffer_with_a_back_buffer() {
+    let mut screen = vec![0u32; STRIDE * HEIGHT];
+    let info = framebuffer_info(WIDTH, HEIGHT, STRIDE, 4);
+    let mut fb = unsafe { FrameBuffer::new(screen.as_mut_ptr() as u64, info) };
+
+    // One page of "physical" memory at 0x1000, backed by a host page
+    let mut memory = vec![0u32; 2048];
+    let page = (memory.as_mut_ptr() as u64).next_multiple_of(4096);
+    let mut allocator = boot_allocator(4096);
+
+    let mut framebuffer = Framebuffer::new(&mut fb, &mut allocator, page - 0x1000).unwrap();
+    assert!(!framebuffer.is_single_buffered());
+    assert_eq!(framebuffer.get_back_buffer_ptr() as u64, page);
+
+    // Nothing shows up before the flip
+    framebuffer.fill_rect(0, 0, 1, 1, FG);
+    assert_eq!(screen[0], 0);
+    framebuffer.flip();
+    assert_eq!(screen[0], FG);
+}
+
+#[test]
+fn test_resize_gives_the_old_back_buffer_back() {
+    let mut screen = vec![0u32; 64 * 48];
+    let mut small = unsafe {
+        FrameBuffer::new(
+            screen.as_mut_ptr() as u64,
+            framebuffer_info(WIDTH, HEIGHT, STRIDE, 4),
+        )
+    };
+    let mut large =
+        unsafe { FrameBuffer::new(screen.as_mut_ptr() as u64, framebuffer_info(64, 48, 64, 4)) };
+
+    // 8 pages of "physical" memory at 0x1000, backed by host pages
+    let mut memory = vec![0u32; 9 * 1024];
+    let base = (memory.as_mut_ptr() as u64).next_multiple_of(4096);
+    let offset = base - 0x1000;
+    let mut allocator = boot_allocator(8 * 4096);
+
+    // The small back buffer fits in a page, the large one needs 3
+    let mut framebuffer = Framebuffer::new(&mut small, &mut allocator, offset).unwrap();
+    assert_eq!(allocator.free_memory(), 7 * 4096);
+
+    framebuffer
+        .resize(&mut large, &mut allocator, offset)
+        .unwrap();
+    assert_eq!((framebuffer.width, framebuffer.height), (64, 48));
+    assert_eq!(framebuffer.stride, 64);
+    assert!(!framebuffer.is_single_buffered());
+
+    // The old page came back and the new buffer starts on it, nothing leaked
+    assert_eq!(allocator.free_memory(), 5 * 4096);
+    assert_eq!(allocator.fragmentation().range_count, 1);
+    assert_eq!(framebuffer.get_back_buffer_ptr() as u64, base);
+
+    // The whole new screen is dirty
+    framebuffer.fill_rect(63, 47, 1, 1, FG);
+    framebuffer.flip();
+    assert_eq!(screen[47 * 64 + 63], FG);
+
+    // Too big for what's left, we draw straight to the screen but keep nothing allocated
+    let mut huge = unsafe {
+        FrameBuffer::new(
+            screen.as_mut_ptr() as u64,
+            framebuffer_info(256, 256, 256, 4),
+        )
+    };
+    assert!(
+        framebuffer
+            .resize(&mut huge, &mut allocator, offset)
+            .is_err()
+    );
+    assert!(framebuffer.is_single_buffered());
+    assert_eq!(allocator.free_memory(), 8 * 4096);
+}
@@ -0,0 +1,69 @@
+use kernel::interrupts::{MachineCheckStatus, PageFaultDescription, SelectorDescription};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+#[test]
+fn test_page_fault_description() {
+    let describe = |code| PageFaultDescription(code).to_string();
+
+    assert_eq!(
+        describe(PageFaultErrorCode::empty()),
+        "not present, read, supervisor mode"
+    );
+    assert_eq!(
+        describe(PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE),
+        "not present, write, user mode"
+    );
+    assert_eq!(
+        describe(
+            PageFaultErrorCode::PROTECTION_VIOLATION
+                | PageFaultErrorCode::INSTRUCTION_FETCH
+                | PageFaultErrorCode::USER_MODE
+        ),
+        "protection violation, instruction fetch, user mode"
+    );
+    assert_eq!(
+        describe(
+            PageFaultErrorCode::PROTECTION_VIOLATION
+                | PageFaultErrorCode::MALFORMED_TABLE
+                | PageFaultErrorCode::PROTECTION_KEY
+        ),
+        "protection violation, read, supervisor mode, reserved bit set, protection key"
+    );
+}
+
+#[test]
+fn test_selector_description() {
+    assert_eq!(
+        SelectorDescription(0).to_string(),
+        "none (not caused by a segment selector)"
+    );
+    // Index 5 in the GDT
+    assert_eq!(SelectorDescription(5 << 3).to_string(), "GDT index 5");
+    // Index 0x21 in the IDT, during an external event
+    assert_eq!(
+        SelectorDescription(0x21 << 3 | 0b011).to_string(),
+        "IDT index 33, external event"
+    );
+    assert_eq!(
+        SelectorDescription(2 << 3 | 0b100).to_string(),
+        "LDT index 2"
+    );
+}
+
+#[test]
+fn test_machine_check_status() {
+    let empty = MachineCheckStatus(0);
+    assert!(!empty.is_valid());
+
+    // VAL | UC | EN | ADDRV | PCC, memory read error (0x0150) with model specific code 0x0001
+    let status = MachineCheckStatus(
+        (1 << 63) | (1 << 61) | (1 << 60) | (1 << 58) | (1 << 57) | (0x0001 << 16) | 0x0150,
+    );
+    assert!(status.is_valid());
+    assert!(status.has_address());
+    assert!(!status.has_misc());
+    assert_eq!(
+        status.to_string(),
+        "MCA error 0x0150, model specific 0x0001, uncorrected, enabled, processor context corrupt"
+    );
+}
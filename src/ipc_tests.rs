@@ -0,0 +1,45 @@
+use kernel::tasks::ipc::{Error, MAILBOX_CAPACITY, MAX_MESSAGE_SIZE, Mailbox, Message};
+
+fn message(sender: u64, data: &[u8]) -> Message {
+    Message {
+        sender,
+        data: data.to_vec(),
+    }
+}
+
+#[test]
+fn test_mailbox_is_first_in_first_out() {
+    let mailbox = Mailbox::new();
+    assert!(mailbox.is_empty());
+    assert_eq!(mailbox.recv(), None);
+
+    mailbox.send(message(1, b"first")).unwrap();
+    mailbox.send(message(2, b"second")).unwrap();
+    assert_eq!(mailbox.len(), 2);
+
+    assert_eq!(mailbox.recv(), Some(message(1, b"first")));
+    assert_eq!(mailbox.recv(), Some(message(2, b"second")));
+    assert_eq!(mailbox.recv(), None);
+}
+
+#[test]
+fn test_mailbox_limits() {
+    let mailbox = Mailbox::new();
+
+    // A page is fine, a byte more isn't
+    let page = vec![0xAB; MAX_MESSAGE_SIZE];
+    mailbox.send(message(1, &page)).unwrap();
+    let too_big = vec![0; MAX_MESSAGE_SIZE + 1];
+    assert_eq!(mailbox.send(message(1, &too_big)), Err(Error::TooLarge));
+
+    // Empty messages count too
+    for _ in 1..MAILBOX_CAPACITY {
+        mailbox.send(message(1, b"")).unwrap();
+    }
+    assert_eq!(mailbox.send(message(1, b"")), Err(Error::Full));
+
+    // Taking one out makes room again
+    assert_eq!(mailbox.recv().unwrap().data, page);
+    mailbox.send(message(1, b"")).unwrap();
+    assert_eq!(mailbox.len(), MAILBOX_CAPACITY);
+}
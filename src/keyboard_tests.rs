@@ -0,0 +1,60 @@
+use kernel::drivers::keyboard::{decode, new_decoder};
+use kernel::events::KeyboardEvent;
+use pc_keyboard::KeyCode;
+
+/// Scancode set 1: left shift and the key right of tab (A on an AZERTY keyboard), released with bit 7
+const LSHIFT: u8 = 0x2A;
+const KEY_A: u8 = 0x10;
+const RELEASE: u8 = 0x80;
+
+/// Feed `scancodes` and collect the characters they typed
+fn typed(scancodes: &[u8]) -> Vec<KeyboardEvent> {
+    let mut keyboard = new_decoder();
+    scancodes
+        .iter()
+        .flat_map(|&scancode| decode(&mut keyboard, scancode))
+        .flatten()
+        .filter(|event| matches!(event, KeyboardEvent::Char(_)))
+        .collect()
+}
+
+#[test]
+fn test_shift_a_types_a_capital_a() {
+    // Shift itself is a raw key
+    let mut keyboard = new_decoder();
+    assert_eq!(
+        decode(&mut keyboard, LSHIFT)[1],
+        Some(KeyboardEvent::Raw(KeyCode::LShift))
+    );
+
+    let events = typed(&[LSHIFT, KEY_A, KEY_A | RELEASE, LSHIFT | RELEASE]);
+    assert_eq!(events, [KeyboardEvent::Char('A')]);
+
+    // Without shift it's lowercase again
+    let events = typed(&[LSHIFT, LSHIFT | RELEASE, KEY_A, KEY_A | RELEASE]);
+    assert_eq!(events, [KeyboardEvent::Char('a')]);
+}
+
+#[test]
+fn test_key_events_come_with_the_typed_character() {
+    let mut keyboard = new_decoder();
+
+    let pressed = decode(&mut keyboard, KEY_A);
+    assert!(matches!(pressed[0], Some(KeyboardEvent::KeyPressed(_))));
+    assert_eq!(pressed[1], Some(KeyboardEvent::Char('a')));
+
+    // Releasing doesn't type anything
+    let released = decode(&mut keyboard, KEY_A | RELEASE);
+    assert!(matches!(released[0], Some(KeyboardEvent::KeyReleased(_))));
+    assert_eq!(released[1], None);
+}
+
+#[test]
+fn test_keys_without_a_character_are_raw() {
+    // The arrows are extended scancodes, the 0xE0 prefix alone completes nothing
+    let mut keyboard = new_decoder();
+    assert_eq!(decode(&mut keyboard, 0xE0), [None, None]);
+
+    let pressed = decode(&mut keyboard, 0x48);
+    assert_eq!(pressed[1], Some(KeyboardEvent::Raw(KeyCode::ArrowUp)));
+}
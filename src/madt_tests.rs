@@ -0,0 +1,100 @@
+use kernel::drivers::apic::{apic_addresses, redirection_entry};
+use kernel::drivers::madt::{IoApic, SourceOverride, parse_madt};
+
+/// A MADT like QEMU's with two CPUs (and a third slot that's disabled)
+fn qemu_madt() -> Vec<u8> {
+    let mut entries = Vec::new();
+    // Local APICs: type, length, processor UID, APIC ID, flags
+    for (uid, id, flags) in [(0u8, 0u8, 1u32), (1, 1, 1), (2, 2, 0)] {
+        entries.extend_from_slice(&[0, 8, uid, id]);
+        entries.extend_from_slice(&flags.to_le_bytes());
+    }
+    // I/O APIC: type, length, ID, reserved, address, GSI base
+    entries.extend_from_slice(&[1, 12, 0, 0]);
+    entries.extend_from_slice(&0xFEC0_0000u32.to_le_bytes());
+    entries.extend_from_slice(&0u32.to_le_bytes());
+    // Interrupt source overrides: type, length, bus, IRQ, GSI, flags
+    for (irq, gsi, flags) in [(0u8, 2u32, 0u16), (9, 9, 0x000D)] {
+        entries.extend_from_slice(&[2, 10, 0, irq]);
+        entries.extend_from_slice(&gsi.to_le_bytes());
+        entries.extend_from_slice(&flags.to_le_bytes());
+    }
+    // Local APIC NMI, which we skip
+    entries.extend_from_slice(&[4, 6, 0xFF, 0, 0, 1]);
+
+    let mut table = vec![0u8; 36];
+    table[0..4].copy_from_slice(b"APIC");
+    let length = (36 + 8 + entries.len()) as u32;
+    table[4..8].copy_from_slice(&length.to_le_bytes());
+    table.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    table.extend_from_slice(&1u32.to_le_bytes()); // PC-AT compatible
+    table.extend_from_slice(&entries);
+    table
+}
+
+#[test]
+fn test_parse_qemu_madt() {
+    let madt = parse_madt(&qemu_madt()).unwrap();
+
+    assert_eq!(madt.cpu_count(), 2);
+    assert_eq!(madt.local_apic_ids, [0, 1]);
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert_eq!(
+        madt.io_apics,
+        [IoApic {
+            id: 0,
+            address: 0xFEC0_0000,
+            gsi_base: 0
+        }]
+    );
+    assert_eq!(
+        madt.overrides[0],
+        SourceOverride {
+            irq: 0,
+            gsi: 2,
+            flags: 0
+        }
+    );
+
+    let addresses = apic_addresses(&madt).unwrap();
+    assert_eq!(addresses.local_apic, 0xFEE0_0000);
+    assert_eq!(addresses.io_apic, 0xFEC0_0000);
+}
+
+#[test]
+fn test_isa_irqs_follow_the_overrides() {
+    let madt = parse_madt(&qemu_madt()).unwrap();
+
+    // The PIT is on GSI 2, the keyboard has no override
+    assert_eq!(madt.isa_irq(0), (2, 0));
+    assert_eq!(madt.isa_irq(1), (1, 0));
+    assert_eq!(madt.isa_irq(9), (9, 0x000D));
+}
+
+#[test]
+fn test_redirection_entry_flags() {
+    // ISA defaults: active high, edge triggered
+    assert_eq!(redirection_entry(33, 0), 33);
+    // Active high, level triggered (like the ACPI SCI on QEMU)
+    assert_eq!(redirection_entry(41, 0x000D), 41 | 1 << 15);
+    // Active low, level triggered
+    assert_eq!(redirection_entry(41, 0x000F), 41 | 1 << 13 | 1 << 15);
+}
+
+#[test]
+fn test_parse_madt_rejects_bad_tables() {
+    let table = qemu_madt();
+
+    assert!(parse_madt(&table[..20]).is_err());
+    // Length says there's more than we have
+    assert!(parse_madt(&table[..table.len() - 1]).is_err());
+
+    let mut wrong_signature = table.clone();
+    wrong_signature[0..4].copy_from_slice(b"FACP");
+    assert!(parse_madt(&wrong_signature).is_err());
+
+    // An entry with length 0 would loop forever
+    let mut zero_length = table.clone();
+    zero_length[45] = 0;
+    assert!(parse_madt(&zero_length).is_err());
+}
@@ -1,34 +1,178 @@
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
 use ovmf_prebuilt::{Arch, FileType, Prebuilt, Source};
 
+#[cfg(test)]
+mod abi_tests;
+#[cfg(test)]
+mod acpi_tests;
 #[cfg(test)]
 mod allocator_tests; // I have no idea why rust shows an error but it works fine so idc
+#[cfg(test)]
+mod boot_tests;
+#[cfg(test)]
+mod console_tests;
+#[cfg(test)]
+mod demand_tests;
+#[cfg(test)]
+mod elf_tests;
+#[cfg(test)]
+mod emergency_tests;
+#[cfg(test)]
+mod events_tests;
+#[cfg(test)]
+mod gdbstub_tests;
+#[cfg(test)]
+mod graphics_tests;
+#[cfg(test)]
+mod interrupts_tests;
+#[cfg(test)]
+mod ipc_tests;
+#[cfg(test)]
+mod keyboard_tests;
+#[cfg(test)]
+mod madt_tests;
+#[cfg(test)]
+mod memory_tests;
+#[cfg(test)]
+mod mouse_tests;
+#[cfg(test)]
+mod page_table_tests;
+#[cfg(test)]
+mod pci_tests;
+#[cfg(test)]
+mod reboot_tests;
+#[cfg(test)]
+mod rtc_tests;
+#[cfg(test)]
+mod runner_tests;
+#[cfg(test)]
+mod scheduler_tests;
+#[cfg(test)]
+mod serial_tests;
+#[cfg(test)]
+mod shm_tests;
+#[cfg(test)]
+mod syscall_tests;
+#[cfg(test)]
+mod task_id_tests;
+#[cfg(test)]
+mod time_tests;
 
 const UEFI_PATH: &str = env!("UEFI_PATH");
+/// The kernel ELF with its symbols, for gdb
+const KERNEL_PATH: &str = env!("KERNEL_PATH");
+
+/// How long QEMU may run before we kill it, override with LYMAD_TIMEOUT_SECS (0 = no limit)
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Size of the disk image made for LYMADOS_DISK when it doesn't say
+const DEFAULT_DISK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Memory QEMU gives the VM, override with LYMADOS_QEMU_MEM (anything `-m` takes, e.g. "1G")
+const DEFAULT_MEMORY: &str = "256M";
+
+/// QEMU's exit status for `QemuExitCode::Success`, the isa-debug-exit device turns it into (code << 1) | 1
+const QEMU_SUCCESS: i32 = 0x11;
+
+/// Kernel selftests `--selftest` boots, each is a `<name>_selftest` kernel feature that exits QEMU when done
+const SELFTESTS: [&str; 5] = ["alignment", "breakpoint", "heap", "oom", "syscall"];
 
 fn main() {
-    println!("kernel binary at: {UEFI_PATH}");
+    let args = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| panic!("{e}"));
+    let debug = args.debug || env_setting("LYMADOS_DEBUG").is_some_and(|value| value == "1");
+
     println!("Downloading OVMF firmware...");
     let prebuilt = Prebuilt::fetch(Source::LATEST, "target/omvf").expect("Failed to download OMVF");
 
+    if !args.selftests.is_empty() {
+        let passed = run_selftests(&prebuilt, &args.selftests);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    println!("kernel binary at: {UEFI_PATH}");
     println!("Starting QEMU...");
 
+    let mut cmd = qemu_command(&prebuilt, Path::new(UEFI_PATH), debug);
+    let timeout = timeout(debug);
+
+    let mut child = cmd.spawn().unwrap();
+    report_exit(wait_with_timeout(&mut child, timeout), timeout);
+}
+
+/// The QEMU command line to boot `image`, everything the environment asks for included
+fn qemu_command(prebuilt: &Prebuilt, image: &Path, debug: bool) -> Command {
     let code = prebuilt.get_file(Arch::X64, FileType::Code);
     let vars = prebuilt.get_file(Arch::X64, FileType::Vars);
 
-    let mut cmd = std::process::Command::new("qemu-system-x86_64");
+    let mut cmd = Command::new("qemu-system-x86_64");
 
-    cmd.arg("-m").arg("256M");
-    cmd.arg("-serial").arg("stdio");
+    let memory = env_setting("LYMADOS_QEMU_MEM").unwrap_or_else(|| DEFAULT_MEMORY.to_string());
+    cmd.arg("-m").arg(&memory);
+    // Only the bootstrap processor runs the kernel for now, the others just sit there
+    let cpus = env_setting("LYMADOS_QEMU_CPUS").map(|cpus| match cpus.parse::<u32>() {
+        Ok(count) if count > 0 => count,
+        _ => panic!("LYMADOS_QEMU_CPUS must be a number of CPUs, got {cpus:?}"),
+    });
+    if let Some(cpus) = cpus {
+        cmd.arg("-smp").arg(cpus.to_string());
+    }
+    println!("QEMU: {memory} of memory, {} CPU(s)", cpus.unwrap_or(1));
+
+    // Save the serial output to a file instead of printing it if LYMAD_SERIAL_LOG is set
+    match std::env::var("LYMAD_SERIAL_LOG") {
+        Ok(path) => {
+            println!("Writing serial output to {path}");
+            cmd.arg("-serial").arg(format!("file:{path}"));
+        }
+        Err(_) => {
+            cmd.arg("-serial").arg("stdio");
+        }
+    }
+    // The second serial port is the kernel's GDB stub, connect with `target remote :<port>`
+    if let Ok(port) = std::env::var("LYMAD_GDB_PORT") {
+        println!("GDB stub listening on port {port}");
+        cmd.arg("-serial")
+            .arg(format!("tcp::{port},server=on,wait=off"));
+    }
+
+    // QEMU's own GDB server, unlike the kernel's stub it can stop the kernel before it runs at all
+    if debug {
+        // The kernel's stub and QEMU's server can't share a port
+        assert!(
+            std::env::var("LYMAD_GDB_PORT")
+                .ok()
+                .is_none_or(|port| port.trim() != "1234"),
+            "LYMAD_GDB_PORT can't be 1234 in debug mode, QEMU's GDB server uses it"
+        );
+        cmd.arg("-s").arg("-S");
+        println!("QEMU is waiting for GDB on port 1234, connect with:");
+        println!("  gdb {KERNEL_PATH} -ex \"target remote :1234\"");
+    }
+
+    // Opt-in, syscall/sysret and the APIC timer behave a little differently under KVM
+    let kvm = kvm_requested() && kvm_available();
+    println!(
+        "QEMU: {}",
+        if kvm {
+            "KVM acceleration"
+        } else {
+            "TCG emulation"
+        }
+    );
+    cmd.args(accel_args(kvm));
 
     // Disable graphics
     // cmd.arg("-display").arg("none"); // This also disables input devices like keyboard and mousev, so we we need to use it with the window
 
-    // Enable debug exit
+    // Enable debug exit (port I/O exits to QEMU under KVM too, so it works either way)
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
 
     cmd.arg("-drive")
-        .arg(format!("format=raw,file={}", UEFI_PATH));
+        .arg(format!("format=raw,file={}", image.display()));
     cmd.arg("-drive").arg(format!(
         "if=pflash,format=raw,unit=0,file={},readonly=on",
         code.display()
@@ -39,23 +183,296 @@ fn main() {
         vars.display()
     ));
 
+    // A scratch disk for the kernel to find on the PCI bus, LYMADOS_DISK=<path>[:<size>]
+    if let Some(setting) = env_setting("LYMADOS_DISK") {
+        let (path, size) =
+            parse_disk_setting(&setting).unwrap_or_else(|e| panic!("LYMADOS_DISK: {e}"));
+        if create_disk_image(&path, size).expect("Failed to create the disk image") {
+            println!("Created a {size} byte disk image at {}", path.display());
+        }
+        println!("Attaching {} as a virtio-blk disk", path.display());
+        cmd.args(disk_args(&path));
+    }
+
     // Helps us when we reboot bc of a triple fault
     // cmd.arg("-d").arg("int");
     // cmd.arg("-no-reboot");
 
-    let mut child = cmd.spawn().unwrap();
-    let status = child.wait().unwrap();
+    cmd
+}
 
-    match status.code() {
-        Some(code) => {
-            if code == 0x11 {
+/// Print how QEMU exited, returns whether the kernel reported success
+fn report_exit(status: Option<ExitStatus>, timeout: Option<Duration>) -> bool {
+    match status.map(|status| status.code()) {
+        Some(Some(code)) => {
+            if code == QEMU_SUCCESS {
                 println!("QEMU exited with success.");
             } else {
                 println!("QEMU exited with failure code: {code}");
             }
+            code == QEMU_SUCCESS
         }
-        None => {
+        Some(None) => {
             println!("QEMU terminated by signal");
+            false
+        }
+        None => {
+            println!(
+                "QEMU timed out after {}s and was killed.",
+                timeout.unwrap_or_default().as_secs()
+            );
+            false
+        }
+    }
+}
+
+/// Build a kernel with each selftest's feature and boot it, returns whether all of them passed
+/// A selftest passes when its kernel exits QEMU with the success code.
+fn run_selftests(prebuilt: &Prebuilt, selftests: &[&str]) -> bool {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target_dir = root.join("target").join("selftest");
+    let timeout = timeout(false);
+    let mut failed = Vec::new();
+
+    for &selftest in selftests {
+        println!("Selftest {selftest}: building the kernel...");
+        let image = match build_selftest_image(selftest, root, &target_dir) {
+            Ok(image) => image,
+            Err(e) => {
+                println!("Selftest {selftest}: {e}");
+                failed.push(selftest);
+                continue;
+            }
+        };
+
+        println!("Selftest {selftest}: starting QEMU...");
+        let mut child = qemu_command(prebuilt, &image, false).spawn().unwrap();
+        if !report_exit(wait_with_timeout(&mut child, timeout), timeout) {
+            failed.push(selftest);
         }
     }
+
+    if failed.is_empty() {
+        println!("All {} selftest(s) passed", selftests.len());
+    } else {
+        println!("Failed selftest(s): {}", failed.join(", "));
+    }
+    failed.is_empty()
+}
+
+/// Build the kernel with `selftest`'s feature and make a UEFI disk image of it
+fn build_selftest_image(selftest: &str, root: &Path, target_dir: &Path) -> Result<PathBuf, String> {
+    // Cargo tells us which cargo runs us, so the selftest kernel uses the same toolchain
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args(selftest_build_args(selftest, root, target_dir))
+        .status()
+        .map_err(|e| format!("failed to run cargo: {e}"))?;
+    if !status.success() {
+        return Err(format!("building the kernel failed ({status})"));
+    }
+
+    let kernel = target_dir
+        .join("x86_64-unknown-none")
+        .join("debug")
+        .join("kernel");
+    let image = target_dir.join(format!("{selftest}.img"));
+    bootloader::UefiBoot::new(&kernel)
+        .create_disk_image(&image)
+        .map_err(|e| format!("creating the disk image failed: {e}"))?;
+
+    Ok(image)
+}
+
+/// Cargo arguments that build the kernel with `selftest`'s feature
+/// A target dir of its own, the normal kernel is built without the feature.
+fn selftest_build_args(selftest: &str, root: &Path, target_dir: &Path) -> Vec<String> {
+    vec![
+        "build".to_string(),
+        "--manifest-path".to_string(),
+        root.join("kernel").join("Cargo.toml").display().to_string(),
+        "--bin".to_string(),
+        "kernel".to_string(),
+        "--target".to_string(),
+        "x86_64-unknown-none".to_string(),
+        "--features".to_string(),
+        format!("{selftest}_selftest"),
+        "--target-dir".to_string(),
+        target_dir.display().to_string(),
+    ]
+}
+
+/// Read a setting from the environment, None if it isn't set
+/// Panics if it's set to nothing, that's a typo rather than a request for the default.
+fn env_setting(name: &str) -> Option<String> {
+    let value = std::env::var(name).ok()?;
+    let value = value.trim();
+    assert!(!value.is_empty(), "{name} is set but empty");
+    Some(value.to_string())
+}
+
+/// LYMADOS_KVM=1 asks for KVM acceleration
+fn kvm_requested() -> bool {
+    env_setting("LYMADOS_KVM").is_some_and(|value| value == "1")
+}
+
+/// Whether we may use KVM, it needs read and write access to /dev/kvm
+fn kvm_available() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/kvm")
+        .is_ok()
+}
+
+/// QEMU arguments for the accelerator, nothing for the default TCG emulation
+fn accel_args(kvm: bool) -> &'static [&'static str] {
+    if kvm {
+        &["-enable-kvm", "-cpu", "host"]
+    } else {
+        &[]
+    }
+}
+
+/// Split LYMADOS_DISK into the image path and its size, `disk.img:128M` or just `disk.img`
+fn parse_disk_setting(setting: &str) -> Result<(PathBuf, u64), String> {
+    let (path, size) = match setting.rsplit_once(':') {
+        // Anything after the last ':' that isn't a size is part of the path
+        Some((path, size)) if parse_size(size).is_some() => (path, parse_size(size)),
+        _ => (setting, Some(DEFAULT_DISK_SIZE)),
+    };
+
+    if path.is_empty() {
+        return Err("no image path".to_string());
+    }
+    match size {
+        Some(size) if size > 0 => Ok((PathBuf::from(path), size)),
+        _ => Err(format!("bad disk size in {setting:?}")),
+    }
+}
+
+/// Parse a size like QEMU takes them: bytes, or a number with a K, M or G suffix
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Create a sparse disk image of `size` bytes at `path` unless there's one already
+/// Returns whether it was created, an existing image is used as it is (whatever its size).
+fn create_disk_image(path: &Path, size: u64) -> std::io::Result<bool> {
+    let file = match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    // Setting the length doesn't write anything, the blocks get allocated as the kernel writes them
+    file.set_len(size)?;
+    Ok(true)
+}
+
+/// QEMU arguments to attach the image as a virtio-blk PCI device, next to the boot drives
+fn disk_args(path: &Path) -> [String; 4] {
+    [
+        "-drive".to_string(),
+        format!("if=none,id=disk0,format=raw,file={}", path.display()),
+        "-device".to_string(),
+        "virtio-blk-pci,drive=disk0".to_string(),
+    ]
+}
+
+/// What the command line asks for
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Args {
+    /// `--debug`, LYMADOS_DEBUG=1 in the environment does the same
+    debug: bool,
+    /// `--selftest` runs all of them, `--selftest=<name>` just one
+    selftests: Vec<&'static str>,
+}
+
+/// Parse the runner's arguments, unknown ones are ignored
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+
+    for arg in args {
+        match arg.as_str() {
+            "--debug" => parsed.debug = true,
+            "--selftest" => parsed.selftests.extend(SELFTESTS),
+            _ => match arg.strip_prefix("--selftest=") {
+                Some(name) => {
+                    let selftest = SELFTESTS
+                        .iter()
+                        .find(|&&selftest| selftest == name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown selftest {name:?}, there are: {}",
+                                SELFTESTS.join(", ")
+                            )
+                        })?;
+                    parsed.selftests.push(selftest);
+                }
+                None => println!("Ignoring unknown argument {arg:?}"),
+            },
+        }
+    }
+
+    // Each one boots a VM of its own, once is enough
+    parsed.selftests.sort_unstable();
+    parsed.selftests.dedup();
+    if parsed.debug && !parsed.selftests.is_empty() {
+        return Err(
+            "--debug and --selftest don't go together, QEMU would wait for gdb".to_string(),
+        );
+    }
+
+    Ok(parsed)
+}
+
+/// Read the time limit from LYMAD_TIMEOUT_SECS, None means no limit
+/// There's no limit by default when debugging, QEMU waits for GDB and then sits at breakpoints.
+fn timeout(debug: bool) -> Option<Duration> {
+    let secs = match std::env::var("LYMAD_TIMEOUT_SECS") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .expect("LYMAD_TIMEOUT_SECS must be a number of seconds"),
+        Err(_) if debug => 0,
+        Err(_) => DEFAULT_TIMEOUT_SECS,
+    };
+
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Wait for QEMU to exit, killing it if it runs longer than `timeout`
+/// Returns None if QEMU had to be killed
+fn wait_with_timeout(child: &mut Child, timeout: Option<Duration>) -> Option<ExitStatus> {
+    let Some(timeout) = timeout else {
+        return Some(child.wait().unwrap());
+    };
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait().unwrap() {
+            return Some(status);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    // It might have exited while we were sleeping, only report a timeout if we really killed it
+    if let Some(status) = child.try_wait().unwrap() {
+        return Some(status);
+    }
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    None
 }
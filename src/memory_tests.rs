@@ -0,0 +1,183 @@
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
+use kernel::mm::memory::{BootInfoFrameAllocator, FragInfo, FreeError, PAGE_SIZE};
+use x86_64::PhysAddr;
+
+fn region(start: u64, end: u64, kind: MemoryRegionKind) -> MemoryRegion {
+    MemoryRegion { start, end, kind }
+}
+
+fn frame_allocator(regions: Vec<MemoryRegion>) -> BootInfoFrameAllocator {
+    let regions: &'static mut [MemoryRegion] = Box::leak(regions.into_boxed_slice());
+    let memory_map: &'static MemoryRegions = Box::leak(Box::new(MemoryRegions::from(regions)));
+
+    unsafe { BootInfoFrameAllocator::init(memory_map) }
+}
+
+#[test]
+fn test_fragmentation_of_memory_map() {
+    let allocator = frame_allocator(vec![
+        region(0x1000, 0x9000, MemoryRegionKind::Usable), // 8 pages
+        region(0x9000, 0x10000, MemoryRegionKind::Bootloader),
+        region(0x10000, 0x30000, MemoryRegionKind::Usable), // 32 pages
+        region(0x40000, 0x44000, MemoryRegionKind::Usable), // 4 pages
+    ]);
+
+    assert_eq!(
+        allocator.fragmentation(),
+        FragInfo {
+            range_count: 3,
+            largest_contiguous_bytes: 32 * PAGE_SIZE,
+            free_bytes: 44 * PAGE_SIZE,
+        }
+    );
+}
+
+#[test]
+fn test_largest_contiguous_free_range() {
+    // Nothing free is not fragmented
+    let empty = frame_allocator(vec![region(0x0, 0x10000, MemoryRegionKind::Bootloader)]);
+    assert_eq!(empty.largest_contiguous_free(), 0);
+    assert_eq!(empty.fragmentation_permille(), 0);
+
+    // One range is all the free memory there is
+    let single = frame_allocator(vec![region(0x0, 0x40000, MemoryRegionKind::Usable)]);
+    assert_eq!(single.largest_contiguous_free(), 64 * PAGE_SIZE);
+    assert_eq!(single.fragmentation_permille(), 0);
+
+    // The biggest range is in the middle of the map, not the first or the last one
+    let allocator = frame_allocator(vec![
+        region(0x1000, 0x3000, MemoryRegionKind::Usable), // 2 pages
+        region(0x3000, 0x4000, MemoryRegionKind::UnknownUefi(0)),
+        region(0x10000, 0x16000, MemoryRegionKind::Usable), // 6 pages
+        region(0x20000, 0x30000, MemoryRegionKind::Usable), // 16 pages
+        region(0x30000, 0x31000, MemoryRegionKind::Bootloader),
+        region(0x40000, 0x48000, MemoryRegionKind::Usable), // 8 pages
+    ]);
+    assert_eq!(allocator.largest_contiguous_free(), 16 * PAGE_SIZE);
+    assert_eq!(
+        allocator.fragmentation().largest_contiguous_bytes,
+        allocator.largest_contiguous_free()
+    );
+
+    // 16 of the 32 free pages are in the biggest range
+    assert_eq!(allocator.free_memory(), 32 * PAGE_SIZE);
+    assert_eq!(allocator.fragmentation_permille(), 500);
+}
+
+#[test]
+fn test_end_of_free_memory() {
+    let empty = frame_allocator(vec![region(0x0, 0x10000, MemoryRegionKind::Bootloader)]);
+    assert_eq!(empty.end_of_free_memory(), 0);
+
+    // Reserved memory above the last usable region doesn't count, regions come in any order
+    let allocator = frame_allocator(vec![
+        region(0x1_0000_0000, 0x1_0001_0000, MemoryRegionKind::Usable),
+        region(0x1000, 0x9000, MemoryRegionKind::Usable),
+        region(
+            0x2_0000_0000,
+            0x2_0001_0000,
+            MemoryRegionKind::UnknownUefi(0),
+        ),
+    ]);
+    assert_eq!(allocator.end_of_free_memory(), 0x1_0001_0000);
+}
+
+#[test]
+fn test_fragmentation_after_allocations() {
+    let mut allocator = frame_allocator(vec![region(0x0, 0x40000, MemoryRegionKind::Usable)]); // 64 pages
+
+    // Punch holes into the single range: [used 1][free 15][used 17][free 31]
+    let first = allocator.allocate_contiguous(16).unwrap();
+    let second = allocator.allocate_contiguous(16).unwrap();
+    unsafe { allocator.free_contiguous(first + 1, 15) };
+    let third = allocator.allocate_contiguous_aligned(1, 0x20000).unwrap();
+    assert_eq!(third.start_address(), PhysAddr::new(0x20000));
+
+    let info = allocator.fragmentation();
+    assert_eq!(info.range_count, 2);
+    assert_eq!(info.largest_contiguous_bytes, 31 * PAGE_SIZE);
+    assert_eq!(info.free_bytes, 46 * PAGE_SIZE);
+    assert_eq!(info.free_bytes, allocator.free_memory());
+
+    // Freeing everything merges it back into one range
+    unsafe {
+        allocator.free_frame(first);
+        allocator.free_contiguous(second, 16);
+        allocator.free_frame(third);
+    }
+
+    let info = allocator.fragmentation();
+    assert_eq!(info.range_count, 1);
+    assert_eq!(info.largest_contiguous_bytes, 64 * PAGE_SIZE);
+    assert_eq!(info.free_bytes, 64 * PAGE_SIZE);
+}
+
+#[test]
+fn test_hand_off_gives_every_free_frame_away_once() {
+    let mut allocator = frame_allocator(vec![
+        region(0x1000, 0x5000, MemoryRegionKind::Usable), // 4 pages
+        region(0x10000, 0x12000, MemoryRegionKind::Usable), // 2 pages
+    ]);
+
+    // Allocated before the handoff, so it stays with its owner
+    let kept = allocator.allocate_contiguous(1).unwrap();
+
+    let mut frames = Vec::new();
+    let handed_off = allocator.hand_off(|frame| frames.push(frame));
+
+    assert_eq!(handed_off, 5 * PAGE_SIZE);
+    assert_eq!(frames.len(), 5);
+    assert!(!frames.contains(&kept));
+    assert!(allocator.is_handed_off());
+    assert_eq!(allocator.free_memory(), 0);
+    assert_eq!(allocator.fragmentation().range_count, 0);
+}
+
+#[test]
+#[should_panic(expected = "after handing its memory")]
+fn test_allocating_after_hand_off_panics() {
+    use x86_64::structures::paging::FrameAllocator;
+
+    let mut allocator = frame_allocator(vec![region(0x0, 0x4000, MemoryRegionKind::Usable)]);
+    allocator.hand_off(|_| {});
+
+    allocator.allocate_frame();
+}
+
+#[test]
+fn test_freeing_into_full_range_table() {
+    // 1024 pages, allocated one by one from the front
+    let mut allocator = frame_allocator(vec![region(0x0, 0x40_0000, MemoryRegionKind::Usable)]);
+    let frames: Vec<_> = (0..1024)
+        .map(|_| allocator.allocate_contiguous(1).unwrap())
+        .collect();
+    assert_eq!(allocator.fragmentation().range_count, 0);
+
+    // Every other frame makes a new range, until the 256 slots are used up
+    for (i, &frame) in frames.iter().enumerate().step_by(2) {
+        let result = unsafe { allocator.try_free_contiguous(frame, 1) };
+        if i < 512 {
+            assert_eq!(result, Ok(()));
+        } else {
+            assert_eq!(result, Err(FreeError::TooManyRanges));
+        }
+    }
+    assert_eq!(allocator.fragmentation().range_count, 256);
+    assert_eq!(allocator.free_memory(), 256 * PAGE_SIZE);
+
+    // A frame next to a free range only grows it, so that works with a full table
+    for &frame in frames[..512].iter().skip(1).step_by(2) {
+        unsafe { allocator.try_free_contiguous(frame, 1) }.unwrap();
+    }
+    assert_eq!(allocator.fragmentation().range_count, 1);
+
+    // The gaps closed, so the rest fits now
+    for &frame in &frames[512..] {
+        unsafe { allocator.try_free_contiguous(frame, 1) }.unwrap();
+    }
+
+    let info = allocator.fragmentation();
+    assert_eq!(info.range_count, 1);
+    assert_eq!(info.free_bytes, 1024 * PAGE_SIZE);
+    assert_eq!(allocator.free_memory(), 1024 * PAGE_SIZE);
+}
@@ -0,0 +1,96 @@
+use kernel::drivers::mouse::{Cursor, MouseButton};
+use kernel::events::Event;
+use ps2_mouse::{Mouse, MouseState};
+
+/// Flags of a PS/2 packet
+const ALWAYS_ONE: u8 = 0x08;
+const LEFT_BUTTON: u8 = 0x01;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+
+/// Run a three byte packet through the PS/2 decoder
+fn packet(flags: u8, x: u8, y: u8) -> MouseState {
+    let mut mouse = Mouse::new();
+    for byte in [ALWAYS_ONE | flags, x, y] {
+        mouse.process_packet(byte);
+    }
+    mouse.get_state()
+}
+
+fn update(cursor: &mut Cursor, state: MouseState) -> Vec<Event> {
+    let mut events = Vec::new();
+    cursor.update(&state, |event| events.push(event));
+    events
+}
+
+#[test]
+fn test_movement_accumulates() {
+    let mut cursor = Cursor::new();
+    cursor.set_bounds(640, 480);
+
+    // Right 10
+    let events = update(&mut cursor, packet(0, 10, 0));
+    assert!(matches!(
+        events[..],
+        [Event::MouseMove {
+            x: 10,
+            y: 0,
+            dx: 10,
+            dy: 0
+        }]
+    ));
+
+    // Left 4 and down 20 (the mouse counts y up, the screen down)
+    // Negative deltas are two's complement with the sign bit in the flags
+    update(&mut cursor, packet(X_SIGN | Y_SIGN, 0xFC, 0xEC));
+    assert_eq!((cursor.x, cursor.y), (6, 20));
+
+    update(&mut cursor, packet(0, 4, 0));
+    assert_eq!((cursor.x, cursor.y), (10, 20));
+}
+
+#[test]
+fn test_cursor_stays_inside_the_bounds() {
+    let mut cursor = Cursor::new();
+    cursor.set_bounds(100, 50);
+
+    update(&mut cursor, packet(X_SIGN, 0x80, 0));
+    assert_eq!((cursor.x, cursor.y), (0, 0));
+
+    for _ in 0..2 {
+        update(&mut cursor, packet(Y_SIGN, 0x7F, 0x80));
+    }
+    assert_eq!((cursor.x, cursor.y), (99, 49));
+
+    // A smaller screen pulls the cursor in
+    cursor.set_bounds(20, 10);
+    assert_eq!((cursor.x, cursor.y), (19, 9));
+}
+
+#[test]
+fn test_buttons_are_reported_when_they_change() {
+    let mut cursor = Cursor::new();
+    cursor.set_bounds(640, 480);
+
+    let events = update(&mut cursor, packet(LEFT_BUTTON, 0, 0));
+    assert!(matches!(
+        events[..],
+        [Event::MouseButton {
+            button: MouseButton::Left,
+            pressed: true
+        }]
+    ));
+
+    // Still held, only the movement is new
+    let events = update(&mut cursor, packet(LEFT_BUTTON, 1, 0));
+    assert!(matches!(events[..], [Event::MouseMove { .. }]));
+
+    let events = update(&mut cursor, packet(0, 0, 0));
+    assert!(matches!(
+        events[..],
+        [Event::MouseButton {
+            button: MouseButton::Left,
+            pressed: false
+        }]
+    ));
+}
@@ -0,0 +1,307 @@
+use kernel::mm::audit::user_accessible_mappings;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageTable, PageTableFlags as Flags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Tables live in normal heap memory, so their "physical" address is just their address
+/// and the physical memory offset is 0
+fn phys(table: &PageTable) -> PhysAddr {
+    PhysAddr::new(table as *const PageTable as u64)
+}
+
+#[test]
+fn test_user_accessible_mappings() {
+    let table_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+
+    let mut l4 = Box::new(PageTable::new());
+    let mut l3 = Box::new(PageTable::new());
+    let mut l2 = Box::new(PageTable::new());
+    let mut l1 = Box::new(PageTable::new());
+    let mut kernel_l2 = Box::new(PageTable::new());
+
+    // A user page and a kernel page in the same level 1 table
+    l1[3].set_addr(
+        PhysAddr::new(0x1234000),
+        Flags::PRESENT | Flags::USER_ACCESSIBLE,
+    );
+    l1[4].set_addr(PhysAddr::new(0x5000), Flags::PRESENT | Flags::WRITABLE);
+    l2[2].set_addr(phys(&l1), table_flags);
+
+    // A user accessible 2MiB huge page
+    l2[5].set_addr(
+        PhysAddr::new(0x20_0000),
+        Flags::PRESENT | Flags::USER_ACCESSIBLE | Flags::HUGE_PAGE,
+    );
+
+    // A user flagged page below a kernel-only entry isn't reachable
+    kernel_l2[0].set_addr(
+        PhysAddr::new(0x40_0000),
+        Flags::PRESENT | Flags::USER_ACCESSIBLE | Flags::HUGE_PAGE,
+    );
+    l3[7].set_addr(phys(&kernel_l2), Flags::PRESENT | Flags::WRITABLE);
+
+    l3[1].set_addr(phys(&l2), table_flags);
+    l4[0].set_addr(phys(&l3), table_flags);
+
+    let mappings = unsafe { user_accessible_mappings(&l4, VirtAddr::new(0)) };
+
+    assert_eq!(mappings.len(), 2);
+
+    let page = mappings.iter().find(|m| m.size == 4096).unwrap();
+    assert_eq!(page.virt.as_u64(), (1 << 30) | (2 << 21) | (3 << 12));
+    assert_eq!(page.phys, PhysAddr::new(0x1234000));
+
+    let huge = mappings.iter().find(|m| m.size == 2 * 1024 * 1024).unwrap();
+    assert_eq!(huge.virt.as_u64(), (1 << 30) | (5 << 21));
+    assert_eq!(huge.phys, PhysAddr::new(0x20_0000));
+}
+
+#[test]
+fn test_translate_huge_pages() {
+    use kernel::mm::memory::translate_addr_in;
+
+    let table_flags = Flags::PRESENT | Flags::WRITABLE;
+
+    let mut l4 = Box::new(PageTable::new());
+    let mut l3 = Box::new(PageTable::new());
+    let mut l2 = Box::new(PageTable::new());
+    let mut l1 = Box::new(PageTable::new());
+
+    // Like the bootloader's physical memory mapping at 0xffff_8000_0000_0000:
+    // the first GiB as one 1GiB page, the second one split into 2MiB pages
+    l3[0].set_addr(PhysAddr::new(0), table_flags | Flags::HUGE_PAGE);
+    l2[3].set_addr(PhysAddr::new(0x4060_0000), table_flags | Flags::HUGE_PAGE);
+    l3[1].set_addr(phys(&l2), table_flags);
+
+    // And a normal 4KiB page next to them
+    l1[7].set_addr(PhysAddr::new(0x1234_5000), table_flags);
+    l2[0].set_addr(phys(&l1), table_flags);
+
+    l4[256].set_addr(phys(&l3), table_flags);
+
+    let l4_frame = PhysFrame::containing_address(phys(&l4));
+    let translate =
+        |addr: u64| unsafe { translate_addr_in(l4_frame, VirtAddr::new(addr), VirtAddr::new(0)) };
+
+    let offset = 0xffff_8000_0000_0000u64;
+    assert_eq!(translate(offset), Some(PhysAddr::new(0)));
+    assert_eq!(
+        translate(offset + 0x3abc_d123),
+        Some(PhysAddr::new(0x3abc_d123))
+    );
+    assert_eq!(
+        translate(offset + 0x4060_0000 + 0x1f_fff0),
+        Some(PhysAddr::new(0x407f_fff0))
+    );
+    assert_eq!(
+        translate(offset + 0x4000_7abc),
+        Some(PhysAddr::new(0x1234_5abc))
+    );
+
+    // Nothing mapped there
+    assert_eq!(translate(offset + 0x4020_0000), None);
+    assert_eq!(translate(0x1000), None);
+}
+
+/// Tables for the user memory tests: 0x400000 and 0x401000 are user pages (only the first one
+/// writable, the second is like a task's code), 0x402000 is a kernel page and 0x403000 isn't mapped
+fn user_test_tables() -> [Box<PageTable>; 4] {
+    let table_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+
+    let mut l4 = Box::new(PageTable::new());
+    let mut l3 = Box::new(PageTable::new());
+    let mut l2 = Box::new(PageTable::new());
+    let mut l1 = Box::new(PageTable::new());
+
+    l1[0].set_addr(
+        PhysAddr::new(0x10000),
+        Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE,
+    );
+    l1[1].set_addr(
+        PhysAddr::new(0x11000),
+        Flags::PRESENT | Flags::USER_ACCESSIBLE,
+    );
+    l1[2].set_addr(PhysAddr::new(0x12000), Flags::PRESENT);
+    l2[2].set_addr(phys(&l1), table_flags);
+    l3[0].set_addr(phys(&l2), table_flags);
+    l4[0].set_addr(phys(&l3), table_flags);
+
+    [l4, l3, l2, l1]
+}
+
+#[test]
+fn test_is_user_accessible() {
+    use kernel::mm::user::is_user_accessible;
+    use x86_64::structures::paging::OffsetPageTable;
+
+    let mut tables = user_test_tables();
+    let mapper = unsafe { OffsetPageTable::new(&mut tables[0], VirtAddr::new(0)) };
+
+    assert!(is_user_accessible(&mapper, 0x400000, 4096));
+    // Crossing into the next user page is fine
+    assert!(is_user_accessible(&mapper, 0x400ff0, 0x20));
+    assert!(is_user_accessible(&mapper, 0x400000, 2 * 4096));
+
+    // Into the kernel page or the hole
+    assert!(!is_user_accessible(&mapper, 0x401ff0, 0x20));
+    assert!(!is_user_accessible(&mapper, 0x402000, 1));
+    assert!(!is_user_accessible(&mapper, 0x403000, 1));
+
+    // Non-canonical or wrapping around
+    assert!(!is_user_accessible(&mapper, 0x0000_8000_0000_0000, 1));
+    assert!(!is_user_accessible(&mapper, u64::MAX, 2));
+}
+
+#[test]
+fn test_check_user_buffer() {
+    use kernel::mm::user::{SyscallError, USER_SPACE_LIMIT, check_user_buffer};
+    use x86_64::structures::paging::OffsetPageTable;
+
+    let mut tables = user_test_tables();
+    let mapper = unsafe { OffsetPageTable::new(&mut tables[0], VirtAddr::new(0)) };
+    let check = |ptr, len| check_user_buffer(&mapper, ptr, len, USER_SPACE_LIMIT);
+
+    // Valid buffers, also across the two user pages
+    assert_eq!(check(0x400010, 16), Ok(()));
+    assert_eq!(check(0x400f00, 0x200), Ok(()));
+
+    // Straddling into the kernel page or the unmapped one
+    assert_eq!(check(0x401f00, 0x200), Err(SyscallError::NotMapped));
+    assert_eq!(check(0x402ff0, 0x20), Err(SyscallError::NotMapped));
+
+    // Overflowing the address space, null and kernel space
+    assert_eq!(check(0x400000, u64::MAX), Err(SyscallError::BadRange));
+    assert_eq!(check(u64::MAX - 4, 16), Err(SyscallError::BadRange));
+    assert_eq!(check(0, 16), Err(SyscallError::BadRange));
+    assert_eq!(check(USER_SPACE_LIMIT - 8, 16), Err(SyscallError::BadRange));
+
+    // The kernel may start lower, at the physical memory mapping
+    assert_eq!(
+        check_user_buffer(&mapper, 0x400ff0, 0x20, 0x401000),
+        Err(SyscallError::BadRange)
+    );
+}
+
+#[test]
+fn test_check_user_buffer_mut_rejects_read_only_pages() {
+    use kernel::mm::user::{
+        SyscallError, USER_SPACE_LIMIT, check_user_buffer_mut, is_user_writable,
+    };
+    use x86_64::structures::paging::OffsetPageTable;
+
+    let mut tables = user_test_tables();
+    let mapper = unsafe { OffsetPageTable::new(&mut tables[0], VirtAddr::new(0)) };
+    let check = |ptr, len| check_user_buffer_mut(&mapper, ptr, len, USER_SPACE_LIMIT);
+
+    assert!(is_user_writable(&mapper, 0x400000, 4096));
+    assert!(!is_user_writable(&mapper, 0x401000, 1));
+    assert!(!is_user_writable(&mapper, 0x400ff0, 0x20));
+
+    // The writable page is fine, a single byte in the read-only one isn't
+    assert_eq!(check(0x400010, 16), Ok(()));
+    assert_eq!(check(0x401010, 16), Err(SyscallError::ReadOnly));
+    assert_eq!(check(0x400ff8, 16), Err(SyscallError::ReadOnly));
+
+    // The other checks still come first
+    assert_eq!(check(0x402000, 8), Err(SyscallError::NotMapped));
+    assert_eq!(check(0, 8), Err(SyscallError::BadRange));
+}
+
+/// Hands out heap allocated tables as frames, like `phys` they're at their own address
+#[derive(Default)]
+struct TestFrames {
+    tables: Vec<Box<PageTable>>,
+    freed: Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for TestFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let table = Box::new(PageTable::new());
+        let frame = PhysFrame::containing_address(phys(&table));
+        self.tables.push(table);
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for TestFrames {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.freed.push(frame);
+    }
+}
+
+#[test]
+fn test_address_spaces_are_private() {
+    use kernel::mm::address_space::{free_address_space, new_address_space};
+    use kernel::mm::memory::translate_addr_in;
+    use x86_64::structures::paging::{Mapper, OffsetPageTable, Page};
+
+    let table_flags = Flags::PRESENT | Flags::WRITABLE;
+
+    // The kernel's table: an APIC-like page in the lower half and a huge page in the higher half
+    let mut l4 = Box::new(PageTable::new());
+    let mut l3 = Box::new(PageTable::new());
+    let mut l2 = Box::new(PageTable::new());
+    let mut l1 = Box::new(PageTable::new());
+    let mut high_l3 = Box::new(PageTable::new());
+
+    l1[0].set_addr(PhysAddr::new(0xfee0_0000), table_flags | Flags::NO_CACHE);
+    l2[503].set_addr(phys(&l1), table_flags);
+    l3[3].set_addr(phys(&l2), table_flags);
+    l4[0].set_addr(phys(&l3), table_flags);
+    high_l3[0].set_addr(PhysAddr::new(0), table_flags | Flags::HUGE_PAGE);
+    l4[256].set_addr(phys(&high_l3), table_flags);
+
+    let kernel = PhysFrame::containing_address(phys(&l4));
+    let offset = VirtAddr::new(0);
+    let mut frames = TestFrames::default();
+
+    let first = unsafe { new_address_space(kernel, offset, &mut frames) }.unwrap();
+    let second = unsafe { new_address_space(kernel, offset, &mut frames) }.unwrap();
+
+    // Both tasks map their program at the same address
+    let user_flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let code = Page::<Size4KiB>::containing_address(VirtAddr::new(0x400000));
+    for (table, frame) in [(first, 0x10_0000), (second, 0x20_0000)] {
+        let table = unsafe { &mut *(table.start_address().as_u64() as *mut PageTable) };
+        let mut mapper = unsafe { OffsetPageTable::new(table, offset) };
+        let frame = PhysFrame::containing_address(PhysAddr::new(frame));
+        // A page that wasn't mapped can't be in the TLB, and flushing would need ring 0
+        unsafe { mapper.map_to(code, frame, user_flags, &mut frames) }
+            .unwrap()
+            .ignore();
+    }
+
+    let translate = |table: PhysFrame, addr: u64| unsafe {
+        translate_addr_in(table, VirtAddr::new(addr), offset)
+    };
+
+    assert_eq!(translate(first, 0x400123), Some(PhysAddr::new(0x10_0123)));
+    assert_eq!(translate(second, 0x400123), Some(PhysAddr::new(0x20_0123)));
+    assert_eq!(translate(kernel, 0x400123), None);
+
+    // The kernel's mappings are in both
+    for table in [first, second] {
+        assert_eq!(
+            translate(table, 0xfee0_0020),
+            Some(PhysAddr::new(0xfee0_0020))
+        );
+        assert_eq!(
+            translate(table, 0xffff_8000_0000_1000),
+            Some(PhysAddr::new(0x1000))
+        );
+    }
+
+    // Only the copied tables are freed: level 4, the three for the APIC and the two for the program
+    unsafe { free_address_space(first, offset, &mut frames) };
+    assert_eq!(frames.freed.len(), 6);
+    assert!(frames.freed.contains(&first));
+    for table in [&l4, &l3, &l2, &l1, &high_l3] {
+        assert!(
+            !frames
+                .freed
+                .contains(&PhysFrame::containing_address(phys(table)))
+        );
+    }
+    assert_eq!(translate(second, 0x400123), Some(PhysAddr::new(0x20_0123)));
+}
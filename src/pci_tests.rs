@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use kernel::drivers::pci::{
+    Bar, ConfigPorts, Function, config_address, read_config, scan, write_config,
+};
+
+/// Config space of any number of functions, every dword starts out as 0
+#[derive(Default)]
+struct MockPorts {
+    address: u32,
+    dwords: HashMap<u32, u32>,
+    accesses: usize,
+}
+
+impl ConfigPorts for MockPorts {
+    fn write_address(&mut self, address: u32) {
+        assert_eq!(address & 3, 0, "unaligned config address");
+        assert_ne!(address & 1 << 31, 0, "enable bit not set");
+        self.address = address;
+    }
+
+    fn read_data(&mut self) -> u32 {
+        self.accesses += 1;
+        self.dwords.get(&self.address).copied().unwrap_or(0)
+    }
+
+    fn write_data(&mut self, value: u32) {
+        self.accesses += 1;
+        self.dwords.insert(self.address, value);
+    }
+}
+
+const FUNCTION: Function = Function {
+    bus: 1,
+    device: 2,
+    function: 3,
+};
+
+#[test]
+fn test_config_address() {
+    assert_eq!(config_address(FUNCTION, 0x10), 0x8001_1310);
+    // The low bits of the offset pick the byte in the dword, not the dword
+    assert_eq!(config_address(FUNCTION, 0x13), 0x8001_1310);
+}
+
+#[test]
+fn test_config_values_round_trip() {
+    let mut ports = MockPorts::default();
+
+    write_config(&mut ports, FUNCTION, 0x10, 4, 0xDEAD_BEEF);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x10, 4), 0xDEAD_BEEF);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x10, 1), 0xEF);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x12, 2), 0xDEAD);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x13, 1), 0xDE);
+
+    // Other functions have their own config space
+    let other = Function {
+        device: 4,
+        ..FUNCTION
+    };
+    assert_eq!(read_config(&mut ports, other, 0x10, 4), 0);
+}
+
+#[test]
+fn test_narrow_writes_keep_the_rest_of_the_dword() {
+    let mut ports = MockPorts::default();
+    write_config(&mut ports, FUNCTION, 0x04, 4, 0x1122_3344);
+
+    write_config(&mut ports, FUNCTION, 0x05, 1, 0xAB);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x04, 4), 0x1122_AB44);
+
+    write_config(&mut ports, FUNCTION, 0x06, 2, 0xFFFF_CDEF);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x04, 4), 0xCDEF_AB44);
+}
+
+#[test]
+fn test_accesses_across_dwords_are_split() {
+    let mut ports = MockPorts::default();
+
+    write_config(&mut ports, FUNCTION, 0x0E, 4, 0x1234_5678);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x0C, 4), 0x5678_0000);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x10, 4), 0x0000_1234);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x0E, 4), 0x1234_5678);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x0F, 2), 0x3456);
+}
+
+#[test]
+fn test_out_of_range_accesses_dont_touch_the_ports() {
+    let mut ports = MockPorts::default();
+
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x100, 4), u32::MAX);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0xFF, 2), 0xFFFF);
+    assert_eq!(read_config(&mut ports, FUNCTION, 0x200, 1), 0xFF);
+    write_config(&mut ports, FUNCTION, 0xFE, 4, 0);
+
+    assert_eq!(ports.accesses, 0);
+}
+
+/// A bus with a few functions, everything else reads as all ones like on real hardware
+#[derive(Default)]
+struct FakeBus {
+    address: u32,
+    dwords: HashMap<u32, u32>,
+}
+
+impl FakeBus {
+    /// Put `value` at `offset` of `function`'s config space
+    fn set(&mut self, function: Function, offset: u16, value: u32) {
+        self.dwords.insert(config_address(function, offset), value);
+    }
+
+    /// Add a function with a type 0 header and the given BARs
+    fn add(&mut self, function: Function, ids: u32, class: u32, header_type: u8, bars: &[u32]) {
+        self.set(function, 0x00, ids);
+        self.set(function, 0x08, class);
+        self.set(function, 0x0C, (header_type as u32) << 16);
+        for bar in 0..6 {
+            let value = bars.get(bar).copied().unwrap_or(0);
+            self.set(function, 0x10 + bar as u16 * 4, value);
+        }
+    }
+}
+
+impl ConfigPorts for FakeBus {
+    fn write_address(&mut self, address: u32) {
+        self.address = address;
+    }
+
+    fn read_data(&mut self) -> u32 {
+        self.dwords.get(&self.address).copied().unwrap_or(u32::MAX)
+    }
+
+    fn write_data(&mut self, _value: u32) {
+        panic!("enumeration must not write to the config space");
+    }
+}
+
+fn function(bus: u8, device: u8, function: u8) -> Function {
+    Function {
+        bus,
+        device,
+        function,
+    }
+}
+
+#[test]
+fn test_scan_finds_a_device_and_decodes_its_bars() {
+    let mut bus = FakeBus::default();
+    // An e1000 network card: class 02:00, memory BAR, I/O BAR, 64-bit prefetchable memory BAR
+    bus.add(
+        function(0, 3, 0),
+        0x100E_8086,
+        0x0200_0003,
+        0,
+        &[0xFEBC_0000, 0xC001, 0x0000_000C, 0x0000_0001],
+    );
+
+    let devices = scan(&mut bus);
+    assert_eq!(devices.len(), 1);
+
+    let device = &devices[0];
+    assert_eq!(device.function, function(0, 3, 0));
+    assert_eq!((device.vendor_id, device.device_id), (0x8086, 0x100E));
+    assert_eq!(
+        (device.class, device.subclass, device.prog_if),
+        (0x02, 0x00, 0x00)
+    );
+    assert!(device.is_class(0x02, 0x00));
+    assert_eq!(
+        device.bars,
+        [
+            Some(Bar::Memory {
+                address: 0xFEBC_0000,
+                prefetchable: false
+            }),
+            Some(Bar::Io { port: 0xC000 }),
+            Some(Bar::Memory {
+                address: 0x1_0000_0000,
+                prefetchable: true
+            }),
+            // Upper half of the 64-bit BAR
+            None,
+            None,
+            None,
+        ]
+    );
+}
+
+#[test]
+fn test_scan_checks_other_functions_only_on_multifunction_devices() {
+    let mut bus = FakeBus::default();
+    // A multifunction device with functions 0 and 2
+    bus.add(function(0, 1, 0), 0x7000_8086, 0x0601_0000, 0x80, &[]);
+    bus.add(function(0, 1, 2), 0x7010_8086, 0x0101_8000, 0, &[]);
+    // A single function device, function 1 must be ignored even though it reads as something
+    bus.add(function(2, 0, 0), 0x1111_1234, 0x0300_0000, 0, &[]);
+    bus.add(function(2, 0, 1), 0x2222_1234, 0x0300_0000, 0, &[]);
+
+    let found: Vec<_> = scan(&mut bus)
+        .iter()
+        .map(|device| device.function)
+        .collect();
+    assert_eq!(
+        found,
+        [function(0, 1, 0), function(0, 1, 2), function(2, 0, 0)]
+    );
+}
@@ -0,0 +1,59 @@
+use kernel::drivers::reboot::{KeyboardController, pulse_reset, wait_input_clear};
+
+/// A controller that stays busy for the first `busy_polls` status reads
+struct MockController {
+    busy_polls: usize,
+    polls: usize,
+    commands: Vec<u8>,
+}
+
+impl MockController {
+    fn new(busy_polls: usize) -> Self {
+        Self {
+            busy_polls,
+            polls: 0,
+            commands: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardController for MockController {
+    fn status(&mut self) -> u8 {
+        self.polls += 1;
+        // Output buffer full is set too, it has nothing to do with us
+        if self.polls <= self.busy_polls {
+            0b11
+        } else {
+            0b01
+        }
+    }
+
+    fn write_command(&mut self, command: u8) {
+        // Writing while the input buffer is full would lose the command
+        assert!(self.polls > self.busy_polls, "command written while busy");
+        self.commands.push(command);
+    }
+}
+
+#[test]
+fn test_reset_waits_for_the_input_buffer() {
+    let mut controller = MockController::new(3);
+
+    assert!(pulse_reset(&mut controller, 10));
+    assert_eq!(controller.polls, 4);
+    assert_eq!(controller.commands, vec![0xFE]);
+}
+
+#[test]
+fn test_reset_gives_up_on_a_stuck_controller() {
+    let mut controller = MockController::new(usize::MAX);
+
+    assert!(!pulse_reset(&mut controller, 10));
+    assert_eq!(controller.polls, 10);
+    assert!(controller.commands.is_empty());
+
+    // A ready controller is ready on the first poll
+    let mut controller = MockController::new(0);
+    assert!(wait_input_clear(&mut controller, 1));
+    assert_eq!(controller.polls, 1);
+}
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+use kernel::drivers::rtc::{Cmos, DateTime, RtcRegisters, read_datetime};
+
+const CENTURY: u8 = 0x32;
+
+/// CMOS registers, optionally changing to a second set partway through (an update happening)
+struct MockCmos {
+    registers: [u8; 128],
+    /// Register values that get written after the given number of reads
+    updates: VecDeque<(usize, u8, u8)>,
+    /// Status A reads that still report an update in progress
+    busy_reads: usize,
+    reads: usize,
+}
+
+impl MockCmos {
+    fn new(values: &[(u8, u8)]) -> Self {
+        let mut registers = [0; 128];
+        for &(register, value) in values {
+            registers[register as usize] = value;
+        }
+        Self {
+            registers,
+            updates: VecDeque::new(),
+            busy_reads: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl Cmos for MockCmos {
+    fn read(&mut self, register: u8) -> u8 {
+        self.reads += 1;
+        while let Some(&(at, updated, value)) = self.updates.front() {
+            if at > self.reads {
+                break;
+            }
+            self.registers[updated as usize] = value;
+            self.updates.pop_front();
+        }
+
+        if register == 0x0A && self.busy_reads > 0 {
+            self.busy_reads -= 1;
+            return 0x80;
+        }
+        self.registers[register as usize]
+    }
+}
+
+/// 2024-02-29 01:45:30 PM in BCD and 12 hour format, like most firmware sets it up
+fn leap_day_bcd() -> MockCmos {
+    MockCmos::new(&[
+        (0x00, 0x30),
+        (0x02, 0x45),
+        (0x04, 0x80 | 0x01),
+        (0x07, 0x29),
+        (0x08, 0x02),
+        (0x09, 0x24),
+        (CENTURY, 0x20),
+        (0x0B, 0x00),
+    ])
+}
+
+#[test]
+fn test_rtc_snapshot_to_timestamp() {
+    let time = read_datetime(&mut leap_day_bcd(), Some(CENTURY));
+    assert_eq!(
+        time,
+        DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 45,
+            second: 30,
+        }
+    );
+    assert_eq!(time.unix_timestamp(), 1_709_214_330);
+    assert_eq!(time.to_string(), "2024-02-29 13:45:30");
+}
+
+#[test]
+fn test_rtc_binary_24_hour_and_century() {
+    let registers = RtcRegisters {
+        second: 59,
+        minute: 59,
+        hour: 23,
+        day: 31,
+        month: 12,
+        year: 99,
+        century: Some(19),
+        status_b: 0b110,
+    };
+    assert_eq!(registers.decode().unix_timestamp(), 946_684_799);
+
+    // Midnight in 12 hour format is 12 AM, without a century register we assume the 2000s
+    let registers = RtcRegisters {
+        second: 0,
+        minute: 0,
+        hour: 0x12,
+        day: 0x01,
+        month: 0x01,
+        year: 0x00,
+        century: None,
+        status_b: 0,
+    };
+    assert_eq!(registers.decode().unix_timestamp(), 946_684_800);
+}
+
+#[test]
+fn test_rtc_waits_out_updates() {
+    let mut cmos = leap_day_bcd();
+    cmos.busy_reads = 3;
+    // The clock ticks over between reading the seconds and the minutes of the first snapshot
+    cmos.updates.push_back((6, 0x00, 0x31));
+
+    let time = read_datetime(&mut cmos, Some(CENTURY));
+    assert_eq!(time.second, 31);
+    assert_eq!(time.unix_timestamp(), 1_709_214_331);
+}
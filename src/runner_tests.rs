@@ -0,0 +1,154 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{
+    Args, SELFTESTS, accel_args, create_disk_image, disk_args, parse_args, parse_disk_setting,
+    selftest_build_args,
+};
+
+fn args(command: &Command) -> Vec<String> {
+    command
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect()
+}
+
+#[test]
+fn test_kvm_args() {
+    let mut emulated = Command::new("qemu-system-x86_64");
+    emulated.arg("-m").arg("256M").args(accel_args(false));
+    assert_eq!(args(&emulated), ["-m", "256M"]);
+
+    let mut accelerated = Command::new("qemu-system-x86_64");
+    accelerated.arg("-m").arg("256M").args(accel_args(true));
+    assert_eq!(
+        args(&accelerated),
+        ["-m", "256M", "-enable-kvm", "-cpu", "host"]
+    );
+}
+
+#[test]
+fn test_disk_setting() {
+    assert_eq!(
+        parse_disk_setting("disk.img:128M"),
+        Ok((PathBuf::from("disk.img"), 128 * 1024 * 1024))
+    );
+    assert_eq!(
+        parse_disk_setting("target/disk.img:4096"),
+        Ok((PathBuf::from("target/disk.img"), 4096))
+    );
+    // No size means the default, a ':' that isn't followed by a size is part of the path
+    assert_eq!(
+        parse_disk_setting("disk.img"),
+        Ok((PathBuf::from("disk.img"), 64 * 1024 * 1024))
+    );
+    assert_eq!(
+        parse_disk_setting("C:\\disks\\disk.img"),
+        Ok((PathBuf::from("C:\\disks\\disk.img"), 64 * 1024 * 1024))
+    );
+
+    assert!(parse_disk_setting(":1G").is_err());
+    assert!(parse_disk_setting("disk.img:0").is_err());
+}
+
+#[test]
+fn test_disk_image_created_with_the_requested_size() {
+    let dir = std::env::temp_dir().join(format!("lymados-disk-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("disk.img");
+    let _ = std::fs::remove_file(&path);
+
+    assert!(create_disk_image(&path, 8 * 1024 * 1024).unwrap());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8 * 1024 * 1024);
+
+    // An existing image is kept as it is
+    assert!(!create_disk_image(&path, 1024).unwrap());
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 8 * 1024 * 1024);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_disk_args_leave_the_boot_drives_alone() {
+    let mut command = Command::new("qemu-system-x86_64");
+    command
+        .arg("-drive")
+        .arg("format=raw,file=uefi.img")
+        .args(disk_args(Path::new("disk.img")));
+
+    assert_eq!(
+        args(&command),
+        [
+            "-drive",
+            "format=raw,file=uefi.img",
+            "-drive",
+            "if=none,id=disk0,format=raw,file=disk.img",
+            "-device",
+            "virtio-blk-pci,drive=disk0",
+        ]
+    );
+}
+
+fn parse(args: &[&str]) -> Result<Args, String> {
+    parse_args(args.iter().map(|arg| arg.to_string()))
+}
+
+#[test]
+fn test_selftest_args() {
+    assert_eq!(parse(&[]), Ok(Args::default()));
+    assert_eq!(
+        parse(&["--debug", "--whatever"]),
+        Ok(Args {
+            debug: true,
+            selftests: Vec::new()
+        })
+    );
+
+    // All of them, or just the ones asked for, each only once
+    assert_eq!(parse(&["--selftest"]).unwrap().selftests, SELFTESTS);
+    assert_eq!(
+        parse(&["--selftest=oom", "--selftest=oom"])
+            .unwrap()
+            .selftests,
+        ["oom"]
+    );
+    assert_eq!(
+        parse(&["--selftest=oom", "--selftest"]).unwrap().selftests,
+        SELFTESTS
+    );
+    assert_eq!(
+        parse(&["--selftest=oom", "--selftest=heap"])
+            .unwrap()
+            .selftests,
+        ["heap", "oom"]
+    );
+
+    assert!(parse(&["--selftest=nope"]).is_err());
+    assert!(parse(&["--selftest=breakpoint", "--debug"]).is_err());
+}
+
+#[test]
+fn test_selftest_kernel_is_built_with_its_feature() {
+    let args = selftest_build_args(
+        "breakpoint",
+        Path::new("/src/lymados"),
+        Path::new("/src/lymados/target/selftest"),
+    );
+
+    assert_eq!(
+        args,
+        [
+            "build",
+            "--manifest-path",
+            "/src/lymados/kernel/Cargo.toml",
+            "--bin",
+            "kernel",
+            "--target",
+            "x86_64-unknown-none",
+            "--features",
+            "breakpoint_selftest",
+            "--target-dir",
+            "/src/lymados/target/selftest",
+        ]
+    );
+}
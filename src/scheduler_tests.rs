@@ -0,0 +1,1073 @@
+use kernel::mm::address_space::new_address_space;
+use kernel::mm::demand::DemandRegions;
+use kernel::mm::memory::kernel_page_table;
+use kernel::mm::shm::{SHM_BASE, ShmMapping};
+use kernel::tasks::DEFAULT_KERNEL_STACK_PAGES;
+use kernel::tasks::abi::{EBUSY, ESRCH};
+use kernel::tasks::ipc::{self, Mailbox, Message};
+use kernel::tasks::ptrace;
+use kernel::tasks::scheduler::{
+    DEFAULT_TIME_SLICE, Error, KILL_EXIT_CODE, RECENT_EXITS, SchedPolicy, Scheduler, TaskInfo,
+    TaskStats,
+};
+use kernel::tasks::stack::{KernelStack, SLOT_PAGES, StackArea};
+use kernel::tasks::switch;
+use kernel::tasks::syscall;
+use kernel::tasks::task::{
+    BlockReason, BrkChange, DEFAULT_MEMORY_LIMIT_PAGES, DEFAULT_PRIORITY, MemoryError, Task,
+    TaskContext, TaskState,
+};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+};
+
+/// Create a task without loading an ELF, the scheduler doesn't care what it runs
+fn dummy_task(id: u64) -> Task {
+    Task {
+        id,
+        state: TaskState::Ready,
+        context: TaskContext::default(),
+        kernel_stack: kernel_stack(DEFAULT_KERNEL_STACK_PAGES),
+        user_pages: Vec::new(),
+        memory_limit_pages: DEFAULT_MEMORY_LIMIT_PAGES,
+        shm_mappings: Vec::new(),
+        demand_regions: DemandRegions::new(),
+        heap_base: VirtAddr::zero(),
+        brk: VirtAddr::zero(),
+        page_table: PhysFrame::containing_address(PhysAddr::new(0)),
+        run_count: 0,
+        total_ticks: 0,
+        priority: DEFAULT_PRIORITY,
+        mailbox: Mailbox::new(),
+    }
+}
+
+/// A kernel stack in a stack area of its own, the kernel's area only exists after boot
+/// Its page tables and frames are leaked, dropping it leaves them alone.
+fn kernel_stack(pages: usize) -> KernelStack {
+    let offset = VirtAddr::new(0);
+    let mut frames = CountingFrames::default();
+    let (table, level_4) = empty_kernel_table();
+    let mut mapper = unsafe { table_mapper(level_4) };
+
+    let mut area = unsafe { StackArea::reserve(&mut mapper, &mut frames, offset) }.unwrap();
+    let stack = unsafe { area.map_stack(pages, &mut mapper, &mut frames, offset) }.unwrap();
+
+    Box::leak(table);
+    frames.frames.into_iter().for_each(|frame| {
+        Box::leak(frame);
+    });
+    stack
+}
+
+/// A mapper for the level 4 table in `level_4`, the physical memory is "mapped" at offset 0
+unsafe fn table_mapper(level_4: PhysFrame) -> OffsetPageTable<'static> {
+    let table = level_4.start_address().as_u64() as *mut PageTable;
+    unsafe { OffsetPageTable::new(&mut *table, VirtAddr::new(0)) }
+}
+
+/// `count` user pages for a task that never maps them, enough for the accounting
+fn user_pages(count: u64) -> Vec<Page<Size4KiB>> {
+    (0..count)
+        .map(|i| Page::containing_address(VirtAddr::new(0x40_0000 + i * 4096)))
+        .collect()
+}
+
+#[test]
+fn test_scheduler_rejects_tasks_over_limit() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_max_tasks(3);
+
+    for id in 1..=3 {
+        assert_eq!(scheduler.add_task(dummy_task(id)), Ok(()));
+    }
+
+    assert_eq!(scheduler.add_task(dummy_task(4)), Err(Error::TooManyTasks));
+    assert_eq!(scheduler.task_count(), 3);
+
+    // The existing tasks are still scheduled normally
+    scheduler.start();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+}
+
+#[test]
+fn test_scheduler_raising_limit_allows_more_tasks() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_max_tasks(1);
+
+    assert_eq!(scheduler.add_task(dummy_task(1)), Ok(()));
+    assert_eq!(scheduler.add_task(dummy_task(2)), Err(Error::TooManyTasks));
+
+    scheduler.set_max_tasks(2);
+    assert_eq!(scheduler.add_task(dummy_task(2)), Ok(()));
+    assert_eq!(scheduler.task_count(), 2);
+}
+
+#[test]
+fn test_scheduler_snapshot() {
+    let mut scheduler = Scheduler::new();
+
+    let mut big = dummy_task(1);
+    big.user_pages = user_pages(20);
+    scheduler.add_task(big).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    assert_eq!(
+        scheduler.snapshot(),
+        [
+            TaskInfo {
+                id: 1,
+                state: TaskState::Running,
+                resident_pages: 20,
+            },
+            TaskInfo {
+                id: 2,
+                state: TaskState::Ready,
+                resident_pages: 0,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_memory_limit_stops_only_the_greedy_task() {
+    let mut greedy = dummy_task(1);
+    greedy.memory_limit_pages = 8;
+    let mut other = dummy_task(2);
+    other.memory_limit_pages = 8;
+
+    // Grow one page at a time, like a task calling sbrk in a loop
+    let mut grown = 0;
+    while greedy.check_memory_limit(1).is_ok() {
+        grown += 1;
+        greedy.user_pages = user_pages(grown);
+        assert!(grown <= 8, "Memory limit not enforced");
+    }
+
+    assert_eq!(greedy.resident_pages(), 8);
+    assert_eq!(
+        greedy.check_memory_limit(1),
+        Err(MemoryError::LimitExceeded)
+    );
+    // Asking for nothing is still fine at the limit
+    assert_eq!(greedy.check_memory_limit(0), Ok(()));
+
+    // The other task still has its whole budget
+    assert_eq!(other.check_memory_limit(8), Ok(()));
+    assert_eq!(other.check_memory_limit(9), Err(MemoryError::LimitExceeded));
+
+    // Huge requests don't overflow into success
+    assert_eq!(
+        other.check_memory_limit(usize::MAX),
+        Err(MemoryError::LimitExceeded)
+    );
+}
+
+#[test]
+fn test_resident_pages_count_shared_memory() {
+    let mut task = dummy_task(1);
+    task.memory_limit_pages = 8;
+    task.user_pages = user_pages(3);
+    assert_eq!(task.resident_pages(), 3);
+
+    // Shared pages count against the limit too, even though the task doesn't own their frames
+    task.shm_mappings.push(ShmMapping {
+        id: 1,
+        base: VirtAddr::new(SHM_BASE),
+        pages: 4,
+    });
+    assert_eq!(task.resident_pages(), 7);
+    assert_eq!(task.check_memory_limit(1), Ok(()));
+    assert_eq!(task.check_memory_limit(2), Err(MemoryError::LimitExceeded));
+
+    // Nothing is really mapped, so there's nothing for Drop to unmap
+    task.shm_mappings.clear();
+}
+
+#[test]
+fn test_scheduler_skips_parked_tasks() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+
+    // Task 1 parks itself, it keeps the CPU until the next switch
+    scheduler.block_current(BlockReason::Events);
+    assert!(scheduler.is_blocked(1));
+    assert!(scheduler.schedule().is_some());
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // Task 2 waits for an interrupt, only task 3 is left to run
+    scheduler.block_current(BlockReason::Interrupt(33));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+    assert!(scheduler.schedule().is_none());
+    assert_eq!(scheduler.current_task_id(), Some(3));
+
+    // Only the task waiting for events wakes up
+    assert_eq!(scheduler.unpark(|reason| reason == BlockReason::Events), 1);
+    assert!(!scheduler.is_blocked(1));
+    assert!(scheduler.is_blocked(2));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+
+    assert_eq!(
+        scheduler.unpark(|reason| matches!(reason, BlockReason::Interrupt(_))),
+        1
+    );
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+}
+
+#[test]
+fn test_scheduler_keeps_running_when_everything_is_parked() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    scheduler.block_current(BlockReason::Events);
+    scheduler.schedule();
+    scheduler.block_current(BlockReason::Events);
+
+    assert!(scheduler.schedule().is_none());
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    assert_eq!(
+        scheduler.snapshot()[1].state,
+        TaskState::Blocked(BlockReason::Events)
+    );
+}
+
+#[test]
+fn test_ptrace_targets() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    // Task 1 is running, reading its registers would be racy
+    assert_eq!(ptrace::target(&scheduler, 1).err(), Some(EBUSY));
+    assert_eq!(ptrace::target(&scheduler, 2).unwrap().id, 2);
+    assert_eq!(ptrace::target(&scheduler, 3).err(), Some(ESRCH));
+
+    // Blocked tasks can be inspected too
+    scheduler.schedule();
+    scheduler.block_current(BlockReason::Events);
+    assert_eq!(ptrace::target(&scheduler, 2).unwrap().id, 2);
+}
+
+#[test]
+fn test_stopped_task_is_not_scheduled_until_continued() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+
+    assert_eq!(scheduler.stop(2), Ok(false));
+    assert!(scheduler.is_stopped(2));
+    assert_eq!(scheduler.stop(4), Err(Error::NoSuchTask));
+
+    // 2 is skipped, round and round
+    for expected in [3, 1, 3, 1] {
+        scheduler.schedule().unwrap();
+        assert_eq!(scheduler.current_task_id(), Some(expected));
+    }
+
+    // Continuing makes it ready, and it runs on its next turn
+    assert_eq!(scheduler.resume(2), Ok(()));
+    assert_eq!(scheduler.task(2).unwrap().state, TaskState::Ready);
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // Continuing a task that isn't stopped does nothing
+    assert_eq!(scheduler.resume(2), Ok(()));
+    assert_eq!(scheduler.task(2).unwrap().state, TaskState::Running);
+    assert_eq!(scheduler.resume(4), Err(Error::NoSuchTask));
+}
+
+#[test]
+fn test_stopping_the_running_task() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    // The caller has to switch away, and the task stays stopped when it does
+    assert_eq!(scheduler.stop(1), Ok(true));
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    assert_eq!(scheduler.task(1).unwrap().state, TaskState::Stopped);
+
+    // Nothing else to run, task 2 keeps going
+    assert!(scheduler.schedule().is_none());
+
+    scheduler.resume(1).unwrap();
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+}
+
+#[test]
+fn test_exited_task_never_runs_again() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    // Task 1 faulted, there is nothing to save its context to
+    scheduler.exit_current(-1);
+    assert_eq!(scheduler.current_task_id(), None);
+    let (old_context, _, _) = scheduler.schedule().unwrap();
+    assert!(old_context.is_null());
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // It's gone, so it can't be stopped or continued
+    assert!(scheduler.task(1).is_none());
+    assert_eq!(scheduler.stop(1), Err(Error::NoSuchTask));
+    assert_eq!(scheduler.resume(1), Err(Error::NoSuchTask));
+    assert!(scheduler.schedule().is_none());
+    assert_eq!(scheduler.current_task_id(), Some(2));
+}
+
+#[test]
+fn test_exit_removes_the_task() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    scheduler.exit_current(42);
+    assert_eq!(scheduler.task_count(), 2);
+    assert!(scheduler.task(2).is_none());
+
+    let exited = scheduler.take_exited();
+    assert_eq!(exited.len(), 1);
+    assert_eq!(exited[0].id, 2);
+    assert_eq!(exited[0].state, TaskState::Exited(42));
+    assert!(scheduler.take_exited().is_empty());
+
+    // Round robin goes on with the task after the exited one
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+}
+
+#[test]
+fn test_exit_of_the_last_task_in_the_list_wraps_around() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+    scheduler.schedule().unwrap();
+
+    scheduler.exit_current(0);
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+
+    // When the only task exits there is nothing left to run
+    scheduler.exit_current(0);
+    assert_eq!(scheduler.task_count(), 0);
+    assert_eq!(scheduler.take_exited().len(), 2);
+    assert!(scheduler.schedule().is_none());
+    assert_eq!(scheduler.current_task_id(), None);
+}
+
+#[test]
+fn test_removing_a_task_keeps_the_running_one() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+    scheduler.schedule().unwrap();
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+
+    // Task 3 moves into the middle slot but keeps running
+    let removed = scheduler.remove_task(2).unwrap();
+    assert_eq!(removed.id, 2);
+    assert_eq!(scheduler.task_count(), 2);
+    assert_eq!(scheduler.current_task_id(), Some(3));
+    assert!(scheduler.remove_task(2).is_none());
+
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+}
+
+#[test]
+fn test_removing_the_running_task_reschedules() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+    scheduler.schedule().unwrap();
+
+    let removed = scheduler.remove_task(2).unwrap();
+    assert_eq!(removed.id, 2);
+    assert_eq!(scheduler.current_task_id(), None);
+    assert!(scheduler.tick());
+
+    // Nothing saves to the removed task's context
+    let (old_context, _, _) = scheduler.schedule().unwrap();
+    assert!(old_context.is_null());
+    assert_eq!(scheduler.current_task_id(), Some(3));
+}
+
+#[test]
+fn test_last_task_cant_be_removed() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    assert!(scheduler.remove_task(2).is_some());
+    assert!(scheduler.remove_task(1).is_none());
+    assert_eq!(scheduler.kill_task(1), Err(Error::LastTask));
+    assert_eq!(scheduler.current_task_id(), Some(1));
+}
+
+#[test]
+fn test_killed_task_is_reaped_like_an_exited_one() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+
+    assert_eq!(scheduler.kill_task(4), Err(Error::NoSuchTask));
+    assert_eq!(scheduler.kill_task(2), Ok(false));
+    assert_eq!(scheduler.kill_task(1), Ok(true));
+    assert_eq!(scheduler.current_task_id(), None);
+
+    let exited = scheduler.take_exited();
+    assert_eq!(exited.len(), 2);
+    assert!(
+        exited
+            .iter()
+            .all(|task| task.state == TaskState::Exited(KILL_EXIT_CODE))
+    );
+
+    scheduler.schedule().unwrap();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+}
+
+#[test]
+fn test_exit_status_outlives_the_task() {
+    let mut scheduler = Scheduler::new();
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.start();
+    assert_eq!(scheduler.exit_status(1), None);
+
+    scheduler.exit_current(-1);
+    scheduler.kill_task(2).unwrap();
+    assert_eq!(scheduler.exit_status(1), Some(-1));
+
+    // Freeing them doesn't forget how they ended
+    drop(scheduler.take_exited());
+    assert_eq!(scheduler.exit_status(1), Some(-1));
+    assert_eq!(scheduler.exit_status(2), Some(KILL_EXIT_CODE));
+    assert_eq!(scheduler.exit_status(3), None);
+
+    // A reused ID reports its latest exit
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.kill_task(1).unwrap();
+    assert_eq!(scheduler.exit_status(1), Some(KILL_EXIT_CODE));
+
+    // Only the last few are kept
+    for id in 10..10 + RECENT_EXITS as u64 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+        scheduler.kill_task(id).unwrap();
+    }
+    assert_eq!(scheduler.exit_status(1), None);
+    assert_eq!(scheduler.exit_status(10), Some(KILL_EXIT_CODE));
+}
+
+#[test]
+fn test_getpid() {
+    let mut scheduler = Scheduler::new();
+    assert_eq!(syscall::getpid(&scheduler), 0);
+
+    scheduler.add_task(dummy_task(7)).unwrap();
+    scheduler.add_task(dummy_task(8)).unwrap();
+    scheduler.start();
+
+    // Asking twice gives the same ID
+    let pid = syscall::getpid(&scheduler);
+    assert_eq!(pid, 7);
+    assert_eq!(syscall::getpid(&scheduler), pid);
+
+    scheduler.schedule().unwrap();
+    assert_eq!(syscall::getpid(&scheduler), 8);
+}
+
+#[test]
+fn test_yielding_task_and_other_task_both_make_progress() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    // The registers on the CPU, rbx counts how far each task got
+    let mut cpu = TaskContext {
+        rip: 0x1000,
+        ..Default::default()
+    };
+
+    for round in 1..=3 {
+        // Task 1 works a bit and yields
+        assert_eq!(scheduler.current_task_id(), Some(1));
+        cpu.rbx += 1;
+        switch::switch_context(&mut scheduler, &mut cpu);
+
+        // Task 2 runs until the timer switches back
+        assert_eq!(scheduler.current_task_id(), Some(2));
+        cpu.rbx += 1;
+        switch::switch_context(&mut scheduler, &mut cpu);
+
+        assert_eq!(cpu.rip, 0x1000);
+        assert_eq!(cpu.rbx, round);
+    }
+    assert_eq!(scheduler.task(2).unwrap().context.rbx, 3);
+
+    // With nothing else to run, yielding comes straight back
+    scheduler.stop(2).unwrap();
+    switch::switch_context(&mut scheduler, &mut cpu);
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    assert_eq!(cpu.rbx, 3);
+}
+
+#[test]
+fn test_brk_grows_and_shrinks_the_heap() {
+    let base = VirtAddr::new(0x600000);
+    let mut task = dummy_task(1);
+    task.heap_base = base;
+    task.brk = base;
+
+    // Growing by 3 pages maps them right after the heap base
+    assert_eq!(
+        task.brk_change(base + 3 * 4096u64),
+        Ok(BrkChange::Grow {
+            start: base,
+            pages: 3
+        })
+    );
+    task.brk = base + 3 * 4096u64;
+    task.user_pages = user_pages(3);
+
+    // A break in the middle of a page keeps the whole page
+    assert_eq!(
+        task.brk_change(base + 4100u64),
+        Ok(BrkChange::Shrink {
+            start: base + 2 * 4096u64,
+            pages: 1
+        })
+    );
+    task.brk = base + 4100u64;
+    task.user_pages = user_pages(2);
+    assert_eq!(
+        task.brk_change(base + 5000u64),
+        Ok(BrkChange::Grow {
+            start: base + 2 * 4096u64,
+            pages: 0
+        })
+    );
+
+    // Below the heap base or way too big
+    assert_eq!(task.brk_change(base - 1u64), Err(MemoryError::InvalidBreak));
+    assert_eq!(
+        task.brk_change(base + (1u64 << 40)),
+        Err(MemoryError::InvalidBreak)
+    );
+
+    // Over the memory limit
+    task.memory_limit_pages = 4;
+    assert_eq!(
+        task.brk_change(base + 4 * 4096u64),
+        Ok(BrkChange::Grow {
+            start: base + 2 * 4096u64,
+            pages: 2
+        })
+    );
+    assert_eq!(
+        task.brk_change(base + 5 * 4096u64),
+        Err(MemoryError::LimitExceeded)
+    );
+
+    // Back to an empty heap
+    assert_eq!(
+        task.brk_change(base),
+        Ok(BrkChange::Shrink {
+            start: base,
+            pages: 2
+        })
+    );
+}
+
+#[test]
+fn test_tasks_switch_when_their_time_slice_runs_out() {
+    let mut scheduler = Scheduler::new();
+    assert_eq!(scheduler.time_slice(), DEFAULT_TIME_SLICE);
+    scheduler.set_time_slice(3);
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+
+    // Like the timer interrupt, which task runs after each tick
+    let mut running = Vec::new();
+    for _ in 0..9 {
+        if scheduler.tick() {
+            scheduler.schedule();
+        }
+        running.push(scheduler.current_task_id().unwrap());
+    }
+    assert_eq!(running, [1, 1, 2, 2, 2, 1, 1, 1, 2]);
+
+    // A task that blocks doesn't get to finish its slice
+    scheduler.block_current(BlockReason::Events);
+    assert!(scheduler.tick());
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+
+    // Slices are at least a tick long
+    scheduler.set_time_slice(0);
+    assert_eq!(scheduler.time_slice(), 1);
+    assert!(scheduler.tick());
+}
+
+#[test]
+fn test_task_stats_count_runs_and_ticks() {
+    let mut scheduler = Scheduler::new();
+    scheduler.set_time_slice(3);
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    assert_eq!(
+        scheduler.task_stats(1),
+        Some(TaskStats {
+            run_count: 0,
+            total_ticks: 0,
+        })
+    );
+
+    scheduler.start();
+    for _ in 0..9 {
+        if scheduler.tick() {
+            scheduler.schedule();
+        }
+    }
+
+    // Task 1 ran for ticks 1-3 and 7-9, task 2 for 4-6 and is running again now
+    assert_eq!(
+        scheduler.task_stats(1),
+        Some(TaskStats {
+            run_count: 2,
+            total_ticks: 6,
+        })
+    );
+    assert_eq!(
+        scheduler.task_stats(2),
+        Some(TaskStats {
+            run_count: 2,
+            total_ticks: 3,
+        })
+    );
+
+    // A yield switches without a tick going by
+    scheduler.schedule();
+    let stats = scheduler.task_stats(1).unwrap();
+    assert_eq!(stats.run_count, 3);
+    assert_eq!(stats.total_ticks, 6);
+
+    let total: u64 = [1, 2]
+        .iter()
+        .map(|&id| scheduler.task_stats(id).unwrap().total_ticks)
+        .sum();
+    assert_eq!(total, 9);
+    assert_eq!(scheduler.task_stats(3), None);
+}
+
+#[test]
+fn test_priority_policy_runs_the_most_important_tasks() {
+    let mut scheduler = Scheduler::new();
+    assert_eq!(scheduler.policy(), SchedPolicy::RoundRobin);
+    scheduler.set_policy(SchedPolicy::Priority);
+
+    for id in 1..=3 {
+        scheduler.add_task(dummy_task(id)).unwrap();
+    }
+    scheduler.set_priority(1, 1).unwrap();
+    scheduler.set_priority(2, 5).unwrap();
+    scheduler.set_priority(3, 5).unwrap();
+    assert_eq!(scheduler.set_priority(4, 5), Err(Error::NoSuchTask));
+    scheduler.start();
+
+    // The two priority 5 tasks take turns, task 1 never gets the CPU back
+    let mut running = Vec::new();
+    for _ in 0..6 {
+        scheduler.schedule();
+        running.push(scheduler.current_task_id().unwrap());
+    }
+    assert_eq!(running, [2, 3, 2, 3, 2, 3]);
+
+    // With one of them blocked the other one keeps running
+    scheduler.block_current(BlockReason::Events);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    assert_eq!(scheduler.schedule(), None);
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // Only once both are blocked task 1 runs
+    scheduler.block_current(BlockReason::Events);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    assert_eq!(scheduler.schedule(), None);
+
+    // A woken up priority 5 task takes over again
+    scheduler.unpark(|reason| reason == BlockReason::Events);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // Back to round-robin everyone gets a turn
+    scheduler.set_policy(SchedPolicy::RoundRobin);
+    let mut running = Vec::new();
+    for _ in 0..3 {
+        scheduler.schedule();
+        running.push(scheduler.current_task_id().unwrap());
+    }
+    assert_eq!(running, [3, 1, 2]);
+}
+
+#[test]
+fn test_ping_pong_between_two_tasks() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.start();
+    scheduler.schedule();
+
+    // Task 2 waits for a message first, its mailbox is empty so it blocks and task 1 runs
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    assert_eq!(scheduler.recv_or_block(), None);
+    assert!(scheduler.is_blocked(2));
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+
+    // The ping wakes task 2 up, task 1 then waits for the answer
+    let ping = Message {
+        sender: 1,
+        data: b"ping".to_vec(),
+    };
+    assert_eq!(scheduler.send_message(2, ping.clone()), Ok(()));
+    assert!(!scheduler.is_blocked(2));
+    assert_eq!(scheduler.recv_or_block(), None);
+    assert!(scheduler.is_blocked(1));
+
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    assert_eq!(scheduler.recv_or_block(), Some(ping));
+
+    let pong = Message {
+        sender: 2,
+        data: b"pong".to_vec(),
+    };
+    assert_eq!(scheduler.send_message(1, pong.clone()), Ok(()));
+    assert!(!scheduler.is_blocked(1));
+
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+    assert_eq!(scheduler.recv_or_block(), Some(pong));
+    assert!(!scheduler.is_blocked(1));
+
+    // Nobody to send to
+    assert_eq!(
+        scheduler.send_message(
+            3,
+            Message {
+                sender: 1,
+                data: Vec::new(),
+            }
+        ),
+        Err(ipc::Error::NoSuchTask)
+    );
+}
+
+#[test]
+fn test_sleeping_task_lets_the_other_one_run() {
+    let mut scheduler = Scheduler::new();
+    scheduler.add_task(dummy_task(1)).unwrap();
+    scheduler.add_task(dummy_task(2)).unwrap();
+    scheduler.set_idle_task(dummy_task(3)).unwrap();
+    scheduler.start();
+
+    // Task 1 sleeps until tick 5, task 2 gets the CPU
+    scheduler.sleep_current(5);
+    assert_eq!(
+        scheduler.task(1).unwrap().state,
+        TaskState::Blocked(BlockReason::Sleep(5))
+    );
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+
+    // Task 1 is skipped until it's woken up, and it isn't woken up early
+    for now in 1..5 {
+        assert_eq!(scheduler.wake_sleepers(now), 0);
+        scheduler.schedule();
+        assert_eq!(scheduler.current_task_id(), Some(2));
+    }
+    assert_eq!(scheduler.wake_sleepers(5), 1);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(1));
+
+    // Once both sleep the idle task runs, and it gives the CPU back as soon as one wakes up
+    scheduler.sleep_current(8);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+    scheduler.sleep_current(7);
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(3));
+    assert!(scheduler.tick());
+    assert!(scheduler.schedule().is_none());
+
+    scheduler.wake_sleepers(7);
+    assert!(scheduler.tick());
+    scheduler.schedule();
+    assert_eq!(scheduler.current_task_id(), Some(2));
+}
+
+#[test]
+fn test_kernel_stack_top_is_page_aligned() {
+    let stack = kernel_stack(8);
+    assert_eq!(stack.pages(), 8);
+    assert_eq!(stack.top() % 4096, 0);
+    assert_eq!(stack.top() - stack.bottom(), 8 * 4096);
+
+    let mut task = dummy_task(1);
+    task.kernel_stack = stack;
+    assert_eq!(task.kernel_stack_top() % 4096, 0);
+}
+
+#[test]
+fn test_kernel_stacks_have_an_unmapped_guard_page() {
+    let offset = VirtAddr::new(0);
+    let mut frames = CountingFrames::default();
+    let (_table, level_4) = empty_kernel_table();
+    let mut mapper = unsafe { table_mapper(level_4) };
+
+    let mut area = unsafe { StackArea::reserve(&mut mapper, &mut frames, offset) }.unwrap();
+    // The area is in the kernel's half, where every address space shares it
+    assert!(
+        mapper.level_4_table()[256]
+            .flags()
+            .contains(PageTableFlags::PRESENT)
+    );
+
+    let stacks: Vec<KernelStack> = (0..2)
+        .map(|_| unsafe { area.map_stack(8, &mut mapper, &mut frames, offset) }.unwrap())
+        .collect();
+    for stack in &stacks {
+        assert!(area.contains(stack.bottom()));
+
+        // Every page of the stack is there and zeroed, the one below it isn't mapped at all
+        for addr in (stack.bottom()..stack.top()).step_by(4096) {
+            let phys = mapper.translate_addr(VirtAddr::new(addr)).unwrap();
+            let memory = unsafe { core::slice::from_raw_parts(phys.as_u64() as *const u8, 4096) };
+            assert!(memory.iter().all(|&byte| byte == 0));
+        }
+        assert_eq!(
+            stack.guard_page().start_address().as_u64(),
+            stack.bottom() - 4096
+        );
+        assert!(mapper.translate_page(stack.guard_page()).is_err());
+    }
+
+    // Overflowing one stack doesn't run into the next one either
+    assert!(stacks[1].guard_page().start_address().as_u64() >= stacks[0].top());
+
+    // Too small, or too big to leave room for the guard page
+    assert!(unsafe { area.map_stack(0, &mut mapper, &mut frames, offset) }.is_err());
+    assert!(unsafe { area.map_stack(SLOT_PAGES, &mut mapper, &mut frames, offset) }.is_err());
+
+    // A freed stack's frames come back and its slot is used again
+    unsafe { area.unmap_stack(&stacks[1], &mut mapper, &mut frames) };
+    assert_eq!(frames.freed.len(), 8);
+    assert!(
+        mapper
+            .translate_addr(VirtAddr::new(stacks[1].bottom()))
+            .is_none()
+    );
+    let again = unsafe { area.map_stack(8, &mut mapper, &mut frames, offset) }.unwrap();
+    assert_eq!(again.bottom(), stacks[1].bottom());
+
+    // Not in the kernel's area, dropping them doesn't touch its page tables
+    drop(stacks);
+    drop(again);
+}
+
+#[repr(C, align(4096))]
+struct Frame([u8; 4096]);
+
+/// Hands out heap allocated frames full of junk and remembers which ones come back,
+/// the physical memory is "mapped" at offset 0
+#[derive(Default)]
+struct CountingFrames {
+    frames: Vec<Box<Frame>>,
+    freed: Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for CountingFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = Box::new(Frame([0xAA; 4096]));
+        let addr = PhysAddr::new(frame.0.as_ptr() as u64);
+        self.frames.push(frame);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for CountingFrames {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.freed.push(frame);
+    }
+}
+
+/// A task with an address space of its own copied from `kernel`, with code, data and a stack page
+/// far away from them (so they need tables of their own) mapped from `frames`
+/// Returns the frames behind the 3 pages.
+fn task_with_user_pages(
+    id: u64,
+    kernel: PhysFrame,
+    frames: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> (Task, Vec<PhysFrame>) {
+    let offset = VirtAddr::new(0);
+
+    let mut task = dummy_task(id);
+    task.page_table = unsafe { new_address_space(kernel, offset, frames) }.unwrap();
+
+    let user_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut mapper = unsafe { table_mapper(task.page_table) };
+    let mut page_frames = Vec::new();
+    for addr in [0x400000u64, 0x401000, 0x7fff_ffff_f000] {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        let frame = frames.allocate_frame().unwrap();
+        // Never active, so nothing to flush (and flushing would need ring 0)
+        unsafe { mapper.map_to(page, frame, user_flags, frames) }
+            .unwrap()
+            .ignore();
+        task.user_pages.push(page);
+        page_frames.push(frame);
+    }
+
+    (task, page_frames)
+}
+
+/// An empty kernel table for tasks to copy, it isn't theirs to free
+fn empty_kernel_table() -> (Box<Frame>, PhysFrame) {
+    let mut table = Box::new(Frame([0; 4096]));
+    let frame = PhysFrame::containing_address(PhysAddr::new(table.0.as_mut_ptr() as u64));
+    (table, frame)
+}
+
+#[test]
+fn test_freeing_a_task_gives_back_every_frame() {
+    let offset = VirtAddr::new(0);
+    let mut frames = CountingFrames::default();
+    let (_table, kernel) = empty_kernel_table();
+
+    let (mut task, page_frames) = task_with_user_pages(90, kernel, &mut frames);
+    assert_eq!(task.resident_pages(), 3);
+    let allocated: Vec<PhysFrame> = frames
+        .frames
+        .iter()
+        .map(|frame| PhysFrame::containing_address(PhysAddr::new(frame.0.as_ptr() as u64)))
+        .collect();
+
+    unsafe { task.free_address_space(&mut frames, offset) };
+
+    // Every frame it got came back once: the 3 pages, level 4 and the tables for both ends of user space
+    assert_eq!(frames.freed.len(), allocated.len());
+    assert_eq!(frames.freed.len(), 3 + 1 + 2 * 3);
+    for frame in &allocated {
+        assert_eq!(frames.freed.iter().filter(|f| *f == frame).count(), 1);
+    }
+    assert!(!frames.freed.contains(&kernel));
+    assert!(task.user_pages.is_empty());
+    assert_eq!(task.resident_pages(), 0);
+
+    // The pages were zeroed before they went back, they were full of junk
+    for frame in page_frames {
+        let bytes = unsafe { &*(frame.start_address().as_u64() as *const [u8; 4096]) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+
+    // Nothing left for Drop to free
+    assert_eq!(task.page_table, kernel_page_table());
+}
+
+/// Hands out heap allocated frames and reuses the ones that come back, so it only grows when
+/// more frames are in use at once than ever before
+#[derive(Default)]
+struct ReusingFrames {
+    frames: Vec<Box<Frame>>,
+    free: Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for ReusingFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free.pop() {
+            return Some(frame);
+        }
+
+        let frame = Box::new(Frame([0; 4096]));
+        let addr = PhysAddr::new(frame.0.as_ptr() as u64);
+        self.frames.push(frame);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for ReusingFrames {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free.push(frame);
+    }
+}
+
+#[test]
+fn test_spawning_and_freeing_tasks_doesnt_grow_memory() {
+    let mut frames = ReusingFrames::default();
+    let (_table, kernel) = empty_kernel_table();
+
+    // Like a task that runs and exits over and over, what one task needed is all that's ever allocated
+    for id in 0..100 {
+        let (mut task, _) = task_with_user_pages(100 + id, kernel, &mut frames);
+        unsafe { task.free_address_space(&mut frames, VirtAddr::new(0)) };
+        // Frees the kernel stack, Drop has nothing else left to do
+        drop(task);
+
+        assert_eq!(frames.free.len(), frames.frames.len());
+    }
+
+    // The 3 pages, level 4 and the tables for both ends of user space
+    assert_eq!(frames.frames.len(), 3 + 1 + 2 * 3);
+}
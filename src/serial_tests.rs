@@ -0,0 +1,49 @@
+use std::collections::VecDeque;
+
+use kernel::drivers::serial::{Uart, receive};
+use kernel::events::{Event, EventKind};
+
+/// A UART with bytes waiting in its receive buffer
+#[derive(Default)]
+struct MockUart {
+    received: VecDeque<u8>,
+    data_reads: usize,
+}
+
+impl Uart for MockUart {
+    fn line_status(&mut self) -> u8 {
+        // Transmitter empty, plus data ready if there's something to read
+        0x60 | !self.received.is_empty() as u8
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.data_reads += 1;
+        self.received.pop_front().unwrap_or(0)
+    }
+}
+
+#[test]
+fn test_receive_reads_waiting_bytes() {
+    let mut uart = MockUart {
+        received: VecDeque::from([b'h', b'i']),
+        ..Default::default()
+    };
+
+    assert_eq!(receive(&mut uart), Some(b'h'));
+    assert_eq!(receive(&mut uart), Some(b'i'));
+    assert_eq!(receive(&mut uart), None);
+    assert_eq!(uart.data_reads, 2);
+}
+
+#[test]
+fn test_receive_leaves_the_data_register_alone_without_data() {
+    let mut uart = MockUart::default();
+
+    assert_eq!(receive(&mut uart), None);
+    assert_eq!(uart.data_reads, 0);
+}
+
+#[test]
+fn test_serial_input_event_kind() {
+    assert_eq!(Event::SerialInput(b'x').kind(), EventKind::Serial);
+}
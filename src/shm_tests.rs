@@ -0,0 +1,141 @@
+use kernel::mm::address_space::new_address_space;
+use kernel::mm::memory::translate_addr_in;
+use kernel::mm::shm::{Error, SHM_BASE, SHM_MAX_PAGES, ShmMapping, ShmRegistry, map_frames};
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB,
+    },
+};
+
+const PAGE_SIZE: usize = 4096;
+
+fn frames(count: u64) -> Vec<PhysFrame> {
+    (0..count)
+        .map(|i| PhysFrame::containing_address(PhysAddr::new(i * PAGE_SIZE as u64)))
+        .collect()
+}
+
+#[test]
+fn test_shm_sizes() {
+    assert_eq!(ShmRegistry::pages_for(0), Err(Error::InvalidSize));
+    assert_eq!(ShmRegistry::pages_for(1), Ok(1));
+    assert_eq!(ShmRegistry::pages_for(4096), Ok(1));
+    assert_eq!(ShmRegistry::pages_for(4097), Ok(2));
+    assert_eq!(
+        ShmRegistry::pages_for((SHM_MAX_PAGES * PAGE_SIZE) as u64 + 1),
+        Err(Error::InvalidSize)
+    );
+}
+
+#[repr(C, align(4096))]
+struct Frame([u8; PAGE_SIZE]);
+
+/// Hands out zeroed heap allocated frames, the physical memory is "mapped" at offset 0
+#[derive(Default)]
+struct TestFrames {
+    frames: Vec<Box<Frame>>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for TestFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = Box::new(Frame([0; PAGE_SIZE]));
+        let addr = PhysAddr::new(frame.0.as_ptr() as u64);
+        self.frames.push(frame);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for TestFrames {
+    unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {}
+}
+
+#[test]
+fn test_shm_two_tasks_share_the_same_memory() {
+    let offset = VirtAddr::new(0);
+    let mut allocator = TestFrames::default();
+
+    // Two tasks with address spaces of their own, copied from an empty kernel table
+    let kernel = allocator.allocate_frame().unwrap();
+    let task_a = unsafe { new_address_space(kernel, offset, &mut allocator) }.unwrap();
+    let task_b = unsafe { new_address_space(kernel, offset, &mut allocator) }.unwrap();
+    assert_ne!(task_a, task_b);
+
+    let mut registry = ShmRegistry::new();
+    let object: Vec<PhysFrame> = (0..2)
+        .map(|_| allocator.allocate_frame().unwrap())
+        .collect();
+    let id = registry.insert(object);
+    assert_eq!(registry.pages(id), Ok(2));
+
+    // Each task gets its own range (with a guard page in between), backed by the same frames
+    let mut map = |table: PhysFrame| {
+        let (base, frames) = registry.attach(id).unwrap();
+        let frames = frames.to_vec();
+        let table = unsafe { &mut *(table.start_address().as_u64() as *mut PageTable) };
+        let mut mapper = unsafe { OffsetPageTable::new(table, offset) };
+        map_frames(&mut mapper, &mut allocator, base, &frames).unwrap();
+        base
+    };
+    let base_a = map(task_a);
+    let base_b = map(task_b);
+    assert_eq!(base_a, VirtAddr::new(SHM_BASE));
+    assert_eq!(base_b, base_a + 3 * PAGE_SIZE as u64);
+    assert_eq!(registry.mapping_count(id), Some(2));
+
+    // What one task writes through its mapping the other reads through its own
+    let byte = |table: PhysFrame, addr: VirtAddr| {
+        let phys = unsafe { translate_addr_in(table, addr, offset) }.unwrap();
+        phys.as_u64() as *mut u8
+    };
+    unsafe { *byte(task_a, base_a + 4096u64 + 8) = 0x42 };
+    assert_eq!(unsafe { *byte(task_b, base_b + 4096u64 + 8) }, 0x42);
+    unsafe { *byte(task_b, base_b) = 0x17 };
+    assert_eq!(unsafe { *byte(task_a, base_a) }, 0x17);
+
+    // Only each task's own range is mapped in its address space
+    assert!(unsafe { translate_addr_in(task_a, base_b, offset) }.is_none());
+    assert!(unsafe { translate_addr_in(task_b, base_a, offset) }.is_none());
+
+    let mapping = ShmMapping {
+        id,
+        base: base_b,
+        pages: 2,
+    };
+    assert!(mapping.contains(base_b + 4095u64));
+    assert!(!mapping.contains(base_b + 2 * PAGE_SIZE as u64));
+}
+
+#[test]
+fn test_shm_frames_freed_with_last_mapping() {
+    let mut registry = ShmRegistry::new();
+    let id = registry.insert(frames(3));
+
+    registry.attach(id).unwrap();
+    registry.attach(id).unwrap();
+
+    // Still mapped twice, nothing to free and no new mappings allowed
+    assert_eq!(registry.destroy(id), Ok(None));
+    assert_eq!(registry.attach(id).err(), Some(Error::NotFound));
+    assert_eq!(registry.destroy(id), Err(Error::NotFound));
+
+    assert_eq!(registry.detach(id), None);
+    assert_eq!(registry.detach(id), Some(frames(3)));
+    assert_eq!(registry.mapping_count(id), None);
+
+    // An object nobody mapped is freed right away
+    let unused = registry.insert(frames(1));
+    assert_ne!(unused, id);
+    assert_eq!(registry.destroy(unused), Ok(Some(frames(1))));
+}
+
+#[test]
+fn test_shm_mappings_keep_object_alive_until_destroyed() {
+    let mut registry = ShmRegistry::new();
+    let id = registry.insert(frames(1));
+
+    registry.attach(id).unwrap();
+    assert_eq!(registry.detach(id), None);
+    assert_eq!(registry.mapping_count(id), Some(0));
+    assert!(registry.attach(id).is_ok());
+}
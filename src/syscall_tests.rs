@@ -0,0 +1,64 @@
+use kernel::tasks::abi::{self, CLOCK_MONOTONIC, EINVAL, ENOSYS, Timespec};
+use kernel::tasks::syscall::{self, Syscall};
+
+#[test]
+fn test_unknown_syscall_returns_enosys() {
+    assert_eq!(syscall::dispatch(12345, [0; 6]), abi::error(ENOSYS));
+    assert_eq!(syscall::dispatch(12345, [0; 6]), u64::MAX - 37);
+    assert_eq!(Syscall::from_number(12345), None);
+}
+
+#[test]
+fn test_syscall_numbers_round_trip() {
+    for number in 0..1024 {
+        if let Some(syscall) = Syscall::from_number(number) {
+            assert_eq!(syscall as u64, number);
+        }
+    }
+
+    assert_eq!(Syscall::from_number(1), Some(Syscall::Write));
+    assert_eq!(Syscall::from_number(60), Some(Syscall::Exit));
+    assert_eq!(Syscall::from_number(141), Some(Syscall::SetPriority));
+    assert_eq!(Syscall::from_number(69), Some(Syscall::Send));
+    assert_eq!(Syscall::from_number(70), Some(Syscall::Recv));
+    assert_eq!(Syscall::from_number(228), Some(Syscall::ClockGettime));
+}
+
+#[test]
+fn test_monotonic_clock_follows_the_ticks() {
+    let at = |ticks, hz| syscall::clock_at(CLOCK_MONOTONIC, ticks, hz).unwrap();
+
+    assert_eq!(at(0, 100), Timespec::default());
+    assert_eq!(
+        at(1, 100),
+        Timespec {
+            tv_sec: 0,
+            tv_nsec: 10_000_000
+        }
+    );
+    assert_eq!(
+        at(2501, 25),
+        Timespec {
+            tv_sec: 100,
+            tv_nsec: 40_000_000
+        }
+    );
+
+    // One more tick is always later
+    for ticks in [0, 24, 25, 999, 1_000_000] {
+        let (before, after) = (at(ticks, 25), at(ticks + 1, 25));
+        assert!((after.tv_sec, after.tv_nsec) > (before.tv_sec, before.tv_nsec));
+    }
+}
+
+#[test]
+fn test_unknown_clocks_are_rejected_before_touching_memory() {
+    assert_eq!(syscall::clock_at(0, 5, 25), Err(EINVAL));
+    assert_eq!(syscall::clock_now(42), Err(EINVAL));
+
+    // The pointer is never looked at, so a null one is fine here
+    assert_eq!(
+        syscall::dispatch(228, [42, 0, 0, 0, 0, 0]),
+        abi::error(EINVAL)
+    );
+}
@@ -0,0 +1,58 @@
+use std::collections::HashSet;
+
+use kernel::tasks::id::TaskIdAllocator;
+
+#[test]
+fn test_task_ids_are_reused_lowest_first() {
+    let ids = TaskIdAllocator::<2>::new();
+
+    // ID 0 is reserved
+    assert!(!ids.is_allocated(0));
+    assert_eq!(ids.allocate(), Some(1));
+    assert_eq!(ids.allocate(), Some(2));
+    assert_eq!(ids.allocate(), Some(3));
+    assert_eq!(ids.allocate(), Some(4));
+
+    // Exit tasks 3 and 2, the lowest free one comes back first
+    assert!(ids.release(3));
+    assert!(ids.release(2));
+    assert_eq!(ids.allocate(), Some(2));
+    assert_eq!(ids.allocate(), Some(3));
+    assert_eq!(ids.allocate(), Some(5));
+
+    // Releasing something that isn't allocated does nothing
+    assert!(!ids.release(0));
+    assert!(!ids.release(6));
+    assert!(!ids.release(1000));
+    assert!(ids.release(5));
+    assert!(!ids.release(5));
+    assert!(!ids.is_allocated(0));
+}
+
+#[test]
+fn test_task_ids_never_collide_among_live_tasks() {
+    let ids = TaskIdAllocator::<2>::new();
+    let mut live = Vec::new();
+
+    // Spawn and exit tasks in a pattern that keeps punching holes
+    for round in 0..1000u64 {
+        if round % 3 == 2 && !live.is_empty() {
+            let index = (round as usize * 7) % live.len();
+            let id = live.swap_remove(index);
+            assert!(ids.release(id));
+        } else if let Some(id) = ids.allocate() {
+            live.push(id);
+        }
+
+        let unique: HashSet<_> = live.iter().collect();
+        assert_eq!(unique.len(), live.len(), "Two live tasks share an ID");
+        assert!(live.iter().all(|&id| id != 0 && ids.is_allocated(id)));
+    }
+
+    // Every ID except 0 can be handed out, then we run dry
+    while let Some(id) = ids.allocate() {
+        live.push(id);
+    }
+    assert_eq!(live.len(), 127);
+    assert!(live.iter().all(|&id| (1..128).contains(&id)));
+}
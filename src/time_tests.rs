@@ -0,0 +1,95 @@
+use kernel::drivers::apic::timer_initial_count;
+use kernel::drivers::pit;
+use kernel::time;
+
+#[test]
+fn test_ticks_to_nanos() {
+    assert_eq!(time::ticks_to_nanos(0, 25), 0);
+    assert_eq!(time::ticks_to_nanos(1, 25), 40_000_000);
+    assert_eq!(time::ticks_to_nanos(25, 25), 1_000_000_000);
+    assert_eq!(time::ticks_to_nanos(3, 1000), 3_000_000);
+
+    // A year of ticks at 1kHz doesn't overflow
+    let year = 365 * 24 * 3600 * 1000;
+    assert_eq!(time::ticks_to_nanos(year, 1000), year * 1_000_000);
+}
+
+#[test]
+fn test_nanos_to_ticks_rounds_up() {
+    assert_eq!(time::nanos_to_ticks(0, 25), 0);
+    assert_eq!(time::nanos_to_ticks(1, 25), 1);
+    assert_eq!(time::nanos_to_ticks(40_000_000, 25), 1);
+    assert_eq!(time::nanos_to_ticks(40_000_001, 25), 2);
+}
+
+// The tick counter is global, this is the only test that advances it
+#[test]
+fn test_uptime_follows_the_tick_counter() {
+    let start = time::ticks();
+    let start_nanos = time::uptime_nanos();
+
+    for _ in 0..5 {
+        time::tick();
+    }
+
+    assert_eq!(time::ticks(), start + 5);
+    assert_eq!(
+        time::uptime_nanos() - start_nanos,
+        time::ticks_to_nanos(5, time::tick_frequency())
+    );
+}
+
+#[test]
+fn test_10ms_sleep_waits_at_least_10ms() {
+    for hz in [25, 100, 1000] {
+        let now = 1234;
+        let deadline = time::deadline_after(now, 10_000_000, hz);
+
+        // Even if the current tick is almost over, the ticks still to come cover 10ms
+        let waited = time::ticks_to_nanos(deadline - now - 1, hz);
+        assert!(waited >= 10_000_000, "{} Hz: only {} ns", hz, waited);
+    }
+
+    // 10ms at 1kHz is 10 ticks, plus the one we're in
+    assert_eq!(time::deadline_after(0, 10_000_000, 1000), 11);
+    assert_eq!(time::deadline_after(5, 0, 1000), 5);
+}
+
+#[test]
+fn test_micros_to_pit_ticks() {
+    assert_eq!(pit::micros_to_pit_ticks(0), 0);
+    assert_eq!(pit::micros_to_pit_ticks(1), 2);
+    assert_eq!(pit::micros_to_pit_ticks(1_000_000), pit::PIT_FREQUENCY);
+    // A 10ms stall is ~11932 PIT cycles, rounded up
+    assert_eq!(pit::micros_to_pit_ticks(10_000), 11932);
+}
+
+#[test]
+fn test_pit_divisor() {
+    // 1193182 / 100 = 11931.82, rounded to the closest divisor
+    assert_eq!(pit::divisor_for(100), Ok(11932));
+    assert_eq!(pit::frequency_for(11932), 99);
+    assert_eq!(pit::divisor_for(1000), Ok(1193));
+    assert_eq!(pit::frequency_for(1193), 1000);
+
+    // The 16 bit divisor can't go below ~19Hz, and mode 3 needs a divisor of at least 2
+    assert_eq!(pit::divisor_for(19), Ok(62799));
+    assert!(pit::divisor_for(18).is_err());
+    assert!(pit::divisor_for(0).is_err());
+    assert_eq!(pit::divisor_for(596_591), Ok(2));
+    assert!(pit::divisor_for(1_000_000).is_err());
+}
+
+#[test]
+fn test_apic_timer_calibration() {
+    // QEMU: 1GHz bus / 16 = 625000 counts in 10ms, 2_500_000 per tick at 25Hz
+    assert_eq!(
+        timer_initial_count(625_000, 10_000, 25),
+        Some((2_500_000, 25))
+    );
+    // Uneven rates round the count down, the real rate comes back
+    assert_eq!(timer_initial_count(1_000, 10_000, 30), Some((3333, 30)));
+    // Faster than the timer counts: fire on every count
+    assert_eq!(timer_initial_count(10, 10_000, 10_000), Some((1, 1000)));
+    assert_eq!(timer_initial_count(0, 10_000, 25), None);
+}
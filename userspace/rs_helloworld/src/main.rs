@@ -9,18 +9,74 @@ fn main() -> ! {
     print("More test");
     print("Test");
 
+    // The monotonic clock has to move while we sleep
+    let before = clock_gettime();
+    sleep(100_000_000);
+    let after = clock_gettime();
+    if (after.tv_sec, after.tv_nsec) > (before.tv_sec, before.tv_nsec) {
+        print("Clock advanced");
+    } else {
+        print("Clock didn't advance");
+    }
+
     loop {
         print("Hello, world!");
 
-        // Sleep for a bit to avoid spamming the output too much
-        for _ in 0..1_000_000 {
-            unsafe {
-                core::arch::asm!("pause");
-            }
-        }
+        // Sleep for a bit to avoid spamming the output too much, the other tasks get the CPU meanwhile
+        sleep(500_000_000);
     }
 }
 
+/// Same layout as the kernel's (and Linux's) struct timespec
+#[repr(C)]
+#[derive(Default)]
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+fn sleep(nanos: u64) {
+    let duration = Timespec {
+        tv_sec: (nanos / 1_000_000_000) as i64,
+        tv_nsec: (nanos % 1_000_000_000) as i64,
+    };
+
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 35u64 => _,            // syscall number 35 = nanosleep
+            in("rdi") &duration as *const Timespec, // how long to sleep
+            in("rsi") 0u64,                         // no remaining time wanted
+
+            lateout("rcx") _,
+            lateout("r11") _,
+
+            options(nostack)
+        );
+    }
+}
+
+/// Time since boot
+fn clock_gettime() -> Timespec {
+    let mut now = Timespec::default();
+
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") 228u64 => _,         // syscall number 228 = clock_gettime
+            in("rdi") 1u64,                       // CLOCK_MONOTONIC
+            in("rsi") &mut now as *mut Timespec,  // where the kernel writes the time
+
+            lateout("rcx") _,
+            lateout("r11") _,
+
+            options(nostack)
+        );
+    }
+
+    now
+}
+
 fn print(s: &str) {
     unsafe {
         core::arch::asm!(
@@ -0,0 +1,6 @@
+# User programs for the kernel selftests, one binary each
+cargo build --release --target x86_64-unknown-none
+
+# Copy to kernel resources
+Copy-Item target\x86_64-unknown-none\release\syscall_args ..\..\kernel\src\resources\syscall_args.elf -Force
+Copy-Item target\x86_64-unknown-none\release\alignment_check ..\..\kernel\src\resources\alignment_check.elf -Force
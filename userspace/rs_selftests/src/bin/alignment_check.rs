@@ -0,0 +1,49 @@
+// User program of the kernel's alignment check selftest
+//
+// Turns on alignment checking and reads from a misaligned address, the kernel should kill us with
+// an #AC fault. The kernel checks how we exited and exits QEMU.
+
+#![no_std]
+#![no_main]
+
+use core::arch::global_asm;
+
+/// RFLAGS.AC, alignment checking in ring 3 (the kernel sets CR0.AM)
+const RFLAGS_AC: u64 = 1 << 18;
+
+static BUFFER: [u64; 2] = [0; 2];
+
+#[unsafe(no_mangle)]
+fn main() -> ! {
+    let misaligned = BUFFER.as_ptr() as usize + 1;
+
+    unsafe {
+        core::arch::asm!(
+            "pushfq",
+            "or qword ptr [rsp], {ac}",
+            "popfq",
+            "mov {tmp}, qword ptr [{ptr}]",
+            ac = in(reg) RFLAGS_AC,
+            ptr = in(reg) misaligned,
+            tmp = out(reg) _,
+        );
+    }
+
+    // The kernel gives up on us after a while, we only get here if the load didn't fault
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// Start our program
+global_asm!(
+    r#".global _start
+    _start:
+    call main
+"#
+);
@@ -0,0 +1,51 @@
+// User program of the kernel's syscall selftest
+//
+// Makes the selftest syscall with six different arguments, the kernel checks that each of them
+// arrived in the right place and exits QEMU.
+
+#![no_std]
+#![no_main]
+
+use core::arch::global_asm;
+
+/// Syscall number the `syscall_selftest` kernel checks the arguments of
+const SELFTEST_ARGS: u64 = 0x5E1F;
+
+#[unsafe(no_mangle)]
+fn main() -> ! {
+    unsafe {
+        core::arch::asm!(
+            "syscall",
+            inlateout("rax") SELFTEST_ARGS => _,
+            in("rdi") 0x1111_1111_1111_1111u64, // arg1
+            in("rsi") 0x2222_2222_2222_2222u64, // arg2
+            in("rdx") 0x3333_3333_3333_3333u64, // arg3
+            in("r10") 0x4444_4444_4444_4444u64, // arg4
+            in("r8") 0x5555_5555_5555_5555u64,  // arg5
+            in("r9") 0x6666_6666_6666_6666u64,  // arg6
+
+            lateout("rcx") _,
+            lateout("r11") _,
+
+            options(nostack)
+        );
+    }
+
+    // The kernel ends the test, we only get here if it doesn't
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
+
+// Start our program
+global_asm!(
+    r#".global _start
+    _start:
+    call main
+"#
+);